@@ -9,9 +9,11 @@ use std::process::{Command, ExitStatus, Stdio};
 
 use log::{debug, error, trace, warn};
 
+use lazy_static::lazy_static;
 use regex::Regex;
 
 pub(crate) mod stage2_config;
+pub(crate) mod stage2_report;
 
 pub(crate) mod defs;
 
@@ -29,11 +31,18 @@ use crate::common::defs::{OLD_ROOT_MP, PIDOF_CMD, WHEREIS_CMD};
 use nix::unistd::sync;
 pub use options::Options;
 
+pub(crate) mod cmd_runner;
+use cmd_runner::CommandRunner;
 pub(crate) mod debug;
 pub(crate) mod disk_util;
+pub(crate) mod flash_transform;
+pub(crate) mod image_format;
+pub(crate) mod image_slices;
+pub(crate) mod log_writer;
 pub(crate) mod stream_progress;
 
 const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
+pub(crate) const REDACTED: &str = "<redacted>";
 const OS_RELEASE_FILE: &str = "/etc/os-release";
 
 #[derive(Debug)]
@@ -53,7 +62,7 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
         .output()
     {
         Ok(output) => {
-            debug!("call: output: {:?}", output);
+            debug!("call: output: {}", redact(&format!("{:?}", output)));
             Ok(CmdRes {
                 stdout: if trim_stdout {
                     String::from(String::from_utf8_lossy(&output.stdout).trim())
@@ -74,7 +83,7 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
     }
 }
 
-pub(crate) fn whereis(cmd: &str) -> Result<String> {
+pub(crate) fn whereis(cmd: &str, runner: &dyn CommandRunner) -> Result<String> {
     const BIN_DIRS: &[&str] = &["./", "/bin", "/usr/bin", "/sbin", "/usr/sbin"];
     // try manually first
     for path in BIN_DIRS {
@@ -86,7 +95,7 @@ pub(crate) fn whereis(cmd: &str) -> Result<String> {
 
     // else try whereis command
     let args: [&str; 2] = ["-b", cmd];
-    let cmd_res = match call(WHEREIS_CMD, &args, true) {
+    let cmd_res = match runner.call(WHEREIS_CMD, &args, true) {
         Ok(cmd_res) => cmd_res,
         Err(why) => {
             // manually try the usual suspects
@@ -129,8 +138,8 @@ pub(crate) fn whereis(cmd: &str) -> Result<String> {
     }
 }
 
-pub(crate) fn pidof(proc_name: &str) -> Result<Vec<u32>> {
-    let cmd_res = call(PIDOF_CMD, &[proc_name], true)?;
+pub(crate) fn pidof(proc_name: &str, runner: &dyn CommandRunner) -> Result<Vec<u32>> {
+    let cmd_res = runner.call(PIDOF_CMD, &[proc_name], true)?;
     let mut res: Vec<u32> = Vec::new();
     if cmd_res.status.success() {
         for pid in cmd_res.stdout.split_whitespace() {
@@ -232,6 +241,74 @@ pub(crate) fn parse_file<P: AsRef<Path>>(fname: P, regex: &Regex) -> Result<Opti
     Ok(None)
 }
 
+/// CRC32 (IEEE 802.3) as used by zlib
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// continue a CRC32 calculated by crc32 with more data
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc: u32 = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = 0u32.wrapping_sub(crc & 1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Remove api keys and authorization tokens from text that is about to be logged
+pub(crate) fn redact(text: &str) -> String {
+    lazy_static! {
+        static ref JSON_KEY_REGEX: Regex =
+            Regex::new(r#"(?i)("(api_?key|device_?api_?key|token)"\s*:\s*)"[^"]*""#).unwrap();
+        static ref AUTH_REGEX: Regex =
+            Regex::new(r#"(?i)(authorization"?\s*[:=]\s*"?(bearer\s+)?)[^\s",}]+"#).unwrap();
+        static ref ENV_REGEX: Regex = Regex::new(
+            r#"(?i)\b(\w*(token|secret|password|passwd|api_?key|credential)\w*=)[^\s"']+"#
+        )
+        .unwrap();
+        // WiFi keys in NetworkManager keyfiles, wpa_supplicant and connman configurations
+        static ref PSK_REGEX: Regex = Regex::new(
+            r#"(?i)\b(\w*(psk|passphrase|password|wep[-_]key\d)\w*[ \t]*=[ \t]*)("[^"]*"|[^\r\n]+)"#
+        )
+        .unwrap();
+    }
+
+    let text = JSON_KEY_REGEX.replace_all(text, format!(r#"$1"{}""#, REDACTED).as_str());
+    let text = AUTH_REGEX.replace_all(&text, format!("${{1}}{}", REDACTED).as_str());
+    let text = ENV_REGEX.replace_all(&text, format!("${{1}}{}", REDACTED).as_str());
+    PSK_REGEX
+        .replace_all(&text, format!("${{1}}{}", REDACTED).as_str())
+        .to_string()
+}
+
+/// Parse an environment variable given as KEY=VALUE, KEY has to be a valid shell variable name
+pub(crate) fn parse_env_var(value: &str) -> Result<(String, String)> {
+    lazy_static! {
+        static ref ENV_KEY_REGEX: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    }
+
+    let invalid = || {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid environment variable '{}', expected KEY=VALUE",
+                redact(value)
+            ),
+        )
+    };
+
+    let pos = value.find('=').ok_or_else(invalid)?;
+    let key = &value[..pos];
+    if !ENV_KEY_REGEX.is_match(key) {
+        return Err(invalid());
+    }
+    Ok((key.to_string(), value[pos + 1..].to_string()))
+}
+
 const GIB_SIZE: u64 = 1024 * 1024 * 1024;
 const MIB_SIZE: u64 = 1024 * 1024;
 const KIB_SIZE: u64 = 1024;
@@ -394,10 +471,92 @@ pub(crate) fn log(text: &str) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::cmd_runner::mock::MockCommandRunner;
+
+    #[test]
+    fn test_pidof() {
+        let mut runner = MockCommandRunner::new();
+        runner.set_result(PIDOF_CMD, 0, "1234 567\n", "");
+        assert_eq!(pidof("NetworkManager", &runner).unwrap(), vec![1234, 567]);
+        assert_eq!(runner.calls(), vec!["pidof NetworkManager".to_string()]);
+
+        // pidof fails if no process is found
+        runner.set_result(PIDOF_CMD, 1, "", "");
+        assert!(pidof("wpa_supplicant", &runner).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
     #[test]
     fn test_path_to_cstring() {
         const PATH: &str = "/bla/blub";
         let c_path = path_to_cstring(PATH).unwrap();
         assert_eq!(&*c_path.to_string_lossy(), PATH);
     }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(r#"{"apiKey":"secret","deviceApiKey": "secret2","deviceType":"intel-nuc"}"#),
+            r#"{"apiKey":"<redacted>","deviceApiKey": "<redacted>","deviceType":"intel-nuc"}"#
+        );
+        assert_eq!(
+            redact(r#"{"authorization": "Bearer secret"}"#),
+            r#"{"authorization": "Bearer <redacted>"}"#
+        );
+        assert_eq!(
+            redact("env:\n  - DEPLOY_TOKEN=secret\n  - SITE=lab-3"),
+            "env:\n  - DEPLOY_TOKEN=<redacted>\n  - SITE=lab-3"
+        );
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn test_redact_wifi_keys() {
+        let nwmgr_file =
+            "[wifi]\nssid=lab-3\n\n[wifi-security]\nkey-mgmt=wpa-psk\npsk=very secret\n";
+        assert_eq!(
+            redact(nwmgr_file),
+            "[wifi]\nssid=lab-3\n\n[wifi-security]\nkey-mgmt=wpa-psk\npsk=<redacted>\n"
+        );
+
+        let wpa_config =
+            "network={\n\tssid=\"lab-3\"\n\tpsk=\"very secret\"\n\twep_key0=0102030405\n}";
+        let redacted = redact(wpa_config);
+        assert!(!redacted.contains("secret"));
+        assert!(!redacted.contains("0102030405"));
+        assert!(redacted.contains("ssid=\"lab-3\""));
+
+        assert_eq!(
+            redact("[service_wifi]\nPassphrase=hunter2\nName=lab-3"),
+            "[service_wifi]\nPassphrase=<redacted>\nName=lab-3"
+        );
+        assert_eq!(
+            redact("802-1x password = hunter2"),
+            "802-1x password = <redacted>"
+        );
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(
+            parse_env_var("SITE=lab-3").unwrap(),
+            ("SITE".to_string(), "lab-3".to_string())
+        );
+        assert_eq!(
+            parse_env_var("_OPTS=a=b").unwrap(),
+            ("_OPTS".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_env_var("EMPTY=").unwrap(),
+            ("EMPTY".to_string(), String::new())
+        );
+        assert!(parse_env_var("SITE").is_err());
+        assert!(parse_env_var("1SITE=x").is_err());
+        assert!(parse_env_var("MY-SITE=x").is_err());
+    }
 }
@@ -0,0 +1,120 @@
+use crate::common::{
+    call,
+    error::{Error, ErrorKind, Result},
+    CmdRes,
+};
+
+pub(crate) trait CommandRunner {
+    fn call(&self, cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes>;
+
+    fn call_command(&self, cmd: &str, args: &[&str], err_msg: &str) -> Result<String> {
+        let cmd_res = self.call(cmd, args, true)?;
+        if cmd_res.status.success() {
+            Ok(cmd_res.stdout)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::ExecProcess,
+                &format!("{}, stderr: {}", err_msg, cmd_res.stderr),
+            ))
+        }
+    }
+}
+
+/// Runs commands on the host system
+pub(crate) struct SysCommandRunner {}
+
+impl SysCommandRunner {
+    pub fn new() -> SysCommandRunner {
+        SysCommandRunner {}
+    }
+}
+
+impl CommandRunner for SysCommandRunner {
+    fn call(&self, cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
+        call(cmd, args, trim_stdout)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use super::CommandRunner;
+    use crate::common::{error::Result, CmdRes};
+
+    /// Records all calls and returns canned results, unknown commands succeed with empty output
+    pub(crate) struct MockCommandRunner {
+        results: HashMap<String, (i32, String, String)>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl MockCommandRunner {
+        pub fn new() -> MockCommandRunner {
+            MockCommandRunner {
+                results: HashMap::new(),
+                calls: RefCell::new(Vec::new()),
+            }
+        }
+
+        pub fn set_result(&mut self, cmd: &str, exit_code: i32, stdout: &str, stderr: &str) {
+            self.results.insert(
+                cmd.to_owned(),
+                (exit_code, stdout.to_owned(), stderr.to_owned()),
+            );
+        }
+
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl CommandRunner for MockCommandRunner {
+        fn call(&self, cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
+            let mut call_str = cmd.to_owned();
+            for arg in args {
+                call_str.push(' ');
+                call_str.push_str(arg);
+            }
+            self.calls.borrow_mut().push(call_str);
+
+            let (exit_code, stdout, stderr) = if let Some(result) = self.results.get(cmd) {
+                result.clone()
+            } else {
+                (0, String::new(), String::new())
+            };
+
+            Ok(CmdRes {
+                stdout: if trim_stdout {
+                    stdout.trim().to_owned()
+                } else {
+                    stdout
+                },
+                stderr,
+                status: ExitStatus::from_raw(exit_code << 8),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockCommandRunner;
+    use super::*;
+
+    #[test]
+    fn test_mock_call_command() {
+        let mut runner = MockCommandRunner::new();
+        runner.set_result("swapoff", 1, "", "permission denied");
+
+        assert_eq!(
+            runner.call_command("telinit", &["u"], "failed").unwrap(),
+            ""
+        );
+        let res = runner.call_command("swapoff", &["-a"], "failed");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::ExecProcess);
+        assert_eq!(runner.calls(), vec!["telinit u", "swapoff -a"]);
+    }
+}
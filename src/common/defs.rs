@@ -7,14 +7,25 @@ pub(crate) const PIDOF_CMD: &str = "pidof";
 pub(crate) const PIVOT_ROOT_CMD: &str = "pivot_root";
 pub(crate) const MOUNT_CMD: &str = "mount";
 pub(crate) const BLKID_CMD: &str = "blkid";
+pub(crate) const MODPROBE_CMD: &str = "modprobe";
 
 pub(crate) const EFIBOOTMGR_CMD: &str = "efibootmgr";
+pub(crate) const TUNE2FS_CMD: &str = "tune2fs";
+pub(crate) const E2FSCK_CMD: &str = "e2fsck";
+pub(crate) const FSCK_VFAT_CMD: &str = "fsck.vfat";
 pub(crate) const DD_CMD: &str = "dd";
+pub(crate) const BUSYBOX_CMD: &str = "busybox";
+pub(crate) const SFDISK_CMD: &str = "sfdisk";
 
 pub(crate) const TAR_CMD: &str = "tar";
+pub(crate) const GPG_CMD: &str = "gpg";
 
 pub(crate) const TAKEOVER_DIR: &str = "/balena-takeover";
 pub(crate) const STAGE2_CONFIG_NAME: &str = "stage2-config.yml";
+// created by the new init in TAKEOVER_DIR to signal that init was restarted
+pub(crate) const INIT_STARTED_NAME: &str = "init-started";
+// path of the stage2 script given with --stage2-script, below the new root
+pub(crate) const STAGE2_SCRIPT_PATH: &str = "/bin/stage2.sh";
 
 pub(crate) const BALENA_IMAGE_NAME: &str = "balena.img.gz";
 pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img.gz";
@@ -22,6 +33,7 @@ pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img.gz";
 pub(crate) const BALENA_CONFIG_PATH: &str = "/config.json";
 
 pub const DISK_BY_LABEL_PATH: &str = "/dev/disk/by-label";
+pub const DISK_BY_PATH_PATH: &str = "/dev/disk/by-path";
 
 pub const BALENA_BOOT_PART: &str = "resin-boot";
 pub const BALENA_BOOT_FSTYPE: &str = "vfat";
@@ -34,12 +46,27 @@ pub const BALENA_BOOT_MP: &str = "/mnt/balena-boot";
 pub const BALENA_PART_MP: &str = "/mnt/balena-part";
 
 pub const SYSTEM_CONNECTIONS_DIR: &str = "system-connections";
+pub const SYSTEMD_NETWORK_DIR: &str = "system-network";
+pub const CONNMAN_SERVICES_DIR: &str = "system-services";
 
 pub const SYS_EFI_DIR: &str = "/sys/firmware/efi";
 pub const SYS_EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
 
 pub const BACKUP_ARCH_NAME: &str = "backup.tgz";
 
+// stage2 log kept on the balena boot partition, the last BOOT_LOG_MAX_SIZE bytes of plain text
+pub const BOOT_LOG_NAME: &str = "/balena-takeover.log";
+pub const BOOT_LOG_MAX_SIZE: u64 = 256 * 1024;
+// stage2 report kept on the balena boot partition
+pub const BOOT_REPORT_NAME: &str = "/balena-takeover-report.json";
+// timezone and locale of the old system, written with --preserve-timezone / --preserve-locale
+pub const BOOT_TIMEZONE_NAME: &str = "/timezone";
+pub const BOOT_LOCALE_NAME: &str = "/locale.conf";
+
+pub const DEF_FLASH_BLOCK_SIZE: u64 = 128 * 1024;
+// SD cards and eMMC prefer writes aligned to their erase block size
+pub const SD_FLASH_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
 pub const NIX_NONE: Option<&'static [u8]> = None;
 
 cfg_if::cfg_if! {
@@ -27,6 +27,18 @@ pub(crate) use plain_file::PlainFile;
 
 pub(crate) const DEF_BLOCK_SIZE: usize = 512;
 
+const FS_LABEL_BUFFER_SIZE: usize = 2048;
+const EXT_SUPERBLOCK_OFFSET: usize = 1024;
+const EXT_MAGIC_OFFSET: usize = EXT_SUPERBLOCK_OFFSET + 0x38;
+const EXT_LABEL_OFFSET: usize = EXT_SUPERBLOCK_OFFSET + 0x78;
+const EXT_LABEL_SIZE: usize = 16;
+const EXT_MAGIC: u16 = 0xEF53;
+const FAT_LABEL_SIZE: usize = 11;
+const FAT16_LABEL_OFFSET: usize = 0x2B;
+const FAT16_FSTYPE_OFFSET: usize = 0x36;
+const FAT32_LABEL_OFFSET: usize = 0x47;
+const FAT32_FSTYPE_OFFSET: usize = 0x52;
+
 #[derive(Debug)]
 pub(crate) enum LabelType {
     GPT,
@@ -160,9 +172,18 @@ impl Disk {
         drive: P,
         // writable: bool,
         block_size: Option<u64>,
+    ) -> Result<Disk> {
+        Disk::from_drive_file_at(drive, block_size, 0)
+    }
+
+    /// open a drive file, treating the data at base_offset as the start of the disk
+    pub fn from_drive_file_at<P: AsRef<Path>>(
+        drive: P,
+        block_size: Option<u64>,
+        base_offset: u64,
     ) -> Result<Disk> {
         Ok(Disk {
-            disk: Box::new(PlainFile::new(drive.as_ref())?),
+            disk: Box::new(PlainFile::new(drive.as_ref(), base_offset)?),
             // writable,
             block_size: if let Some(block_size) = block_size {
                 block_size
@@ -172,6 +193,14 @@ impl Disk {
         })
     }
 
+    /// read the file system label of a vfat or ext2/3/4 partition
+    pub fn get_fs_label(&mut self, part: &PartInfo) -> Result<Option<String>> {
+        let mut buffer: [u8; FS_LABEL_BUFFER_SIZE] = [0; FS_LABEL_BUFFER_SIZE];
+        self.disk
+            .fill(part.start_lba * self.block_size, &mut buffer)?;
+        Ok(fs_label_from_buffer(&buffer))
+    }
+
     pub fn get_image_file(&self) -> PathBuf {
         self.disk.get_path()
     }
@@ -409,6 +438,43 @@ impl<'a> Iterator for PartitionIterator<'a> {
     }
 }
 
+fn label_from_bytes(bytes: &[u8]) -> Option<String> {
+    let label = String::from_utf8_lossy(bytes)
+        .trim_end_matches(|c: char| c == ' ' || c == '\0')
+        .to_string();
+    if label.is_empty() || label == "NO NAME" {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// extract a vfat or ext2/3/4 file system label from the first bytes of a partition
+pub(crate) fn fs_label_from_buffer(buffer: &[u8]) -> Option<String> {
+    if buffer.len() < FS_LABEL_BUFFER_SIZE {
+        return None;
+    }
+
+    let ext_magic = u16::from_le_bytes([buffer[EXT_MAGIC_OFFSET], buffer[EXT_MAGIC_OFFSET + 1]]);
+    if ext_magic == EXT_MAGIC {
+        return label_from_bytes(&buffer[EXT_LABEL_OFFSET..EXT_LABEL_OFFSET + EXT_LABEL_SIZE]);
+    }
+
+    if buffer[510] == 0x55 && buffer[511] == 0xAA {
+        if &buffer[FAT32_FSTYPE_OFFSET..FAT32_FSTYPE_OFFSET + 5] == b"FAT32" {
+            return label_from_bytes(
+                &buffer[FAT32_LABEL_OFFSET..FAT32_LABEL_OFFSET + FAT_LABEL_SIZE],
+            );
+        } else if &buffer[FAT16_FSTYPE_OFFSET..FAT16_FSTYPE_OFFSET + 3] == b"FAT" {
+            return label_from_bytes(
+                &buffer[FAT16_LABEL_OFFSET..FAT16_LABEL_OFFSET + FAT_LABEL_SIZE],
+            );
+        }
+    }
+
+    None
+}
+
 #[allow(dead_code)]
 pub(crate) struct PartitionReader<'a> {
     disk: &'a mut Disk,
@@ -469,7 +535,10 @@ impl<'a> Read for PartitionReader<'a> {
 
 mod test {
     use crate::common::disk_util::PartitionIterator;
-    use crate::common::disk_util::{Disk, LabelType};
+    use crate::common::disk_util::{
+        fs_label_from_buffer, Disk, LabelType, EXT_LABEL_OFFSET, EXT_MAGIC_OFFSET,
+        FAT32_FSTYPE_OFFSET, FAT32_LABEL_OFFSET, FS_LABEL_BUFFER_SIZE,
+    };
     use crate::common::path_append;
     use std::path::{Path, PathBuf};
 
@@ -511,4 +580,28 @@ mod test {
             panic!("Invalid label type - not Dos");
         }
     }
+
+    #[test]
+    fn read_fs_labels() {
+        let mut buffer: [u8; FS_LABEL_BUFFER_SIZE] = [0; FS_LABEL_BUFFER_SIZE];
+        assert_eq!(fs_label_from_buffer(&buffer), None);
+
+        buffer[510] = 0x55;
+        buffer[511] = 0xAA;
+        buffer[FAT32_FSTYPE_OFFSET..FAT32_FSTYPE_OFFSET + 8].copy_from_slice(b"FAT32   ");
+        buffer[FAT32_LABEL_OFFSET..FAT32_LABEL_OFFSET + 11].copy_from_slice(b"resin-boot ");
+        assert_eq!(
+            fs_label_from_buffer(&buffer),
+            Some("resin-boot".to_string())
+        );
+
+        let mut buffer: [u8; FS_LABEL_BUFFER_SIZE] = [0; FS_LABEL_BUFFER_SIZE];
+        buffer[EXT_MAGIC_OFFSET] = 0x53;
+        buffer[EXT_MAGIC_OFFSET + 1] = 0xEF;
+        buffer[EXT_LABEL_OFFSET..EXT_LABEL_OFFSET + 10].copy_from_slice(b"resin-data");
+        assert_eq!(
+            fs_label_from_buffer(&buffer),
+            Some("resin-data".to_string())
+        );
+    }
 }
@@ -7,10 +7,11 @@ use crate::common::{disk_util::image_file::ImageFile, Error, ErrorKind, Result,
 pub(crate) struct PlainFile {
     path: PathBuf,
     file: File,
+    base_offset: u64,
 }
 
 impl PlainFile {
-    pub fn new(path: &Path) -> Result<PlainFile> {
+    pub fn new(path: &Path, base_offset: u64) -> Result<PlainFile> {
         let file = match OpenOptions::new()
             .write(false)
             .read(true)
@@ -33,6 +34,7 @@ impl PlainFile {
         Ok(PlainFile {
             path: path.to_path_buf(),
             file,
+            base_offset,
         })
     }
 }
@@ -40,7 +42,7 @@ impl PlainFile {
 impl ImageFile for PlainFile {
     fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
         self.file
-            .seek(SeekFrom::Start(offset))
+            .seek(SeekFrom::Start(self.base_offset + offset))
             .upstream_with_context(&format!("failed to seek to offset {}", offset))?;
         match self.file.read_exact(buffer) {
             Ok(_) => Ok(()),
@@ -18,6 +18,7 @@ pub enum ErrorKind {
     Permission,
     FileExists,
     NotPermitted,
+    PartitionFull,
     Displayed,
 }
 
@@ -37,6 +38,7 @@ impl Display for ErrorKind {
             Self::Permission => "Permission was denied",
             Self::NotPermitted => "Operation is not permitted",
             Self::FileExists => "The file exists",
+            Self::PartitionFull => "The target partition is full",
             Self::Displayed => "The error was displayed upstream",
         };
         write!(f, "{}", output)
@@ -0,0 +1,192 @@
+use std::cmp::{max, min};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::error::{Error, ErrorKind, Result, ToError};
+
+/// The transforms selected with --flash-transform, their data is given by separate options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashTransformKind {
+    /// overwrite the image at an offset with the content of a file, see --patch
+    Patch,
+    /// XOR the image with a repeated key, see --xor-key
+    Xor,
+}
+
+impl FromStr for FlashTransformKind {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "patch" => Ok(FlashTransformKind::Patch),
+            "xor" => Ok(FlashTransformKind::Xor),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid flash transform: '{}', expected one of patch, xor",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// A transform applied to the decompressed image on its way to the flash device
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) enum FlashTransform {
+    Patch { offset: u64, data: Vec<u8> },
+    Xor { key: Vec<u8> },
+}
+
+impl FlashTransform {
+    /// transform buffer, which holds the image data starting at image offset pos
+    fn apply(&self, pos: u64, buffer: &mut [u8]) {
+        match self {
+            FlashTransform::Patch { offset, data } => {
+                let start = max(*offset, pos);
+                let end = min(offset + data.len() as u64, pos + buffer.len() as u64);
+                if start < end {
+                    buffer[(start - pos) as usize..(end - pos) as usize]
+                        .copy_from_slice(&data[(start - offset) as usize..(end - offset) as usize]);
+                }
+            }
+            FlashTransform::Xor { key } => {
+                if key.is_empty() {
+                    return;
+                }
+                let key_len = key.len() as u64;
+                for (idx, byte) in buffer.iter_mut().enumerate() {
+                    *byte ^= key[((pos + idx as u64) % key_len) as usize];
+                }
+            }
+        }
+    }
+}
+
+/// Parse a --patch value of the form OFFSET=FILE, OFFSET is decimal or hex with a 0x prefix
+pub(crate) fn parse_patch(value: &str) -> Result<(u64, PathBuf)> {
+    let invalid = || {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid patch '{}', expected OFFSET=FILE", value),
+        )
+    };
+
+    let pos = value.find('=').ok_or_else(invalid)?;
+    let (offset, file) = (value[..pos].trim(), &value[pos + 1..]);
+    if file.is_empty() {
+        return Err(invalid());
+    }
+
+    let offset = if offset.starts_with("0x") || offset.starts_with("0X") {
+        u64::from_str_radix(&offset[2..], 16)
+    } else {
+        offset.parse::<u64>()
+    }
+    .error_with_all(
+        ErrorKind::InvParam,
+        &format!("Invalid offset in patch '{}'", value),
+    )?;
+
+    Ok((offset, PathBuf::from(file)))
+}
+
+/// Applies the transforms in order to the data read from the inner reader
+pub(crate) struct TransformReader {
+    inner: Box<dyn Read>,
+    transforms: Vec<FlashTransform>,
+    pos: u64,
+}
+
+impl Read for TransformReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buffer)?;
+        for transform in &self.transforms {
+            transform.apply(self.pos, &mut buffer[..bytes_read]);
+        }
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+/// Wrap the image stream in a TransformReader, the stream is returned as is without transforms
+pub(crate) fn apply_transforms(
+    reader: Box<dyn Read>,
+    transforms: &[FlashTransform],
+) -> Box<dyn Read> {
+    if transforms.is_empty() {
+        reader
+    } else {
+        Box::new(TransformReader {
+            inner: reader,
+            transforms: transforms.to_vec(),
+            pos: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// returns at most chunk_size bytes per read like a pipe or decoder would
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let len = min(
+                min(buffer.len(), self.chunk_size),
+                self.data.len() - self.pos,
+            );
+            buffer[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_transform_reader() {
+        let reader = Box::new(ChunkedReader {
+            data: vec![0u8; 16],
+            pos: 0,
+            chunk_size: 5,
+        });
+        let mut reader = apply_transforms(
+            reader,
+            &[
+                FlashTransform::Xor { key: vec![1, 2] },
+                FlashTransform::Patch {
+                    offset: 3,
+                    data: vec![0xaa, 0xbb, 0xcc],
+                },
+            ],
+        );
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(
+            output,
+            vec![1, 2, 1, 0xaa, 0xbb, 0xcc, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        assert_eq!(
+            parse_patch("0x200=serial.bin").unwrap(),
+            (0x200, PathBuf::from("serial.bin"))
+        );
+        assert_eq!(
+            parse_patch("1024=/tmp/a=b").unwrap(),
+            (1024, PathBuf::from("/tmp/a=b"))
+        );
+        assert!(parse_patch("serial.bin").is_err());
+        assert!(parse_patch("12=").is_err());
+        assert!(parse_patch("0xzz=serial.bin").is_err());
+    }
+}
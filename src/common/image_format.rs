@@ -0,0 +1,176 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use xz2::read::XzDecoder;
+
+use crate::common::error::{Error, ErrorKind, Result, ToError};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const MAX_MAGIC_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) enum ImageFormat {
+    Raw,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl ImageFormat {
+    fn magic(self) -> Option<&'static [u8]> {
+        match self {
+            ImageFormat::Raw => None,
+            ImageFormat::Gzip => Some(GZIP_MAGIC),
+            ImageFormat::Xz => Some(XZ_MAGIC),
+            ImageFormat::Zstd => Some(ZSTD_MAGIC),
+        }
+    }
+}
+
+impl Display for ImageFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Gzip => "gzip",
+            ImageFormat::Xz => "xz",
+            ImageFormat::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "raw" => Ok(ImageFormat::Raw),
+            "gzip" => Ok(ImageFormat::Gzip),
+            "xz" => Ok(ImageFormat::Xz),
+            "zstd" => Ok(ImageFormat::Zstd),
+            "bmap" => Err(Error::with_context(
+                ErrorKind::InvParam,
+                "Image format bmap is not supported, images are always written completely",
+            )),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid image format: '{}', expected one of raw, gzip, xz, zstd",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+fn read_magic(image_path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(image_path).upstream_with_context(&format!(
+        "Failed to open image file '{}'",
+        image_path.display()
+    ))?;
+
+    let mut magic: Vec<u8> = Vec::new();
+    file.take(MAX_MAGIC_LEN as u64)
+        .read_to_end(&mut magic)
+        .upstream_with_context(&format!(
+            "Failed to read from image file '{}'",
+            image_path.display()
+        ))?;
+    Ok(magic)
+}
+
+/// Determine the image format from the magic bytes at the start of the file, files without
+/// known magic bytes are raw images
+pub(crate) fn detect_image_format(image_path: &Path) -> Result<ImageFormat> {
+    let magic = read_magic(image_path)?;
+    let format = [ImageFormat::Gzip, ImageFormat::Xz, ImageFormat::Zstd]
+        .iter()
+        .find(|format| magic.starts_with(format.magic().unwrap()))
+        .copied()
+        .unwrap_or(ImageFormat::Raw);
+    debug!(
+        "detect_image_format: '{}' has format {}",
+        image_path.display(),
+        format
+    );
+    Ok(format)
+}
+
+/// Use the forced image format if given, failing if the image does not carry the magic bytes
+/// of the format, else detect the format
+pub(crate) fn get_image_format(
+    image_path: &Path,
+    forced: Option<ImageFormat>,
+) -> Result<ImageFormat> {
+    let detected = detect_image_format(image_path)?;
+    if let Some(forced) = forced {
+        if let Some(magic) = forced.magic() {
+            if !read_magic(image_path)?.starts_with(magic) {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "The image '{}' is not in {} format, the {} magic bytes were not found",
+                        image_path.display(),
+                        forced,
+                        forced
+                    ),
+                ));
+            }
+        } else if detected != ImageFormat::Raw {
+            warn!(
+                "The image '{}' looks like a {} image but is processed as a raw image",
+                image_path.display(),
+                detected
+            );
+        }
+        Ok(forced)
+    } else {
+        Ok(detected)
+    }
+}
+
+/// Open the image for reading the uncompressed image data
+pub(crate) fn open_image(image_path: &Path, format: ImageFormat) -> Result<Box<dyn Read>> {
+    debug!(
+        "open_image: opening '{}' as {} image",
+        image_path.display(),
+        format
+    );
+
+    let file = File::open(image_path).upstream_with_context(&format!(
+        "Failed to open image file '{}'",
+        image_path.display()
+    ))?;
+
+    Ok(match format {
+        ImageFormat::Gzip => Box::new(GzDecoder::new(file)),
+        ImageFormat::Xz => Box::new(XzDecoder::new(file)),
+        ImageFormat::Zstd => Box::new(
+            zstd::stream::read::Decoder::new(file).upstream_with_context(&format!(
+                "Failed to create zstd decoder for '{}'",
+                image_path.display()
+            ))?,
+        ),
+        ImageFormat::Raw => Box::new(file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_format_from_str() {
+        assert_eq!(ImageFormat::from_str("gzip").unwrap(), ImageFormat::Gzip);
+        assert_eq!(ImageFormat::from_str("XZ").unwrap(), ImageFormat::Xz);
+        assert!(ImageFormat::from_str("bmap").is_err());
+        assert!(ImageFormat::from_str("zip").is_err());
+    }
+}
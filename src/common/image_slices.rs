@@ -0,0 +1,303 @@
+use std::fs::{metadata, read_to_string};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{
+    error::{Error, ErrorKind, Result, ToError},
+    format_size_with_unit, path_append,
+};
+
+// partitions start on 4 MiB boundaries which suits SD cards and the default flash block sizes
+const PART_ALIGN: u64 = 4 * 1024 * 1024;
+const SECTOR_SIZE: u64 = 512;
+// space kept free at the end of the device for the backup GPT
+const GPT_BACKUP_SIZE: u64 = 1024 * 1024;
+const MAX_DOS_PARTITIONS: usize = 4;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SliceLabel {
+    Dos,
+    Gpt,
+}
+
+/// A partition of the slice manifest, the image is written into the partition
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct SlicePartition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub part_type: String,
+    /// size in bytes, the last partition may omit the size to use the rest of the device
+    pub size: Option<u64>,
+    pub image: Option<PathBuf>,
+}
+
+/// Per-partition images and the partition table they are written to, given as a YAML file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct SliceManifest {
+    pub label: SliceLabel,
+    pub partitions: Vec<SlicePartition>,
+}
+
+/// Position of a partition on the device in bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SliceLayout {
+    pub start: u64,
+    pub size: u64,
+}
+
+/// true if the image path points to a slice manifest rather than a disk image
+pub(crate) fn is_slice_manifest(image_path: &Path) -> bool {
+    if let Some(extension) = image_path.extension() {
+        extension == "yml" || extension == "yaml"
+    } else {
+        false
+    }
+}
+
+fn align_up(value: u64) -> u64 {
+    (value + PART_ALIGN - 1) / PART_ALIGN * PART_ALIGN
+}
+
+impl SliceManifest {
+    /// Read the manifest, image paths are relative to the directory of the manifest
+    pub fn from_file(manifest_path: &Path) -> Result<SliceManifest> {
+        let manifest_str = read_to_string(manifest_path).upstream_with_context(&format!(
+            "Failed to read slice manifest '{}'",
+            manifest_path.display()
+        ))?;
+        let mut manifest = SliceManifest::parse(&manifest_str)?;
+
+        let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new("/"));
+        for partition in manifest.partitions.iter_mut() {
+            if let Some(ref image) = partition.image {
+                if image.is_relative() {
+                    partition.image = Some(manifest_dir.join(image));
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn parse(manifest_str: &str) -> Result<SliceManifest> {
+        let manifest: SliceManifest = serde_yaml::from_str(manifest_str)
+            .upstream_with_context("Failed to parse slice manifest")?;
+
+        if manifest.partitions.is_empty() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "The slice manifest contains no partitions",
+            ));
+        }
+
+        if manifest.label == SliceLabel::Dos && manifest.partitions.len() > MAX_DOS_PARTITIONS {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The slice manifest contains {} partitions, a dos partition table supports {}",
+                    manifest.partitions.len(),
+                    MAX_DOS_PARTITIONS
+                ),
+            ));
+        }
+
+        let last = manifest.partitions.len() - 1;
+        for (index, partition) in manifest.partitions.iter().enumerate() {
+            match partition.size {
+                Some(size) if size == 0 || size % SECTOR_SIZE != 0 => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "The size of partition '{}' is not a non zero multiple of {}",
+                            partition.name, SECTOR_SIZE
+                        ),
+                    ));
+                }
+                None if index != last => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "Partition '{}' has no size, only the last partition may omit the size",
+                            partition.name
+                        ),
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Place the partitions on a device of dev_size bytes
+    pub fn layout(&self, dev_size: u64) -> Result<Vec<SliceLayout>> {
+        let dev_end = if self.label == SliceLabel::Gpt {
+            dev_size.saturating_sub(GPT_BACKUP_SIZE)
+        } else {
+            dev_size
+        };
+
+        let mut layout: Vec<SliceLayout> = Vec::new();
+        let mut start = PART_ALIGN;
+        for partition in &self.partitions {
+            let size = if let Some(size) = partition.size {
+                size
+            } else {
+                // rest of the device rounded down to whole sectors
+                dev_end.saturating_sub(start) / SECTOR_SIZE * SECTOR_SIZE
+            };
+
+            if size == 0 || start + size > dev_end {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Partition '{}' does not fit on the device of {}",
+                        partition.name,
+                        format_size_with_unit(dev_size)
+                    ),
+                ));
+            }
+
+            layout.push(SliceLayout { start, size });
+            start = align_up(start + size);
+        }
+        Ok(layout)
+    }
+
+    /// Path of the image of the partition at index below root
+    pub fn image_path(&self, index: usize, root: &Path) -> Option<PathBuf> {
+        if let Some(ref image) = self.partitions[index].image {
+            Some(path_append(root, image))
+        } else {
+            None
+        }
+    }
+
+    /// Check that the images below root fit into their partitions, returns the sum of the
+    /// image sizes
+    pub fn check_images(&self, layout: &[SliceLayout], root: &Path) -> Result<u64> {
+        let mut tot_size: u64 = 0;
+        for (index, partition) in self.partitions.iter().enumerate() {
+            if let Some(image_path) = self.image_path(index, root) {
+                let image_size = metadata(&image_path)
+                    .upstream_with_context(&format!(
+                        "Failed to retrieve size of image '{}'",
+                        image_path.display()
+                    ))?
+                    .len();
+                debug!(
+                    "check_images: partition '{}': image size {}, partition size {}",
+                    partition.name,
+                    format_size_with_unit(image_size),
+                    format_size_with_unit(layout[index].size)
+                );
+                if image_size > layout[index].size {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "The image '{}' of {} does not fit into partition '{}' of {}",
+                            image_path.display(),
+                            format_size_with_unit(image_size),
+                            partition.name,
+                            format_size_with_unit(layout[index].size)
+                        ),
+                    ));
+                }
+                tot_size += image_size;
+            }
+        }
+        Ok(tot_size)
+    }
+
+    /// The partition table in sfdisk script format
+    pub fn sfdisk_script(&self, layout: &[SliceLayout]) -> String {
+        let mut script = format!(
+            "label: {}\nunit: sectors\n\n",
+            match self.label {
+                SliceLabel::Dos => "dos",
+                SliceLabel::Gpt => "gpt",
+            }
+        );
+
+        for (partition, part_layout) in self.partitions.iter().zip(layout.iter()) {
+            script.push_str(&format!(
+                "start={}, size={}, type={}",
+                part_layout.start / SECTOR_SIZE,
+                part_layout.size / SECTOR_SIZE,
+                partition.part_type
+            ));
+            if self.label == SliceLabel::Gpt {
+                script.push_str(&format!(", name={}", partition.name));
+            }
+            script.push('\n');
+        }
+        script
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    const MANIFEST: &str = r##"
+label: dos
+partitions:
+  - name: boot
+    type: c
+    size: 41943040
+    image: boot.img
+  - name: rootA
+    type: "83"
+    size: 104857600
+    image: rootA.img
+  - name: data
+    type: "83"
+"##;
+
+    #[test]
+    fn test_slice_layout() {
+        let manifest = SliceManifest::parse(MANIFEST).unwrap();
+        let layout = manifest.layout(1024 * MIB).unwrap();
+        assert_eq!(
+            layout,
+            vec![
+                SliceLayout {
+                    start: 4 * MIB,
+                    size: 40 * MIB
+                },
+                SliceLayout {
+                    start: 44 * MIB,
+                    size: 100 * MIB
+                },
+                SliceLayout {
+                    start: 144 * MIB,
+                    size: 880 * MIB
+                },
+            ]
+        );
+        assert!(manifest.layout(100 * MIB).is_err());
+
+        assert_eq!(
+            manifest.sfdisk_script(&layout),
+            "label: dos\nunit: sectors\n\nstart=8192, size=81920, type=c\nstart=90112, size=204800, type=83\nstart=294912, size=1802240, type=83\n"
+        );
+    }
+
+    #[test]
+    fn test_slice_manifest_invalid() {
+        let manifest = r##"
+label: gpt
+partitions:
+  - name: boot
+    type: c
+  - name: data
+    type: "83"
+    size: 1024
+"##;
+        assert!(SliceManifest::parse(manifest).is_err());
+    }
+}
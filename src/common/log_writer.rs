@@ -0,0 +1,233 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use mod_logger::{LogDestination, Logger};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::common::{
+    error::{Result, ToError},
+    redact,
+};
+
+/// One log record as written with --log-format json
+#[derive(Debug, Serialize)]
+struct JsonRecord<'a> {
+    timestamp: f64,
+    level: Option<&'a str>,
+    module: Option<&'a str>,
+    message: &'a str,
+    stage: &'a str,
+}
+
+/// Split a line formatted by mod_logger into level, module and message. Parts that can not be
+/// recognized are left out, the whole line is the message then.
+fn parse_line(line: &str) -> (Option<&str>, Option<&str>, &str) {
+    lazy_static! {
+        // an optional timestamp, the level, optionally bracketed, and an optional module path
+        static ref LINE_REGEX: Regex = Regex::new(
+            r"^(?:[\d\-:./T ]+\s+)?\[?\s*(ERROR|WARN|INFO|DEBUG|TRACE)\s*\]?\s*(?:\[?([a-z_][a-z0-9_]*(?:::[a-z0-9_]+)+)\]?:?\s+)?(.*)$"
+        )
+        .unwrap();
+    }
+
+    if let Some(captures) = LINE_REGEX.captures(line) {
+        (
+            captures.get(1).map(|level| level.as_str()),
+            captures.get(2).map(|module| module.as_str()),
+            captures.get(3).map_or("", |message| message.as_str()),
+        )
+    } else {
+        (None, None, line)
+    }
+}
+
+/// Receives the lines mod_logger writes, redacts them and writes them to all outputs, as text or
+/// as JSON objects, one per line. Colors have to be disabled for JSON, the lines are parsed.
+pub(crate) struct LogWriter {
+    stage: &'static str,
+    json: bool,
+    outputs: Vec<Box<dyn Write + Send>>,
+    pending: Vec<u8>,
+}
+
+impl LogWriter {
+    pub fn new(stage: &'static str, json: bool, outputs: Vec<Box<dyn Write + Send>>) -> LogWriter {
+        LogWriter {
+            stage,
+            json,
+            outputs,
+            pending: Vec::new(),
+        }
+    }
+
+    fn write_record(&mut self, line: &str) -> io::Result<()> {
+        if !self.json {
+            let line = format!("{}\n", redact(line));
+            for output in &mut self.outputs {
+                output.write_all(line.as_bytes())?;
+            }
+            return Ok(());
+        }
+
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+
+        lazy_static! {
+            // lines buffered before JSON logging was set up can be colored
+            static ref COLOR_REGEX: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+        }
+
+        let line = redact(&COLOR_REGEX.replace_all(line, ""));
+        let (level, module, message) = parse_line(&line);
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_millis() as f64 / 1000.0,
+            Err(_) => 0.0,
+        };
+        let record = JsonRecord {
+            timestamp,
+            level,
+            module,
+            message,
+            stage: self.stage,
+        };
+        let mut json = serde_json::to_string(&record)
+            .map_err(|why| io::Error::new(io::ErrorKind::Other, why))?;
+        json.push('\n');
+        for output in &mut self.outputs {
+            output.write_all(json.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buffer);
+        while let Some(pos) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            self.write_record(String::from_utf8_lossy(&line).trim_end())?;
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let line: Vec<u8> = self.pending.drain(..).collect();
+            self.write_record(String::from_utf8_lossy(&line).trim_end())?;
+        }
+        for output in &mut self.outputs {
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Make the logger write redacted records for stage to stderr and, if given, to log_path, as JSON
+/// objects if json is set
+pub(crate) fn set_log_writer(
+    stage: &'static str,
+    log_path: Option<&Path>,
+    append: bool,
+    json: bool,
+) -> Result<()> {
+    let mut outputs: Vec<Box<dyn Write + Send>> = vec![Box::new(io::stderr())];
+    if let Some(log_path) = log_path {
+        let log_file: File = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(log_path)
+            .upstream_with_context(&format!("Failed to open log file '{}'", log_path.display()))?;
+        outputs.push(Box::new(log_file));
+    }
+
+    if json {
+        Logger::set_color(false);
+        Logger::set_brief_info(false);
+    }
+    Logger::set_log_dest(
+        &LogDestination::Stream,
+        Some(LogWriter::new(stage, json, outputs)),
+    )
+    .upstream_with_context("Failed to set up logging")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("2020-06-10 12:00:01.123 INFO  [takeover::stage1] Found 2 GiB total"),
+            (Some("INFO"), Some("takeover::stage1"), "Found 2 GiB total")
+        );
+        assert_eq!(
+            parse_line("[WARN ] No external power is connected"),
+            (Some("WARN"), None, "No external power is connected")
+        );
+        assert_eq!(parse_line("plain text"), (None, None, "plain text"));
+    }
+
+    #[test]
+    fn test_json_log_writer() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut writer = LogWriter::new("stage2", true, vec![Box::new(buffer.clone())]);
+        writer
+            .write_all(b"ERROR [takeover::stage2] Flash ")
+            .unwrap();
+        writer
+            .write_all(b"failed \"badly\"\n\n\x1b[33mrest\x1b[0m")
+            .unwrap();
+        writer.flush().unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["level"], "ERROR");
+        assert_eq!(records[0]["module"], "takeover::stage2");
+        assert_eq!(records[0]["message"], "Flash failed \"badly\"");
+        assert_eq!(records[0]["stage"], "stage2");
+        assert_eq!(records[1]["message"], "rest");
+        assert!(records[1]["level"].is_null());
+    }
+
+    #[test]
+    fn test_text_log_writer() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut writer = LogWriter::new("stage1", false, vec![Box::new(buffer.clone())]);
+        writer
+            .write_all(b"DEBUG [takeover::stage1] wpa config: psk=\"secret\"\n\n")
+            .unwrap();
+        writer.flush().unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "DEBUG [takeover::stage1] wpa config: psk=<redacted>\n\n"
+        );
+    }
+}
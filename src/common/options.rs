@@ -1,9 +1,235 @@
+use std::env::current_dir;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_link, symlink_metadata, File};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use log::Level;
+use serde::Deserialize;
 use structopt::StructOpt;
 
+use crate::common::{
+    defs::{
+        CONNMAN_SERVICES_DIR, DISK_BY_PATH_PATH, SYSTEMD_NETWORK_DIR, SYSTEM_CONNECTIONS_DIR,
+        TELINIT_CMD,
+    },
+    error::{Error, ErrorKind, Result, ToError},
+    flash_transform::FlashTransformKind,
+    image_format::ImageFormat,
+    REDACTED,
+};
+
 const DEFAULT_CHECK_TIMEOUT: u64 = 10;
+const DEFAULT_INIT_TIMEOUT: u64 = 30;
+const DEFAULT_MOUNT_RETRIES: u32 = 3;
+const DEFAULT_FLASH_OPEN_RETRIES: u32 = 5;
+const DEFAULT_MIN_BATTERY: u64 = 50;
+const DEFAULT_MEMORY_WARN_RATIO: f64 = 1.2;
+const DEFAULT_INIT_REEXEC_ARGS: &str = "u";
+const DEFAULT_MIN_FLASH_SPEED_WINDOW: u64 = 60;
+const DEFAULT_DELTA_FLASH_THRESHOLD: u64 = 50;
+const DEFAULT_STATUS_THRESHOLD: u64 = 10;
+// zstd levels for --compress-image, a single core can not afford slow levels
+const DEFAULT_COMPRESSION_LEVEL_SINGLE_CORE: i32 = 1;
+const DEFAULT_COMPRESSION_LEVEL_MULTI_CORE: i32 = 6;
+const FLASH_TO_AUTO: &str = "auto";
+// prefixes of the topology strings udev uses for the links in /dev/disk/by-path
+const BY_PATH_PREFIXES: [&str; 6] = ["pci-", "platform-", "acpi-", "usb-", "virtio-", "scsi-"];
+pub(crate) const NFS_URL_PREFIX: &str = "nfs://";
+pub(crate) const CIFS_URL_PREFIX: &str = "cifs://";
+
+/// Option defaults of a device profile, see --no-device-profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ProfileDefaults {
+    pub flash_bs: Option<u64>,
+    pub mount_retries: Option<u32>,
+    pub flash_open_retries: Option<u32>,
+    pub memory_warn_ratio: Option<f64>,
+}
+
+/// ways of making init re-execute itself as the bind-mounted takeover binary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitRestart {
+    /// run the init re-exec command, 'telinit u' by default
+    Telinit,
+    /// send SIGTERM to pid 1
+    Term,
+    /// send SIGHUP to pid 1
+    Hup,
+}
+
+impl FromStr for InitRestart {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "telinit" => Ok(InitRestart::Telinit),
+            "term" => Ok(InitRestart::Term),
+            "hup" => Ok(InitRestart::Hup),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid init restart method: '{}', expected one of telinit, term, hup",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// when config.json and the network configuration are placed on the balena boot partition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagingOrder {
+    /// flash the image and place the configuration on the flashed device in stage2
+    FlashFirst,
+    /// place the configuration in a copy of the image in stage1 and flash the copy
+    ConfigFirst,
+}
+
+impl FromStr for StagingOrder {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "flash-first" => Ok(StagingOrder::FlashFirst),
+            "config-first" => Ok(StagingOrder::ConfigFirst),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid staging order: '{}', expected one of flash-first, config-first",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// the format of log records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// plain text lines
+    Text,
+    /// one JSON object per record
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid log format: '{}', expected one of text, json",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// network managers that takeover can create network configurations for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkBackend {
+    NetworkManager,
+    SystemdNetworkd,
+    Connman,
+}
+
+impl NetworkBackend {
+    /// name of the directory the network configurations are written to
+    pub fn config_dir(self) -> &'static str {
+        match self {
+            NetworkBackend::NetworkManager => SYSTEM_CONNECTIONS_DIR,
+            NetworkBackend::SystemdNetworkd => SYSTEMD_NETWORK_DIR,
+            NetworkBackend::Connman => CONNMAN_SERVICES_DIR,
+        }
+    }
+}
+
+impl Display for NetworkBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NetworkBackend::NetworkManager => "networkmanager",
+            NetworkBackend::SystemdNetworkd => "systemd-networkd",
+            NetworkBackend::Connman => "connman",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for NetworkBackend {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "networkmanager" => Ok(NetworkBackend::NetworkManager),
+            "systemd-networkd" => Ok(NetworkBackend::SystemdNetworkd),
+            "connman" => Ok(NetworkBackend::Connman),
+            _ => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid network backend: '{}', expected one of networkmanager, systemd-networkd, connman",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// A secret option value, its Debug output masks the value so that logging Options is safe
+#[derive(Clone)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(Secret(value.to_string()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", REDACTED)
+    }
+}
+
+/// A secret option value given as 'Name: Value' or 'KEY=VALUE', its Debug output keeps the
+/// name and masks the value
+#[derive(Clone)]
+pub(crate) struct SecretPair(String);
+
+impl SecretPair {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl FromStr for SecretPair {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(SecretPair(value.to_string()))
+    }
+}
+
+impl fmt::Debug for SecretPair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(pos) = self.0.find(|c| c == ':' || c == '=') {
+            write!(f, "{:?}", format!("{}{}", &self.0[..=pos], REDACTED))
+        } else {
+            write!(f, "{:?}", REDACTED)
+        }
+    }
+}
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = env!("CARGO_PKG_NAME"), author, about)]
@@ -22,9 +248,38 @@ pub struct Options {
         long,
         value_name = "IMAGE",
         parse(from_os_str),
-        help = "Path to balena-os image"
+        help = "Path to balena-os image, or an nfs://HOST/PATH or cifs://HOST/SHARE/PATH URL of an image on a network share"
     )]
     image: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "File with username=, password= and optionally domain= lines to mount a cifs:// image share"
+    )]
+    cifs_credentials: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "OPTIONS",
+        help = "Additional comma separated mount options for an nfs:// or cifs:// image share, e.g. vers=3.0"
+    )]
+    share_mount_options: Option<String>,
+    #[structopt(
+        long,
+        value_name = "SIGNATURE",
+        parse(from_os_str),
+        requires_all = &["image", "image-pubkey"],
+        help = "Verify the balena-os image using the detached GPG signature SIGNATURE before flashing"
+    )]
+    image_signature: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "PUBKEY",
+        parse(from_os_str),
+        requires = "image-signature",
+        help = "Public key file used to verify the image signature"
+    )]
+    image_pubkey: Option<PathBuf>,
     #[structopt(
         short,
         long,
@@ -32,14 +287,28 @@ pub struct Options {
         help = "Version of balena-os image to download"
     )]
     version: Option<String>,
+    #[structopt(
+        long,
+        value_name = "HEADER",
+        number_of_values = 1,
+        help = "Add HEADER given as 'Name: Value' to the image download requests, can be repeated"
+    )]
+    download_header: Vec<SecretPair>,
+    #[structopt(
+        long,
+        value_name = "TOKEN",
+        help = "Authorize image download requests with bearer TOKEN instead of the api key"
+    )]
+    download_auth_bearer: Option<Secret>,
     #[structopt(
         short,
         long,
         value_name = "CONFIG_JSON",
         parse(from_os_str),
-        help = "Path to balena config.json"
+        number_of_values = 1,
+        help = "Path to balena config.json, can be repeated to deep-merge several files, later files override earlier ones"
     )]
-    config: Option<PathBuf>,
+    config: Vec<PathBuf>,
     #[structopt(
         long,
         default_value = "info",
@@ -53,6 +322,13 @@ pub struct Options {
         help = "Set stage1 log file name"
     )]
     log_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        parse(try_from_str),
+        help = "Set the log format for stage1 and stage2, one of [text,json], json writes one object per record with timestamp, level, module, message and stage, defaults to text"
+    )]
+    log_format: Option<LogFormat>,
     #[structopt(
         long,
         value_name = "BACKUP-CONFIG",
@@ -62,40 +338,396 @@ pub struct Options {
     backup_cfg: Option<PathBuf>,
     #[structopt(
         long,
-        help = "Set stage2 log level, one of [error,warn,info,debug,trace]"
+        help = "Set stage1 log level, overrides --log-level and --quiet for stage1"
+    )]
+    log_level_stage1: Option<Level>,
+    #[structopt(
+        long,
+        alias = "log-level-stage2",
+        help = "Set stage2 log level, one of [error,warn,info,debug,trace], overrides --log-level and --quiet for stage2"
     )]
     s2_log_level: Option<Level>,
+    #[structopt(
+        long,
+        conflicts_with = "log-level",
+        help = "Only log warnings and errors, same as --log-level warn"
+    )]
+    quiet: bool,
     #[structopt(
         long,
         help = "Scripted mode - no interactive acknoledgement of takeover"
     )]
     no_ack: bool,
+    #[structopt(short = "y", long, help = "Assume yes as answer to all confirmations")]
+    assume_yes: bool,
     #[structopt(long, help = "Pretend mode, do not flash device")]
     pretend: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "image",
+            "version",
+            "download-only",
+            "delta-flash",
+            "resumable-flash",
+            "zero-remainder",
+            "preserve-partition",
+            "randomize-uuids",
+            "save-plan",
+            "from-plan",
+            "flash-transform",
+            "staging-order",
+            "verify-sample"
+        ],
+        help = "Do not flash an image, place config.json and the network configuration on the existing balena partitions of the flash device"
+    )]
+    no_flash: bool,
+    #[structopt(
+        long,
+        help = "Zero or discard the space on the flash device beyond the image"
+    )]
+    zero_remainder: bool,
+    #[structopt(
+        long,
+        value_name = "PARTITION",
+        number_of_values = 1,
+        help = "Save the partition of the flash device given by number or label in memory and write it back to the partition with the same number after flashing, can be repeated"
+    )]
+    preserve_partition: Vec<String>,
+    #[structopt(
+        long,
+        help = "Give the flashed partitions and file systems new random UUIDs and update the references to them in the boot configuration"
+    )]
+    randomize_uuids: bool,
+    #[structopt(
+        long,
+        help = "Check the file systems of the flashed partitions before placing files on them"
+    )]
+    fsck_before_mount: bool,
+    #[structopt(
+        long,
+        value_name = "DEST",
+        help = "Archive the old root file system to DEST before flashing, DEST is an ext3/ext4 partition not on the flash device or tcp://<host>:<port>"
+    )]
+    archive_old_root: Option<String>,
+    #[structopt(
+        long,
+        help = "Start a busybox shell on the console after a successful flash and reboot when it exits"
+    )]
+    shell_after_flash: bool,
+    #[structopt(
+        long,
+        value_name = "TIMEOUT",
+        help = "Wait up to TIMEOUT seconds in stage2 for approval right before flashing, abort and reboot into the old OS if it is not approved"
+    )]
+    await_approval: Option<u64>,
+    #[structopt(
+        long,
+        help = "Write the system time to the hardware clock before stage2 reboots, so balena-os starts with the current time"
+    )]
+    set_rtc: bool,
+    #[structopt(
+        long,
+        help = "Carry the timezone of the system over to the balena boot partition, read from /etc/timezone or the /etc/localtime link"
+    )]
+    preserve_timezone: bool,
+    #[structopt(
+        long,
+        help = "Carry the locale (LANG) of the system over to the balena boot partition"
+    )]
+    preserve_locale: bool,
+    #[structopt(
+        long,
+        help = "Keep the end of the stage2 log on the balena boot partition as balena-takeover.log"
+    )]
+    log_to_boot: bool,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        help = "Block size used to write the image in stage2, defaults to a device specific value"
+    )]
+    flash_bs: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        parse(try_from_str = parse_byte_offset),
+        help = "Byte offset on the flash device at which the image is written, decimal or hex with 0x prefix, overrides the offset of the device type"
+    )]
+    flash_offset: Option<u64>,
+    #[structopt(
+        long,
+        help = "Write the image using O_DIRECT, bypassing the page cache"
+    )]
+    flash_direct: bool,
+    #[structopt(
+        long,
+        value_name = "BYTES_PER_SEC",
+        help = "Abort flashing if the write throughput stays below BYTES_PER_SEC for --min-flash-speed-window seconds"
+    )]
+    min_flash_speed: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        help = "Window for --min-flash-speed in seconds, defaults to 60"
+    )]
+    min_flash_speed_window: Option<u64>,
+    #[structopt(
+        long,
+        help = "Only write the parts of the image that differ from the contents of the flash device"
+    )]
+    delta_flash: bool,
+    #[structopt(
+        long,
+        value_name = "PERCENT",
+        requires = "delta-flash",
+        help = "Flash the whole image if more than PERCENT of it differs from the flash device, defaults to 50"
+    )]
+    delta_flash_threshold: Option<u64>,
+    #[structopt(
+        long,
+        conflicts_with = "delta-flash",
+        help = "Record the flash progress at the end of the flash device and resume an interrupted flash of the same image"
+    )]
+    resumable_flash: bool,
+    #[structopt(
+        long,
+        value_name = "PERCENT",
+        parse(try_from_str = parse_percent),
+        help = "Read back a reproducible random sample of PERCENT of the image blocks after flashing and compare them to the image"
+    )]
+    verify_sample: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "ORDER",
+        parse(try_from_str),
+        help = "When to place config.json and the network configuration: flash-first places them on the flashed device in stage2, config-first places them in a copy of the uncompressed image in stage1, defaults to flash-first"
+    )]
+    staging_order: Option<StagingOrder>,
+    #[structopt(
+        long,
+        value_name = "TRANSFORMS",
+        use_delimiter = true,
+        parse(try_from_str),
+        help = "Transform the decompressed image on its way to the flash device, applied in order: patch, xor"
+    )]
+    flash_transform: Vec<FlashTransformKind>,
+    #[structopt(
+        long,
+        value_name = "OFFSET=FILE",
+        number_of_values = 1,
+        help = "Overwrite the image at OFFSET with the content of FILE for the patch transform, OFFSET is decimal or hex with a 0x prefix, can be repeated"
+    )]
+    patch: Vec<String>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "XOR the image with the repeated content of FILE for the xor transform"
+    )]
+    xor_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Store an uncompressed image zstd compressed in memory in stage2 to save memory"
+    )]
+    compress_image: bool,
+    #[structopt(
+        long,
+        value_name = "LEVEL",
+        requires = "compress-image",
+        help = "zstd level 1-19 for --compress-image, higher levels save memory but take longer, defaults to 1 on single core devices and 6 otherwise"
+    )]
+    stage_compression_level: Option<i32>,
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        parse(try_from_str),
+        help = "Process the image as FORMAT instead of detecting the format: raw, gzip, xz, zstd"
+    )]
+    image_format: Option<ImageFormat>,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        parse(try_from_str),
+        help = "Uncompressed size of a compressed image, the image is decompressed in stage1 to determine it otherwise"
+    )]
+    image_uncompressed_size: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "SCRIPT",
+        parse(from_os_str),
+        help = "Run the shell script SCRIPT from the new init instead of stage2, the script is expected to exec stage2"
+    )]
+    stage2_script: Option<PathBuf>,
     #[structopt(long, help = "Internal - stage2 invocation")]
     stage2: bool,
     #[structopt(long, help = "Use internal tar instead of external command")]
     tar_internal: bool,
     #[structopt(long, help = "Debug - do not cleanup after stage1 failure")]
     no_cleanup: bool,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "Load a YAML database mapping device tree model, cpuinfo or DMI patterns to balena device types from FILE"
+    )]
+    device_types_db: Option<PathBuf>,
     #[structopt(long, help = "Do not check if OS is supported")]
     no_os_check: bool,
+    #[structopt(
+        long,
+        help = "Query the balena API to make sure no device is registered with the uuid in config.json"
+    )]
+    check_uuid_unique: bool,
     #[structopt(long, help = "Do not check if balena API is available")]
     no_api_check: bool,
     #[structopt(long, help = "Do not check if balena VPN is available")]
     no_vpn_check: bool,
+    #[structopt(
+        long,
+        help = "Check DNS, TCP and TLS reachability of the API and VPN endpoints in config.json"
+    )]
+    check_api: bool,
+    #[structopt(long, help = "Only warn if the --check-api endpoint check fails")]
+    check_api_warn: bool,
     #[structopt(long, help = "Do not setup EFI boot")]
     no_efi_setup: bool,
     #[structopt(long, help = "Do not check network manager files exist")]
     no_nwmgr_check: bool,
+    #[structopt(
+        long,
+        help = "Create a DHCP ethernet configuration as fallback if no other network configuration is migrated"
+    )]
+    default_dhcp_ethernet: bool,
+    #[structopt(
+        long,
+        requires = "default-dhcp-ethernet",
+        help = "Create the DHCP ethernet configuration of --default-dhcp-ethernet even if other network configurations are migrated"
+    )]
+    force_default_dhcp_ethernet: bool,
+    #[structopt(
+        long,
+        help = "Skip invalid network configuration files instead of aborting, as long as a valid one remains or --no-nwmgr-check is given"
+    )]
+    continue_on_nonfatal: bool,
+    #[structopt(
+        long,
+        help = "Bring the network configurations up on this host one at a time and check that the API can be reached before migrating, this briefly interrupts the network connections of the host"
+    )]
+    test_network: bool,
+    #[structopt(
+        long,
+        help = "Seed the kernel entropy pool from the hardware RNG or wait for the kernel to gather entropy if it is low, TLS connections can stall otherwise"
+    )]
+    seed_entropy: bool,
     #[structopt(long, help = "Do not migrate host-name")]
     no_keep_name: bool,
+    #[structopt(
+        long,
+        value_name = "TEMPLATE",
+        help = "Set the hostname of the migrated device from TEMPLATE, {mac}, {serial} and {hostname} are replaced by the identifiers of the device"
+    )]
+    hostname_template: Option<String>,
+    #[structopt(
+        long,
+        value_name = "KEYS_FILE",
+        parse(from_os_str),
+        help = "Add the SSH public keys in KEYS_FILE to os.sshKeys in config.json"
+    )]
+    ssh_authorized_keys: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "KEY=VALUE",
+        number_of_values = 1,
+        help = "Add a device tag given as 'key=value' to takeoverTags in config.json, can be repeated"
+    )]
+    tag: Vec<String>,
+    #[structopt(
+        long,
+        value_name = "KEY=VALUE",
+        number_of_values = 1,
+        help = "Set an environment variable for stage2 and the stage2 script, can be repeated"
+    )]
+    env: Vec<SecretPair>,
+    #[structopt(
+        long,
+        help = "Add tags recording the source OS, takeover version and time of the migration to takeoverTags in config.json"
+    )]
+    migration_tags: bool,
     #[structopt(
         short,
         long,
         help = "Download image only, do not check device and migrate"
     )]
     download_only: bool,
+    #[structopt(
+        long,
+        help = "List the balena-os versions available for the device type and exit, --version filters by semver range"
+    )]
+    list_os_versions: bool,
+    #[structopt(
+        long,
+        help = "List the block devices with their partitions and exit, use --json for JSON output"
+    )]
+    list_block_devices: bool,
+    #[structopt(
+        long,
+        requires = "list-block-devices",
+        help = "Print the block device list as JSON"
+    )]
+    json: bool,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        parse(from_os_str),
+        help = "Write the executables, libraries and stage2 script that stage2 runs with to DIR and exit"
+    )]
+    dump_assets: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Print the takeover plan and exit, works without root privileges"
+    )]
+    plan: bool,
+    #[structopt(
+        long,
+        help = "Log the effective configuration of this run at info level, it is logged at debug level otherwise"
+    )]
+    show_config: bool,
+    #[structopt(
+        long,
+        help = "Do not apply the option defaults of the built-in profile for the detected device type"
+    )]
+    no_device_profile: bool,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "Save the decisions of stage1 and checksums of the image and config to FILE when migrating"
+    )]
+    save_plan: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        conflicts_with = "save-plan",
+        help = "Replay the migration plan saved to FILE, fails if the device, image or config changed"
+    )]
+    from_plan: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "SLUG",
+        help = "Device type to list versions for, defaults to the device type in config.json"
+    )]
+    device_type: Option<String>,
+    #[structopt(long, help = "List production versions only")]
+    list_prod_only: bool,
+    #[structopt(
+        long,
+        value_name = "COUNT",
+        help = "List the latest COUNT versions only"
+    )]
+    list_latest: Option<usize>,
+    #[structopt(long, help = "List versions as JSON")]
+    list_json: bool,
     #[structopt(
         long,
         value_name = "TIMEOUT",
@@ -111,14 +743,27 @@ pub struct Options {
         help = "Write stage2 log to LOG_DEVICE"
     )]
     log_to: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "PATH",
+        parse(from_os_str),
+        requires = "log-to",
+        help = "Write Prometheus metrics of stage2 to PATH on the log device"
+    )]
+    metrics_file: Option<PathBuf>,
     #[structopt(
         short,
         long,
         value_name = "INSTALL_DEVICE",
         parse(from_os_str),
-        help = "Use INSTALL_DEVICE to flash balena to"
+        help = "Use INSTALL_DEVICE to flash balena to, 'auto' to use the root device if it is the only candidate, a /dev/disk/by-path link or topology string selects the device by the port it is attached to"
     )]
     flash_to: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Refuse to flash an automatically selected device that is removable or attached via USB"
+    )]
+    refuse_removable_flash: bool,
     #[structopt(
         long,
         help = "Do not create network manager configurations for configured wifis"
@@ -137,9 +782,460 @@ pub struct Options {
         help = "Supply a network manager file to inject into balena-os"
     )]
     nwmgr_cfg: Option<Vec<PathBuf>>,
+    #[structopt(
+        long,
+        value_name = "BACKEND",
+        parse(try_from_str),
+        help = "Network manager to create network configurations for: networkmanager, systemd-networkd, connman, detected from the device type if not given"
+    )]
+    network_backend: Option<NetworkBackend>,
+    #[structopt(
+        long,
+        value_name = "FS_TYPES",
+        use_delimiter = true,
+        default_value = "overlay,tmpfs,squashfs,aufs",
+        help = "File system types to look through when resolving the root device"
+    )]
+    ignore_fs_types: Vec<String>,
+    #[structopt(
+        long,
+        value_name = "DEVICE",
+        use_delimiter = true,
+        parse(from_os_str),
+        help = "Block device nodes to create in the takeover /dev if it has to be copied"
+    )]
+    ensure_dev: Vec<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "METHODS",
+        use_delimiter = true,
+        default_value = "telinit,term,hup",
+        parse(try_from_str),
+        help = "Methods to restart init with, tried in order until the new init is running: telinit, term, hup"
+    )]
+    init_restart: Vec<InitRestart>,
+    #[structopt(
+        long,
+        value_name = "TIMEOUT",
+        parse(try_from_str),
+        help = "Seconds to wait for the new init to start after each init restart method"
+    )]
+    init_timeout: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "CMD",
+        help = "Command the telinit init restart method runs to make init re-execute itself, defaults to telinit"
+    )]
+    init_reexec_cmd: Option<String>,
+    #[structopt(
+        long,
+        value_name = "RETRIES",
+        parse(try_from_str),
+        help = "Times to retry mounting a file system in the takeover directory after a transient error, defaults to 3"
+    )]
+    mount_retries: Option<u32>,
+    #[structopt(
+        long,
+        value_name = "RETRIES",
+        parse(try_from_str),
+        help = "Times stage2 retries opening the flash device while it is busy after unmounting its partitions, defaults to 5"
+    )]
+    flash_open_retries: Option<u32>,
+    #[structopt(
+        long,
+        value_name = "RATIO",
+        parse(try_from_str = parse_memory_ratio),
+        help = "Warn if free memory is less than RATIO times the memory takeover requires, defaults to 1.2"
+    )]
+    memory_warn_ratio: Option<f64>,
+    #[structopt(
+        long,
+        help = "Refuse to migrate if free memory is below the --memory-warn-ratio margin"
+    )]
+    strict_memory: bool,
+    #[structopt(
+        long,
+        value_name = "PERCENT",
+        parse(try_from_str = parse_percent),
+        help = "Refuse to migrate a device running on battery with less charge, defaults to 50"
+    )]
+    min_battery: Option<u64>,
+    #[structopt(
+        long,
+        help = "Migrate a device running on a low battery or with unknown charge anyway"
+    )]
+    ignore_power: bool,
+    #[structopt(
+        long,
+        value_name = "ARGS",
+        allow_hyphen_values = true,
+        help = "Space separated arguments of the init re-exec command, defaults to 'u'"
+    )]
+    init_reexec_args: Option<String>,
+    #[structopt(
+        long,
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "Path of the init binary to replace, by default it is read from /proc/1/exe"
+    )]
+    init_path: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "TIMEOUT",
+        parse(try_from_str),
+        help = "Abort stage1 and clean up if it has not reached the point of no return after TIMEOUT seconds"
+    )]
+    timeout: Option<u64>,
+    #[structopt(
+        long,
+        alias = "support-bundle",
+        value_name = "BUNDLE_FILE",
+        parse(from_os_str),
+        help = "Write a tar.gz with the log, stage2 config, block devices and detected device type to BUNDLE_FILE when stage1 terminates"
+    )]
+    diagnostics: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "SOCKET",
+        parse(from_os_str),
+        help = "Stream stage1 status events as JSON lines to clients of unix socket SOCKET"
+    )]
+    status_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Only send status progress events when the step changes or the percentage grew by --status-threshold, the final event carries a summary of the run"
+    )]
+    status_on_change: bool,
+    #[structopt(
+        long,
+        value_name = "PERCENT",
+        requires = "status-on-change",
+        parse(try_from_str = parse_percent),
+        help = "Percentage the progress has to grow by for --status-on-change to send an event, defaults to 10"
+    )]
+    status_threshold: Option<u64>,
+}
+
+/// Canonicalize an input path and make sure it exists and can be read
+fn check_input_path(option: &str, path: &Path, is_dir: bool) -> Result<PathBuf> {
+    let abs_path = path.canonicalize().error_with_all(
+        ErrorKind::FileNotFound,
+        &format!(
+            "Invalid value for option '{}': '{}' could not be found",
+            option,
+            path.display()
+        ),
+    )?;
+
+    if is_dir != abs_path.is_dir() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid value for option '{}': '{}' is not a {}",
+                option,
+                path.display(),
+                if is_dir { "directory" } else { "file" }
+            ),
+        ));
+    }
+
+    if abs_path.is_file() {
+        File::open(&abs_path).error_with_all(
+            ErrorKind::Permission,
+            &format!(
+                "Invalid value for option '{}': '{}' can not be read",
+                option,
+                path.display()
+            ),
+        )?;
+    }
+
+    Ok(abs_path)
+}
+
+/// Check that an input path is a readable regular file, telling a missing file, a symbolic link
+/// to a missing file and a directory apart
+fn check_input_file(option: &str, path: &Path) -> Result<PathBuf> {
+    let link_md = match symlink_metadata(path) {
+        Ok(link_md) => link_md,
+        Err(why) if why.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::with_context(
+                ErrorKind::FileNotFound,
+                &format!(
+                    "Invalid value for option '{}': '{}' could not be found",
+                    option,
+                    path.display()
+                ),
+            ));
+        }
+        Err(why) => {
+            return Err(Error::from_upstream(
+                Box::new(why),
+                &format!(
+                    "Invalid value for option '{}': failed to read metadata of '{}'",
+                    option,
+                    path.display()
+                ),
+            ));
+        }
+    };
+
+    let abs_path = match path.canonicalize() {
+        Ok(abs_path) => abs_path,
+        Err(_) if link_md.file_type().is_symlink() => {
+            let target = read_link(path).unwrap_or_default();
+            return Err(Error::with_context(
+                ErrorKind::FileNotFound,
+                &format!(
+                    "Invalid value for option '{}': '{}' is a symbolic link to '{}' which does not exist",
+                    option,
+                    path.display(),
+                    target.display()
+                ),
+            ));
+        }
+        Err(why) => {
+            return Err(Error::from_upstream(
+                Box::new(why),
+                &format!(
+                    "Invalid value for option '{}': failed to resolve '{}'",
+                    option,
+                    path.display()
+                ),
+            ));
+        }
+    };
+
+    if abs_path.is_dir() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid value for option '{}': '{}' is a directory, please give the path of a file in it",
+                option,
+                path.display()
+            ),
+        ));
+    }
+
+    if !abs_path.is_file() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid value for option '{}': '{}' is not a regular file",
+                option,
+                path.display()
+            ),
+        ));
+    }
+
+    File::open(&abs_path).error_with_all(
+        ErrorKind::Permission,
+        &format!(
+            "Invalid value for option '{}': '{}' can not be read",
+            option,
+            path.display()
+        ),
+    )?;
+
+    Ok(abs_path)
+}
+
+/// Check if a flash device is given as /dev/disk/by-path link or as a topology string like
+/// 'pci-0000:00:14.0-usb-0:2:1.0-scsi-0:0:0:0'
+/// --image is the URL of an image on an NFS or CIFS share
+pub(crate) fn is_share_url(path: &Path) -> bool {
+    if let Some(url) = path.to_str() {
+        url.starts_with(NFS_URL_PREFIX) || url.starts_with(CIFS_URL_PREFIX)
+    } else {
+        false
+    }
+}
+
+fn is_by_path_spec(path: &Path) -> bool {
+    if path.starts_with(DISK_BY_PATH_PATH) {
+        return true;
+    }
+
+    if let Some(spec) = path.to_str() {
+        !spec.contains('/')
+            && BY_PATH_PREFIXES
+                .iter()
+                .any(|prefix| spec.starts_with(prefix))
+    } else {
+        false
+    }
+}
+
+fn parse_percent(value: &str) -> Result<u64> {
+    match value.parse::<u64>() {
+        Ok(percent) if (1..=100).contains(&percent) => Ok(percent),
+        _ => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid percentage: '{}', expected 1-100", value),
+        )),
+    }
+}
+
+fn parse_memory_ratio(value: &str) -> Result<f64> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio >= 1.0 => Ok(ratio),
+        _ => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid memory ratio: '{}', expected a number of at least 1.0",
+                value
+            ),
+        )),
+    }
+}
+
+fn parse_byte_offset(value: &str) -> Result<u64> {
+    let res = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u64>()
+    };
+    res.map_err(|_| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid byte offset: '{}', expected a decimal or 0x prefixed hex number",
+                value
+            ),
+        )
+    })
 }
 
-impl Options {
+/// Make an output path absolute without requiring it to exist
+fn make_absolute(option: &str, path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(current_dir()
+            .upstream_with_context(&format!(
+                "Failed to resolve path '{}' for option '{}'",
+                path.display(),
+                option
+            ))?
+            .join(path))
+    }
+}
+
+impl Options {
+    /// Turn all path options into absolute paths and check that input files exist.
+    /// Must be called before the current directory is changed.
+    pub fn normalize_paths(&mut self) -> Result<()> {
+        if let Some(work_dir) = &self.work_dir {
+            self.work_dir = Some(check_input_path("--work-dir", work_dir, true)?);
+        }
+
+        if let Some(image) = &self.image {
+            // a network share is mounted later
+            if !is_share_url(image) {
+                self.image = Some(check_input_file("--image", image)?);
+            }
+        }
+
+        if let Some(cifs_credentials) = &self.cifs_credentials {
+            self.cifs_credentials = Some(check_input_file("--cifs-credentials", cifs_credentials)?);
+        }
+
+        if let Some(xor_key) = &self.xor_key {
+            self.xor_key = Some(check_input_file("--xor-key", xor_key)?);
+        }
+
+        if let Some(device_types_db) = &self.device_types_db {
+            self.device_types_db = Some(check_input_path(
+                "--device-types-db",
+                device_types_db,
+                false,
+            )?);
+        }
+
+        if let Some(image_signature) = &self.image_signature {
+            self.image_signature = Some(check_input_path(
+                "--image-signature",
+                image_signature,
+                false,
+            )?);
+        }
+
+        if let Some(image_pubkey) = &self.image_pubkey {
+            self.image_pubkey = Some(check_input_path("--image-pubkey", image_pubkey, false)?);
+        }
+
+        let mut config_files: Vec<PathBuf> = Vec::new();
+        for config in &self.config {
+            config_files.push(check_input_file("--config", config)?);
+        }
+        self.config = config_files;
+
+        if let Some(ssh_authorized_keys) = &self.ssh_authorized_keys {
+            self.ssh_authorized_keys = Some(check_input_path(
+                "--ssh-authorized-keys",
+                ssh_authorized_keys,
+                false,
+            )?);
+        }
+
+        if let Some(backup_cfg) = &self.backup_cfg {
+            self.backup_cfg = Some(check_input_path("--backup-cfg", backup_cfg, false)?);
+        }
+
+        if let Some(nwmgr_cfg) = &self.nwmgr_cfg {
+            let mut nwmgr_files: Vec<PathBuf> = Vec::new();
+            for nwmgr_file in nwmgr_cfg {
+                nwmgr_files.push(check_input_path("--nwmgr-cfg", nwmgr_file, false)?);
+            }
+            self.nwmgr_cfg = Some(nwmgr_files);
+        }
+
+        if let Some(flash_to) = self.flash_to() {
+            // by-path specifiers are resolved to the current device node in stage1
+            if !is_by_path_spec(flash_to) {
+                self.flash_to = Some(check_input_path("--flash-to", flash_to, false)?);
+            }
+        }
+
+        if let Some(init_path) = &self.init_path {
+            self.init_path = Some(check_input_path("--init-path", init_path, false)?);
+        }
+
+        if let Some(stage2_script) = &self.stage2_script {
+            self.stage2_script = Some(check_input_path("--stage2-script", stage2_script, false)?);
+        }
+
+        if let Some(log_to) = &self.log_to {
+            self.log_to = Some(check_input_path("--log-to", log_to, false)?);
+        }
+
+        if let Some(log_file) = &self.log_file {
+            self.log_file = Some(make_absolute("--log-file", log_file)?);
+        }
+
+        if let Some(diagnostics) = &self.diagnostics {
+            self.diagnostics = Some(make_absolute("--diagnostics", diagnostics)?);
+        }
+
+        if let Some(dump_assets) = &self.dump_assets {
+            self.dump_assets = Some(make_absolute("--dump-assets", dump_assets)?);
+        }
+
+        if let Some(status_socket) = &self.status_socket {
+            self.status_socket = Some(make_absolute("--status-socket", status_socket)?);
+        }
+
+        if let Some(save_plan) = &self.save_plan {
+            self.save_plan = Some(make_absolute("--save-plan", save_plan)?);
+        }
+
+        if let Some(from_plan) = &self.from_plan {
+            self.from_plan = Some(check_input_path("--from-plan", from_plan, false)?);
+        }
+
+        Ok(())
+    }
+
     pub fn backup_config(&self) -> Option<&Path> {
         if let Some(backup_cfg) = &self.backup_cfg {
             Some(backup_cfg.as_path())
@@ -148,6 +1244,10 @@ impl Options {
         }
     }
 
+    pub fn stage2_script(&self) -> Option<&Path> {
+        self.stage2_script.as_deref()
+    }
+
     pub fn stage2(&self) -> bool {
         self.stage2
     }
@@ -168,6 +1268,53 @@ impl Options {
         &self.image
     }
 
+    pub fn image_signature(&self) -> Option<&Path> {
+        if let Some(image_signature) = &self.image_signature {
+            Some(image_signature.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn cifs_credentials(&self) -> Option<&Path> {
+        if let Some(cifs_credentials) = &self.cifs_credentials {
+            Some(cifs_credentials.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn share_mount_options(&self) -> Option<&str> {
+        if let Some(share_mount_options) = &self.share_mount_options {
+            Some(share_mount_options.as_str())
+        } else {
+            None
+        }
+    }
+
+    pub fn image_pubkey(&self) -> Option<&Path> {
+        if let Some(image_pubkey) = &self.image_pubkey {
+            Some(image_pubkey.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn download_headers(&self) -> Vec<&str> {
+        self.download_header
+            .iter()
+            .map(|header| header.as_str())
+            .collect()
+    }
+
+    pub fn download_auth_bearer(&self) -> Option<&str> {
+        if let Some(ref token) = self.download_auth_bearer {
+            Some(token.as_str())
+        } else {
+            None
+        }
+    }
+
     pub fn version(&self) -> &str {
         if let Some(ref version) = self.version {
             version.as_str()
@@ -176,38 +1323,284 @@ impl Options {
         }
     }
 
-    pub fn no_ack(&self) -> bool {
-        self.no_ack
+    pub fn list_os_versions(&self) -> bool {
+        self.list_os_versions
+    }
+
+    pub fn plan(&self) -> bool {
+        self.plan
+    }
+
+    pub fn show_config(&self) -> bool {
+        self.show_config
+    }
+
+    pub fn no_device_profile(&self) -> bool {
+        self.no_device_profile
+    }
+
+    /// Set the options that were not given on the command line to the defaults of a device
+    /// profile, returns the options that were set
+    pub(crate) fn apply_profile_defaults(&mut self, defaults: &ProfileDefaults) -> Vec<String> {
+        let mut applied: Vec<String> = Vec::new();
+        if let (None, Some(flash_bs)) = (self.flash_bs, defaults.flash_bs) {
+            self.flash_bs = Some(flash_bs);
+            applied.push(format!("--flash-bs {}", flash_bs));
+        }
+        if let (None, Some(retries)) = (self.mount_retries, defaults.mount_retries) {
+            self.mount_retries = Some(retries);
+            applied.push(format!("--mount-retries {}", retries));
+        }
+        if let (None, Some(retries)) = (self.flash_open_retries, defaults.flash_open_retries) {
+            self.flash_open_retries = Some(retries);
+            applied.push(format!("--flash-open-retries {}", retries));
+        }
+        if let (None, Some(ratio)) = (self.memory_warn_ratio, defaults.memory_warn_ratio) {
+            self.memory_warn_ratio = Some(ratio);
+            applied.push(format!("--memory-warn-ratio {}", ratio));
+        }
+        applied
+    }
+
+    pub fn save_plan(&self) -> Option<&Path> {
+        self.save_plan.as_deref()
+    }
+
+    pub fn from_plan(&self) -> Option<&Path> {
+        self.from_plan.as_deref()
+    }
+
+    pub fn device_type(&self) -> Option<&str> {
+        if let Some(device_type) = &self.device_type {
+            Some(device_type.as_str())
+        } else {
+            None
+        }
+    }
+
+    pub fn list_block_devices(&self) -> bool {
+        self.list_block_devices
+    }
+
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    pub fn dump_assets(&self) -> Option<&Path> {
+        self.dump_assets.as_deref()
+    }
+
+    pub fn list_prod_only(&self) -> bool {
+        self.list_prod_only
+    }
+
+    pub fn list_latest(&self) -> Option<usize> {
+        self.list_latest
+    }
+
+    pub fn list_json(&self) -> bool {
+        self.list_json
+    }
+
+    pub fn assume_yes(&self) -> bool {
+        self.assume_yes || self.no_ack
     }
 
     pub fn migrate(&self) -> bool {
         !self.download_only
     }
 
-    pub fn config(&self) -> &Option<PathBuf> {
-        &self.config
+    pub fn config(&self) -> &[PathBuf] {
+        self.config.as_slice()
     }
 
     pub fn pretend(&self) -> bool {
         self.pretend
     }
 
+    pub fn no_flash(&self) -> bool {
+        self.no_flash
+    }
+
+    pub fn zero_remainder(&self) -> bool {
+        self.zero_remainder
+    }
+
+    pub fn preserve_partitions(&self) -> &[String] {
+        self.preserve_partition.as_slice()
+    }
+
+    pub fn randomize_uuids(&self) -> bool {
+        self.randomize_uuids
+    }
+
+    pub fn fsck_before_mount(&self) -> bool {
+        self.fsck_before_mount
+    }
+
+    pub fn shell_after_flash(&self) -> bool {
+        self.shell_after_flash
+    }
+
+    pub fn await_approval(&self) -> Option<u64> {
+        self.await_approval
+    }
+
+    pub fn set_rtc(&self) -> bool {
+        self.set_rtc
+    }
+
+    pub fn preserve_timezone(&self) -> bool {
+        self.preserve_timezone
+    }
+
+    pub fn preserve_locale(&self) -> bool {
+        self.preserve_locale
+    }
+
+    pub fn archive_old_root(&self) -> Option<&str> {
+        if let Some(dest) = &self.archive_old_root {
+            Some(dest.as_str())
+        } else {
+            None
+        }
+    }
+
+    pub fn flash_bs(&self) -> Option<u64> {
+        self.flash_bs
+    }
+
+    pub fn flash_offset(&self) -> Option<u64> {
+        self.flash_offset
+    }
+
+    pub fn flash_direct(&self) -> bool {
+        self.flash_direct
+    }
+
+    pub fn min_flash_speed(&self) -> Option<u64> {
+        self.min_flash_speed
+    }
+
+    pub fn min_flash_speed_window(&self) -> u64 {
+        if let Some(window) = self.min_flash_speed_window {
+            window
+        } else {
+            DEFAULT_MIN_FLASH_SPEED_WINDOW
+        }
+    }
+
+    pub fn delta_flash(&self) -> bool {
+        self.delta_flash
+    }
+
+    pub fn resumable_flash(&self) -> bool {
+        self.resumable_flash
+    }
+
+    pub fn verify_sample(&self) -> Option<u64> {
+        self.verify_sample
+    }
+
+    pub fn staging_order(&self) -> StagingOrder {
+        if let Some(staging_order) = self.staging_order {
+            staging_order
+        } else {
+            StagingOrder::FlashFirst
+        }
+    }
+
+    pub fn flash_transform(&self) -> &[FlashTransformKind] {
+        self.flash_transform.as_slice()
+    }
+
+    pub fn patch(&self) -> &[String] {
+        self.patch.as_slice()
+    }
+
+    pub fn xor_key(&self) -> Option<&Path> {
+        self.xor_key.as_deref()
+    }
+
+    pub fn delta_flash_threshold(&self) -> u64 {
+        if let Some(threshold) = self.delta_flash_threshold {
+            threshold
+        } else {
+            DEFAULT_DELTA_FLASH_THRESHOLD
+        }
+    }
+
+    pub fn compress_image(&self) -> bool {
+        self.compress_image
+    }
+
+    pub fn stage_compression_level(&self) -> i32 {
+        if let Some(level) = self.stage_compression_level {
+            level
+        } else if unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } > 1 {
+            DEFAULT_COMPRESSION_LEVEL_MULTI_CORE
+        } else {
+            DEFAULT_COMPRESSION_LEVEL_SINGLE_CORE
+        }
+    }
+
+    pub fn image_uncompressed_size(&self) -> Option<u64> {
+        self.image_uncompressed_size
+    }
+
+    pub(crate) fn image_format(&self) -> Option<ImageFormat> {
+        self.image_format
+    }
+
+    pub fn log_to_boot(&self) -> bool {
+        self.log_to_boot
+    }
+
     pub fn log_file(&self) -> &Option<PathBuf> {
         &self.log_file
     }
 
+    pub fn log_format(&self) -> LogFormat {
+        if let Some(log_format) = self.log_format {
+            log_format
+        } else {
+            LogFormat::Text
+        }
+    }
+
+    /// the log level for both stages unless a stage specific level is given
+    fn default_log_level(&self) -> Level {
+        if self.quiet {
+            Level::Warn
+        } else {
+            self.log_level
+        }
+    }
+
     pub fn log_level(&self) -> Level {
-        self.log_level
+        if let Some(level) = self.log_level_stage1 {
+            level
+        } else {
+            self.default_log_level()
+        }
     }
 
     pub fn s2_log_level(&self) -> Level {
         if let Some(level) = self.s2_log_level {
             level
         } else {
-            self.log_level
+            self.default_log_level()
         }
     }
 
+    pub fn device_types_db(&self) -> Option<&Path> {
+        self.device_types_db.as_deref()
+    }
+
+    pub fn check_uuid_unique(&self) -> bool {
+        self.check_uuid_unique
+    }
+
     pub fn os_check(&self) -> bool {
         !self.no_os_check
     }
@@ -224,12 +1617,46 @@ impl Options {
         !self.no_vpn_check
     }
 
+    pub fn check_api(&self) -> bool {
+        self.check_api
+    }
+
+    pub fn check_api_warn(&self) -> bool {
+        self.check_api_warn
+    }
+
     pub fn log_to(&self) -> &Option<PathBuf> {
         &self.log_to
     }
 
-    pub fn flash_to(&self) -> &Option<PathBuf> {
-        &self.flash_to
+    pub fn metrics_file(&self) -> Option<&Path> {
+        self.metrics_file.as_deref()
+    }
+
+    pub fn flash_to(&self) -> Option<&Path> {
+        if let Some(flash_to) = &self.flash_to {
+            if flash_to.as_os_str() != FLASH_TO_AUTO {
+                return Some(flash_to.as_path());
+            }
+        }
+        None
+    }
+
+    /// the flash device if it was given as /dev/disk/by-path link or topology string
+    pub fn flash_to_by_path(&self) -> Option<&Path> {
+        self.flash_to().filter(|flash_to| is_by_path_spec(flash_to))
+    }
+
+    pub fn flash_to_auto(&self) -> bool {
+        if let Some(flash_to) = &self.flash_to {
+            flash_to.as_os_str() == FLASH_TO_AUTO
+        } else {
+            false
+        }
+    }
+
+    pub fn refuse_removable_flash(&self) -> bool {
+        self.refuse_removable_flash
     }
 
     pub fn check_timeout(&self) -> u64 {
@@ -262,10 +1689,58 @@ impl Options {
         }
     }
 
+    pub fn network_backend(&self) -> Option<NetworkBackend> {
+        self.network_backend
+    }
+
     pub fn no_nwmgr_check(&self) -> bool {
         self.no_nwmgr_check
     }
 
+    pub fn default_dhcp_ethernet(&self) -> bool {
+        self.default_dhcp_ethernet
+    }
+
+    pub fn force_default_dhcp_ethernet(&self) -> bool {
+        self.force_default_dhcp_ethernet
+    }
+
+    pub fn continue_on_nonfatal(&self) -> bool {
+        self.continue_on_nonfatal
+    }
+
+    pub fn test_network(&self) -> bool {
+        self.test_network
+    }
+
+    pub fn seed_entropy(&self) -> bool {
+        self.seed_entropy
+    }
+
+    pub fn ssh_authorized_keys(&self) -> Option<&Path> {
+        if let Some(ssh_authorized_keys) = &self.ssh_authorized_keys {
+            Some(ssh_authorized_keys.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn tags(&self) -> &[String] {
+        self.tag.as_slice()
+    }
+
+    pub fn env(&self) -> Vec<&str> {
+        self.env.iter().map(|env_var| env_var.as_str()).collect()
+    }
+
+    pub fn migration_tags(&self) -> bool {
+        self.migration_tags
+    }
+
+    pub fn hostname_template(&self) -> Option<&str> {
+        self.hostname_template.as_deref()
+    }
+
     pub fn migrate_name(&self) -> bool {
         !self.no_keep_name
     }
@@ -273,4 +1748,151 @@ impl Options {
     pub fn cleanup(&self) -> bool {
         !self.no_cleanup
     }
+
+    pub fn init_restart(&self) -> &[InitRestart] {
+        self.init_restart.as_slice()
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    pub fn init_path(&self) -> Option<&Path> {
+        self.init_path.as_deref()
+    }
+
+    pub fn init_timeout(&self) -> u64 {
+        if let Some(timeout) = self.init_timeout {
+            timeout
+        } else {
+            DEFAULT_INIT_TIMEOUT
+        }
+    }
+
+    pub fn mount_retries(&self) -> u32 {
+        if let Some(retries) = self.mount_retries {
+            retries
+        } else {
+            DEFAULT_MOUNT_RETRIES
+        }
+    }
+
+    pub fn flash_open_retries(&self) -> u32 {
+        if let Some(retries) = self.flash_open_retries {
+            retries
+        } else {
+            DEFAULT_FLASH_OPEN_RETRIES
+        }
+    }
+
+    pub fn memory_warn_ratio(&self) -> f64 {
+        if let Some(ratio) = self.memory_warn_ratio {
+            ratio
+        } else {
+            DEFAULT_MEMORY_WARN_RATIO
+        }
+    }
+
+    pub fn strict_memory(&self) -> bool {
+        self.strict_memory
+    }
+
+    pub fn min_battery(&self) -> u64 {
+        if let Some(min_battery) = self.min_battery {
+            min_battery
+        } else {
+            DEFAULT_MIN_BATTERY
+        }
+    }
+
+    pub fn ignore_power(&self) -> bool {
+        self.ignore_power
+    }
+
+    pub fn init_reexec_cmd(&self) -> &str {
+        if let Some(init_reexec_cmd) = &self.init_reexec_cmd {
+            init_reexec_cmd
+        } else {
+            TELINIT_CMD
+        }
+    }
+
+    pub fn init_reexec_args(&self) -> Vec<&str> {
+        if let Some(init_reexec_args) = &self.init_reexec_args {
+            init_reexec_args.split_whitespace().collect()
+        } else {
+            vec![DEFAULT_INIT_REEXEC_ARGS]
+        }
+    }
+
+    pub fn diagnostics(&self) -> Option<&Path> {
+        if let Some(diagnostics) = &self.diagnostics {
+            Some(diagnostics.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn status_socket(&self) -> Option<&Path> {
+        if let Some(status_socket) = &self.status_socket {
+            Some(status_socket.as_path())
+        } else {
+            None
+        }
+    }
+
+    /// The percentage threshold for progress events in --status-on-change mode, None if every
+    /// progress event is sent
+    pub fn status_change_threshold(&self) -> Option<u8> {
+        if self.status_on_change {
+            Some(self.status_threshold.unwrap_or(DEFAULT_STATUS_THRESHOLD) as u8)
+        } else {
+            None
+        }
+    }
+
+    pub fn ensure_dev(&self) -> &[PathBuf] {
+        self.ensure_dev.as_slice()
+    }
+
+    pub fn ignore_fs_types(&self) -> &[String] {
+        self.ignore_fs_types.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_byte_offset() {
+        assert_eq!(parse_byte_offset("8192").unwrap(), 8192);
+        assert_eq!(parse_byte_offset("0x8000").unwrap(), 0x8000);
+        assert_eq!(parse_byte_offset("0X2000").unwrap(), 0x2000);
+        assert!(parse_byte_offset("0x").is_err());
+        assert!(parse_byte_offset("-1").is_err());
+        assert!(parse_byte_offset("8k").is_err());
+    }
+
+    #[test]
+    fn test_debug_masks_secrets() {
+        let opts = Options::from_iter(&[
+            "takeover",
+            "--download-auth-bearer",
+            "tok-1234",
+            "--download-header",
+            "X-Auth: hdr-5678",
+            "--env",
+            "DEPLOY_TOKEN=env-9012",
+        ]);
+        let debug = format!("{:#?}", opts);
+        assert!(!debug.contains("tok-1234"));
+        assert!(!debug.contains("hdr-5678"));
+        assert!(!debug.contains("env-9012"));
+        assert!(debug.contains("X-Auth:<redacted>"));
+        assert!(debug.contains("DEPLOY_TOKEN=<redacted>"));
+        assert_eq!(opts.download_auth_bearer(), Some("tok-1234"));
+        assert_eq!(opts.download_headers(), vec!["X-Auth: hdr-5678"]);
+        assert_eq!(opts.env(), vec!["DEPLOY_TOKEN=env-9012"]);
+    }
 }
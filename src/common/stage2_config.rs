@@ -1,8 +1,17 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-use crate::common::error::{Result, ToError};
+use crate::common::{
+    crc32,
+    error::{Error, ErrorKind, Result, ToError},
+    flash_transform::FlashTransform,
+    image_format::ImageFormat,
+    image_slices::SliceManifest,
+};
+
+const CHECKSUM_PREFIX: &str = "# crc32: ";
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct UmountPart {
@@ -17,13 +26,80 @@ pub(crate) struct LogDevice {
     pub fs_type: String,
 }
 
+/// Where stage2 writes the archive of the old root file system
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) enum ArchiveTarget {
+    Device { dev_name: PathBuf, fs_type: String },
+    Network { address: SocketAddr },
+}
+
+/// A partition of the flash device that stage2 saves to memory before flashing and writes back
+/// to the partition with the same number afterwards
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct PreservePart {
+    /// the partition number or label given on the command line
+    pub name: String,
+    pub index: usize,
+    /// offset and size on the flash device in bytes
+    pub start: u64,
+    pub size: u64,
+    /// the slice manifest leaves the partition untouched, it is neither saved nor restored
+    pub in_place: bool,
+}
+
+/// Where stage2 writes Prometheus metrics and the labels of the samples
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct MetricsConfig {
+    pub path: PathBuf,
+    pub device_type: String,
+    pub image_version: String,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct Stage2Config {
     pub log_dev: Option<LogDevice>,
     pub log_level: String,
+    pub json_log: bool,
+    pub log_to_boot: bool,
     pub flash_dev: PathBuf,
+    pub flash_offset: u64,
+    pub flash_block_size: u64,
+    pub flash_direct: bool,
+    pub flash_open_retries: u32,
+    pub min_flash_speed: Option<u64>,
+    pub min_flash_speed_window: u64,
+    pub delta_flash: bool,
+    pub delta_flash_threshold: u64,
+    pub resumable_flash: bool,
+    pub no_flash: bool,
+    pub flash_transforms: Vec<FlashTransform>,
+    pub config_in_image: bool,
+    pub verify_sample: Option<u64>,
+    // KEY=VALUE, set for stage2 and the stage2 script
+    pub env: Vec<String>,
+    pub compress_image: bool,
+    pub compression_level: i32,
+    pub image_format: Option<ImageFormat>,
+    pub image_slices: Option<SliceManifest>,
+    // mountpoint of the network share the image is read from, unmounted after copying it
+    pub image_share: Option<PathBuf>,
     pub pretend: bool,
+    pub zero_remainder: bool,
+    pub randomize_uuids: bool,
+    pub fsck_before_mount: bool,
+    pub archive_old_root: Option<ArchiveTarget>,
+    pub shell_after_flash: bool,
+    pub await_approval: Option<u64>,
+    pub set_rtc: bool,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    pub metrics: Option<MetricsConfig>,
+    pub stage2_script: Option<PathBuf>,
+    pub network_dir: String,
     pub umount_parts: Vec<UmountPart>,
+    pub preserve_parts: Vec<PreservePart>,
+    /// size of the files stage1 staged for stage2 to copy to the tmpfs
+    pub total_staged_bytes: u64,
     pub work_dir: PathBuf,
     pub image_path: PathBuf,
     pub config_path: PathBuf,
@@ -41,12 +117,47 @@ impl Stage2Config {
         }
     }
 
+    /// serialize to YAML followed by a comment line containing the CRC32 of the YAML text
     pub fn serialize(&self) -> Result<String> {
-        Ok(serde_yaml::to_string(self)
-            .upstream_with_context("Failed to deserialize stage2 config")?)
+        let mut config_str = serde_yaml::to_string(self)
+            .upstream_with_context("Failed to deserialize stage2 config")?;
+        if !config_str.ends_with('\n') {
+            config_str.push('\n');
+        }
+        let checksum = crc32(config_str.as_bytes());
+        Ok(format!(
+            "{}{}{:08x}\n",
+            config_str, CHECKSUM_PREFIX, checksum
+        ))
     }
 
     pub fn deserialze(config_str: &str) -> Result<Stage2Config> {
+        let trimmed = config_str.trim_end_matches('\n');
+        let (config_str, checksum) = if let Some(pos) = trimmed.rfind(CHECKSUM_PREFIX) {
+            (&trimmed[..pos], &trimmed[pos + CHECKSUM_PREFIX.len()..])
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                "Corrupt stage2 config: checksum is missing, the file might be truncated",
+            ));
+        };
+
+        let expected = u32::from_str_radix(checksum.trim(), 16).error_with_all(
+            ErrorKind::InvState,
+            &format!("Corrupt stage2 config: invalid checksum '{}'", checksum),
+        )?;
+
+        let found = crc32(config_str.as_bytes());
+        if found != expected {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Corrupt stage2 config: checksum mismatch, expected {:08x}, found {:08x}",
+                    expected, found
+                ),
+            ));
+        }
+
         Ok(serde_yaml::from_str(&config_str)
             .upstream_with_context("Failed to parse stage2 config")?)
     }
@@ -55,3 +166,73 @@ impl Stage2Config {
         &self.flash_dev
     }
 }
+
+#[cfg(test)]
+pub(crate) fn test_config() -> Stage2Config {
+    Stage2Config {
+        log_dev: None,
+        log_level: "info".to_string(),
+        json_log: false,
+        log_to_boot: false,
+        flash_dev: PathBuf::from("/dev/sda"),
+        flash_offset: 0,
+        flash_block_size: 128 * 1024,
+        flash_direct: false,
+        flash_open_retries: 5,
+        min_flash_speed: None,
+        min_flash_speed_window: 60,
+        delta_flash: false,
+        delta_flash_threshold: 50,
+        resumable_flash: false,
+        no_flash: false,
+        flash_transforms: Vec::new(),
+        config_in_image: false,
+        verify_sample: None,
+        env: Vec::new(),
+        compress_image: false,
+        compression_level: 3,
+        image_format: None,
+        image_slices: None,
+        image_share: None,
+        pretend: false,
+        zero_remainder: false,
+        randomize_uuids: false,
+        fsck_before_mount: false,
+        archive_old_root: None,
+        shell_after_flash: false,
+        await_approval: None,
+        set_rtc: false,
+        timezone: None,
+        locale: None,
+        metrics: None,
+        stage2_script: None,
+        network_dir: String::from("system-connections"),
+        umount_parts: Vec::new(),
+        preserve_parts: Vec::new(),
+        total_staged_bytes: 0,
+        work_dir: PathBuf::from("/home/takeover"),
+        image_path: PathBuf::from("/home/takeover/balena.img.gz"),
+        config_path: PathBuf::from("/home/takeover/config.json"),
+        backup_path: None,
+        tty: PathBuf::from("/dev/pts/0"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_round_trip() {
+        let config_str = test_config().serialize().unwrap();
+        let config = Stage2Config::deserialze(&config_str).unwrap();
+        assert_eq!(config.flash_dev, PathBuf::from("/dev/sda"));
+
+        let corrupted = config_str.replace("/dev/sda", "/dev/sdb");
+        let res = Stage2Config::deserialze(&corrupted);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvState);
+
+        let truncated = &config_str[..config_str.len() / 2];
+        assert!(Stage2Config::deserialze(truncated).is_err());
+    }
+}
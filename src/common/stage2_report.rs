@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::error::{Result, ToError};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct StepResult {
+    pub name: String,
+    pub success: bool,
+    pub message: Option<String>,
+}
+
+/// What stage2 did, written to the log device and the boot partition so it can be read after
+/// the reboot
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct Stage2Report {
+    pub version: String,
+    pub device: PathBuf,
+    pub flash_offset: u64,
+    pub bytes_flashed: Option<u64>,
+    pub flash_secs: Option<u64>,
+    pub validated: Option<bool>,
+    pub backup_restored: bool,
+    pub duration_secs: u64,
+    pub success: bool,
+    pub steps: Vec<StepResult>,
+}
+
+impl Stage2Report {
+    pub fn new(device: &Path, flash_offset: u64) -> Stage2Report {
+        Stage2Report {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            device: device.to_path_buf(),
+            flash_offset,
+            bytes_flashed: None,
+            flash_secs: None,
+            validated: None,
+            backup_restored: false,
+            duration_secs: 0,
+            success: false,
+            steps: Vec::new(),
+        }
+    }
+
+    /// record the outcome of a step, returns true if the step succeeded
+    pub fn add_step<T>(&mut self, name: &str, res: &Result<T>) -> bool {
+        let (success, message) = match res {
+            Ok(_) => (true, None),
+            Err(why) => (false, Some(why.to_string())),
+        };
+        self.steps.push(StepResult {
+            name: name.to_string(),
+            success,
+            message,
+        });
+        success
+    }
+
+    pub fn add_failed_step(&mut self, name: &str, message: &str) {
+        self.steps.push(StepResult {
+            name: name.to_string(),
+            success: false,
+            message: Some(message.to_string()),
+        });
+    }
+
+    /// the report is successful if all recorded steps succeeded
+    pub fn finish(&mut self, duration_secs: u64) {
+        self.duration_secs = duration_secs;
+        self.success = self.steps.iter().all(|step| step.success);
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .upstream_with_context("Failed to serialize stage2 report")
+    }
+
+    /// Prometheus text format metrics of the finished report, every sample carries the labels
+    pub fn to_metrics(&self, device_type: &str, image_version: &str) -> String {
+        let labels = format!(
+            "{{device_type=\"{}\",image_version=\"{}\"}}",
+            escape_label(device_type),
+            escape_label(image_version)
+        );
+
+        let mut metrics: Vec<(&str, &str, u64)> = vec![
+            (
+                "takeover_duration_seconds",
+                "Duration of stage2 in seconds",
+                self.duration_secs,
+            ),
+            (
+                "takeover_success",
+                "1 if all steps of stage2 succeeded, else 0",
+                self.success as u64,
+            ),
+        ];
+        if let Some(bytes_flashed) = self.bytes_flashed {
+            metrics.push((
+                "takeover_bytes_flashed",
+                "Bytes written to the flash device",
+                bytes_flashed,
+            ));
+            if let Some(flash_secs) = self.flash_secs {
+                metrics.push((
+                    "takeover_flash_throughput_bytes",
+                    "Bytes written to the flash device per second",
+                    bytes_flashed / flash_secs.max(1),
+                ));
+            }
+        }
+
+        metrics
+            .iter()
+            .map(|(name, help, value)| {
+                format!(
+                    "# HELP {} {}\n# TYPE {} gauge\n{}{} {}\n",
+                    name, help, name, name, labels, value
+                )
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn from_json(report_str: &str) -> Result<Stage2Report> {
+        serde_json::from_str(report_str).upstream_with_context("Failed to parse stage2 report")
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::error::{Error, ErrorKind};
+
+    #[test]
+    fn test_report_round_trip() {
+        let mut report = Stage2Report::new(Path::new("/dev/sda"), 0);
+        report.bytes_flashed = Some(1024);
+        assert!(report.add_step("flash", &Ok(())));
+        report.finish(10);
+        assert!(report.success);
+
+        let report = Stage2Report::from_json(&report.to_json().unwrap()).unwrap();
+        assert_eq!(report.device, PathBuf::from("/dev/sda"));
+        assert_eq!(report.bytes_flashed, Some(1024));
+        assert_eq!(report.duration_secs, 10);
+        assert!(report.success);
+    }
+
+    #[test]
+    fn test_report_metrics() {
+        let mut report = Stage2Report::new(Path::new("/dev/sda"), 0);
+        report.bytes_flashed = Some(1000);
+        report.flash_secs = Some(10);
+        assert!(report.add_step("flash", &Ok(())));
+        report.finish(20);
+
+        let metrics = report.to_metrics("raspberrypi3", "2.50.1+rev1");
+        assert!(metrics.contains("# TYPE takeover_success gauge\n"));
+        assert!(metrics.contains(
+            "takeover_flash_throughput_bytes{device_type=\"raspberrypi3\",image_version=\"2.50.1+rev1\"} 100\n"
+        ));
+        assert!(metrics.contains("takeover_duration_seconds{"));
+        assert_eq!(escape_label("a\"b"), "a\\\"b");
+    }
+
+    #[test]
+    fn test_report_failed_step() {
+        let mut report = Stage2Report::new(Path::new("/dev/sda"), 0);
+        assert!(report.add_step("copy_files", &Ok(())));
+        let res: Result<()> = Err(Error::with_context(ErrorKind::InvState, "no space"));
+        assert!(!report.add_step("transfer_files", &res));
+        report.finish(1);
+        assert!(!report.success);
+        assert_eq!(report.steps.len(), 2);
+        assert!(report.steps[1]
+            .message
+            .as_ref()
+            .unwrap()
+            .contains("no space"));
+    }
+}
@@ -603,7 +603,6 @@ pub(crate) fn mkdir<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
     }
 }
 
-#[allow(dead_code)]
 pub(crate) fn chmod<P: AsRef<Path>>(file_name: P, mode: mode_t) -> Result<()> {
     let fd = Fd::open(file_name.as_ref(), O_RDONLY)?;
     let res = unsafe { libc::fchmod(fd.get_fd(), mode) };
@@ -617,6 +616,25 @@ pub(crate) fn chmod<P: AsRef<Path>>(file_name: P, mode: mode_t) -> Result<()> {
     }
 }
 
+/// Restrict a file containing secrets to root with mode 0600, NetworkManager ignores connection
+/// files that are accessible by others
+pub(crate) fn set_root_only<P: AsRef<Path>>(file_name: P) -> Result<()> {
+    chmod(&file_name, 0o600)?;
+    // only root can change the owner, takeover always runs as root
+    if unsafe { libc::getuid() } == 0 {
+        let c_path = path_to_cstring(&file_name)?;
+        let res = unsafe { libc::chown(c_path.as_ptr(), 0, 0) };
+        if res != 0 {
+            return Err(sys_error(&format!(
+                "libc::chown failed on file '{}'",
+                file_name.as_ref().display()
+            )));
+        }
+    }
+    debug!("set_root_only: '{}'", file_name.as_ref().display());
+    Ok(())
+}
+
 enum CopyInodes {
     SameFs,
     SeparateFs(HashMap<ino_t, PathBuf>),
@@ -1,8 +1,11 @@
 use crate::{
     common::{
         call,
-        defs::{MOUNT_CMD, NIX_NONE, PIVOT_ROOT_CMD, TAKEOVER_DIR},
-        get_mountpoint, path_append, whereis, Error, Result, ToError,
+        cmd_runner::SysCommandRunner,
+        defs::{INIT_STARTED_NAME, MOUNT_CMD, NIX_NONE, PIVOT_ROOT_CMD, TAKEOVER_DIR},
+        get_mountpoint,
+        log_writer::set_log_writer,
+        parse_env_var, path_append, whereis, Error, Result, ToError,
     },
     stage2::{read_stage2_config, reboot},
     ErrorKind,
@@ -17,10 +20,11 @@ use nix::{
 };
 use std::env::set_current_dir;
 use std::ffi::CString;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, File};
 use std::io;
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
+use std::path::PathBuf;
 use std::process::Command;
 use std::str::FromStr;
 use std::thread::sleep;
@@ -81,8 +85,7 @@ fn setup_log(log_dev: &LogDevice) -> Result<()> {
         );
 
         let logfile = path_append(&mountpoint, "stage2-init.log");
-        Logger::set_log_file(&LogDestination::Stderr, &logfile, false)
-            .upstream_with_context(&format!("Failed set log file to  '{}'", logfile.display()))?;
+        set_log_writer("init", Some(&logfile), false, false)?;
         info!(
             "Now logging to '{}' on '{}'",
             logfile.display(),
@@ -229,6 +232,14 @@ pub fn init() -> ! {
         reboot();
     }
 
+    // let stage1 know that init was restarted
+    if let Err(why) = File::create(INIT_STARTED_NAME) {
+        warn!(
+            "Failed to create '{}' in '{}', error: {:?}",
+            INIT_STARTED_NAME, TAKEOVER_DIR, why
+        );
+    }
+
     let s2_config = match read_stage2_config(Some(TAKEOVER_DIR)) {
         Ok(s2_config) => s2_config,
         Err(why) => {
@@ -274,7 +285,8 @@ pub fn init() -> ! {
     Logger::flush();
     sync();
 
-    match whereis(MOUNT_CMD) {
+    let runner = SysCommandRunner::new();
+    match whereis(MOUNT_CMD, &runner) {
         Ok(mount_cmd) => {
             if let Err(why) = call_command!(
                 mount_cmd.as_str(),
@@ -294,7 +306,7 @@ pub fn init() -> ! {
         }
     }
 
-    match whereis(PIVOT_ROOT_CMD) {
+    match whereis(PIVOT_ROOT_CMD, &runner) {
         Ok(pivot_root_cmd) => {
             if let Err(why) = call_command!(
                 pivot_root_cmd.as_str(),
@@ -317,13 +329,35 @@ pub fn init() -> ! {
         }
     }
 
-    let _child_pid = match Command::new(&format!("/bin/{}", env!("CARGO_PKG_NAME")))
+    // a stage2 script receives the stage2 arguments and is expected to exec stage2
+    let stage2_cmd = if let Some(ref stage2_script) = s2_config.stage2_script {
+        info!("Running stage2 script '{}'", stage2_script.display());
+        stage2_script.clone()
+    } else {
+        PathBuf::from(format!("/bin/{}", env!("CARGO_PKG_NAME")))
+    };
+
+    // variables given with --env, stage2 and the commands it runs inherit them
+    let mut env_vars: Vec<(String, String)> = Vec::new();
+    for env_var in &s2_config.env {
+        match parse_env_var(env_var) {
+            Ok(key_value) => env_vars.push(key_value),
+            Err(why) => warn!("Ignoring environment variable: {}", why),
+        }
+    }
+
+    let _child_pid = match Command::new(&stage2_cmd)
         .args(&["--stage2", "--s2-log-level", &s2_config.log_level])
+        .envs(env_vars)
         .spawn()
     {
         Ok(cmd_res) => cmd_res.id(),
         Err(why) => {
-            error!("Failed to spawn stage2 worker process, error: {:?}", why);
+            error!(
+                "Failed to spawn stage2 worker process '{}', error: {:?}",
+                stage2_cmd.display(),
+                why
+            );
             reboot();
         }
     };
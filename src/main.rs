@@ -29,11 +29,11 @@ fn main() {
     if is_init() {
         init();
     } else {
-        let opts = Options::from_args();
+        let mut opts = Options::from_args();
 
         if opts.stage2() {
             stage2(&opts);
-        } else if let Err(why) = stage1(&opts) {
+        } else if let Err(why) = opts.normalize_paths().and_then(|_| stage1(&opts)) {
             exit_code = 1;
             match why.kind() {
                 ErrorKind::Displayed => (),
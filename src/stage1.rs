@@ -1,68 +1,154 @@
 mod backup;
 
-use std::env::set_current_dir;
-use std::fs::{copy, create_dir, create_dir_all, read_dir, read_link, remove_dir_all, OpenOptions};
-use std::io::Write;
+use std::env::{current_dir, set_current_dir, set_var};
+use std::fs::{
+    copy, create_dir, create_dir_all, metadata, read, read_dir, read_link, read_to_string,
+    remove_dir, remove_dir_all, OpenOptions,
+};
+use std::io::{self, Write};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
 use std::thread::sleep;
 use std::time::Duration;
 
 use nix::{
-    mount::{mount, MsFlags},
-    unistd::sync,
+    mount::{mount, umount, umount2, MntFlags, MsFlags},
+    sys::{
+        signal::{kill, Signal},
+        stat::makedev,
+    },
+    unistd::{sync, Pid},
 };
 
-use libc::MS_BIND;
+use libc::{MS_BIND, S_IFBLK};
 
-use log::{debug, error, info, warn, Level};
+use log::{debug, error, info, log, warn, Level};
+use regex::Regex;
 
 pub(crate) mod migrate_info;
 
 mod api_calls;
+mod benchmark;
 mod block_device_info;
+mod block_device_list;
+mod config_staging;
 mod defs;
 mod device;
 mod device_impl;
+mod device_profile;
+mod dump_assets;
+mod entropy;
 
+mod exe_arch;
 mod exe_copy;
+mod fstab;
 
+mod host_settings;
+mod hostname;
 mod image_retrieval;
+mod image_share;
+mod image_signature;
+mod instance_lock;
+mod kernel_modules;
+mod migration_plan;
+mod network_diff;
+mod network_test;
+mod plan;
+mod power_supply;
+mod preserve_parts;
+mod ssh_keys;
+mod stage2_script;
+mod status_socket;
+mod support_bundle;
 mod utils;
+mod watchdog;
 mod wifi_config;
 
 use crate::{
     common::{
-        call,
+        cmd_runner::{CommandRunner, SysCommandRunner},
         defs::{
-            NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SWAPOFF_CMD, SYSTEM_CONNECTIONS_DIR,
-            SYS_EFIVARS_DIR, SYS_EFI_DIR, TELINIT_CMD,
+            INIT_STARTED_NAME, NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SWAPOFF_CMD,
+            SYS_EFIVARS_DIR, SYS_EFI_DIR,
         },
         error::{Error, ErrorKind, Result, ToError},
-        file_exists, format_size_with_unit, get_mem_info, is_admin,
-        options::Options,
-        path_append,
-        stage2_config::{Stage2Config, UmountPart},
+        file_exists,
+        flash_transform::{parse_patch, FlashTransform, FlashTransformKind},
+        format_size_with_unit, get_mem_info,
+        image_format::{get_image_format, open_image, ImageFormat},
+        image_slices::is_slice_manifest,
+        is_admin,
+        log_writer::set_log_writer,
+        options::{InitRestart, LogFormat, Options, StagingOrder},
+        parse_env_var, path_append, redact,
+        stage2_config::{ArchiveTarget, MetricsConfig, Stage2Config, UmountPart},
         system::copy_dir,
+        whereis,
     },
     stage1::{
-        block_device_info::BlockDevice, block_device_info::BlockDeviceInfo, exe_copy::ExeCopy,
-        migrate_info::MigrateInfo, utils::mount_fs,
+        block_device_info::BlockDevice,
+        block_device_info::BlockDeviceInfo,
+        block_device_info::DeviceNum,
+        block_device_list::list_block_devices,
+        config_staging::stage_config_in_image,
+        device_impl::get_device,
+        device_profile::apply_device_profile,
+        dump_assets::dump_assets,
+        entropy::{check_entropy, warn_if_entropy_low},
+        exe_copy::ExeCopy,
+        fstab::check_fstab,
+        host_settings::{get_locale, get_timezone},
+        image_retrieval::list_os_versions,
+        instance_lock::InstanceLock,
+        kernel_modules::check_kernel_modules,
+        migrate_info::MigrateInfo,
+        migration_plan::MigrationPlan,
+        network_test::test_network,
+        plan::print_plan,
+        power_supply::check_power,
+        preserve_parts::get_preserve_parts,
+        stage2_script::{get_script_shell, write_stage2_script},
+        status_socket::{ProgressCallback, StatusSocket},
+        support_bundle::{create_support_bundle, BundleInfo},
+        utils::{confirm, get_mounts_below, mount_fs, resolve_host, retry_eintr, sleep_full},
+        watchdog::Watchdog,
+        wifi_config::create_dhcp_ethernet_file,
     },
 };
 
-use crate::common::defs::{DD_CMD, EFIBOOTMGR_CMD, TAKEOVER_DIR};
+use crate::common::defs::{
+    BALENA_BOOT_PART, BALENA_DATA_PART, BUSYBOX_CMD, DD_CMD, E2FSCK_CMD, EFIBOOTMGR_CMD,
+    FSCK_VFAT_CMD, SFDISK_CMD, TAKEOVER_DIR, TUNE2FS_CMD,
+};
 use crate::common::dir_exists;
 use crate::common::stage2_config::LogDevice;
-use crate::common::system::{is_dir, mkdir, stat};
-use mod_logger::{LogDestination, Logger, NO_STREAM};
-
+use crate::common::system::{is_dir, mkdir, mknod, set_root_only, stat};
+use mod_logger::Logger;
+
+// O_DIRECT requires writes aligned to the logical sector size
+const FLASH_BLOCK_ALIGN: u64 = 512;
+// zstd levels supported for staging the image compressed
+const MIN_COMPRESSION_LEVEL: i32 = 1;
+const MAX_COMPRESSION_LEVEL: i32 = 19;
+const PROC_INIT_EXE: &str = "/proc/1/exe";
+const PROC_INIT_CMDLINE: &str = "/proc/1/cmdline";
+// where init binaries are commonly found, in the order they are tried
+const INIT_PATHS: [&str; 4] = [
+    "/sbin/init",
+    "/lib/systemd/systemd",
+    "/usr/lib/systemd/systemd",
+    "/bin/init",
+];
+// milliseconds between checks for the new init
+const INIT_POLL_INTERVAL: u64 = 500;
 const S1_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024; // const XTRA_MEM_FREE: u64 = 10 * 1024 * 1024; // 10 MB
 
 fn prepare_configs<P1: AsRef<Path>>(
     work_dir: P1,
     mig_info: &mut MigrateInfo,
+    opts: &Options,
     // takeover_dir: P2,
 ) -> Result<()> {
     let work_dir = work_dir.as_ref();
@@ -70,9 +156,10 @@ fn prepare_configs<P1: AsRef<Path>>(
     mig_info.update_config()?;
 
     // *********************************************************
-    // write network_manager filess to tmpfs
+    // write network configuration files to tmpfs
     let mut nwmgr_cfgs: u64 = 0;
-    let nwmgr_path = path_append(&work_dir, SYSTEM_CONNECTIONS_DIR);
+    let network_backend = mig_info.network_backend();
+    let nwmgr_path = path_append(&work_dir, network_backend.config_dir());
     create_dir_all(&nwmgr_path).upstream_with_context(&format!(
         "Failed to create directory '{}",
         nwmgr_path.display()
@@ -81,20 +168,66 @@ fn prepare_configs<P1: AsRef<Path>>(
     for source_file in mig_info.nwmgr_files() {
         nwmgr_cfgs += 1;
         let target_file = path_append(&nwmgr_path, &format!("balena-{:02}", nwmgr_cfgs));
-        copy(&source_file, &target_file).upstream_with_context(&format!(
+        let size = copy(&source_file, &target_file).upstream_with_context(&format!(
             "Failed to copy '{}' to '{}'",
             source_file.display(),
             target_file.display()
         ))?;
+        set_root_only(&target_file)?;
         info!(
             "Copied '{}' to '{}'",
             source_file.display(),
             target_file.display()
         );
+        debug!(
+            "prepare_configs: '{}' -> '{}', {}",
+            source_file.display(),
+            target_file.display(),
+            format_size_with_unit(size)
+        );
     }
 
+    let mut skipped: Vec<String> = Vec::new();
     for wifi_config in mig_info.wifis() {
-        wifi_config.create_nwmgr_file(&nwmgr_path, nwmgr_cfgs)?;
+        match wifi_config.create_network_file(&nwmgr_path, nwmgr_cfgs + 1, network_backend) {
+            Ok(index) => nwmgr_cfgs = index,
+            Err(why) => {
+                if opts.continue_on_nonfatal() {
+                    warn!(
+                        "Skipping network configuration for wifi '{}', error: {}",
+                        wifi_config.get_ssid(),
+                        why
+                    );
+                    skipped.push(wifi_config.get_ssid().to_string());
+                } else {
+                    return Err(why);
+                }
+            }
+        }
+    }
+
+    if opts.default_dhcp_ethernet() {
+        if nwmgr_cfgs == 0 || opts.force_default_dhcp_ethernet() {
+            create_dhcp_ethernet_file(&nwmgr_path, network_backend)?;
+            nwmgr_cfgs += 1;
+        } else {
+            info!(
+                "Not creating a DHCP ethernet configuration, {} network configurations are migrated",
+                nwmgr_cfgs
+            );
+        }
+    }
+
+    if !skipped.is_empty() {
+        warn!(
+            "Skipped network configurations for {} wifis: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+        if nwmgr_cfgs == 0 && !opts.no_nwmgr_check() {
+            error!("No valid network configuration remains, the device might not be able to come online, use --no-nwmgr-check to continue anyway");
+            return Err(Error::displayed());
+        }
     }
 
     Ok(())
@@ -150,7 +283,7 @@ fn mount_sys_filesystems(
     // *********************************************************
     // mount tmpfs
 
-    mount_fs(&takeover_dir, "tmpfs", "tmpfs", None)?;
+    mount_fs(&takeover_dir, "tmpfs", "tmpfs", None, opts.mount_retries())?;
 
     let curr_path = takeover_dir.join("etc");
     create_dir(&curr_path).upstream_with_context(&format!(
@@ -170,28 +303,68 @@ fn mount_sys_filesystems(
     info!("Created mtab in  '{}'", curr_path.display());
 
     let curr_path = takeover_dir.join("proc");
-    mount_fs(curr_path, "proc", "proc", Some(mig_info))?;
+    mount_fs(
+        curr_path,
+        "proc",
+        "proc",
+        Some(mig_info),
+        opts.mount_retries(),
+    )?;
 
     let curr_path = takeover_dir.join("tmp");
-    mount_fs(&curr_path, "tmpfs", "tmpfs", Some(mig_info))?;
+    mount_fs(
+        &curr_path,
+        "tmpfs",
+        "tmpfs",
+        Some(mig_info),
+        opts.mount_retries(),
+    )?;
 
     let curr_path = takeover_dir.join("sys");
-    mount_fs(&curr_path, "sys", "sysfs", Some(mig_info))?;
+    mount_fs(
+        &curr_path,
+        "sys",
+        "sysfs",
+        Some(mig_info),
+        opts.mount_retries(),
+    )?;
 
     if dir_exists(SYS_EFIVARS_DIR)? {
         let curr_path = path_append(&takeover_dir, SYS_EFIVARS_DIR);
         create_dir_all(&curr_path)?;
-        mount_fs(&curr_path, "efivarfs", "efivarfs", Some(mig_info))?;
+        mount_fs(
+            &curr_path,
+            "efivarfs",
+            "efivarfs",
+            Some(mig_info),
+            opts.mount_retries(),
+        )?;
         // TODO: copy stuff ?
     }
 
     let curr_path = takeover_dir.join("dev");
-    if mount_fs(&curr_path, "dev", "devtmpfs", Some(mig_info)).is_err() {
+    if mount_fs(
+        &curr_path,
+        "dev",
+        "devtmpfs",
+        Some(mig_info),
+        opts.mount_retries(),
+    )
+    .is_err()
+    {
         warn!("Failed to mount devtmpfs on /dev, trying to copy device nodes");
-        mount_fs(&curr_path, "tmpfs", "tmpfs", Some(mig_info))?;
+        mount_fs(
+            &curr_path,
+            "tmpfs",
+            "tmpfs",
+            Some(mig_info),
+            opts.mount_retries(),
+        )?;
 
         copy_dir("/dev", &curr_path)?;
 
+        ensure_dev_nodes(&curr_path, opts.ensure_dev())?;
+
         let curr_path = takeover_dir.join("dev/pts");
         if curr_path.exists() {
             remove_dir_all(&curr_path).upstream_with_context(&format!(
@@ -205,39 +378,820 @@ fn mount_sys_filesystems(
         use crate::common::debug::check_loop_control;
         check_loop_control("After dev mount", &curr_path);
     } else {
-        debug!("(??)Log Level: {:?}", opts.log_level());
+        debug!("(??)Log Level: {:?}", opts.log_level());
+    }
+
+    let curr_path = takeover_dir.join("dev/pts");
+    mount_fs(
+        &curr_path,
+        "devpts",
+        "devpts",
+        Some(mig_info),
+        opts.mount_retries(),
+    )?;
+
+    Ok(())
+}
+
+/// create block device nodes missing from a copied /dev using the device numbers from sysfs
+fn ensure_dev_nodes(dev_dir: &Path, dev_nodes: &[PathBuf]) -> Result<()> {
+    for dev_node in dev_nodes {
+        let rel_path = if let Ok(rel_path) = dev_node.strip_prefix("/dev") {
+            rel_path
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid device node '{}', device nodes must be located in /dev",
+                    dev_node.display()
+                ),
+            ));
+        };
+
+        let node_path = dev_dir.join(rel_path);
+        if node_path.exists() {
+            debug!("ensure_dev_nodes: '{}' exists", node_path.display());
+            continue;
+        }
+
+        let dev_name = if let Some(dev_name) = dev_node.file_name() {
+            dev_name
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Failed to retrieve device name from '{}'",
+                    dev_node.display()
+                ),
+            ));
+        };
+
+        let sys_path = path_append(path_append("/sys/class/block", dev_name), "dev");
+        let dev_num = DeviceNum::from_str(&read_to_string(&sys_path).error_with_all(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "Failed to read device number of '{}' from '{}'",
+                dev_node.display(),
+                sys_path.display()
+            ),
+        )?)?;
+
+        if let Some(parent) = node_path.parent() {
+            create_dir_all(parent).upstream_with_context(&format!(
+                "Failed to create directory '{}'",
+                parent.display()
+            ))?;
+        }
+
+        mknod(
+            &node_path,
+            S_IFBLK | 0o660,
+            makedev(dev_num.major(), dev_num.minor()),
+        )?;
+
+        info!(
+            "Created device node '{}' as {}",
+            node_path.display(),
+            dev_num
+        );
+    }
+
+    Ok(())
+}
+
+/// unmount file systems left mounted in the takeover directory by a previous run
+fn umount_leftovers(takeover_dir: &Path) -> Result<()> {
+    let mut leftovers = get_mounts_below(takeover_dir)?;
+    if leftovers.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Found {} file systems still mounted below '{}', possibly by a previous run, trying to unmount them",
+        leftovers.len(),
+        takeover_dir.display()
+    );
+
+    while let Some(mountpoint) = leftovers.pop() {
+        if let Err(why) = retry_eintr(|| umount(&mountpoint)) {
+            error!(
+                "Failed to unmount '{}', error: {} - please unmount manually",
+                mountpoint.display(),
+                why
+            );
+            return Err(Error::displayed());
+        }
+        info!("Unmounted '{}'", mountpoint.display());
+    }
+
+    Ok(())
+}
+
+/// Create the mountpoint stage2 mounts the old root on and check that it is an empty directory
+/// without mounts, stage2 fails after the point of no return otherwise
+fn prepare_old_root_mp(old_root_mp: &Path) -> Result<()> {
+    let existed = old_root_mp.exists();
+    create_dir_all(old_root_mp).upstream_with_context(&format!(
+        "Failed to create directory '{}'",
+        old_root_mp.display()
+    ))?;
+
+    let mounts = get_mounts_below(old_root_mp)?;
+    if !mounts.is_empty() {
+        error!(
+            "The old root mountpoint '{}' has file systems mounted at or below it: {:?} - please unmount them and retry",
+            old_root_mp.display(),
+            mounts
+        );
+        return Err(Error::displayed());
+    }
+
+    let entries = read_dir(old_root_mp)
+        .upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            old_root_mp.display()
+        ))?
+        .count();
+    if entries > 0 {
+        error!(
+            "The old root mountpoint '{}' is not empty, it contains {} entries - please remove them and retry",
+            old_root_mp.display(),
+            entries
+        );
+        return Err(Error::displayed());
+    }
+
+    info!(
+        "Old root mountpoint '{}' {}, it is empty and nothing is mounted on it",
+        old_root_mp.display(),
+        if existed { "exists" } else { "was created" }
+    );
+    Ok(())
+}
+
+/// the commands stage2 needs in the takeover file system
+pub(crate) fn get_copy_commands(opts: &Options, is_x86: bool) -> Result<Vec<&'static str>> {
+    let mut copy_commands = vec![DD_CMD];
+    if is_x86 && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR)? {
+        copy_commands.push(EFIBOOTMGR_CMD)
+    }
+    if opts.randomize_uuids() {
+        copy_commands.push(TUNE2FS_CMD)
+    }
+    if opts.fsck_before_mount() {
+        copy_commands.push(E2FSCK_CMD);
+        copy_commands.push(FSCK_VFAT_CMD);
+    }
+    if opts.shell_after_flash() {
+        copy_commands.push(BUSYBOX_CMD);
+    }
+    if let Some(image) = opts.image() {
+        if is_slice_manifest(image) {
+            copy_commands.push(SFDISK_CMD);
+        }
+    }
+    if let Some(stage2_script) = opts.stage2_script() {
+        copy_commands.push(get_script_shell(stage2_script)?);
+    }
+    Ok(copy_commands)
+}
+
+/// check the flash candidates for --flash-to auto, the root device is only selected when it is
+/// the single candidate. A removable root device is no candidate, so booting from a stick fails
+/// here rather than flashing the stick.
+fn check_auto_flash_candidates(root_device: &str, candidates: &[String]) -> Result<()> {
+    match candidates {
+        [] => Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            "No possible flash devices found, please select one using --flash-to",
+        )),
+        [candidate] if candidate == root_device => Ok(()),
+        _ => Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The root device '{}' is not the only possible flash device, found: {}, please select one using --flash-to",
+                root_device,
+                candidates.join(", ")
+            ),
+        )),
+    }
+}
+
+/// select the device to flash balena-os to, either the configured device or the root device
+fn get_flash_device<'a>(
+    opts: &Options,
+    block_dev_info: &'a BlockDeviceInfo,
+) -> Result<&'a Rc<dyn BlockDevice>> {
+    let flash_dev = if let Some(topology) = opts.flash_to_by_path() {
+        let flash_dev = block_dev_info.get_device_by_path(topology)?;
+        info!(
+            "Using flash device '{}' found at '{}'",
+            flash_dev.get_dev_path().display(),
+            topology.display()
+        );
+        flash_dev
+    } else if let Some(flash_dev) = opts.flash_to() {
+        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
+            flash_dev
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Could not find configured flash device '{}'",
+                    flash_dev.display()
+                ),
+            ));
+        }
+    } else if opts.flash_to_auto() {
+        let root_device = block_dev_info.get_root_device();
+        let candidates: Vec<String> = block_dev_info
+            .get_flash_candidates()
+            .iter()
+            .map(|device| device.get_dev_path().display().to_string())
+            .collect();
+        check_auto_flash_candidates(
+            &root_device.get_dev_path().display().to_string(),
+            &candidates,
+        )?;
+        info!(
+            "Using root device '{}' as flash device",
+            root_device.get_dev_path().display()
+        );
+        root_device
+    } else {
+        let root_device = block_dev_info.get_root_device();
+        warn!(
+            "No flash device given, using root device '{}', consider using '--flash-to auto'",
+            root_device.get_dev_path().display()
+        );
+        root_device
+    };
+
+    if !file_exists(&flash_dev.as_ref().get_dev_path()) {
+        return Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "The device could not be found: '{}'",
+                flash_dev.get_dev_path().display()
+            ),
+        ));
+    }
+
+    Ok(flash_dev)
+}
+
+/// Check that the flash device is of the expected kind. An automatically selected device that
+/// is removable or attached via USB is likely a stick the device was booted from rather than the
+/// internal storage
+fn check_flash_device_type(opts: &Options, flash_dev: &Rc<dyn BlockDevice>) -> Result<()> {
+    let removable = BlockDeviceInfo::is_removable(flash_dev.as_ref());
+    let bus_type = BlockDeviceInfo::get_bus_type(flash_dev.as_ref());
+    let dev_path = flash_dev.get_dev_path();
+    debug!(
+        "check_flash_device_type: '{}' removable: {:?}, bus: {:?}",
+        dev_path.display(),
+        removable,
+        bus_type
+    );
+
+    let is_external = removable == Some(true) || bus_type == Some("usb");
+    if opts.flash_to().is_some() {
+        info!(
+            "Flashing {} device '{}' on bus {}",
+            if is_external { "removable" } else { "internal" },
+            dev_path.display(),
+            bus_type.unwrap_or("unknown")
+        );
+    } else if is_external {
+        if opts.refuse_removable_flash() {
+            error!(
+                "The automatically selected flash device '{}' is removable or attached via USB, use --flash-to to select the device to flash",
+                dev_path.display()
+            );
+            return Err(Error::displayed());
+        }
+        warn!(
+            "The automatically selected flash device '{}' is removable or attached via USB, use --flash-to to select internal storage",
+            dev_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check if the image resides on a partition of the flash device that stage2 unmounts. Stage2
+/// copies the image to memory before unmounting partitions, which fails if the file system is
+/// served by a FUSE daemon as stage2 terminates all processes first.
+fn check_image_source(mig_info: &MigrateInfo, umount_parts: &[UmountPart]) -> Result<()> {
+    let mut image_paths: Vec<PathBuf> = Vec::new();
+    if let Some(image_slices) = mig_info.image_slices() {
+        for index in 0..image_slices.partitions.len() {
+            if let Some(image_path) = image_slices.image_path(index, Path::new("/")) {
+                image_paths.push(image_path);
+            }
+        }
+    } else {
+        image_paths.push(mig_info.image_path().to_path_buf());
+    }
+
+    for image_path in &image_paths {
+        let image_dev = stat(image_path)?.st_dev;
+        for umount_part in umount_parts {
+            if stat(&umount_part.mountpoint)?.st_dev != image_dev {
+                continue;
+            }
+
+            if umount_part.fs_type.starts_with("fuse") {
+                error!(
+                    "The image '{}' resides on '{}' mounted on '{}' using FUSE ({}), stage2 terminates the FUSE daemon before the image is copied. Please copy the image to a different file system",
+                    image_path.display(),
+                    umount_part.dev_name.display(),
+                    umount_part.mountpoint.display(),
+                    umount_part.fs_type
+                );
+                return Err(Error::displayed());
+            }
+
+            info!(
+                "The image '{}' resides on '{}' which is a partition of the flash device, it is copied to memory before '{}' is unmounted",
+                image_path.display(),
+                umount_part.dev_name.display(),
+                umount_part.mountpoint.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that the partitions of the slice manifest fit on the flash device and the images fit
+/// into their partitions
+fn check_image_slices(mig_info: &MigrateInfo, flash_dev: &Rc<dyn BlockDevice>) -> Result<()> {
+    let image_slices = if let Some(image_slices) = mig_info.image_slices() {
+        image_slices
+    } else {
+        return Ok(());
+    };
+
+    if mig_info.flash_offset() != 0 {
+        error!(
+            "Slice manifests can not be used on device types that flash the image at offset 0x{:x}",
+            mig_info.flash_offset()
+        );
+        return Err(Error::displayed());
+    }
+
+    let size_path = path_append("/sys/block", &format!("{}/size", flash_dev.get_name()));
+    let dev_size = read_to_string(&size_path)
+        .upstream_with_context(&format!("Failed to read '{}'", size_path.display()))?
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!(
+            "Failed to parse device size from '{}'",
+            size_path.display()
+        ))?
+        * 512;
+
+    let layout = image_slices.layout(dev_size)?;
+    let slices_size = image_slices.check_images(&layout, Path::new("/"))?;
+    info!(
+        "Slice manifest fits on '{}', {} of partition images",
+        flash_dev.get_dev_path().display(),
+        format_size_with_unit(slices_size)
+    );
+    Ok(())
+}
+
+/// Check that the uncompressed image fits on the flash device behind the flash offset. The
+/// uncompressed size of compressed images is taken from --image-uncompressed-size if given, else
+/// the image is decompressed to determine it, as not all formats record it reliably.
+fn check_image_fits(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    flash_dev: &Rc<dyn BlockDevice>,
+) -> Result<()> {
+    if mig_info.image_slices().is_some() {
+        return Ok(());
+    }
+
+    let image_path = mig_info.image_path();
+    let image_format = get_image_format(image_path, opts.image_format())?;
+    let image_size = match (image_format, opts.image_uncompressed_size()) {
+        (ImageFormat::Raw, uncompressed_size) => {
+            if uncompressed_size.is_some() {
+                warn!("Ignoring --image-uncompressed-size for the uncompressed image");
+            }
+            metadata(image_path)
+                .upstream_with_context(&format!(
+                    "Failed to retrieve metadata for '{}'",
+                    image_path.display()
+                ))?
+                .len()
+        }
+        (_, Some(uncompressed_size)) => {
+            info!(
+                "Using uncompressed image size {} given with --image-uncompressed-size",
+                format_size_with_unit(uncompressed_size)
+            );
+            uncompressed_size
+        }
+        (image_format, None) => {
+            info!(
+                "Decompressing the {} image '{}' to determine its size, use --image-uncompressed-size to skip this",
+                image_format,
+                image_path.display()
+            );
+            io::copy(&mut open_image(image_path, image_format)?, &mut io::sink())
+                .upstream_with_context(&format!(
+                    "Failed to decompress image '{}'",
+                    image_path.display()
+                ))?
+        }
+    };
+
+    let dev_size = if let Some(dev_size) = BlockDeviceInfo::get_size(flash_dev.as_ref()) {
+        dev_size
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Failed to determine the size of the flash device '{}'",
+                flash_dev.get_dev_path().display()
+            ),
+        ));
+    };
+
+    if !image_fits(mig_info.flash_offset(), image_size, dev_size) {
+        error!(
+            "The uncompressed image of {} written at offset 0x{:x} does not fit on the flash device '{}' of {}",
+            format_size_with_unit(image_size),
+            mig_info.flash_offset(),
+            flash_dev.get_dev_path().display(),
+            format_size_with_unit(dev_size)
+        );
+        return Err(Error::displayed());
+    }
+
+    info!(
+        "The uncompressed image of {} fits on the flash device '{}' of {}",
+        format_size_with_unit(image_size),
+        flash_dev.get_dev_path().display(),
+        format_size_with_unit(dev_size)
+    );
+    Ok(())
+}
+
+/// Whether an image of image_size bytes written at flash_offset ends within the device
+fn image_fits(flash_offset: u64, image_size: u64, dev_size: u64) -> bool {
+    flash_offset
+        .checked_add(image_size)
+        .map_or(false, |end| end <= dev_size)
+}
+
+/// In no-flash mode the files are placed on the partitions of an existing balena-os installation,
+/// the flash device has to carry the balena boot and data partitions
+fn check_balena_partitions(
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<()> {
+    let labels: Vec<&str> = block_dev_info
+        .get_devices()
+        .values()
+        .filter(|device| {
+            device.get_parent().map(|parent| parent.get_name()) == Some(flash_dev.get_name())
+        })
+        .filter_map(|device| device.get_partition_info())
+        .filter_map(|part_info| part_info.fs_label())
+        .collect();
+    debug!(
+        "check_balena_partitions: file system labels on '{}': {:?}",
+        flash_dev.get_dev_path().display(),
+        labels
+    );
+
+    let missing: Vec<&str> = [BALENA_BOOT_PART, BALENA_DATA_PART]
+        .iter()
+        .filter(|label| !labels.contains(*label))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        info!(
+            "Found the balena partitions on '{}', the image is not flashed in no-flash mode",
+            flash_dev.get_dev_path().display()
+        );
+        Ok(())
+    } else {
+        error!(
+            "The flash device '{}' does not look like a balena-os disk, the partitions {} were not found. --no-flash needs an existing balena-os installation",
+            flash_dev.get_dev_path().display(),
+            missing.join(", ")
+        );
+        Err(Error::displayed())
+    }
+}
+
+/// A flash interrupted by a power loss can only be resumed by running takeover again, which
+/// needs a system that does not live on the flash device
+fn check_resumable_flash(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<()> {
+    if !opts.resumable_flash() {
+        return Ok(());
+    }
+
+    if mig_info.image_slices().is_some() {
+        error!("--resumable-flash can not be used with a slice manifest");
+        return Err(Error::displayed());
+    }
+
+    if flash_dev.get_name() == block_dev_info.get_root_device().get_name() {
+        error!(
+            "--resumable-flash requires a flash device that does not hold the running system, '{}' is the root device",
+            flash_dev.get_dev_path().display()
+        );
+        return Err(Error::displayed());
+    }
+
+    warn!(
+        "Resumable flash is enabled, an interrupted flash leaves '{}' unbootable until takeover is run again with the same image",
+        flash_dev.get_dev_path().display()
+    );
+    Ok(())
+}
+
+/// Build the transforms selected with --flash-transform from the patch and key files, stage2
+/// applies them to the decompressed image before it is written or compared to the flash device
+fn get_flash_transforms(opts: &Options, mig_info: &MigrateInfo) -> Result<Vec<FlashTransform>> {
+    let kinds = opts.flash_transform();
+    if !kinds.contains(&FlashTransformKind::Patch) && !opts.patch().is_empty() {
+        error!("--patch requires --flash-transform patch");
+        return Err(Error::displayed());
+    }
+    if !kinds.contains(&FlashTransformKind::Xor) && opts.xor_key().is_some() {
+        error!("--xor-key requires --flash-transform xor");
+        return Err(Error::displayed());
+    }
+    if kinds.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if mig_info.image_slices().is_some() {
+        error!("--flash-transform can not be used with a slice manifest");
+        return Err(Error::displayed());
+    }
+
+    let mut transforms: Vec<FlashTransform> = Vec::new();
+    for kind in kinds {
+        match kind {
+            FlashTransformKind::Patch => {
+                if opts.patch().is_empty() {
+                    error!("The patch transform requires at least one --patch");
+                    return Err(Error::displayed());
+                }
+                for patch in opts.patch() {
+                    let (offset, patch_path) = parse_patch(patch)?;
+                    let data = read(&patch_path).upstream_with_context(&format!(
+                        "Failed to read patch file '{}'",
+                        patch_path.display()
+                    ))?;
+                    info!(
+                        "Flash transform: patching {} bytes at offset 0x{:x} from '{}'",
+                        data.len(),
+                        offset,
+                        patch_path.display()
+                    );
+                    transforms.push(FlashTransform::Patch { offset, data });
+                }
+            }
+            FlashTransformKind::Xor => {
+                let key_path = if let Some(key_path) = opts.xor_key() {
+                    key_path
+                } else {
+                    error!("The xor transform requires --xor-key");
+                    return Err(Error::displayed());
+                };
+                let key = read(key_path).upstream_with_context(&format!(
+                    "Failed to read key file '{}'",
+                    key_path.display()
+                ))?;
+                if key.is_empty() {
+                    error!("The key file '{}' is empty", key_path.display());
+                    return Err(Error::displayed());
+                }
+                info!(
+                    "Flash transform: XOR with a {} byte key from '{}'",
+                    key.len(),
+                    key_path.display()
+                );
+                transforms.push(FlashTransform::Xor { key });
+            }
+        }
+    }
+    Ok(transforms)
+}
+
+/// Find the binary of the running init that the new init is bind-mounted over. /proc/1/exe can
+/// not be read with restricted proc access (hidepid) or when PID 1 is in another namespace, then
+/// /proc/1/cmdline and well known init paths are tried.
+fn get_old_init_path(opts: &Options) -> Result<PathBuf> {
+    if let Some(init_path) = opts.init_path() {
+        info!("Using init binary '{}'", init_path.display());
+        return Ok(init_path.to_path_buf());
+    }
+
+    match read_link(PROC_INIT_EXE) {
+        Ok(init_path) => return Ok(init_path),
+        Err(why) => warn!("Failed to read link for {}: {}", PROC_INIT_EXE, why),
+    }
+
+    match read_to_string(PROC_INIT_CMDLINE) {
+        Ok(cmdline) => {
+            let init_cmd = Path::new(cmdline.split('\0').next().unwrap_or(""));
+            if init_cmd.is_absolute() {
+                if let Ok(init_path) = init_cmd.canonicalize() {
+                    info!(
+                        "Using init binary '{}' from {}",
+                        init_path.display(),
+                        PROC_INIT_CMDLINE
+                    );
+                    return Ok(init_path);
+                }
+            }
+            warn!(
+                "The init command '{}' from {} could not be resolved",
+                init_cmd.display(),
+                PROC_INIT_CMDLINE
+            );
+        }
+        Err(why) => warn!("Failed to read {}: {}", PROC_INIT_CMDLINE, why),
+    }
+
+    for init_path in &INIT_PATHS {
+        if let Ok(init_path) = Path::new(init_path).canonicalize() {
+            warn!(
+                "Assuming that '{}' is the running init, use --init-path if it is not",
+                init_path.display()
+            );
+            return Ok(init_path);
+        }
+    }
+
+    error!(
+        "Failed to determine the init binary from {}, {} or the paths {}, please specify it using --init-path",
+        PROC_INIT_EXE,
+        PROC_INIT_CMDLINE,
+        INIT_PATHS.join(", ")
+    );
+    Err(Error::displayed())
+}
+
+/// the metrics stage2 writes to the log device, the image version is taken from the image file
+/// name as used for downloaded images
+fn get_metrics_config(opts: &Options, mig_info: &MigrateInfo) -> Result<Option<MetricsConfig>> {
+    let path = if let Some(path) = opts.metrics_file() {
+        path
+    } else {
+        return Ok(None);
+    };
+
+    let version_re = Regex::new(r"\d+\.\d+\.\d+(\+rev\d+)?")
+        .upstream_with_context("Failed to create regex for image version")?;
+    let image_version = mig_info
+        .image_path()
+        .file_name()
+        .and_then(|file_name| {
+            version_re
+                .find(&file_name.to_string_lossy())
+                .map(|m| m.as_str().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    Ok(Some(MetricsConfig {
+        path: path.to_path_buf(),
+        device_type: mig_info.balena_cfg().get_device_type()?,
+        image_version,
+    }))
+}
+
+/// determine where stage2 archives the old root file system to, the archive must not be written
+/// to the flash device or to the root file system that is archived
+fn get_archive_target(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<Option<ArchiveTarget>> {
+    let dest = if let Some(dest) = opts.archive_old_root() {
+        dest
+    } else {
+        return Ok(None);
+    };
+
+    if let Some(address) = dest.strip_prefix("tcp://") {
+        // resolve the address here, stage2 has no name resolution
+        let (host, port) = if let Some(pos) = address.rfind(':') {
+            (&address[..pos], &address[pos + 1..])
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("No port given in archive destination '{}'", dest),
+            ));
+        };
+        let port = u16::from_str(port)
+            .upstream_with_context(&format!("Invalid port in archive destination '{}'", dest))?;
+        let address = resolve_host(host.trim_start_matches('[').trim_end_matches(']'), port)?[0];
+        info!("Old root will be archived to {}", address);
+        return Ok(Some(ArchiveTarget::Network { address }));
+    }
+
+    let dev_path = PathBuf::from(dest);
+    let archive_dev = if let Some(archive_dev) = block_dev_info.get_devices().get(&dev_path) {
+        archive_dev
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            &format!("The archive device '{}' could not be found", dest),
+        ));
+    };
+
+    if archive_dev.get_name() == flash_dev.get_name()
+        || archive_dev.get_parent().map(|parent| parent.get_name()) == Some(flash_dev.get_name())
+    {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The archive device '{}' is on the flash device '{}'",
+                dest,
+                flash_dev.get_dev_path().display()
+            ),
+        ));
+    }
+
+    if let Some(root_partition) = block_dev_info.get_root_partition() {
+        if root_partition.get_name() == archive_dev.get_name() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("The archive device '{}' holds the root file system", dest),
+            ));
+        }
+    }
+
+    const SUPPORTED_ARCHIVE_FS_TYPES: [&str; 2] = ["ext3", "ext4"];
+    let fs_type = archive_dev
+        .get_partition_info()
+        .and_then(|partition_info| partition_info.fs_type());
+    if let Some(fs_type) = fs_type {
+        if SUPPORTED_ARCHIVE_FS_TYPES.iter().any(|val| *val == fs_type) {
+            info!("Old root will be archived to '{}'", dest);
+            Ok(Some(ArchiveTarget::Device {
+                dev_name: dev_path,
+                fs_type: fs_type.to_owned(),
+            }))
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The archive device's ('{}') file system type '{}' is not in the list of supported file systems: {:?}",
+                    dest, fs_type, SUPPORTED_ARCHIVE_FS_TYPES
+                ),
+            ))
+        }
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The archive device '{}' is not a partition with a known file system type",
+                dest
+            ),
+        ))
     }
-
-    let curr_path = takeover_dir.join("dev/pts");
-    mount_fs(&curr_path, "devpts", "devpts", Some(mig_info))?;
-
-    Ok(())
 }
 
-fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
+fn prepare(
+    opts: &Options,
+    mig_info: &mut MigrateInfo,
+    runner: &dyn CommandRunner,
+    status: &StatusSocket,
+    bundle_info: &BundleInfo,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
     info!("Preparing for takeover..");
 
     // *********************************************************
     // turn off swap
-    call_command!(SWAPOFF_CMD, &["-a"], "Failed to disable SWAP")?;
+    runner.call_command(SWAPOFF_CMD, &["-a"], "Failed to disable SWAP")?;
 
     // *********************************************************
     // calculate required memory
 
-    let mut req_space: u64 = 0;
-    let mut copy_commands = vec![DD_CMD];
-    if mig_info.is_x86() && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR)? {
-        copy_commands.push(EFIBOOTMGR_CMD)
-    }
+    let copy_commands = get_copy_commands(opts, mig_info.is_x86())?;
 
-    let commands = match ExeCopy::new(copy_commands) {
+    let commands = match ExeCopy::new(copy_commands, runner) {
         Ok(commands) => {
-            let cmd_space = commands.get_req_space();
             debug!(
                 "Space required for commands: {}",
-                format_size_with_unit(cmd_space)
+                format_size_with_unit(commands.get_req_space())
             );
-            req_space += cmd_space;
             commands
         }
         Err(why) => {
@@ -248,25 +1202,11 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         }
     };
 
-    let (mem_tot, mem_free) = get_mem_info()?;
-    info!(
-        "Found {} total, {} free memory",
-        format_size_with_unit(mem_tot),
-        format_size_with_unit(mem_free)
-    );
-
-    // TODO: maybe kill some procs first
-    if mem_free < req_space + S1_XTRA_FS_SIZE {
-        return Err(Error::with_context(ErrorKind::InvState, &format!(
-            "Not enough memory space found to copy files to RAMFS, required size is {} free memory is {}",
-            format_size_with_unit(req_space + S1_XTRA_FS_SIZE),
-            format_size_with_unit(mem_free)
-        )));
-    }
-
     // *********************************************************
     // make mountpoint for tmpfs
     let takeover_dir = PathBuf::from(TAKEOVER_DIR);
+
+    umount_leftovers(&takeover_dir)?;
     match stat(&takeover_dir) {
         Ok(stat) => {
             if is_dir(&stat) {
@@ -296,7 +1236,15 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         }
         Err(why) => {
             if why.kind() == ErrorKind::FileNotFound {
-                mkdir(&takeover_dir, 0o755)?;
+                // init expects the takeover directory at this fixed path, there is no fallback
+                if let Err(why) = mkdir(&takeover_dir, 0o755) {
+                    error!(
+                        "Failed to create the takeover directory '{}', the root file system has to be writable, error: {}",
+                        takeover_dir.display(),
+                        why
+                    );
+                    return Err(Error::displayed());
+                }
             } else {
                 return Err(Error::from_upstream(
                     Box::new(why),
@@ -315,56 +1263,95 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     // *********************************************************
     // create mountpoint for old root
 
-    let curr_path = path_append(&takeover_dir, OLD_ROOT_MP);
+    prepare_old_root_mp(&path_append(&takeover_dir, OLD_ROOT_MP))?;
 
-    create_dir_all(&curr_path).upstream_with_context(&format!(
-        "Failed to create directory '{}'",
-        curr_path.display()
-    ))?;
+    prepare_configs(opts.work_dir(), mig_info, opts)?;
+
+    let config_in_image = if opts.staging_order() == StagingOrder::ConfigFirst {
+        stage_config_in_image(opts, mig_info)?
+    } else {
+        false
+    };
+
+    // *********************************************************
+    // check memory, the commands are copied to the tmpfs now, the staged files in stage2
+
+    let total_staged_bytes = mig_info.total_staged_bytes()?;
+    info!(
+        "Staged {} of files to be copied in stage2",
+        format_size_with_unit(total_staged_bytes)
+    );
 
-    info!("Created directory '{}'", curr_path.display());
+    let req_space = commands.get_req_space() + total_staged_bytes + S1_XTRA_FS_SIZE;
+    let (mem_tot, mem_free) = get_mem_info()?;
+    info!(
+        "Found {} total, {} free memory",
+        format_size_with_unit(mem_tot),
+        format_size_with_unit(mem_free)
+    );
 
-    commands.copy_files(&takeover_dir)?;
+    // TODO: maybe kill some procs first
+    if mem_free < req_space {
+        return Err(Error::with_context(ErrorKind::InvState, &format!(
+            "Not enough memory space found to copy files to RAMFS, required size is {} free memory is {}",
+            format_size_with_unit(req_space),
+            format_size_with_unit(mem_free)
+        )));
+    }
+    check_memory_margin(opts, req_space, mem_free)?;
 
-    prepare_configs(opts.work_dir(), mig_info)?;
+    status.step("copy_files", "Copying files to takeover directory");
+    commands.copy_files(&takeover_dir, |copied, total| {
+        status.progress(
+            "copy_files",
+            &format!("Copied {} of {} files", copied, total),
+            (copied * 100 / total.max(1)) as u8,
+        )
+    })?;
 
     // *********************************************************
     // setup new init
 
-    let old_init_path =
-        read_link("/proc/1/exe").upstream_with_context("Failed to read link for /proc/1/exe")?;
+    let old_init_path = get_old_init_path(opts)?;
+    let reexec_cmd = get_init_reexec_cmd(opts, runner)?;
 
     // TODO: make new_init_path point to /$takeover_dir/bin/takeover directly
     let new_init_path = path_append(&takeover_dir, &format!("/bin/{}", env!("CARGO_PKG_NAME")));
-    // Assets::write_stage2_script(&takeover_dir, &new_init_path, &tty, opts.get_s2_log_level())?;
 
-    let block_dev_info = BlockDeviceInfo::new()?;
+    let tty = read_link("/proc/self/fd/1")
+        .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?;
 
-    let flash_dev = if let Some(flash_dev) = opts.flash_to() {
-        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
-            flash_dev
-        } else {
-            return Err(Error::with_context(
-                ErrorKind::InvState,
-                &format!(
-                    "Could not find configured flash device '{}'",
-                    flash_dev.display()
-                ),
-            ));
-        }
+    let stage2_script = if let Some(script_path) = opts.stage2_script() {
+        Some(write_stage2_script(
+            script_path,
+            &takeover_dir,
+            &tty,
+            &opts.s2_log_level().to_string(),
+        )?)
     } else {
-        block_dev_info.get_root_device()
+        None
     };
 
-    if !file_exists(&flash_dev.as_ref().get_dev_path()) {
-        return Err(Error::with_context(
-            ErrorKind::DeviceNotFound,
-            &format!(
-                "The device could not be found: '{}'",
-                flash_dev.get_dev_path().display()
-            ),
-        ));
+    let block_dev_info = BlockDeviceInfo::new(opts.ignore_fs_types(), runner)?;
+
+    let flash_dev = get_flash_device(opts, &block_dev_info)?;
+    check_flash_device_type(opts, flash_dev)?;
+
+    check_image_slices(mig_info, flash_dev)?;
+    if opts.no_flash() {
+        check_balena_partitions(flash_dev, &block_dev_info)?;
+    } else {
+        check_image_fits(opts, mig_info, flash_dev)?;
+    }
+    check_resumable_flash(opts, mig_info, flash_dev, &block_dev_info)?;
+    let flash_transforms = get_flash_transforms(opts, mig_info)?;
+
+    let umount_parts = get_umount_parts(flash_dev, &block_dev_info)?;
+    check_fstab(flash_dev, &block_dev_info, &umount_parts)?;
+    if !opts.no_flash() {
+        check_image_source(mig_info, &umount_parts)?;
     }
+    let preserve_parts = get_preserve_parts(opts, mig_info, flash_dev, &block_dev_info)?;
 
     let log_device = if let Some(log_dev_path) = opts.log_to() {
         if let Some(log_dev) = block_dev_info.get_devices().get(log_dev_path) {
@@ -402,14 +1389,117 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         None
     };
 
+    let flash_block_size = if let Some(flash_bs) = opts.flash_bs() {
+        flash_bs
+    } else {
+        mig_info.flash_block_size()
+    };
+
+    if flash_block_size == 0 || flash_block_size % FLASH_BLOCK_ALIGN != 0 {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The flash block size {} is not a multiple of {}",
+                flash_block_size, FLASH_BLOCK_ALIGN
+            ),
+        ));
+    }
+
+    if mig_info.flash_offset() % flash_block_size != 0 {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The flash offset 0x{:x} is not a multiple of the flash block size {}",
+                mig_info.flash_offset(),
+                flash_block_size
+            ),
+        ));
+    }
+
+    if opts.compress_image() {
+        let level = opts.stage_compression_level();
+        if !(MIN_COMPRESSION_LEVEL..=MAX_COMPRESSION_LEVEL).contains(&level) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The compression level {} is not in the range {} to {}",
+                    level, MIN_COMPRESSION_LEVEL, MAX_COMPRESSION_LEVEL
+                ),
+            ));
+        }
+        info!(
+            "Staging the image in memory compressed with zstd level {}",
+            level
+        );
+    }
+
+    info!(
+        "Using a flash block size of {}{}",
+        format_size_with_unit(flash_block_size),
+        if opts.flash_direct() {
+            " with O_DIRECT"
+        } else {
+            ""
+        }
+    );
+
     // collect partitions that need to be unmounted
 
     let s2_cfg = Stage2Config {
         log_dev: log_device,
+        log_to_boot: opts.log_to_boot(),
         log_level: opts.s2_log_level().to_string(),
+        json_log: opts.log_format() == LogFormat::Json,
         flash_dev: flash_dev.get_dev_path(),
+        flash_offset: mig_info.flash_offset(),
+        flash_block_size,
+        flash_direct: opts.flash_direct(),
+        flash_open_retries: opts.flash_open_retries(),
+        min_flash_speed: opts.min_flash_speed(),
+        min_flash_speed_window: opts.min_flash_speed_window(),
+        delta_flash: opts.delta_flash(),
+        delta_flash_threshold: opts.delta_flash_threshold(),
+        resumable_flash: opts.resumable_flash(),
+        no_flash: opts.no_flash(),
+        flash_transforms,
+        config_in_image,
+        verify_sample: opts.verify_sample(),
+        env: opts
+            .env()
+            .iter()
+            .map(|env_var| env_var.to_string())
+            .collect(),
+        compress_image: opts.compress_image(),
+        compression_level: opts.stage_compression_level(),
+        image_format: opts.image_format(),
+        image_slices: mig_info.image_slices().cloned(),
+        image_share: mig_info
+            .image_share_mountpoint()
+            .map(|mountpoint| mountpoint.to_path_buf()),
         pretend: opts.pretend(),
-        umount_parts: get_umount_parts(flash_dev, &block_dev_info)?,
+        zero_remainder: opts.zero_remainder(),
+        randomize_uuids: opts.randomize_uuids(),
+        fsck_before_mount: opts.fsck_before_mount(),
+        archive_old_root: get_archive_target(opts, flash_dev, &block_dev_info)?,
+        shell_after_flash: opts.shell_after_flash(),
+        await_approval: opts.await_approval(),
+        set_rtc: opts.set_rtc(),
+        timezone: if opts.preserve_timezone() {
+            get_timezone()
+        } else {
+            None
+        },
+        locale: if opts.preserve_locale() {
+            get_locale()
+        } else {
+            None
+        },
+        metrics: get_metrics_config(opts, mig_info)?,
+        stage2_script,
+        network_dir: mig_info.network_backend().config_dir().to_string(),
+        umount_parts,
+        preserve_parts,
+        total_staged_bytes,
         work_dir: opts
             .work_dir()
             .canonicalize()
@@ -424,10 +1514,26 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         } else {
             None
         },
-        tty: read_link("/proc/self/fd/1")
-            .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?,
+        tty,
+    };
+
+    let s2_cfg = if let Some(plan_path) = opts.from_plan() {
+        let plan = MigrationPlan::load(plan_path)?;
+        plan.verify(&MigrationPlan::new(
+            &mig_info.device_type(),
+            s2_cfg.clone(),
+        )?)?;
+        info!("Replaying migration plan from '{}'", plan_path.display());
+        plan.replay(s2_cfg)
+    } else {
+        if let Some(plan_path) = opts.save_plan() {
+            MigrationPlan::new(&mig_info.device_type(), s2_cfg.clone())?.save(plan_path)?;
+        }
+        s2_cfg
     };
 
+    check_kernel_modules(&s2_cfg, runner);
+
     let s2_cfg_path = takeover_dir.join(STAGE2_CONFIG_NAME);
     let mut s2_cfg_file = OpenOptions::new()
         .create(true)
@@ -439,29 +1545,37 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         ))?;
 
     let s2_cfg_txt = s2_cfg.serialize()?;
-    debug!("Stage 2 config: \n{}", s2_cfg_txt);
+    debug!("Stage 2 config: \n{}", redact(&s2_cfg_txt));
+    bundle_info.set_stage2_config(&s2_cfg_txt);
 
     s2_cfg_file
-        .write(s2_cfg_txt.as_bytes())
+        .write_all(s2_cfg_txt.as_bytes())
         .upstream_with_context(&format!(
             "Failed to write stage2 config file to '{}'",
             s2_cfg_path.display()
         ))?;
 
+    s2_cfg_file.sync_all().upstream_with_context(&format!(
+        "Failed to sync stage2 config file '{}'",
+        s2_cfg_path.display()
+    ))?;
+
     info!("Wrote stage2 config to '{}'", s2_cfg_path.display());
 
-    set_current_dir(&takeover_dir).upstream_with_context(&format!(
-        "Failed to change current dir to '{}'",
-        takeover_dir.display()
-    ))?;
+    // point of no return, stage1 can not be aborted once the new init is in place
+    if let Some(watchdog) = watchdog {
+        watchdog.disarm();
+    }
 
-    mount(
-        Some(&new_init_path),
-        &old_init_path,
-        NIX_NONE,
-        MsFlags::from_bits(MS_BIND).unwrap(),
-        NIX_NONE,
-    )
+    retry_eintr(|| {
+        mount(
+            Some(&new_init_path),
+            &old_init_path,
+            NIX_NONE,
+            MsFlags::from_bits(MS_BIND).unwrap(),
+            NIX_NONE,
+        )
+    })
     .upstream_with_context(&format!(
         "Failed to bind-mount '{}' to '{}'",
         new_init_path.display(),
@@ -472,19 +1586,147 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
 
     //return Ok(());
 
-    debug!("calling '{} u'", TELINIT_CMD,);
-    call_command!(
-        TELINIT_CMD,
-        &["u"],
-        &format!("Call to {} failed", TELINIT_CMD)
-    )?;
+    write_support_bundle(opts, bundle_info, "Stage1 succeeded, restarting init");
+
+    // nothing depends on the current directory from here on, leaving the old one only avoids
+    // keeping its file system busy
+    if let Err(why) = set_current_dir(&takeover_dir) {
+        warn!(
+            "Failed to change current dir to '{}', error: {}",
+            takeover_dir.display(),
+            why
+        );
+    }
 
-    info!("Restarted init");
+    status.step("restart_init", "Restarting init");
 
-    Ok(())
+    restart_init(opts, runner, &takeover_dir, &reexec_cmd)
+}
+
+/// absolute path of a command, falls back to the command name if it can not be found
+fn get_abs_cmd_path(cmd: &str, runner: &dyn CommandRunner) -> String {
+    match whereis(cmd, runner) {
+        Ok(cmd_path) => {
+            let cmd_path = PathBuf::from(cmd_path);
+            if cmd_path.is_absolute() {
+                cmd_path.to_string_lossy().to_string()
+            } else if let Ok(curr_dir) = current_dir() {
+                curr_dir.join(cmd_path).to_string_lossy().to_string()
+            } else {
+                warn!("Failed to make path of '{}' absolute", cmd);
+                cmd.to_string()
+            }
+        }
+        Err(why) => {
+            warn!("Failed to locate command '{}', error: {}", cmd, why);
+            cmd.to_string()
+        }
+    }
+}
+
+/// Resolve the init re-exec command to an absolute path, it is run after the current directory
+/// changed. Fails if the command can not be found and the telinit init restart method is used.
+fn get_init_reexec_cmd(opts: &Options, runner: &dyn CommandRunner) -> Result<String> {
+    let cmd = opts.init_reexec_cmd();
+    let cmd_path = if cmd.contains('/') {
+        Path::new(cmd)
+            .canonicalize()
+            .ok()
+            .map(|cmd_path| cmd_path.to_string_lossy().to_string())
+    } else if whereis(cmd, runner).is_ok() {
+        Some(get_abs_cmd_path(cmd, runner))
+    } else {
+        None
+    };
+
+    if let Some(cmd_path) = cmd_path {
+        debug!("get_init_reexec_cmd: using '{}'", cmd_path);
+        Ok(cmd_path)
+    } else if opts.init_restart().contains(&InitRestart::Telinit) {
+        error!(
+            "The init re-exec command '{}' could not be found, select a different command using --init-reexec-cmd or different methods using --init-restart",
+            cmd
+        );
+        Err(Error::displayed())
+    } else {
+        Ok(cmd.to_string())
+    }
+}
+
+/// wait for the new init to create its marker file in the takeover directory
+fn wait_for_init(marker_path: &Path, timeout: u64) -> bool {
+    for _ in 0..(timeout * 1000 / INIT_POLL_INTERVAL) {
+        if file_exists(marker_path) {
+            return true;
+        }
+        sleep(Duration::from_millis(INIT_POLL_INTERVAL));
+    }
+    file_exists(marker_path)
+}
+
+/// Restart init using the configured methods in sequence until the new init confirms it is
+/// running. All methods are safe to use once the new init is bind-mounted over the old one.
+fn restart_init(
+    opts: &Options,
+    runner: &dyn CommandRunner,
+    takeover_dir: &Path,
+    reexec_cmd: &str,
+) -> Result<()> {
+    let marker_path = path_append(takeover_dir, INIT_STARTED_NAME);
+    let timeout = opts.init_timeout();
+    let reexec_args = opts.init_reexec_args();
+    let reexec_call = format!("{} {}", reexec_cmd, reexec_args.join(" "));
+
+    for method in opts.init_restart() {
+        let res = match method {
+            InitRestart::Telinit => {
+                info!("Restarting init using '{}'", reexec_call.trim_end());
+                runner
+                    .call_command(
+                        reexec_cmd,
+                        &reexec_args,
+                        &format!("Call to '{}' failed", reexec_call.trim_end()),
+                    )
+                    .map(|_| ())
+            }
+            InitRestart::Term => {
+                info!("Restarting init by sending SIGTERM to pid 1");
+                kill(Pid::from_raw(1), Signal::SIGTERM)
+                    .upstream_with_context("Failed to send SIGTERM to pid 1")
+            }
+            InitRestart::Hup => {
+                info!("Restarting init by sending SIGHUP to pid 1");
+                kill(Pid::from_raw(1), Signal::SIGHUP)
+                    .upstream_with_context("Failed to send SIGHUP to pid 1")
+            }
+        };
+
+        if let Err(why) = res {
+            warn!("Init restart method {:?} failed, error: {}", method, why);
+            continue;
+        }
+
+        if wait_for_init(&marker_path, timeout) {
+            info!("Restarted init");
+            return Ok(());
+        }
+
+        warn!(
+            "The new init did not start within {} seconds after init restart method {:?}",
+            timeout, method
+        );
+    }
+
+    error!("Failed to restart init, all configured init restart methods failed");
+    Err(Error::displayed())
 }
 
 pub fn stage1(opts: &Options) -> Result<()> {
+    stage1_with_progress(opts, None)
+}
+
+/// Run stage1 and pass the status events, the ones streamed by --status-socket, to on_progress
+pub fn stage1_with_progress(opts: &Options, on_progress: Option<ProgressCallback>) -> Result<()> {
     Logger::set_default_level(opts.log_level());
     Logger::set_brief_info(true);
     Logger::set_color(true);
@@ -497,23 +1739,206 @@ pub fn stage1(opts: &Options) -> Result<()> {
         }
     */
 
-    if let Some(s1_log_path) = opts.log_file() {
-        Logger::set_log_file(&LogDestination::StreamStderr, &s1_log_path, true)
-            .upstream_with_context(&format!(
-                "Failed to set logging to '{}'",
-                s1_log_path.display(),
-            ))?;
+    // all records pass the log writer, which redacts secrets
+    set_log_writer(
+        "stage1",
+        opts.log_file().as_ref().map(|path| path.as_path()),
+        true,
+        opts.log_format() == LogFormat::Json,
+    )?;
+
+    log_effective_config(opts);
+
+    if opts.list_os_versions() {
+        return list_os_versions(opts);
+    }
+
+    if opts.list_block_devices() {
+        return list_block_devices(opts);
+    }
+
+    if let Some(dump_dir) = opts.dump_assets() {
+        return dump_assets(opts, dump_dir);
+    }
+
+    if opts.plan() {
+        return print_plan(opts);
+    }
+
+    // held until stage1 terminates
+    let _lock = InstanceLock::acquire()?;
+
+    let status = StatusSocket::new(
+        opts.status_socket(),
+        on_progress,
+        opts.status_change_threshold(),
+    )?;
+    let bundle_info = BundleInfo::default();
+
+    let watchdog = if let Some(timeout) = opts.timeout() {
+        let opts = opts.clone();
+        let status = status.clone();
+        let bundle_info = bundle_info.clone();
+        Some(Watchdog::start(Duration::from_secs(timeout), move || {
+            let message = format!("Stage1 timed out after {} seconds", timeout);
+            error!("{}", message);
+            if opts.cleanup() {
+                cleanup_takeover_dir(Path::new(TAKEOVER_DIR));
+            }
+            write_support_bundle(&opts, &bundle_info, &message);
+            status.finish(false, &message);
+        })?)
+    } else {
+        None
+    };
+
+    let res = takeover(opts, &status, &bundle_info, watchdog.as_ref());
+
+    match &res {
+        Ok(_) => status.finish(true, "Stage1 completed successfully"),
+        Err(why) => {
+            let message = match why.kind() {
+                ErrorKind::Displayed => "Stage1 failed".to_string(),
+                _ => format!("Stage1 failed with error: {}", why),
+            };
+            write_support_bundle(opts, &bundle_info, &message);
+            status.finish(false, &message);
+        }
+    }
+
+    res
+}
+
+fn write_support_bundle(opts: &Options, bundle_info: &BundleInfo, status: &str) {
+    if let Some(bundle_path) = opts.diagnostics() {
+        Logger::flush();
+        if let Err(why) = create_support_bundle(opts, bundle_info, bundle_path, status) {
+            warn!(
+                "Failed to write support bundle to '{}', error: {}",
+                bundle_path.display(),
+                why
+            );
+        }
+    }
+}
+
+/// Clean up the takeover directory from the watchdog thread. The mounts are detached as the
+/// stage1 thread might still be using them.
+fn cleanup_takeover_dir(takeover_dir: &Path) {
+    match get_mounts_below(takeover_dir) {
+        Ok(mut mounts) => {
+            while let Some(mountpoint) = mounts.pop() {
+                if let Err(why) = retry_eintr(|| umount2(&mountpoint, MntFlags::MNT_DETACH)) {
+                    warn!(
+                        "Failed to unmount mountpoint: '{}', error : {:?}",
+                        mountpoint.display(),
+                        why
+                    );
+                }
+            }
+        }
+        Err(why) => {
+            warn!(
+                "Failed to retrieve mounts below '{}', error: {}",
+                takeover_dir.display(),
+                why
+            );
+        }
+    }
+
+    // only an empty directory is removed, it might not have been set up by this run yet
+    if dir_exists(takeover_dir).unwrap_or(false) {
+        if let Err(why) = remove_dir(takeover_dir) {
+            warn!(
+                "Failed to remove takeover directory: '{}', error : {:?}",
+                takeover_dir.display(),
+                why
+            );
+        }
+    }
+}
+
+/// Warn if free memory exceeds the required memory by less than --memory-warn-ratio, the kernel
+/// and the processes still running need memory while flashing too. Fails with --strict-memory.
+fn check_memory_margin(opts: &Options, required: u64, mem_free: u64) -> Result<()> {
+    let ratio = opts.memory_warn_ratio();
+    let margin = (required as f64 * ratio) as u64;
+    if mem_free >= margin {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Free memory of {} is less than {} times the required {}, takeover might run out of memory while flashing",
+        format_size_with_unit(mem_free),
+        ratio,
+        format_size_with_unit(required)
+    );
+    if opts.strict_memory() {
+        error!("{}, refusing to migrate due to --strict-memory", message);
+        Err(Error::displayed())
     } else {
-        Logger::set_log_dest(&LogDestination::Stderr, NO_STREAM)
-            .upstream_with_context("Failed to set up logging")?;
+        warn!("{}", message);
+        Ok(())
+    }
+}
+
+/// Log the options this run uses once, secrets are redacted
+fn log_effective_config(opts: &Options) {
+    let level = if opts.show_config() {
+        Level::Info
+    } else {
+        Level::Debug
+    };
+    log!(
+        level,
+        "Effective configuration: work dir: '{}', stage1 log level: {}, stage2 log level: {}, options: {}",
+        opts.work_dir().display(),
+        opts.log_level(),
+        opts.s2_log_level(),
+        redact(&format!("{:#?}", opts))
+    );
+}
+
+/// Set the variables given with --env for stage1, commands run from here inherit them
+fn set_env_vars(opts: &Options) -> Result<()> {
+    for env_var in opts.env() {
+        let (key, value) = parse_env_var(env_var)?;
+        debug!("set_env_vars: setting {}", redact(env_var));
+        set_var(key, value);
     }
+    Ok(())
+}
+
+fn takeover(
+    opts: &Options,
+    status: &StatusSocket,
+    bundle_info: &BundleInfo,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
+    status.step("check", "Checking device and configuration");
+    set_env_vars(opts)?;
+    check_entropy(opts.seed_entropy());
+
+    let runner = SysCommandRunner::new();
+    let device = get_device(opts, &runner)?;
+    bundle_info.set_device_type(&device.get_device_type().to_string());
+    let profiled_opts;
+    let opts = if opts.no_device_profile() {
+        opts
+    } else if let Some(opts) = apply_device_profile(opts, device.as_ref())? {
+        profiled_opts = opts;
+        &profiled_opts
+    } else {
+        opts
+    };
 
-    let mut mig_info = match MigrateInfo::new(&opts) {
+    let mut mig_info = match MigrateInfo::new(&opts, device, &runner) {
         Ok(mig_info) => mig_info,
         Err(why) => {
             if why.kind() == ErrorKind::ImageDownloaded {
                 return Ok(());
             } else {
+                warn_if_entropy_low("Checking the configuration or downloading the image");
                 return Err(Error::from_upstream(
                     Box::new(why),
                     "Failed to create migrate info",
@@ -527,41 +1952,35 @@ pub fn stage1(opts: &Options) -> Result<()> {
         return Err(Error::displayed());
     }
 
-    if !opts.no_ack() {
-        println!("{} will prepare your device for migration. Are you sure you want to migrate this device: [Y/n]", env!("CARGO_PKG_NAME"));
-        loop {
-            let mut buffer = String::new();
-            match std::io::stdin().read_line(&mut buffer) {
-                Ok(_) => match buffer.trim() {
-                    "Y" | "y" => {
-                        break;
-                    }
-                    "n" => {
-                        info!("Terminating on user request");
-                        return Err(Error::displayed());
-                    }
-                    _ => {
-                        println!("please type Y for yes or n for no");
-                        continue;
-                    }
-                },
-                Err(why) => {
-                    return Err(Error::from_upstream(
-                        Box::new(why),
-                        "Failed to read line from stdin",
-                    ))
-                }
-            }
-        }
+    if !confirm(
+        opts,
+        &format!(
+            "{} will prepare your device for migration. Are you sure you want to migrate this device:",
+            env!("CARGO_PKG_NAME")
+        ),
+    )? {
+        info!("Terminating on user request");
+        return Err(Error::displayed());
     }
 
     if opts.migrate() {
-        match prepare(&opts, &mut mig_info) {
+        if opts.test_network() {
+            status.step("test_network", "Testing network configuration");
+            test_network(opts, &mig_info, &runner)?;
+        }
+
+        check_power(opts)?;
+
+        status.step("prepare", "Preparing for takeover");
+        match prepare(&opts, &mut mig_info, &runner, status, bundle_info, watchdog) {
             Ok(_) => {
+                mig_info.keep_image_share();
                 info!("Takeover initiated successfully, please wait for the device to be reflashed and reboot");
                 Logger::flush();
+                // sync can neither fail nor be interrupted, the wait gives the new init time to
+                // take over before stage1 exits
                 sync();
-                sleep(Duration::from_secs(10));
+                sleep_full(Duration::from_secs(10));
                 Ok(())
             }
             Err(why) => {
@@ -575,3 +1994,33 @@ pub fn stage1(opts: &Options) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_auto_flash_candidates() {
+        let root = "/dev/mmcblk0";
+        assert!(check_auto_flash_candidates(root, &[root.to_string()]).is_ok());
+        assert!(check_auto_flash_candidates(root, &[]).is_err());
+        // booted from a removable device, the internal disk is the only candidate
+        let err = check_auto_flash_candidates("/dev/sda", &[root.to_string()]).unwrap_err();
+        assert!(err.to_string().contains(root));
+        assert!(
+            check_auto_flash_candidates(root, &[root.to_string(), "/dev/nvme0n1".to_string()])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_image_fits() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        assert!(image_fits(0, 4 * GIB, 4 * GIB));
+        assert!(image_fits(0x8000, 4 * GIB - 0x8000, 4 * GIB));
+        // the offset pushes the end of the image beyond the device
+        assert!(!image_fits(0x8000, 4 * GIB, 4 * GIB));
+        assert!(!image_fits(8 * GIB, 0, 4 * GIB));
+        assert!(!image_fits(u64::MAX, 2, 4 * GIB));
+    }
+}
@@ -1,17 +1,20 @@
 use std::io::Read;
+use std::time::Duration;
 
 use log::debug;
 
 use reqwest::{blocking::Client, header};
 use serde::{Deserialize, Serialize};
 
-use crate::common::{Error, ErrorKind, Result, ToError};
+use crate::common::{options::Options, redact, Error, ErrorKind, Result, ToError, REDACTED};
 
 const OS_VERSION_URL_P1: &str = "/device-types/v1/";
 const OS_VERSION_URL_P2: &str = "/images";
 
 const OS_IMG_URL: &str = "/download";
 
+const DEVICE_URL: &str = "/v6/device";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Versions {
     pub versions: Vec<String>,
@@ -27,13 +30,154 @@ struct ImageRequestData {
     file_type: String,
 }
 
-pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -> Result<Versions> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(api_key)
-            .upstream_with_context("Failed to create auth header")?,
-    );
+/// A device registered in the balena API
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegisteredDevice {
+    pub device_name: String,
+    pub is_online: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceQueryResult {
+    d: Vec<RegisteredDevice>,
+}
+
+/// Headers added to the requests for OS versions and images, the values are never logged
+#[derive(Clone, Default)]
+pub(crate) struct DownloadHeaders {
+    headers: Vec<(String, String)>,
+    bearer: Option<String>,
+}
+
+impl DownloadHeaders {
+    pub fn from_opts(opts: &Options) -> Result<DownloadHeaders> {
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for header in opts.download_headers() {
+            if let Some(pos) = header.find(':') {
+                headers.push((
+                    header[..pos].trim().to_string(),
+                    header[pos + 1..].trim().to_string(),
+                ));
+            } else {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Invalid download header '{}', expected 'Name: Value'",
+                        redact(header)
+                    ),
+                ));
+            }
+        }
+
+        Ok(DownloadHeaders {
+            headers,
+            bearer: opts.download_auth_bearer().map(|token| token.to_string()),
+        })
+    }
+
+    /// the request headers, authorized by the bearer token if given, else by the api key
+    fn header_map(&self, api_key: &str) -> Result<header::HeaderMap> {
+        let mut headers = header::HeaderMap::new();
+        let auth_value = if let Some(ref token) = self.bearer {
+            debug!(
+                "Authorizing download requests with bearer token {}",
+                REDACTED
+            );
+            format!("Bearer {}", token)
+        } else {
+            api_key.to_string()
+        };
+        let mut auth_value = header::HeaderValue::from_str(&auth_value)
+            .upstream_with_context("Failed to create auth header")?;
+        auth_value.set_sensitive(true);
+        headers.insert(header::AUTHORIZATION, auth_value);
+
+        for (name, value) in &self.headers {
+            debug!("Adding download header '{}: {}'", name, REDACTED);
+            let header_name = header::HeaderName::from_bytes(name.as_bytes())
+                .upstream_with_context(&format!("Invalid download header name '{}'", name))?;
+            let mut header_value = header::HeaderValue::from_str(value)
+                .upstream_with_context(&format!("Invalid value for download header '{}'", name))?;
+            header_value.set_sensitive(true);
+            headers.insert(header_name, header_value);
+        }
+        Ok(headers)
+    }
+}
+
+/// Send an unauthenticated request to url, any HTTP response proves that the TLS handshake succeeded
+pub(crate) fn check_https_connect(url: &str, timeout: u64) -> Result<()> {
+    let res = Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .get(url)
+        .send()
+        .upstream_with_context(&format!("Failed to send https request url: '{}'", url))?;
+
+    debug!("check_https_connect: '{}' returned {}", url, res.status());
+    Ok(())
+}
+
+/// Look up the device registered with uuid, None if no device is registered with it
+pub(crate) fn get_device_by_uuid(
+    api_endpoint: &str,
+    api_key: &str,
+    uuid: &str,
+    timeout: u64,
+) -> Result<Option<RegisteredDevice>> {
+    let request_url = format!("{}{}", api_endpoint.trim_end_matches('/'), DEVICE_URL);
+    debug!("get_device_by_uuid: request_url: '{}'", request_url);
+
+    let res = Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .get(&request_url)
+        .bearer_auth(api_key)
+        .query(&[
+            ("$select", "device_name,is_online"),
+            ("$filter", &format!("uuid eq '{}'", uuid)),
+        ])
+        .send()
+        .upstream_with_context(&format!(
+            "Failed to send https request url: '{}'",
+            request_url
+        ))?;
+
+    debug!("Result = {}", redact(&format!("{:?}", res)));
+
+    let status = res.status();
+    if status == 200 {
+        Ok(res
+            .json::<DeviceQueryResult>()
+            .upstream_with_context("Failed to parse request results")?
+            .d
+            .into_iter()
+            .next())
+    } else if status == 401 || status == 403 {
+        Err(Error::with_context(
+            ErrorKind::Permission,
+            &format!(
+                "Balena API request was not authorized with status: {}, please check the apiKey in config.json",
+                status
+            ),
+        ))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Balena API request failed with status: {}", status),
+        ))
+    }
+}
+
+pub(crate) fn get_os_versions(
+    api_endpoint: &str,
+    api_key: &str,
+    device: &str,
+    extra_headers: &DownloadHeaders,
+) -> Result<Versions> {
+    let headers = extra_headers.header_map(api_key)?;
 
     let request_url = format!(
         "{}{}{}{}",
@@ -53,13 +197,21 @@ pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -
             request_url
         ))?;
 
-    debug!("Result = {:?}", res);
+    debug!("Result = {}", redact(&format!("{:?}", res)));
 
     let status = res.status();
     if status == 200 {
         Ok(res
             .json::<Versions>()
             .upstream_with_context("Failed to parse request results")?)
+    } else if status == 401 || status == 403 {
+        Err(Error::with_context(
+            ErrorKind::Permission,
+            &format!(
+                "Balena API request was not authorized with status: {}, please check the apiKey in config.json",
+                status
+            ),
+        ))
     } else {
         Err(Error::with_context(
             ErrorKind::InvState,
@@ -73,13 +225,9 @@ pub(crate) fn get_os_image(
     api_key: &str,
     device: &str,
     version: &str,
+    extra_headers: &DownloadHeaders,
 ) -> Result<Box<dyn Read>> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(api_key)
-            .upstream_with_context("Failed to create auth header")?,
-    );
+    let headers = extra_headers.header_map(api_key)?;
 
     let request_url = format!("{}{}", api_endpoint, OS_IMG_URL);
 
@@ -104,7 +252,7 @@ pub(crate) fn get_os_image(
             request_url
         ))?;
 
-    debug!("Result = {:?}", res);
+    debug!("Result = {}", redact(&format!("{:?}", res)));
 
     Ok(Box::new(res))
 }
@@ -14,6 +14,7 @@ mod ext_tar_archiver;
 
 use crate::{
     common::{
+        cmd_runner::CommandRunner,
         error::{Error, ErrorKind, Result, ToError},
         path_append,
     },
@@ -110,10 +111,14 @@ fn archive_dir<'a>(
 }
 
 #[allow(dead_code)]
-pub(crate) fn create_ext(file: &Path, config: Vec<VolumeConfig>) -> Result<bool> {
+pub(crate) fn create_ext(
+    file: &Path,
+    config: Vec<VolumeConfig>,
+    runner: &dyn CommandRunner,
+) -> Result<bool> {
     if !config.is_empty() {
         info!("creating new backup in '{}", file.display());
-        let mut archiver = ExtTarArchiver::new(file)?;
+        let mut archiver = ExtTarArchiver::new(file, runner)?;
         if create_int(&mut archiver, config)? {
             info!("The backup was created successfully");
             Ok(true)
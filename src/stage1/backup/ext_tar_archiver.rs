@@ -6,10 +6,10 @@ use crate::common::system::symlink;
 use crate::stage1::utils::mktemp;
 use crate::{
     common::{
-        call,
+        cmd_runner::CommandRunner,
         defs::{BACKUP_ARCH_NAME, TAR_CMD},
         dir_exists,
-        error::{Error, ErrorKind, Result, ToError},
+        error::{Result, ToError},
         path_append,
     },
     stage1::backup::archiver::Archiver,
@@ -19,24 +19,29 @@ use crate::{
 // strategy is to link  (ln -s ) all files / directories to a temporary directory
 // and tar/gizip that directory on finish
 #[cfg(target_os = "linux")]
-pub(crate) struct ExtTarArchiver {
+pub(crate) struct ExtTarArchiver<'a> {
     tmp_dir: PathBuf,
     archive: PathBuf,
+    runner: &'a dyn CommandRunner,
 }
 
 #[cfg(target_os = "linux")]
-impl ExtTarArchiver {
-    pub fn new<P: AsRef<Path>>(file: P) -> Result<ExtTarArchiver> {
+impl<'a> ExtTarArchiver<'a> {
+    pub fn new<P: AsRef<Path>>(
+        file: P,
+        runner: &'a dyn CommandRunner,
+    ) -> Result<ExtTarArchiver<'a>> {
         const NO_PATH: Option<&Path> = None;
         Ok(ExtTarArchiver {
             tmp_dir: mktemp(true, None, None, NO_PATH)?,
             archive: PathBuf::from(file.as_ref()),
+            runner,
         })
     }
 }
 
 #[cfg(target_os = "linux")]
-impl Archiver for ExtTarArchiver {
+impl<'a> Archiver for ExtTarArchiver<'a> {
     fn add_file(&mut self, target: &Path, source: &Path) -> Result<()> {
         debug!(
             "ExtTarArchiver::add_file: '{}' , '{}'",
@@ -77,7 +82,7 @@ impl Archiver for ExtTarArchiver {
     }
 
     fn finish(&mut self) -> Result<()> {
-        let _res = call_command!(
+        let _res = self.runner.call_command(
             TAR_CMD,
             &[
                 "-h",
@@ -87,7 +92,7 @@ impl Archiver for ExtTarArchiver {
                 &*self.tmp_dir.to_string_lossy(),
                 ".",
             ],
-            &format!("Failed to create archive in '{}'", self.archive.display(),)
+            &format!("Failed to create archive in '{}'", self.archive.display(),),
         )?;
 
         if let Err(why) = remove_dir_all(&self.tmp_dir) {
@@ -0,0 +1,70 @@
+use std::fs::{remove_file, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use log::debug;
+
+use crate::{
+    common::{format_size_with_unit, Result, ToError},
+    stage1::utils::mktemp,
+};
+
+const BENCH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Measure the write throughput of the file system at dir in bytes per second by writing a
+/// temporary file of size bytes and syncing it to the device, the file is removed afterwards
+pub(crate) fn measure_write_throughput(dir: &Path, size: u64) -> Result<u64> {
+    let file_path = mktemp(false, Some("takeover-bench-"), None, Some(dir))?;
+    let res = write_bench_file(&file_path, size);
+    if let Err(why) = remove_file(&file_path) {
+        debug!(
+            "measure_write_throughput: failed to remove '{}': {:?}",
+            file_path.display(),
+            why
+        );
+    }
+    let elapsed = res?;
+
+    let micros = std::cmp::max(elapsed, 1);
+    let throughput = size * 1_000_000 / micros;
+    debug!(
+        "measure_write_throughput: wrote {} to '{}' in {} us, {}/s",
+        format_size_with_unit(size),
+        dir.display(),
+        micros,
+        format_size_with_unit(throughput)
+    );
+    Ok(throughput)
+}
+
+/// write size bytes to file_path, returns the elapsed time in microseconds
+fn write_bench_file(file_path: &Path, size: u64) -> Result<u64> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(file_path)
+        .upstream_with_context(&format!(
+            "Failed to open benchmark file '{}'",
+            file_path.display()
+        ))?;
+
+    let buffer = vec![0xA5u8; BENCH_BUFFER_SIZE];
+    let start = Instant::now();
+    let mut written: u64 = 0;
+    while written < size {
+        let to_write = std::cmp::min(size - written, BENCH_BUFFER_SIZE as u64) as usize;
+        file.write_all(&buffer[0..to_write])
+            .upstream_with_context(&format!(
+                "Failed to write to benchmark file '{}'",
+                file_path.display()
+            ))?;
+        written += to_write as u64;
+    }
+    file.sync_all().upstream_with_context(&format!(
+        "Failed to sync benchmark file '{}'",
+        file_path.display()
+    ))?;
+
+    Ok(start.elapsed().as_micros() as u64)
+}
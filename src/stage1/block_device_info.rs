@@ -1,4 +1,6 @@
-use crate::common::{path_append, Error, Result, ToError};
+use crate::common::{
+    cmd_runner::CommandRunner, defs::DISK_BY_PATH_PATH, path_append, Error, Result, ToError,
+};
 
 use lazy_static::lazy_static;
 use log::{debug, trace};
@@ -112,9 +114,8 @@ pub(crate) struct BlockDeviceInfo {
 }
 
 impl BlockDeviceInfo {
-    pub fn new() -> Result<BlockDeviceInfo> {
-        let stat_res = stat("/").upstream_with_context("Failed to stat root")?;
-        let root_number = DeviceNum::new(stat_res.st_dev);
+    pub fn new(ignore_fs_types: &[String], runner: &dyn CommandRunner) -> Result<BlockDeviceInfo> {
+        let root_number = BlockDeviceInfo::get_root_number(ignore_fs_types)?;
         let mounts = Mount::from_mtab()?;
 
         debug!(
@@ -186,6 +187,7 @@ impl BlockDeviceInfo {
                         &curr_path,
                         &root_number,
                         &mut device_map,
+                        runner,
                     )?;
                     device_map.insert(dev_path, device.clone());
 
@@ -237,12 +239,80 @@ impl BlockDeviceInfo {
         ))
     }
 
+    /// the directories backing an overlay or aufs mount in the order given in its options
+    fn backing_dirs(options: &str) -> Vec<&str> {
+        let mut backing_dirs: Vec<&str> = Vec::new();
+        for option in options.split(',') {
+            let dirs = if option.starts_with("upperdir=") {
+                &option["upperdir=".len()..]
+            } else if option.starts_with("lowerdir=") {
+                &option["lowerdir=".len()..]
+            } else if option.starts_with("br:") {
+                &option["br:".len()..]
+            } else {
+                continue;
+            };
+
+            for dir in dirs.split(':') {
+                // aufs branches carry a '=rw' / '=ro' suffix
+                let dir = dir.split('=').next().unwrap_or(dir);
+                if !dir.is_empty() {
+                    backing_dirs.push(dir);
+                }
+            }
+        }
+        backing_dirs
+    }
+
+    /// find the device number of the block device backing root, looking through pseudo file
+    /// systems like overlay to their backing directories
+    fn get_root_number(ignore_fs_types: &[String]) -> Result<DeviceNum> {
+        let stat_res = stat("/").upstream_with_context("Failed to stat root")?;
+        let (fs_type, options) = Mount::get_root_fs()?;
+
+        if !ignore_fs_types.iter().any(|ignored| *ignored == fs_type) {
+            return Ok(DeviceNum::new(stat_res.st_dev));
+        }
+
+        debug!(
+            "get_root_number: root file system type '{}' is ignored, options: '{}'",
+            fs_type, options
+        );
+
+        for dir in BlockDeviceInfo::backing_dirs(&options) {
+            match stat(dir) {
+                Ok(stat_res) => {
+                    let dev_num = DeviceNum::new(stat_res.st_dev);
+                    if dev_num.major() != 0 {
+                        debug!(
+                            "get_root_number: found backing device {} for '{}'",
+                            dev_num, dir
+                        );
+                        return Ok(dev_num);
+                    }
+                }
+                Err(why) => {
+                    debug!("get_root_number: failed to stat '{}': {}", dir, why);
+                }
+            }
+        }
+
+        Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "No block device could be found backing the root file system of type '{}'",
+                fs_type
+            ),
+        ))
+    }
+
     fn read_partitions<P: AsRef<Path>>(
         device: &Rc<dyn BlockDevice>,
         mounts: &MountTab,
         dev_path: P,
         root_number: &DeviceNum,
         device_map: &mut DeviceMap,
+        runner: &dyn CommandRunner,
     ) -> Result<()> {
         trace!(
             "read_partitions: device: {} dev_path: {}",
@@ -299,6 +369,7 @@ impl BlockDeviceInfo {
                             curr_number,
                             mounted,
                             device.clone(),
+                            runner,
                         )?) as Rc<dyn BlockDevice>;
 
                         debug!(
@@ -329,7 +400,6 @@ impl BlockDeviceInfo {
         &self.root_device
     }
 
-    #[allow(dead_code)]
     pub fn get_root_partition(&self) -> &Option<Rc<dyn BlockDevice>> {
         &self.root_partition
     }
@@ -338,6 +408,177 @@ impl BlockDeviceInfo {
         &self.devices
     }
 
+    /// get all whole disk devices that are plausible flash targets: non removable, writable and
+    /// of non zero size
+    pub fn get_flash_candidates(&self) -> Vec<&Rc<dyn BlockDevice>> {
+        let mut candidates: Vec<&Rc<dyn BlockDevice>> = self
+            .devices
+            .values()
+            .filter(|device| !device.is_partition())
+            .filter(|device| {
+                let sys_path = path_append("/sys/block", device.get_name());
+                BlockDeviceInfo::read_sys_flag(&sys_path, "removable") == Some(false)
+                    && BlockDeviceInfo::read_sys_flag(&sys_path, "ro") == Some(false)
+                    && BlockDeviceInfo::read_sys_flag(&sys_path, "size") == Some(true)
+            })
+            .collect();
+        candidates.sort_by(|dev1, dev2| dev1.get_name().cmp(dev2.get_name()));
+        candidates
+    }
+
+    /// get the device a /dev/disk/by-path link or topology string currently resolves to, this
+    /// selects a device by the port it is attached to regardless of enumeration order
+    pub fn get_device_by_path(&self, topology: &Path) -> Result<&Rc<dyn BlockDevice>> {
+        let link_path = if topology.is_absolute() {
+            topology.to_path_buf()
+        } else {
+            path_append(DISK_BY_PATH_PATH, topology)
+        };
+
+        let dev_path = link_path.canonicalize().error_with_all(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "The device path '{}' does not resolve to a device, please check '{}' for the available device paths",
+                topology.display(),
+                DISK_BY_PATH_PATH
+            ),
+        )?;
+
+        if let Some(device) = self.devices.get(&dev_path) {
+            debug!(
+                "get_device_by_path: '{}' resolves to '{}'",
+                link_path.display(),
+                dev_path.display()
+            );
+            Ok(device)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::DeviceNotFound,
+                &format!(
+                    "The device path '{}' resolves to '{}' which is not a supported block device",
+                    topology.display(),
+                    dev_path.display()
+                ),
+            ))
+        }
+    }
+
+    /// true if the whole disk device is flagged removable, None if the flag can not be read
+    pub fn is_removable(device: &dyn BlockDevice) -> Option<bool> {
+        BlockDeviceInfo::read_sys_flag(&path_append("/sys/block", device.get_name()), "removable")
+    }
+
+    /// get the bus the whole disk device is attached to, eg. usb, mmc, nvme or ata
+    pub fn get_bus_type(device: &dyn BlockDevice) -> Option<&'static str> {
+        let sys_path = path_append("/sys/block", device.get_name());
+        match sys_path.canonicalize() {
+            Ok(dev_path) => {
+                let bus_type = BlockDeviceInfo::bus_type_from_path(&dev_path);
+                debug!(
+                    "get_bus_type: '{}' -> '{}': {:?}",
+                    sys_path.display(),
+                    dev_path.display(),
+                    bus_type
+                );
+                bus_type
+            }
+            Err(why) => {
+                debug!(
+                    "get_bus_type: failed to resolve '{}': {}",
+                    sys_path.display(),
+                    why
+                );
+                None
+            }
+        }
+    }
+
+    /// get the size of the device or partition in bytes, None if it can not be read
+    pub fn get_size(device: &dyn BlockDevice) -> Option<u64> {
+        BlockDeviceInfo::read_sys_str(&path_append("/sys/class/block", device.get_name()), "size")
+            .and_then(|size| size.parse::<u64>().ok())
+            .map(|sectors| sectors * 512)
+    }
+
+    /// get the model of the whole disk device, mmc devices report it as name
+    pub fn get_model(device: &dyn BlockDevice) -> Option<String> {
+        let sys_path = path_append("/sys/block", device.get_name());
+        BlockDeviceInfo::read_sys_str(&sys_path, "device/model")
+            .or_else(|| BlockDeviceInfo::read_sys_str(&sys_path, "device/name"))
+    }
+
+    /// get the serial number of the whole disk device if the driver exposes it
+    pub fn get_serial(device: &dyn BlockDevice) -> Option<String> {
+        let sys_path = path_append("/sys/block", device.get_name());
+        BlockDeviceInfo::read_sys_str(&sys_path, "device/serial")
+            .or_else(|| BlockDeviceInfo::read_sys_str(&sys_path, "device/wwid"))
+    }
+
+    /// read a sysfs attribute as a trimmed string, None if it can not be read or is empty
+    fn read_sys_str(sys_path: &Path, attribute: &str) -> Option<String> {
+        let attr_path = path_append(sys_path, attribute);
+        match read_to_string(&attr_path) {
+            Ok(value) if !value.trim().is_empty() => Some(value.trim().to_string()),
+            Ok(_) => None,
+            Err(why) => {
+                trace!(
+                    "read_sys_str: failed to read '{}': {}",
+                    attr_path.display(),
+                    why
+                );
+                None
+            }
+        }
+    }
+
+    /// derive the bus type from the device path below /sys/devices, a USB device anywhere in the
+    /// path wins, so USB card readers and SATA adapters are reported as usb
+    fn bus_type_from_path(dev_path: &Path) -> Option<&'static str> {
+        let mut bus_type = None;
+        for element in dev_path.iter() {
+            let element = element.to_string_lossy();
+            if element.starts_with("usb") {
+                return Some("usb");
+            } else if element.starts_with("mmc") {
+                bus_type = Some("mmc");
+            } else if element.starts_with("nvme") {
+                bus_type = Some("nvme");
+            } else if element.starts_with("ata") {
+                bus_type = Some("ata");
+            } else if element.starts_with("virtio") {
+                bus_type = Some("virtio");
+            }
+        }
+        bus_type
+    }
+
+    /// read a numeric sysfs attribute as a flag, None if it can not be read
+    fn read_sys_flag(sys_path: &Path, attribute: &str) -> Option<bool> {
+        let attr_path = path_append(sys_path, attribute);
+        match read_to_string(&attr_path) {
+            Ok(value) => match value.trim().parse::<u64>() {
+                Ok(value) => Some(value != 0),
+                Err(why) => {
+                    debug!(
+                        "read_sys_flag: failed to parse '{}' from '{}': {}",
+                        value.trim(),
+                        attr_path.display(),
+                        why
+                    );
+                    None
+                }
+            },
+            Err(why) => {
+                debug!(
+                    "read_sys_flag: failed to read '{}': {}",
+                    attr_path.display(),
+                    why
+                );
+                None
+            }
+        }
+    }
+
     fn get_maj_minor<P: AsRef<Path>>(dev_path: P) -> Result<DeviceNum> {
         let dev_info_path = path_append(dev_path.as_ref(), "dev");
         let dev_info = read_to_string(&dev_info_path).upstream_with_context(&format!(
@@ -371,3 +612,32 @@ impl BlockDeviceInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backing_dirs() {
+        assert_eq!(
+            BlockDeviceInfo::backing_dirs(
+                "rw,relatime,lowerdir=/media/root-ro:/media/base:/media/extra,upperdir=/media/root-rw/upper,workdir=/media/root-rw/work"
+            ),
+            vec![
+                "/media/root-ro",
+                "/media/base",
+                "/media/extra",
+                "/media/root-rw/upper"
+            ]
+        );
+        assert_eq!(
+            BlockDeviceInfo::backing_dirs("rw,relatime,si=8c1d2b3a,br:/mnt/rw=rw:/mnt/ro=ro"),
+            vec!["/mnt/rw", "/mnt/ro"]
+        );
+        assert_eq!(
+            BlockDeviceInfo::backing_dirs("rw,lowerdir=::/media/base"),
+            vec!["/media/base"]
+        );
+        assert!(BlockDeviceInfo::backing_dirs("rw,relatime,size=1024k").is_empty());
+    }
+}
@@ -58,4 +58,63 @@ impl Mount {
 
         Ok(mounts)
     }
+
+    /// file system type and mount options of the root mount in mtab_str
+    fn parse_root_fs(mtab_str: &str) -> Option<(String, String)> {
+        let mut root_fs: Option<(String, String)> = None;
+        for line in mtab_str.lines() {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() >= 4 && columns[1] == "/" {
+                // the last mount on / is the one that is visible
+                root_fs = Some((columns[2].to_string(), columns[3].to_string()));
+            }
+        }
+        root_fs
+    }
+
+    /// retrieve file system type and mount options of the root mount
+    pub fn get_root_fs() -> Result<(String, String)> {
+        let mtab_str =
+            read_to_string("/etc/mtab").upstream_with_context("Failed to read from '/etc/mtab'")?;
+
+        if let Some(root_fs) = Mount::parse_root_fs(&mtab_str) {
+            Ok(root_fs)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                "Failed to find root mount in '/etc/mtab'",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_root_fs() {
+        let mtab = "/dev/root / ext4 ro,relatime 0 0\n\
+                    proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                    overlay / overlay rw,relatime,lowerdir=/media/root-ro:/media/base,upperdir=/media/root-rw/upper,workdir=/media/root-rw/work 0 0\n\
+                    tmpfs /run tmpfs rw,nosuid,nodev 0 0\n";
+        assert_eq!(
+            Mount::parse_root_fs(mtab),
+            Some((
+                String::from("overlay"),
+                String::from("rw,relatime,lowerdir=/media/root-ro:/media/base,upperdir=/media/root-rw/upper,workdir=/media/root-rw/work")
+            ))
+        );
+
+        let mtab = "none / aufs rw,relatime,si=8c1d2b3a,br:/mnt/rw=rw:/mnt/ro=ro 0 0\n";
+        assert_eq!(
+            Mount::parse_root_fs(mtab),
+            Some((
+                String::from("aufs"),
+                String::from("rw,relatime,si=8c1d2b3a,br:/mnt/rw=rw:/mnt/ro=ro")
+            ))
+        );
+
+        assert_eq!(Mount::parse_root_fs("proc /proc proc rw 0 0\n"), None);
+    }
 }
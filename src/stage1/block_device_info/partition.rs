@@ -4,7 +4,7 @@ use std::rc::Rc;
 use crate::stage1::block_device_info::DeviceNum;
 use crate::{
     common::{
-        call,
+        cmd_runner::CommandRunner,
         defs::BLKID_CMD,
         error::{Error, ErrorKind, Result, ToError},
         path_append,
@@ -20,22 +20,23 @@ pub(crate) struct PartitionInfo {
     uuid: Option<String>,
     block_size: Option<u32>,
     fs_type: Option<String>,
+    fs_label: Option<String>,
     label: Option<String>,
     part_uuid: Option<String>,
 }
 
 impl PartitionInfo {
-    pub fn new<P: AsRef<Path>>(device: P) -> Result<PartitionInfo> {
+    pub fn new<P: AsRef<Path>>(device: P, runner: &dyn CommandRunner) -> Result<PartitionInfo> {
         lazy_static! {
             static ref START_REGEX: Regex = Regex::new(r"^([^:]+):\s+(.+)$").unwrap();
             static ref NEXT_REGEX: Regex =
                 Regex::new(r##"^([^=]+)="([^"]*)"(\s+(.+))?$"##).unwrap();
         }
 
-        let cmd_res = call_command!(
+        let cmd_res = runner.call_command(
             BLKID_CMD,
             &[&*device.as_ref().to_string_lossy()],
-            "Failed to call blkid"
+            "Failed to call blkid",
         )?;
 
         if let Some(captures) = START_REGEX.captures(cmd_res.as_str()) {
@@ -44,6 +45,7 @@ impl PartitionInfo {
             let mut uuid: Option<String> = None;
             let mut block_size: Option<u32> = None;
             let mut fs_type: Option<String> = None;
+            let mut fs_label: Option<String> = None;
             let mut label: Option<String> = None;
             let mut part_uuid: Option<String> = None;
 
@@ -69,6 +71,9 @@ impl PartitionInfo {
                         "TYPE" => {
                             fs_type = Some(param_value.to_owned());
                         }
+                        "LABEL" => {
+                            fs_label = Some(param_value.to_owned());
+                        }
                         "PARTLABEL" => {
                             label = Some(param_value.to_owned());
                         }
@@ -90,6 +95,7 @@ impl PartitionInfo {
                 block_size,
                 part_uuid,
                 fs_type,
+                fs_label,
                 label,
             };
             debug!(
@@ -112,6 +118,16 @@ impl PartitionInfo {
             None
         }
     }
+
+    /// the file system label
+    pub fn fs_label(&self) -> Option<&str> {
+        self.fs_label.as_deref()
+    }
+
+    /// the GPT partition label
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
 }
 
 #[derive(Clone)]
@@ -129,13 +145,14 @@ impl Partition {
         device_num: DeviceNum,
         mounted: Option<Mount>,
         parent: Rc<dyn BlockDevice>,
+        runner: &dyn CommandRunner,
     ) -> Result<Partition> {
         Ok(Partition {
             name: name.to_owned(),
             device_num,
             mounted,
             parent,
-            partition_info: PartitionInfo::new(&format!("/dev/{}", name))?,
+            partition_info: PartitionInfo::new(&format!("/dev/{}", name), runner)?,
         })
     }
 }
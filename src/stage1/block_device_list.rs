@@ -0,0 +1,199 @@
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::{
+    common::{
+        cmd_runner::{CommandRunner, SysCommandRunner},
+        format_size_with_unit, Options, Result, ToError,
+    },
+    stage1::block_device_info::{BlockDevice, BlockDeviceInfo},
+};
+
+#[derive(Debug, Serialize)]
+struct ListedPartition {
+    name: String,
+    path: String,
+    size: Option<u64>,
+    fs_type: Option<String>,
+    fs_label: Option<String>,
+    label: Option<String>,
+    mountpoint: Option<String>,
+    is_root: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ListedDevice {
+    name: String,
+    path: String,
+    size: Option<u64>,
+    model: Option<String>,
+    serial: Option<String>,
+    removable: Option<bool>,
+    bus: Option<String>,
+    fs_type: Option<String>,
+    mountpoint: Option<String>,
+    is_root: bool,
+    flash_candidate: bool,
+    partitions: Vec<ListedPartition>,
+}
+
+fn is_same(device: &Rc<dyn BlockDevice>, other: Option<&Rc<dyn BlockDevice>>) -> bool {
+    other.map(|other| other.get_name()) == Some(device.get_name())
+}
+
+fn get_mountpoint(device: &dyn BlockDevice) -> Option<String> {
+    device
+        .get_mountpoint()
+        .as_ref()
+        .map(|mount| mount.get_mountpoint().display().to_string())
+}
+
+fn get_listed_devices(block_dev_info: &BlockDeviceInfo) -> Vec<ListedDevice> {
+    let root_device = block_dev_info.get_root_device();
+    let root_partition = block_dev_info.get_root_partition().as_ref();
+    let candidates = block_dev_info.get_flash_candidates();
+
+    let mut devices: Vec<&Rc<dyn BlockDevice>> = block_dev_info
+        .get_devices()
+        .values()
+        .filter(|device| !device.is_partition())
+        .collect();
+    devices.sort_by(|dev1, dev2| dev1.get_name().cmp(dev2.get_name()));
+
+    devices
+        .iter()
+        .map(|device| {
+            let mut partitions: Vec<ListedPartition> = block_dev_info
+                .get_devices()
+                .values()
+                .filter(|partition| is_same(device, partition.get_parent()))
+                .map(|partition| {
+                    let part_info = partition.get_partition_info();
+                    ListedPartition {
+                        name: partition.get_name().to_string(),
+                        path: partition.get_dev_path().display().to_string(),
+                        size: BlockDeviceInfo::get_size(partition.as_ref()),
+                        fs_type: part_info
+                            .and_then(|info| info.fs_type())
+                            .map(|val| val.to_string()),
+                        fs_label: part_info
+                            .and_then(|info| info.fs_label())
+                            .map(|val| val.to_string()),
+                        label: part_info
+                            .and_then(|info| info.label())
+                            .map(|val| val.to_string()),
+                        mountpoint: get_mountpoint(partition.as_ref()),
+                        is_root: is_same(partition, root_partition),
+                    }
+                })
+                .collect();
+            partitions.sort_by(|part1, part2| part1.name.cmp(&part2.name));
+
+            ListedDevice {
+                name: device.get_name().to_string(),
+                path: device.get_dev_path().display().to_string(),
+                size: BlockDeviceInfo::get_size(device.as_ref()),
+                model: BlockDeviceInfo::get_model(device.as_ref()),
+                serial: BlockDeviceInfo::get_serial(device.as_ref()),
+                removable: BlockDeviceInfo::is_removable(device.as_ref()),
+                bus: BlockDeviceInfo::get_bus_type(device.as_ref()).map(|bus| bus.to_string()),
+                fs_type: device
+                    .get_partition_info()
+                    .and_then(|info| info.fs_type())
+                    .map(|val| val.to_string()),
+                mountpoint: get_mountpoint(device.as_ref()),
+                is_root: is_same(device, Some(root_device)),
+                flash_candidate: candidates
+                    .iter()
+                    .any(|candidate| is_same(device, Some(*candidate))),
+                partitions,
+            }
+        })
+        .collect()
+}
+
+fn opt_str(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("-")
+}
+
+fn opt_size(value: Option<u64>) -> String {
+    value
+        .map(format_size_with_unit)
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn print_table(devices: &[ListedDevice]) {
+    println!(
+        "{:<16} {:>10} {:<6} {:<4} {:<24} {:<20} {:<8} {:<12} {}",
+        "NAME", "SIZE", "BUS", "RM", "MODEL", "SERIAL", "FSTYPE", "LABEL", "MOUNTPOINT"
+    );
+    for device in devices {
+        let mut flags: Vec<&str> = Vec::new();
+        if device.is_root {
+            flags.push("root device");
+        }
+        if device.flash_candidate {
+            flags.push("flash candidate");
+        }
+        println!(
+            "{:<16} {:>10} {:<6} {:<4} {:<24} {:<20} {:<8} {:<12} {}{}",
+            device.name,
+            opt_size(device.size),
+            device.bus.as_deref().unwrap_or("-"),
+            match device.removable {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "-",
+            },
+            opt_str(&device.model),
+            opt_str(&device.serial),
+            opt_str(&device.fs_type),
+            "-",
+            opt_str(&device.mountpoint),
+            if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", flags.join(", "))
+            }
+        );
+        for partition in &device.partitions {
+            println!(
+                "  {:<14} {:>10} {:<6} {:<4} {:<24} {:<20} {:<8} {:<12} {}{}",
+                partition.name,
+                opt_size(partition.size),
+                "",
+                "",
+                "",
+                "",
+                opt_str(&partition.fs_type),
+                partition
+                    .fs_label
+                    .as_deref()
+                    .or_else(|| partition.label.as_deref())
+                    .unwrap_or("-"),
+                opt_str(&partition.mountpoint),
+                if partition.is_root { " (root)" } else { "" }
+            );
+        }
+    }
+}
+
+/// The block devices stage1 can see and select from as JSON
+pub(crate) fn get_block_devices_json(opts: &Options, runner: &dyn CommandRunner) -> Result<String> {
+    let block_dev_info = BlockDeviceInfo::new(opts.ignore_fs_types(), runner)?;
+    serde_json::to_string_pretty(&get_listed_devices(&block_dev_info))
+        .upstream_with_context("Failed to format block devices as JSON")
+}
+
+/// Print the block devices stage1 can see and select from, as a table or as JSON
+pub(crate) fn list_block_devices(opts: &Options) -> Result<()> {
+    let runner = SysCommandRunner::new();
+    if opts.json() {
+        println!("{}", get_block_devices_json(opts, &runner)?);
+    } else {
+        let block_dev_info = BlockDeviceInfo::new(opts.ignore_fs_types(), &runner)?;
+        print_table(&get_listed_devices(&block_dev_info));
+    }
+    Ok(())
+}
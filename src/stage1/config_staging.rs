@@ -0,0 +1,235 @@
+use std::fs::{copy, create_dir_all, metadata, read_dir, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use nix::{
+    mount::{mount, umount, MsFlags},
+    sys::statvfs::statvfs,
+    unistd::sync,
+};
+
+use crate::{
+    common::{
+        defs::{BALENA_BOOT_FSTYPE, BALENA_BOOT_PART, BALENA_CONFIG_PATH, NIX_NONE},
+        disk_util::{Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
+        format_size_with_unit,
+        image_format::{get_image_format, ImageFormat},
+        loop_device::LoopDevice,
+        options::Options,
+        path_append, Error, ErrorKind, Result, ToError,
+    },
+    stage1::{migrate_info::MigrateInfo, utils::retry_eintr},
+};
+
+const STAGED_IMAGE_NAME: &str = "balena-staged.img";
+const STAGING_MP_NAME: &str = "mnt_staged_boot";
+
+/// The boot partition of the image, found by its label like stage2 does on the flashed device
+fn get_boot_partition(image_path: &Path) -> Result<PartInfo> {
+    let mut disk = Disk::from_drive_file(image_path, None)?;
+    let partitions: Vec<PartInfo> = PartitionIterator::new(&mut disk)?.collect();
+    for partition in &partitions {
+        if let Ok(Some(label)) = disk.get_fs_label(partition) {
+            if label == BALENA_BOOT_PART {
+                return Ok(partition.clone());
+            }
+        }
+    }
+    Err(Error::with_context(
+        ErrorKind::NotFound,
+        &format!(
+            "No partition labelled '{}' found in '{}'",
+            BALENA_BOOT_PART,
+            image_path.display()
+        ),
+    ))
+}
+
+/// Copy config.json and the network configuration to the mounted boot partition
+fn copy_config_files(mig_info: &MigrateInfo, work_dir: &Path, mountpoint: &Path) -> Result<()> {
+    let src_path = mig_info.balena_cfg().get_path();
+    let target_path = path_append(mountpoint, BALENA_CONFIG_PATH);
+    copy(src_path, &target_path).upstream_with_context(&format!(
+        "Failed to copy '{}' to '{}'",
+        src_path.display(),
+        target_path.display()
+    ))?;
+    debug!(
+        "copy_config_files: copied '{}' to '{}'",
+        src_path.display(),
+        target_path.display()
+    );
+
+    let network_dir = mig_info.network_backend().config_dir();
+    let src_dir = path_append(work_dir, network_dir);
+    if !src_dir.exists() {
+        return Ok(());
+    }
+
+    let target_dir = path_append(mountpoint, network_dir);
+    create_dir_all(&target_dir).upstream_with_context(&format!(
+        "Failed to create directory '{}'",
+        target_dir.display()
+    ))?;
+
+    for entry in read_dir(&src_dir)
+        .upstream_with_context(&format!("Failed to read directory '{}'", src_dir.display()))?
+    {
+        let src_path = entry
+            .upstream_with_context(&format!(
+                "Failed to read directory entry in '{}'",
+                src_dir.display()
+            ))?
+            .path();
+        if let Some(file_name) = src_path.file_name() {
+            if src_path.is_file() {
+                let target_path = path_append(&target_dir, file_name);
+                copy(&src_path, &target_path).upstream_with_context(&format!(
+                    "Failed to copy '{}' to '{}'",
+                    src_path.display(),
+                    target_path.display()
+                ))?;
+                debug!(
+                    "copy_config_files: copied '{}' to '{}'",
+                    src_path.display(),
+                    target_path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mount the boot partition of the image and place the configuration on it
+fn inject_config(image_path: &Path, mig_info: &MigrateInfo, work_dir: &Path) -> Result<()> {
+    let boot_part = get_boot_partition(image_path)?;
+    let loop_device = LoopDevice::for_file(
+        image_path,
+        Some(boot_part.start_lba * DEF_BLOCK_SIZE as u64),
+        Some(boot_part.num_sectors * DEF_BLOCK_SIZE as u64),
+        None,
+        true,
+    )?;
+
+    let mountpoint = path_append(work_dir, STAGING_MP_NAME);
+    create_dir_all(&mountpoint).upstream_with_context(&format!(
+        "Failed to create directory '{}'",
+        mountpoint.display()
+    ))?;
+
+    retry_eintr(|| {
+        mount(
+            Some(loop_device.get_path()),
+            &mountpoint,
+            Some(BALENA_BOOT_FSTYPE.as_bytes()),
+            MsFlags::empty(),
+            NIX_NONE,
+        )
+    })
+    .upstream_with_context(&format!(
+        "Failed to mount the boot partition of '{}' on '{}'",
+        image_path.display(),
+        mountpoint.display()
+    ))?;
+
+    let res = copy_config_files(mig_info, work_dir, &mountpoint);
+    sync();
+
+    umount(&mountpoint)
+        .upstream_with_context(&format!("Failed to unmount '{}'", mountpoint.display()))?;
+    let _res = remove_dir(&mountpoint);
+    res
+}
+
+/// Check whether the configuration can be placed in a copy of the image, the reason if not
+fn check_staging_possible(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    work_dir: &Path,
+) -> Result<Option<String>> {
+    if mig_info.image_slices().is_some() {
+        return Ok(Some("the image is a slice manifest".to_string()));
+    }
+
+    let image_path = mig_info.image_path();
+    let image_format = get_image_format(image_path, opts.image_format())?;
+    if image_format != ImageFormat::Raw {
+        return Ok(Some(format!(
+            "the image is {} compressed, an uncompressed image is required",
+            image_format
+        )));
+    }
+
+    let image_size = metadata(image_path)
+        .upstream_with_context(&format!(
+            "Failed to retrieve metadata for '{}'",
+            image_path.display()
+        ))?
+        .len();
+    let fs_stat = statvfs(work_dir).upstream_with_context(&format!(
+        "Failed to determine the free space in '{}'",
+        work_dir.display()
+    ))?;
+    let available = fs_stat.blocks_available() as u64 * fs_stat.fragment_size() as u64;
+    if available < image_size {
+        return Ok(Some(format!(
+            "the copy of the image needs {} but only {} are available in '{}'",
+            format_size_with_unit(image_size),
+            format_size_with_unit(available),
+            work_dir.display()
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Place config.json and the network configuration on the boot partition of a copy of the image
+/// in the work directory for --staging-order config-first. The copy replaces the image in
+/// mig_info. Returns false if the configuration has to be placed after flashing instead.
+pub(crate) fn stage_config_in_image(opts: &Options, mig_info: &mut MigrateInfo) -> Result<bool> {
+    let work_dir = opts.work_dir();
+    if let Some(reason) = check_staging_possible(opts, mig_info, &work_dir)? {
+        warn!(
+            "Can not place the configuration in the image: {}, it is placed on the flashed device instead",
+            reason
+        );
+        return Ok(false);
+    }
+
+    let staged_path: PathBuf = path_append(&work_dir, STAGED_IMAGE_NAME);
+    info!(
+        "Copying the image '{}' to '{}' to place the configuration in it",
+        mig_info.image_path().display(),
+        staged_path.display()
+    );
+    copy(mig_info.image_path(), &staged_path).upstream_with_context(&format!(
+        "Failed to copy '{}' to '{}'",
+        mig_info.image_path().display(),
+        staged_path.display()
+    ))?;
+
+    match inject_config(&staged_path, mig_info, &work_dir) {
+        Ok(_) => {
+            info!(
+                "Placed config.json and the network configuration in the image '{}'",
+                staged_path.display()
+            );
+            mig_info.set_image_path(staged_path);
+            Ok(true)
+        }
+        Err(why) => {
+            warn!(
+                "Failed to place the configuration in the image: {}, it is placed on the flashed device instead",
+                why
+            );
+            if let Err(why) = remove_file(&staged_path) {
+                warn!(
+                    "Failed to remove '{}', error: {}",
+                    staged_path.display(),
+                    why
+                );
+            }
+            Ok(false)
+        }
+    }
+}
@@ -1,12 +1,25 @@
 use std::fmt::{self, Display, Debug};
 
 use crate::{
+    common::{defs::DEF_FLASH_BLOCK_SIZE, options::NetworkBackend},
     stage1::{defs::DeviceType, },
 };
 
 pub(crate) trait Device {
     fn supports_device_type(&self, dev_type: &str) -> bool;
     fn get_device_type(&self) -> DeviceType;
+    /// byte offset on the flash device at which the image is written
+    fn get_flash_offset(&self) -> u64 {
+        0
+    }
+    /// block size used to write the image to the flash device
+    fn get_flash_block_size(&self) -> u64 {
+        DEF_FLASH_BLOCK_SIZE
+    }
+    /// network backends supported by balena-os on this device type, the first is the default
+    fn get_network_backends(&self) -> &'static [NetworkBackend] {
+        &[NetworkBackend::NetworkManager]
+    }
 }
 
 impl Display for dyn Device {
@@ -3,15 +3,18 @@ use std::fs::read_to_string;
 
 use crate::common::ToError;
 use crate::{
-    common::{get_os_name, Error, ErrorKind, Options, Result},
+    common::{cmd_runner::CommandRunner, get_os_name, Error, ErrorKind, Options, Result},
     stage1::{defs::OSArch, device::Device, utils::get_os_arch},
 };
 
 // mod beaglebone;
 mod beaglebone;
+mod device_db;
 mod intel_nuc;
 mod raspberrypi;
 
+use device_db::{DeviceDb, Signatures};
+
 const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
 
 pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Result<bool> {
@@ -39,10 +42,12 @@ pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Re
     }
 }
 
-pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
+pub(crate) fn get_device(opts: &Options, runner: &dyn CommandRunner) -> Result<Box<dyn Device>> {
     let os_arch = get_os_arch()?;
     info!("Detected OS Architecture is {:?}", os_arch);
 
+    let device_db = DeviceDb::new(opts.device_types_db())?;
+
     match os_arch {
         OSArch::ARMHF | OSArch::ARM64 => {
             let dev_tree_model = String::from(
@@ -55,6 +60,11 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
                     .trim_end(),
             );
 
+            let signatures = Signatures::read(Some(&dev_tree_model));
+            if let Some(device) = device_db.get_device(opts, &os_arch, &signatures, runner)? {
+                return Ok(device);
+            }
+
             if let Some(device) = raspberrypi::is_rpi(opts, &dev_tree_model)? {
                 return Ok(device);
             }
@@ -70,7 +80,14 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
             error!("{}", message);
             Err(Error::with_context(ErrorKind::InvState, &message))
         }
-        OSArch::AMD64 => Ok(Box::new(intel_nuc::IntelNuc::from_config(opts)?)),
+        OSArch::AMD64 => {
+            let signatures = Signatures::read(None);
+            if let Some(device) = device_db.get_device(opts, &os_arch, &signatures, runner)? {
+                return Ok(device);
+            }
+            info!("Using the generic x86_64 device type");
+            Ok(Box::new(intel_nuc::IntelNuc::from_config(opts, runner)?))
+        }
         /*            OSArch::I386 => {
                     migrator.init_i386()?;
                 },
@@ -2,7 +2,7 @@ use log::{debug, error, trace};
 use regex::Regex;
 
 use crate::{
-    common::{Error, ErrorKind, Options, Result},
+    common::{defs::SD_FLASH_BLOCK_SIZE, Error, ErrorKind, Options, Result},
     stage1::{
         defs::{DeviceType, DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_BBXM},
         device::Device,
@@ -74,7 +74,7 @@ pub(crate) struct BeagleboneGreen {}
 
 impl BeagleboneGreen {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboneGreen> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboneGreen> {
         if !check_os(&SUPPORTED_OSSES, opts, "Beaglebone Green")? {
             return Err(Error::displayed());
         }
@@ -91,13 +91,17 @@ impl Device for BeagleboneGreen {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::BeagleboneGreen
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
 
 pub(crate) struct BeagleboneBlack {}
 
 impl BeagleboneBlack {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboneBlack> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboneBlack> {
         if !check_os(&SUPPORTED_OSSES, opts, "Beaglebone Black")? {
             return Err(Error::displayed());
         }
@@ -114,13 +118,17 @@ impl Device for BeagleboneBlack {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::BeagleboneBlack
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
 
 pub(crate) struct BeagleboardXM {}
 
 impl BeagleboardXM {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboardXM> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboardXM> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Beagleboard XM")? {
             return Err(Error::displayed());
         }
@@ -137,4 +145,8 @@ impl Device for BeagleboardXM {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::BeagleboardXM
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
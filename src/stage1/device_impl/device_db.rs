@@ -0,0 +1,270 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::path::Path;
+
+use log::{debug, info};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    common::{cmd_runner::CommandRunner, Error, ErrorKind, Options, Result, ToError},
+    stage1::{
+        defs::{
+            OSArch, DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_BBXM, DEV_TYPE_GEN_X86_64,
+            DEV_TYPE_INTEL_NUC, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
+        },
+        device::Device,
+        device_impl::{
+            beaglebone::{BeagleboardXM, BeagleboneBlack, BeagleboneGreen},
+            intel_nuc::IntelNuc,
+            raspberrypi::{RaspberryPi1, RaspberryPi2, RaspberryPi3, RaspberryPi4_64},
+        },
+    },
+};
+
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+const DMI_ID_DIR: &str = "/sys/class/dmi/id";
+const DMI_ID_FILES: [&str; 4] = ["sys_vendor", "product_name", "board_vendor", "board_name"];
+
+// models the built-in detection does not recognize
+const DEFAULT_DEVICE_DB: &str = r#"
+- source: dt-model
+  pattern: '^Raspberry Pi 3 Model [AB] Plus Rev \S+$'
+  device-type: raspberrypi3
+- source: dt-model
+  pattern: '^Raspberry Pi Compute Module 3 Rev \S+$'
+  device-type: raspberrypi3
+- source: dt-model
+  pattern: '^Raspberry Pi (400|Compute Module 4) Rev \S+$'
+  device-type: raspberrypi4-64
+- source: dt-model
+  pattern: '^Raspberry Pi Zero( W)? Rev \S+$'
+  device-type: raspberry-pi
+"#;
+
+/// The hardware signature a device type database entry is matched against
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SignatureSource {
+    /// the device tree model in /proc/device-tree/model
+    DtModel,
+    /// the contents of /proc/cpuinfo, patterns are matched per line
+    Cpuinfo,
+    /// the DMI ids in /sys/class/dmi/id as 'name: value' lines, patterns are matched per line
+    Dmi,
+}
+
+impl Display for SignatureSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::DtModel => "device tree model",
+                Self::Cpuinfo => "cpuinfo",
+                Self::Dmi => "DMI",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DeviceDbEntry {
+    source: SignatureSource,
+    pattern: String,
+    device_type: String,
+}
+
+/// The hardware signatures of the device, missing sources are empty
+pub(crate) struct Signatures {
+    pub dt_model: Option<String>,
+    pub cpuinfo: Option<String>,
+    pub dmi: Option<String>,
+}
+
+impl Signatures {
+    pub fn read(dt_model: Option<&str>) -> Signatures {
+        let dmi: Vec<String> = DMI_ID_FILES
+            .iter()
+            .filter_map(|name| {
+                read_to_string(Path::new(DMI_ID_DIR).join(name))
+                    .ok()
+                    .map(|value| format!("{}: {}", name, value.trim()))
+            })
+            .collect();
+
+        Signatures {
+            dt_model: dt_model.map(String::from),
+            cpuinfo: read_to_string(CPUINFO_PATH).ok(),
+            dmi: if dmi.is_empty() {
+                None
+            } else {
+                Some(dmi.join("\n"))
+            },
+        }
+    }
+
+    fn get(&self, source: SignatureSource) -> Option<&str> {
+        match source {
+            SignatureSource::DtModel => self.dt_model.as_deref(),
+            SignatureSource::Cpuinfo => self.cpuinfo.as_deref(),
+            SignatureSource::Dmi => self.dmi.as_deref(),
+        }
+    }
+}
+
+/// Maps hardware signatures to balena device types. Entries from a database file are matched
+/// before the embedded default entries, so they can extend and override them.
+pub(crate) struct DeviceDb {
+    entries: Vec<(DeviceDbEntry, Regex, String)>,
+}
+
+impl DeviceDb {
+    pub fn new(db_file: Option<&Path>) -> Result<DeviceDb> {
+        let mut db = DeviceDb {
+            entries: Vec::new(),
+        };
+
+        if let Some(db_file) = db_file {
+            let db_str = read_to_string(db_file).upstream_with_context(&format!(
+                "Failed to read device type database '{}'",
+                db_file.display()
+            ))?;
+            db.add_entries(&db_str, &db_file.display().to_string())?;
+        }
+
+        db.add_entries(DEFAULT_DEVICE_DB, "embedded database")?;
+        Ok(db)
+    }
+
+    fn add_entries(&mut self, db_str: &str, origin: &str) -> Result<()> {
+        let entries: Vec<DeviceDbEntry> = serde_yaml::from_str(db_str).upstream_with_context(
+            &format!("Failed to parse device type database from {}", origin),
+        )?;
+
+        for entry in entries {
+            // cpuinfo and DMI consist of several lines
+            let regex = Regex::new(&format!("(?m){}", entry.pattern)).error_with_all(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid pattern '{}' in device type database {}",
+                    entry.pattern, origin
+                ),
+            )?;
+            self.entries.push((entry, regex, origin.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Find the first entry matching the signatures, returns the device type, the matched
+    /// source and the origin of the entry
+    fn lookup(&self, signatures: &Signatures) -> Option<(&str, SignatureSource, &str)> {
+        self.entries.iter().find_map(|(entry, regex, origin)| {
+            if let Some(signature) = signatures.get(entry.source) {
+                if regex.is_match(signature) {
+                    return Some((entry.device_type.as_str(), entry.source, origin.as_str()));
+                }
+            }
+            None
+        })
+    }
+
+    /// Look the device up in the database, None if no entry matches
+    pub fn get_device(
+        &self,
+        opts: &Options,
+        os_arch: &OSArch,
+        signatures: &Signatures,
+        runner: &dyn CommandRunner,
+    ) -> Result<Option<Box<dyn Device>>> {
+        if let Some((device_type, source, origin)) = self.lookup(signatures) {
+            info!(
+                "Identified device type {} from the {} using the {}",
+                device_type, source, origin
+            );
+            Ok(Some(device_from_slug(opts, os_arch, device_type, runner)?))
+        } else {
+            debug!("DeviceDb::get_device: no matching entry found");
+            Ok(None)
+        }
+    }
+}
+
+/// Create the device implementation for a balena device type
+fn device_from_slug(
+    opts: &Options,
+    os_arch: &OSArch,
+    slug: &str,
+    runner: &dyn CommandRunner,
+) -> Result<Box<dyn Device>> {
+    let is_arm = matches!(os_arch, OSArch::ARMHF | OSArch::ARM64);
+    let device: Option<Box<dyn Device>> = match slug {
+        DEV_TYPE_INTEL_NUC | DEV_TYPE_GEN_X86_64 if matches!(os_arch, OSArch::AMD64) => {
+            Some(Box::new(IntelNuc::from_config(opts, runner)?))
+        }
+        DEV_TYPE_RPI1 if is_arm => Some(Box::new(RaspberryPi1::from_config(opts)?)),
+        DEV_TYPE_RPI2 if is_arm => Some(Box::new(RaspberryPi2::from_config(opts)?)),
+        DEV_TYPE_RPI3 if is_arm => Some(Box::new(RaspberryPi3::from_config(opts)?)),
+        DEV_TYPE_RPI4_64 if is_arm => Some(Box::new(RaspberryPi4_64::from_config(opts)?)),
+        DEV_TYPE_BBG if is_arm => Some(Box::new(BeagleboneGreen::from_config(opts)?)),
+        DEV_TYPE_BBB if is_arm => Some(Box::new(BeagleboneBlack::from_config(opts)?)),
+        DEV_TYPE_BBXM if is_arm => Some(Box::new(BeagleboardXM::from_config(opts)?)),
+        _ => None,
+    };
+
+    if let Some(device) = device {
+        Ok(device)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Device type '{}' from the device type database is not supported on {:?}",
+                slug, os_arch
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_db_lookup() {
+        let mut db = DeviceDb {
+            entries: Vec::new(),
+        };
+        db.add_entries(
+            "- source: dmi\n  pattern: '^product_name: NUC7'\n  device-type: intel-nuc\n",
+            "test",
+        )
+        .unwrap();
+        db.add_entries(DEFAULT_DEVICE_DB, "embedded database")
+            .unwrap();
+
+        let signatures = Signatures {
+            dt_model: Some("Raspberry Pi 3 Model B Plus Rev 1.3".to_string()),
+            cpuinfo: None,
+            dmi: None,
+        };
+        assert_eq!(
+            db.lookup(&signatures),
+            Some((
+                "raspberrypi3",
+                SignatureSource::DtModel,
+                "embedded database"
+            ))
+        );
+
+        let signatures = Signatures {
+            dt_model: None,
+            cpuinfo: None,
+            dmi: Some("sys_vendor: Intel Corporation\nproduct_name: NUC7i5BNH".to_string()),
+        };
+        assert_eq!(
+            db.lookup(&signatures),
+            Some(("intel-nuc", SignatureSource::Dmi, "test"))
+        );
+    }
+}
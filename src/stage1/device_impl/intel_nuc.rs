@@ -2,7 +2,7 @@ use log::{error, info};
 
 use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{Error, Options, Result},
+    common::{cmd_runner::CommandRunner, Error, Options, Result},
     // linux_common::is_secure_boot,
     stage1::{
         defs::{DeviceType, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC},
@@ -16,7 +16,7 @@ const X86_SLUGS: [&str; 2] = [DEV_TYPE_INTEL_NUC, DEV_TYPE_GEN_X86_64];
 pub(crate) struct IntelNuc;
 
 impl IntelNuc {
-    pub fn from_config(opts: &Options) -> Result<IntelNuc> {
+    pub fn from_config(opts: &Options, runner: &dyn CommandRunner) -> Result<IntelNuc> {
         const SUPPORTED_OSSES: &[&str] = &[
             "Ubuntu 20.04 LTS",
             "Ubuntu 18.04.4 LTS",
@@ -39,7 +39,7 @@ impl IntelNuc {
             // ** AMD64 specific initialisation/checks
             // **********************************************************************
 
-            let secure_boot = is_secure_boot()?;
+            let secure_boot = is_secure_boot(runner)?;
             info!(
                 "Secure boot is {}enabled",
                 if secure_boot { "" } else { "not " }
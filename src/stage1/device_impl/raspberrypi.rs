@@ -3,7 +3,7 @@ use regex::Regex;
 
 use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{options::Options, Error, ErrorKind, Result},
+    common::{defs::SD_FLASH_BLOCK_SIZE, options::Options, Error, ErrorKind, Result},
     stage1::{
         defs::{DeviceType, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64},
         device::Device,
@@ -106,6 +106,10 @@ impl Device for RaspberryPi1 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi1
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
 
 pub(crate) struct RaspberryPi2;
@@ -127,6 +131,10 @@ impl Device for RaspberryPi2 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi2
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
 
 pub(crate) struct RaspberryPi3;
@@ -149,6 +157,10 @@ impl Device for RaspberryPi3 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi3
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
 
 pub(crate) struct RaspberryPi4_64;
@@ -171,4 +183,8 @@ impl Device for RaspberryPi4_64 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi4
     }
+
+    fn get_flash_block_size(&self) -> u64 {
+        SD_FLASH_BLOCK_SIZE
+    }
 }
@@ -0,0 +1,97 @@
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::{
+    common::{
+        options::{Options, ProfileDefaults},
+        Result, ToError,
+    },
+    stage1::device::Device,
+};
+
+// defaults for device types that reliably need them, options given on the command line win
+const DEVICE_PROFILES: &str = r#"
+# 512 MiB of memory, little room besides the RAMFS
+- device-type: raspberry-pi
+  memory-warn-ratio: 1.5
+  flash-open-retries: 10
+- device-type: beaglebone-black
+  memory-warn-ratio: 1.5
+  flash-open-retries: 10
+- device-type: beaglebone-green
+  memory-warn-ratio: 1.5
+  flash-open-retries: 10
+- device-type: beagleboard-xm
+  memory-warn-ratio: 1.5
+  flash-open-retries: 10
+# SD card controllers can take a while to release the card
+- device-type: raspberry-pi2
+  flash-open-retries: 10
+- device-type: raspberrypi3
+  flash-open-retries: 10
+- device-type: raspberrypi4-64
+  flash-open-retries: 10
+"#;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DeviceProfile {
+    device_type: String,
+    #[serde(flatten)]
+    defaults: ProfileDefaults,
+}
+
+fn get_profiles() -> Result<Vec<DeviceProfile>> {
+    serde_yaml::from_str(DEVICE_PROFILES)
+        .upstream_with_context("Failed to parse the built-in device profiles")
+}
+
+/// Returns a copy of opts with the defaults of the profile of the detected device filled in, or
+/// None if there is no profile for the device
+pub(crate) fn apply_device_profile(opts: &Options, device: &dyn Device) -> Result<Option<Options>> {
+    let profile = if let Some(profile) = get_profiles()?
+        .into_iter()
+        .find(|profile| device.supports_device_type(&profile.device_type))
+    {
+        profile
+    } else {
+        debug!(
+            "apply_device_profile: no profile for device {}",
+            device.get_device_type()
+        );
+        return Ok(None);
+    };
+
+    let mut opts = opts.clone();
+    let applied = opts.apply_profile_defaults(&profile.defaults);
+    if applied.is_empty() {
+        info!(
+            "The device profile for '{}' sets no defaults that were not given on the command line",
+            profile.device_type
+        );
+    } else {
+        info!(
+            "Applied the device profile for '{}', defaults: {}",
+            profile.device_type,
+            applied.join(", ")
+        );
+    }
+    Ok(Some(opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let profiles = get_profiles().unwrap();
+        let profile = profiles
+            .iter()
+            .find(|profile| profile.device_type == "raspberry-pi")
+            .unwrap();
+        assert_eq!(profile.defaults.memory_warn_ratio, Some(1.5));
+        assert_eq!(profile.defaults.flash_open_retries, Some(10));
+        assert_eq!(profile.defaults.flash_bs, None);
+    }
+}
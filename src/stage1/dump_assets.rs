@@ -0,0 +1,67 @@
+use std::fs::{create_dir_all, read_link};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    common::{
+        cmd_runner::{CommandRunner, SysCommandRunner},
+        defs::BUSYBOX_CMD,
+        options::Options,
+        whereis, Result, ToError,
+    },
+    stage1::{
+        defs::OSArch, exe_copy::ExeCopy, get_copy_commands, stage2_script::write_stage2_script,
+        utils::get_os_arch,
+    },
+};
+
+const DEFAULT_TTY: &str = "/dev/console";
+
+fn log_busybox_version(busybox_path: &str, runner: &dyn CommandRunner) {
+    // busybox prints its version in the first line of its help
+    match runner.call(busybox_path, &["--help"], true) {
+        Ok(cmd_res) => {
+            if let Some(version) = cmd_res.stdout.lines().next() {
+                info!("Bundled busybox: {}", version);
+            }
+        }
+        Err(why) => warn!("Failed to determine the busybox version: {}", why),
+    }
+}
+
+/// Write the executables and libraries stage1 copies to the takeover directory and the rendered
+/// stage2 script to dump_dir, laid out as in the takeover directory, without migrating
+pub(crate) fn dump_assets(opts: &Options, dump_dir: &Path) -> Result<()> {
+    create_dir_all(dump_dir).upstream_with_context(&format!(
+        "Failed to create directory '{}'",
+        dump_dir.display()
+    ))?;
+
+    // the device type is not determined here, the kernel architecture stands in for it
+    let is_x86 = matches!(get_os_arch()?, OSArch::AMD64 | OSArch::I386);
+    let copy_commands = get_copy_commands(opts, is_x86)?;
+    info!("Dumping the stage2 commands {:?}", copy_commands);
+
+    let runner = SysCommandRunner::new();
+    ExeCopy::new(copy_commands.clone(), &runner)?.copy_files(dump_dir, |_, _| ())?;
+
+    if copy_commands.contains(&BUSYBOX_CMD) {
+        if let Ok(busybox_path) = whereis(BUSYBOX_CMD, &runner) {
+            log_busybox_version(&busybox_path, &runner);
+        }
+    }
+
+    if let Some(script_path) = opts.stage2_script() {
+        let tty = read_link("/proc/self/fd/1").unwrap_or_else(|_| PathBuf::from(DEFAULT_TTY));
+        write_stage2_script(
+            script_path,
+            dump_dir,
+            &tty,
+            &opts.s2_log_level().to_string(),
+        )?;
+    }
+
+    info!("Dumped the stage2 assets to '{}'", dump_dir.display());
+    Ok(())
+}
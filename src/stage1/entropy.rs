@@ -0,0 +1,147 @@
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libc::ioctl;
+use log::{debug, info, warn};
+
+use crate::common::{defs::IoctlReq, file_exists, Error, ErrorKind, Result, ToError};
+
+const ENTROPY_AVAIL_PATH: &str = "/proc/sys/kernel/random/entropy_avail";
+const HW_RNG_PATH: &str = "/dev/hwrng";
+const RANDOM_PATH: &str = "/dev/random";
+// below this many bits TLS handshakes and key generation can block on older kernels
+const MIN_ENTROPY_BITS: u32 = 256;
+const SEED_BYTES: usize = 64;
+const GATHER_TIMEOUT: Duration = Duration::from_secs(10);
+const GATHER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// RNDADDENTROPY, adds data to the pool and credits it as entropy
+const IOCTL_RND_ADD_ENTROPY: IoctlReq = 0x4008_5203;
+
+fn get_entropy_avail() -> Option<u32> {
+    match read_to_string(ENTROPY_AVAIL_PATH) {
+        Ok(content) => content.trim().parse::<u32>().ok(),
+        Err(why) => {
+            debug!(
+                "get_entropy_avail: failed to read '{}', error: {}",
+                ENTROPY_AVAIL_PATH, why
+            );
+            None
+        }
+    }
+}
+
+/// Read from the hardware RNG and credit it to the kernel entropy pool
+fn seed_from_hw_rng() -> Result<()> {
+    let mut seed = [0u8; SEED_BYTES];
+    File::open(HW_RNG_PATH)
+        .upstream_with_context(&format!("Failed to open '{}'", HW_RNG_PATH))?
+        .read_exact(&mut seed)
+        .upstream_with_context(&format!("Failed to read from '{}'", HW_RNG_PATH))?;
+
+    // struct rand_pool_info: entropy_count in bits, buf_size in bytes, followed by the data
+    let mut pool_info: Vec<u8> = Vec::with_capacity(8 + SEED_BYTES);
+    pool_info.extend_from_slice(&((SEED_BYTES * 8) as i32).to_ne_bytes());
+    pool_info.extend_from_slice(&(SEED_BYTES as i32).to_ne_bytes());
+    pool_info.extend_from_slice(&seed);
+
+    let random = OpenOptions::new()
+        .write(true)
+        .open(RANDOM_PATH)
+        .upstream_with_context(&format!("Failed to open '{}'", RANDOM_PATH))?;
+    let ioctl_res = unsafe {
+        ioctl(
+            random.as_raw_fd(),
+            IOCTL_RND_ADD_ENTROPY,
+            pool_info.as_ptr(),
+        )
+    };
+    if ioctl_res == 0 {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to add entropy to '{}', error: {}",
+                RANDOM_PATH,
+                io::Error::last_os_error()
+            ),
+        ))
+    }
+}
+
+/// Wait for the kernel to gather entropy, returns the available entropy on success
+fn gather_entropy() -> Option<u32> {
+    let start = Instant::now();
+    while start.elapsed() < GATHER_TIMEOUT {
+        match get_entropy_avail() {
+            Some(bits) if bits >= MIN_ENTROPY_BITS => return Some(bits),
+            _ => sleep(GATHER_POLL_INTERVAL),
+        }
+    }
+    None
+}
+
+/// Warn about low kernel entropy before downloads and API checks, which can stall in TLS
+/// handshakes without it. With seed the pool is seeded from the hardware RNG if there is one,
+/// otherwise the kernel gets some time to gather entropy.
+pub(crate) fn check_entropy(seed: bool) {
+    let bits = if let Some(bits) = get_entropy_avail() {
+        bits
+    } else {
+        return;
+    };
+
+    if bits >= MIN_ENTROPY_BITS {
+        debug!("check_entropy: {} bits of entropy available", bits);
+        return;
+    }
+
+    warn!(
+        "The kernel has only {} bits of entropy available, TLS connections for downloads and API checks might stall",
+        bits
+    );
+
+    if !seed {
+        warn!("Use --seed-entropy to seed the kernel entropy pool before connecting");
+        return;
+    }
+
+    if file_exists(HW_RNG_PATH) {
+        match seed_from_hw_rng() {
+            Ok(_) => info!(
+                "Seeded the kernel entropy pool with {} bytes from '{}'",
+                SEED_BYTES, HW_RNG_PATH
+            ),
+            Err(why) => warn!("Failed to seed entropy from the hardware RNG: {}", why),
+        }
+    } else {
+        info!(
+            "No hardware RNG found, waiting up to {} seconds for the kernel to gather entropy",
+            GATHER_TIMEOUT.as_secs()
+        );
+    }
+
+    match gather_entropy() {
+        Some(bits) => info!("The kernel has {} bits of entropy available now", bits),
+        None => warn!(
+            "The kernel entropy is still low after {} seconds, installing rng-tools or haveged can help",
+            GATHER_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+/// Point at low entropy as a likely cause when a network operation failed
+pub(crate) fn warn_if_entropy_low(operation: &str) {
+    if let Some(bits) = get_entropy_avail() {
+        if bits < MIN_ENTROPY_BITS {
+            warn!(
+                "{} failed while the kernel has only {} bits of entropy available, stalled TLS handshakes due to low entropy are a likely cause",
+                operation, bits
+            );
+        }
+    }
+}
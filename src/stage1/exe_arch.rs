@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::Read;
+
+use log::debug;
+
+use crate::{
+    common::{Error, ErrorKind, Result, ToError},
+    stage1::{defs::OSArch, utils::get_os_arch},
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const EI_DATA: usize = 5;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_DATA_MSB: u8 = 2;
+const E_MACHINE_OFFSET: usize = 18;
+const ELF_HEADER_PREFIX: usize = 20;
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+fn machine_name(machine: u16) -> String {
+    match machine {
+        EM_386 => "i386".to_string(),
+        EM_ARM => "arm".to_string(),
+        EM_X86_64 => "x86_64".to_string(),
+        EM_AARCH64 => "aarch64".to_string(),
+        _ => format!("unknown machine {}", machine),
+    }
+}
+
+/// The machines the kernel can execute, 64 bit kernels usually run 32 bit executables of
+/// their architecture too
+fn compatible_machines(os_arch: &OSArch) -> &'static [u16] {
+    match os_arch {
+        OSArch::AMD64 => &[EM_X86_64, EM_386],
+        OSArch::I386 => &[EM_386],
+        OSArch::ARMHF => &[EM_ARM],
+        OSArch::ARM64 => &[EM_AARCH64, EM_ARM],
+    }
+}
+
+/// e_machine from the start of an ELF header, None if header is not an ELF header
+fn parse_elf_machine(header: &[u8]) -> Option<u16> {
+    if header.len() < ELF_HEADER_PREFIX || &header[..ELF_MAGIC.len()] != ELF_MAGIC {
+        return None;
+    }
+    let machine = [header[E_MACHINE_OFFSET], header[E_MACHINE_OFFSET + 1]];
+    match header[EI_DATA] {
+        ELF_DATA_LSB => Some(u16::from_le_bytes(machine)),
+        ELF_DATA_MSB => Some(u16::from_be_bytes(machine)),
+        _ => None,
+    }
+}
+
+fn read_elf_machine(path: &str) -> Result<u16> {
+    let mut header = [0u8; ELF_HEADER_PREFIX];
+    File::open(path)
+        .upstream_with_context(&format!("Failed to open '{}'", path))?
+        .read_exact(&mut header)
+        .upstream_with_context(&format!("Failed to read ELF header from '{}'", path))?;
+    parse_elf_machine(&header).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("'{}' is not an ELF executable", path),
+        )
+    })
+}
+
+/// Make sure the kernel can execute the executables copied to stage2, stage2 has no way to
+/// recover from an executable that fails to run
+pub(crate) fn check_exe_arch<'a, I: IntoIterator<Item = &'a String>>(executables: I) -> Result<()> {
+    let os_arch = get_os_arch()?;
+    let compatible = compatible_machines(&os_arch);
+    for exe_path in executables {
+        let machine = read_elf_machine(exe_path)?;
+        debug!(
+            "check_exe_arch: '{}' is built for {}",
+            exe_path,
+            machine_name(machine)
+        );
+        if !compatible.contains(&machine) {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Architecture mismatch: '{}' is built for {} but the kernel architecture is {:?}",
+                    exe_path,
+                    machine_name(machine),
+                    os_arch
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_elf_machine() {
+        let mut header = [0u8; ELF_HEADER_PREFIX];
+        header[..4].copy_from_slice(ELF_MAGIC);
+        header[EI_DATA] = ELF_DATA_LSB;
+        header[E_MACHINE_OFFSET] = 183;
+        assert_eq!(parse_elf_machine(&header), Some(EM_AARCH64));
+
+        header[EI_DATA] = ELF_DATA_MSB;
+        header[E_MACHINE_OFFSET] = 0;
+        header[E_MACHINE_OFFSET + 1] = 40;
+        assert_eq!(parse_elf_machine(&header), Some(EM_ARM));
+
+        assert_eq!(parse_elf_machine(&header[..10]), None);
+        header[0] = b'#';
+        assert_eq!(parse_elf_machine(&header), None);
+    }
+}
@@ -1,56 +1,161 @@
+use crate::common::cmd_runner::CommandRunner;
 use crate::common::system::stat;
-use crate::common::{call, dir_exists, path_append, whereis, Error, ErrorKind, Result, ToError};
+use crate::common::{dir_exists, path_append, whereis, Error, ErrorKind, Result, ToError};
+use crate::stage1::exe_arch::check_exe_arch;
 
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
 use regex::Regex;
 use std::collections::HashSet;
-use std::fs::{copy, create_dir, create_dir_all, read_link};
+use std::env::temp_dir;
+use std::ffi::OsStr;
+use std::fs::{copy, create_dir, create_dir_all, read_link, remove_file};
 use std::path::{Path, PathBuf};
+use std::process;
+
+const BUSYBOX_NAME: &str = "busybox";
+const SELF_EXE_PATH: &str = "/proc/self/exe";
+const DELETED_SUFFIX: &str = " (deleted)";
 
 pub(crate) struct ExeCopy {
     req_space: u64,
     libraries: HashSet<String>,
     executables: HashSet<String>,
+    // temporary copy of this executable if it was deleted or replaced since it was started
+    self_exe_copy: Option<String>,
 }
 
 impl ExeCopy {
-    pub fn new(cmd_list: Vec<&str>) -> Result<ExeCopy> {
+    pub fn new(cmd_list: Vec<&str>, runner: &dyn CommandRunner) -> Result<ExeCopy> {
         trace!("new: entered with {:?}", cmd_list);
 
         let mut executables: HashSet<String> = HashSet::new();
 
-        executables.insert(
-            read_link("/proc/self/exe")
-                .upstream_with_context("Failed to read link to this executable")?
-                .to_string_lossy()
-                .to_string(),
-        );
+        let (self_exe, is_copy) = ExeCopy::get_self_exe()?;
+        let self_exe_copy = if is_copy {
+            Some(self_exe.clone())
+        } else {
+            None
+        };
+        executables.insert(self_exe);
 
         for command in cmd_list {
-            executables.insert(whereis(&command).error_with_all(
+            let cmd_path = whereis(&command, runner).error_with_all(
                 ErrorKind::FileNotFound,
                 &format!("Command '{}' could not be located", command),
-            )?);
+            )?;
+            ExeCopy::check_busybox_applet(command, &cmd_path, runner)?;
+            executables.insert(cmd_path);
         }
 
+        check_exe_arch(&executables)?;
+
         let mut efi_files = ExeCopy {
             req_space: 0,
             libraries: HashSet::new(),
             executables,
+            self_exe_copy,
         };
 
-        efi_files.get_libs_for()?;
+        efi_files.get_libs_for(runner)?;
 
         Ok(efi_files)
     }
+    /// make sure commands provided by busybox are compiled into the busybox binary
+    fn check_busybox_applet(
+        command: &str,
+        cmd_path: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let real_path = Path::new(cmd_path)
+            .canonicalize()
+            .upstream_with_context(&format!("Failed to canonicalize path '{}'", cmd_path))?;
+
+        if let Some(file_name) = real_path.file_name() {
+            if file_name != BUSYBOX_NAME {
+                return Ok(());
+            }
+        } else {
+            return Ok(());
+        }
+
+        debug!(
+            "check_busybox_applet: '{}' is provided by '{}'",
+            command,
+            real_path.display()
+        );
+
+        let applets = runner.call_command(
+            &*real_path.to_string_lossy(),
+            &["--list"],
+            &format!("Failed to list applets of '{}'", real_path.display()),
+        )?;
+
+        if applets.lines().any(|applet| applet.trim() == command) {
+            Ok(())
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "Command '{}' is not available as an applet in '{}'",
+                    command,
+                    real_path.display()
+                ),
+            ))
+        }
+    }
+
+    /// get the path to copy this executable from. If the executable has been deleted or replaced
+    /// since it was started it is copied from /proc/self/exe to a temporary file first, ldd run on
+    /// /proc/self/exe would resolve the link in its own process. The flag is set for such a copy.
+    fn get_self_exe() -> Result<(String, bool)> {
+        let exe_path = read_link(SELF_EXE_PATH)
+            .upstream_with_context("Failed to read link to this executable")?
+            .to_string_lossy()
+            .to_string();
+
+        let self_stat = stat(SELF_EXE_PATH)
+            .upstream_with_context(&format!("Failed to stat '{}'", SELF_EXE_PATH))?;
+
+        let replaced = if exe_path.ends_with(DELETED_SUFFIX) {
+            true
+        } else {
+            match stat(&exe_path) {
+                Ok(exe_stat) => {
+                    exe_stat.st_dev != self_stat.st_dev || exe_stat.st_ino != self_stat.st_ino
+                }
+                Err(_) => true,
+            }
+        };
+
+        if replaced {
+            warn!(
+                "The running executable '{}' has been deleted or replaced, copying it from '{}'. Please consider running {} from a stable location",
+                exe_path.trim_end_matches(DELETED_SUFFIX),
+                SELF_EXE_PATH,
+                env!("CARGO_PKG_NAME")
+            );
+            let copy_path =
+                temp_dir().join(format!("{}-{}", env!("CARGO_PKG_NAME"), process::id()));
+            copy(SELF_EXE_PATH, &copy_path).upstream_with_context(&format!(
+                "Failed to copy '{}' to '{}'",
+                SELF_EXE_PATH,
+                copy_path.display()
+            ))?;
+            Ok((copy_path.to_string_lossy().to_string(), true))
+        } else {
+            Ok((exe_path, false))
+        }
+    }
+
     pub fn get_req_space(&self) -> u64 {
         self.req_space
     }
 
-    fn get_libs_for(&mut self) -> Result<()> {
+    fn get_libs_for(&mut self, runner: &dyn CommandRunner) -> Result<()> {
         trace!("get_libs_for: entered");
-        let ldd_path = whereis("ldd").upstream_with_context("Failed to locate ldd executable")?;
+        let ldd_path =
+            whereis("ldd", runner).upstream_with_context("Failed to locate ldd executable")?;
         let mut check_libs: HashSet<String> = HashSet::new();
 
         // TODO: this_path processing
@@ -59,7 +164,7 @@ impl ExeCopy {
             let stat = stat(curr_path)
                 .upstream_with_context(&format!("Failed to stat '{}'", curr_path))?;
             self.req_space += stat.st_size as u64;
-            self.get_libs(&curr_path, ldd_path.as_str(), &mut check_libs)?;
+            self.get_libs(&curr_path, ldd_path.as_str(), &mut check_libs, runner)?;
         }
 
         while !check_libs.is_empty() {
@@ -68,7 +173,7 @@ impl ExeCopy {
                 self.add_lib(curr)?;
             }
             for curr in &check_libs {
-                self.get_libs(curr, ldd_path.as_str(), &mut unchecked_libs)?;
+                self.get_libs(curr, ldd_path.as_str(), &mut unchecked_libs, runner)?;
             }
             check_libs = unchecked_libs;
         }
@@ -94,9 +199,15 @@ impl ExeCopy {
         }
     }
 
-    fn get_libs(&self, file: &str, ldd_path: &str, found: &mut HashSet<String>) -> Result<()> {
+    fn get_libs(
+        &self,
+        file: &str,
+        ldd_path: &str,
+        found: &mut HashSet<String>,
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
         trace!("get_libs: entered with '{}'", file);
-        let ldd_res = match call(ldd_path, &[file], true) {
+        let ldd_res = match runner.call(ldd_path, &[file], true) {
             Ok(cmd_res) => {
                 if cmd_res.status.success() {
                     cmd_res.stdout
@@ -205,15 +316,25 @@ impl ExeCopy {
         Ok(())
     }
 
-    pub fn copy_files<P: AsRef<Path>>(&self, takeover_dir: P) -> Result<()> {
+    /// Copy the executables and libraries to takeover_dir, on_copied receives the number of
+    /// files copied and the total after each file
+    pub fn copy_files<P: AsRef<Path>, F: Fn(usize, usize)>(
+        &self,
+        takeover_dir: P,
+        on_copied: F,
+    ) -> Result<()> {
         trace!(
             "copy_files: entered with '{}'",
             takeover_dir.as_ref().display()
         );
         let takeover_dir = takeover_dir.as_ref();
+        let total = self.libraries.len() + self.executables.len();
+        let mut copied = 0;
 
         for src_path in &self.libraries {
             ExeCopy::copy_file(src_path, takeover_dir)?;
+            copied += 1;
+            on_copied(copied, total);
         }
 
         let dest_path = path_append(takeover_dir, "/bin");
@@ -225,7 +346,13 @@ impl ExeCopy {
         }
 
         for file in &self.executables {
-            if let Some(file_name) = PathBuf::from(file).file_name() {
+            let file_name = if Some(file) == self.self_exe_copy.as_ref() {
+                Some(OsStr::new(env!("CARGO_PKG_NAME")))
+            } else {
+                Path::new(file).file_name()
+            };
+
+            if let Some(file_name) = file_name {
                 let dest_path = path_append(&dest_path, file_name);
                 trace!(
                     "copy_files: copying '{}' to '{}'",
@@ -238,6 +365,8 @@ impl ExeCopy {
                     dest_path.display()
                 ))?;
                 info!("Copied '{}' to '{}'", &file, dest_path.display());
+                copied += 1;
+                on_copied(copied, total);
             } else {
                 return Err(Error::with_context(
                     ErrorKind::InvState,
@@ -249,3 +378,16 @@ impl ExeCopy {
         Ok(())
     }
 }
+
+impl Drop for ExeCopy {
+    fn drop(&mut self) {
+        if let Some(self_exe_copy) = &self.self_exe_copy {
+            if let Err(why) = remove_file(self_exe_copy) {
+                warn!(
+                    "Failed to remove temporary copy '{}', error: {}",
+                    self_exe_copy, why
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::{debug, info, warn};
+
+use crate::{
+    common::{file_exists, path_append, stage2_config::UmountPart, Result, ToError},
+    stage1::block_device_info::{BlockDevice, BlockDeviceInfo},
+};
+
+const FSTAB_PATH: &str = "/etc/fstab";
+const AUTOMOUNT_OPTION: &str = "x-systemd.automount";
+const NOAUTO_OPTION: &str = "noauto";
+
+// tags fstab uses to refer to devices and the directories their links are in
+const SPEC_TAGS: [(&str, &str); 4] = [
+    ("UUID=", "/dev/disk/by-uuid"),
+    ("LABEL=", "/dev/disk/by-label"),
+    ("PARTUUID=", "/dev/disk/by-partuuid"),
+    ("PARTLABEL=", "/dev/disk/by-partlabel"),
+];
+
+#[derive(Debug, PartialEq)]
+struct FstabEntry {
+    spec: String,
+    mountpoint: PathBuf,
+    fs_type: String,
+    options: Vec<String>,
+}
+
+impl FstabEntry {
+    fn has_option(&self, option: &str) -> bool {
+        self.options.iter().any(|curr| curr == option)
+    }
+}
+
+/// fstab escapes blanks in fields as octal
+fn unescape(field: &str) -> String {
+    field.replace("\\040", " ").replace("\\011", "\t")
+}
+
+fn parse_fstab(content: &str) -> Vec<FstabEntry> {
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                debug!("parse_fstab: skipping invalid line '{}'", line);
+                return None;
+            }
+            Some(FstabEntry {
+                spec: unescape(fields[0]),
+                mountpoint: PathBuf::from(unescape(fields[1])),
+                fs_type: fields[2].to_string(),
+                options: fields
+                    .get(3)
+                    .map(|options| {
+                        options
+                            .split(',')
+                            .map(|option| option.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_else(Vec::new),
+            })
+        })
+        .collect()
+}
+
+/// The path of the device link the spec refers to, None for specs that are not block devices
+fn spec_path(spec: &str) -> Option<PathBuf> {
+    if spec.starts_with('/') {
+        return Some(PathBuf::from(spec));
+    }
+    SPEC_TAGS
+        .iter()
+        .find(|(tag, _)| spec.starts_with(tag))
+        .map(|(tag, dir)| path_append(dir, &spec[tag.len()..]))
+}
+
+/// Log the fstab entries that refer to partitions of the flash device and warn about the ones that
+/// are not mounted now. Stage2 only unmounts the partitions mounted when stage1 runs, a partition
+/// mounted later, e.g. on access through an automount, stays mounted while the device is flashed.
+pub(crate) fn check_fstab(
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+    umount_parts: &[UmountPart],
+) -> Result<()> {
+    if !file_exists(FSTAB_PATH) {
+        debug!("check_fstab: '{}' does not exist", FSTAB_PATH);
+        return Ok(());
+    }
+
+    let content = read_to_string(FSTAB_PATH)
+        .upstream_with_context(&format!("Failed to read file '{}'", FSTAB_PATH))?;
+
+    for entry in parse_fstab(&content) {
+        let dev_path = if let Some(dev_path) = spec_path(&entry.spec)
+            .as_deref()
+            .and_then(|link| Path::canonicalize(link).ok())
+        {
+            dev_path
+        } else {
+            continue;
+        };
+
+        let device = if let Some(device) = block_dev_info.get_devices().get(&dev_path) {
+            device
+        } else {
+            continue;
+        };
+
+        let on_flash_dev = device.get_name() == flash_dev.get_name()
+            || device.get_parent().map(|parent| parent.get_name()) == Some(flash_dev.get_name());
+        if !on_flash_dev {
+            continue;
+        }
+
+        info!(
+            "fstab entry '{}' on '{}' ({}, {}) refers to '{}' on the flash device",
+            entry.spec,
+            entry.mountpoint.display(),
+            entry.fs_type,
+            entry.options.join(","),
+            dev_path.display()
+        );
+
+        if umount_parts
+            .iter()
+            .any(|part| part.mountpoint == entry.mountpoint)
+        {
+            continue;
+        }
+
+        if entry.has_option(AUTOMOUNT_OPTION) {
+            warn!(
+                "'{}' is mounted on '{}' on access by a systemd automount, it will not be unmounted before flashing if it gets mounted during the migration. Consider stopping the automount",
+                dev_path.display(),
+                entry.mountpoint.display()
+            );
+        } else if entry.has_option(NOAUTO_OPTION) {
+            warn!(
+                "'{}' is configured with noauto to be mounted on '{}', it will not be unmounted before flashing if it gets mounted during the migration",
+                dev_path.display(),
+                entry.mountpoint.display()
+            );
+        } else {
+            warn!(
+                "'{}' is configured to be mounted on '{}' but is not mounted now, it will not be unmounted before flashing if it gets mounted during the migration",
+                dev_path.display(),
+                entry.mountpoint.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fstab() {
+        let entries = parse_fstab(
+            "# /etc/fstab\n\
+             UUID=0d5e2a4c / ext4 defaults 0 1\n\
+             \n\
+             LABEL=data /mnt/my\\040data ext4 noauto,x-systemd.automount 0 2\n\
+             proc /proc proc\n\
+             invalid\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].spec, "UUID=0d5e2a4c");
+        assert_eq!(entries[1].mountpoint, PathBuf::from("/mnt/my data"));
+        assert!(entries[1].has_option(AUTOMOUNT_OPTION));
+        assert!(entries[1].has_option(NOAUTO_OPTION));
+        assert!(entries[2].options.is_empty());
+    }
+
+    #[test]
+    fn test_spec_path() {
+        assert_eq!(
+            spec_path("UUID=0d5e2a4c"),
+            Some(PathBuf::from("/dev/disk/by-uuid/0d5e2a4c"))
+        );
+        assert_eq!(
+            spec_path("PARTUUID=1234-01"),
+            Some(PathBuf::from("/dev/disk/by-partuuid/1234-01"))
+        );
+        assert_eq!(spec_path("/dev/sda1"), Some(PathBuf::from("/dev/sda1")));
+        assert_eq!(spec_path("tmpfs"), None);
+    }
+}
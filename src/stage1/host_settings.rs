@@ -0,0 +1,129 @@
+use std::env;
+use std::fs::{read_link, read_to_string};
+use std::path::Path;
+
+use log::{debug, warn};
+
+const TIMEZONE_PATH: &str = "/etc/timezone";
+const LOCALTIME_PATH: &str = "/etc/localtime";
+const ZONEINFO_DIR: &str = "zoneinfo/";
+const LOCALE_PATHS: [&str; 2] = ["/etc/locale.conf", "/etc/default/locale"];
+
+/// The zone name from a /etc/localtime link target like ../usr/share/zoneinfo/Europe/Berlin
+fn zone_from_link(target: &Path) -> Option<String> {
+    let target = target.to_string_lossy();
+    let pos = target.rfind(ZONEINFO_DIR)?;
+    let zone = target[pos + ZONEINFO_DIR.len()..].trim_matches('/');
+    if zone.is_empty() {
+        None
+    } else {
+        Some(zone.to_string())
+    }
+}
+
+/// The LANG setting of a locale.conf style file, LC_ALL if LANG is not set
+fn parse_locale(content: &str) -> Option<String> {
+    let get = |name: &str| {
+        content.lines().find_map(|line| {
+            let mut parts = line.trim().splitn(2, '=');
+            if parts.next()?.trim() == name {
+                let value = parts.next()?.trim().trim_matches(|c| c == '"' || c == '\'');
+                if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_string())
+                }
+            } else {
+                None
+            }
+        })
+    };
+    get("LANG").or_else(|| get("LC_ALL"))
+}
+
+/// The timezone of the host from /etc/timezone or the /etc/localtime link, None if neither
+/// exists
+pub(crate) fn get_timezone() -> Option<String> {
+    if let Ok(content) = read_to_string(TIMEZONE_PATH) {
+        let zone = content.trim();
+        if !zone.is_empty() {
+            debug!("get_timezone: found '{}' in '{}'", zone, TIMEZONE_PATH);
+            return Some(zone.to_string());
+        }
+    }
+
+    match read_link(LOCALTIME_PATH) {
+        Ok(target) => {
+            let zone = zone_from_link(&target);
+            if zone.is_none() {
+                warn!(
+                    "Failed to determine the timezone from '{}' -> '{}'",
+                    LOCALTIME_PATH,
+                    target.display()
+                );
+            }
+            zone
+        }
+        Err(why) => {
+            warn!(
+                "Failed to determine the timezone, neither '{}' nor the link '{}' is usable: {}",
+                TIMEZONE_PATH, LOCALTIME_PATH, why
+            );
+            None
+        }
+    }
+}
+
+/// The locale of the host from the system locale configuration or the LANG environment
+/// variable, None if none is set
+pub(crate) fn get_locale() -> Option<String> {
+    let locale = LOCALE_PATHS
+        .iter()
+        .find_map(|path| {
+            let locale = parse_locale(&read_to_string(path).ok()?)?;
+            debug!("get_locale: found '{}' in '{}'", locale, path);
+            Some(locale)
+        })
+        .or_else(|| env::var("LANG").ok().filter(|lang| !lang.is_empty()));
+    if locale.is_none() {
+        warn!(
+            "Failed to determine the locale, none of {:?} sets LANG",
+            LOCALE_PATHS
+        );
+    }
+    locale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_zone_from_link() {
+        assert_eq!(
+            zone_from_link(&PathBuf::from("../usr/share/zoneinfo/Europe/Berlin")),
+            Some(String::from("Europe/Berlin"))
+        );
+        assert_eq!(
+            zone_from_link(&PathBuf::from("/usr/share/zoneinfo/UTC")),
+            Some(String::from("UTC"))
+        );
+        assert_eq!(zone_from_link(&PathBuf::from("/usr/share/zoneinfo/")), None);
+        assert_eq!(zone_from_link(&PathBuf::from("/etc/localtime.bak")), None);
+    }
+
+    #[test]
+    fn test_parse_locale() {
+        assert_eq!(
+            parse_locale("# generated\nLANG=\"en_GB.UTF-8\"\nLC_TIME=C\n"),
+            Some(String::from("en_GB.UTF-8"))
+        );
+        assert_eq!(
+            parse_locale("LC_ALL=de_DE.UTF-8\n"),
+            Some(String::from("de_DE.UTF-8"))
+        );
+        assert_eq!(parse_locale("LANG=\n"), None);
+        assert_eq!(parse_locale(""), None);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use log::{debug, warn};
+
+use crate::common::{path_append, Error, ErrorKind, Result};
+
+const SYS_NET_DIR: &str = "/sys/class/net";
+const DT_SERIAL_PATH: &str = "/proc/device-tree/serial-number";
+const DMI_SERIAL_PATH: &str = "/sys/class/dmi/id/product_serial";
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+/// used when the template refers to an identifier this device does not have
+const FALLBACK_TEMPLATE: &str = "balena-{mac}";
+const MAX_HOSTNAME_LEN: usize = 63;
+
+/// Make an identifier usable in a hostname: lower case with anything but letters and digits
+/// replaced by '-'. MAC addresses lose their colons.
+fn sanitize(value: &str) -> Option<String> {
+    let value: String = value
+        .trim()
+        .trim_matches(char::from(0))
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let value = value.trim_matches('-');
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// The MAC address of the first physical network interface in name order
+fn read_mac() -> Option<String> {
+    let mut ifaces: Vec<String> = read_dir(SYS_NET_DIR)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("device").exists())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    ifaces.sort();
+
+    ifaces.iter().find_map(|iface| {
+        let mac = read_to_string(path_append(path_append(SYS_NET_DIR, iface), "address")).ok()?;
+        if mac.trim() == "00:00:00:00:00:00" {
+            None
+        } else {
+            debug!("read_mac: using MAC address of interface {}", iface);
+            sanitize(&mac)
+        }
+    })
+}
+
+/// The serial number from the device tree, the DMI product serial or the Serial line of
+/// /proc/cpuinfo found on Raspberry Pis
+fn read_serial() -> Option<String> {
+    let read = |path: &str| read_to_string(Path::new(path)).ok();
+    read(DT_SERIAL_PATH)
+        .or_else(|| read(DMI_SERIAL_PATH))
+        .or_else(|| {
+            read(CPUINFO_PATH)?.lines().find_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                if parts.next()?.trim() == "Serial" {
+                    Some(parts.next()?.to_string())
+                } else {
+                    None
+                }
+            })
+        })
+        .and_then(|serial| sanitize(&serial))
+}
+
+fn read_hostname() -> Option<String> {
+    read_to_string(HOSTNAME_PATH)
+        .ok()
+        .and_then(|hostname| sanitize(&hostname))
+}
+
+/// Replace the placeholders in the template, fails on unknown placeholders and returns None if
+/// an identifier is not available
+fn expand(template: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<Option<String>> {
+    let mut hostname = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        hostname.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Unterminated placeholder in hostname template '{}'",
+                    template
+                ),
+            )
+        })? + start;
+        let name = &rest[start + 1..end];
+        match name {
+            "mac" | "serial" | "hostname" => {
+                if let Some(value) = lookup(name) {
+                    hostname.push_str(&value);
+                } else {
+                    warn!("The {} of this device could not be determined", name);
+                    return Ok(None);
+                }
+            }
+            _ => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Unknown placeholder '{{{}}}' in hostname template '{}', supported are {{mac}}, {{serial}} and {{hostname}}",
+                        name, template
+                    ),
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    hostname.push_str(rest);
+    Ok(Some(hostname))
+}
+
+/// Check that the hostname is a valid RFC 1123 host name label
+fn check_hostname(hostname: &str) -> Result<()> {
+    if hostname.is_empty()
+        || hostname.len() > MAX_HOSTNAME_LEN
+        || hostname.starts_with('-')
+        || hostname.ends_with('-')
+        || !hostname
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "'{}' is not a valid hostname, it must consist of 1 to {} lower case letters, digits and '-' and must not start or end with '-'",
+                hostname, MAX_HOSTNAME_LEN
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn resolve_with(template: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let hostname = if let Some(hostname) = expand(template, lookup)? {
+        hostname
+    } else if let Some(hostname) = expand(FALLBACK_TEMPLATE, lookup)? {
+        warn!(
+            "Using hostname template '{}' instead of '{}'",
+            FALLBACK_TEMPLATE, template
+        );
+        hostname
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "Failed to resolve hostname template '{}' and fallback '{}'",
+                template, FALLBACK_TEMPLATE
+            ),
+        ));
+    };
+
+    check_hostname(&hostname)?;
+    Ok(hostname)
+}
+
+/// Derive the hostname of the migrated device from the template, placeholders are replaced by
+/// the identifiers of this device
+pub(crate) fn resolve_hostname(template: &str) -> Result<String> {
+    resolve_with(template, &|name| match name {
+        "mac" => read_mac(),
+        "serial" => read_serial(),
+        "hostname" => read_hostname(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_hostname() {
+        let lookup = |name: &str| match name {
+            "mac" => sanitize("DC:A6:32:01:02:03\n"),
+            "serial" => sanitize("10000000abcdef01\u{0}"),
+            _ => None,
+        };
+
+        assert_eq!(
+            resolve_with("device-{mac}", &lookup).unwrap(),
+            "device-dca632010203"
+        );
+        assert_eq!(
+            resolve_with("{serial}", &lookup).unwrap(),
+            "10000000abcdef01"
+        );
+        // missing identifier falls back to the default template
+        assert_eq!(
+            resolve_with("site-{hostname}", &lookup).unwrap(),
+            "balena-dca632010203"
+        );
+        assert!(resolve_with("{model}", &lookup).is_err());
+        assert!(resolve_with("Device_{mac}", &lookup).is_err());
+        assert!(resolve_with("-{mac}", &lookup).is_err());
+    }
+}
@@ -12,17 +12,19 @@ use crate::{
         disk_util::{Disk, PartitionIterator, PartitionReader},
         is_admin,
         loop_device::LoopDevice,
+        options::Options,
         path_append,
         stream_progress::StreamProgress,
         Error, Result, ToError,
     },
     stage1::{
-        api_calls::{get_os_image, get_os_versions, Versions},
+        api_calls::{get_os_image, get_os_versions, DownloadHeaders, Versions},
         defs::{
             DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC, DEV_TYPE_RPI1,
             DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
         },
         migrate_info::balena_cfg_json::BalenaCfgJson,
+        utils::retry_eintr,
     },
     ErrorKind,
 };
@@ -70,6 +72,90 @@ fn parse_versions(versions: &Versions) -> Vec<Version> {
     sem_vers
 }
 
+fn is_prod_version(version: &Version) -> bool {
+    !version.is_prerelease()
+        && version
+            .build
+            .contains(&Identifier::AlphaNumeric("prod".to_string()))
+}
+
+/// print the available OS versions for the device type, filtered by the list options
+pub(crate) fn list_os_versions(opts: &Options) -> Result<()> {
+    let balena_cfg = if !opts.config().is_empty() {
+        BalenaCfgJson::from_files(opts.config())?
+    } else {
+        error!("The required parameter --config/-c was not provided, it is needed to access the balena API");
+        return Err(Error::displayed());
+    };
+
+    let device_type = if let Some(device_type) = opts.device_type() {
+        device_type.to_string()
+    } else {
+        balena_cfg.get_device_type()?
+    };
+
+    let api_key = balena_cfg.get_api_key().upstream_with_context(
+        "Failed to retrieve api-key from config.json - unable to list os versions",
+    )?;
+
+    let api_endpoint = balena_cfg.get_api_endpoint().upstream_with_context(
+        "Failed to retrieve api-endpoint from config.json - unable to list os versions",
+    )?;
+
+    let download_headers = DownloadHeaders::from_opts(opts)?;
+    let versions = match get_os_versions(&api_endpoint, &api_key, &device_type, &download_headers) {
+        Ok(versions) => versions,
+        Err(why) => {
+            error!(
+                "Failed to retrieve os versions for device type '{}' from '{}', error: {}",
+                device_type, api_endpoint, why
+            );
+            return Err(Error::displayed());
+        }
+    };
+
+    let ver_req =
+        match opts.version() {
+            "default" | "latest" => None,
+            ver_str => Some(VersionReq::parse(ver_str).upstream_with_context(&format!(
+                "Failed to parse version range from '{}'",
+                ver_str
+            ))?),
+        };
+
+    let mut selected: Vec<String> = parse_versions(&versions)
+        .iter()
+        .filter(|version| !opts.list_prod_only() || is_prod_version(version))
+        .filter(|version| {
+            if let Some(ver_req) = &ver_req {
+                ver_req.matches(version)
+            } else {
+                true
+            }
+        })
+        .map(|version| version.to_string())
+        .collect();
+
+    if let Some(count) = opts.list_latest() {
+        selected.truncate(count);
+    }
+
+    if opts.list_json() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&selected)
+                .upstream_with_context("Failed to format os versions as JSON")?
+        );
+    } else {
+        info!("Available os versions for device type '{}':", device_type);
+        for version in selected {
+            println!("{}", version);
+        }
+    }
+
+    Ok(())
+}
+
 fn determine_version(ver_str: &str, versions: &Versions) -> Result<Version> {
     match ver_str {
         "latest" => {
@@ -85,12 +171,7 @@ fn determine_version(ver_str: &str, versions: &Versions) -> Result<Version> {
             let mut found: Option<Version> = None;
             for cmp_ver in parse_versions(&versions) {
                 debug!("Looking at version {}", cmp_ver);
-                if cmp_ver.is_prerelease() {
-                    continue;
-                } else if cmp_ver
-                    .build
-                    .contains(&Identifier::AlphaNumeric("prod".to_string()))
-                {
+                if is_prod_version(&cmp_ver) {
                     found = Some(cmp_ver);
                     break;
                 }
@@ -114,12 +195,7 @@ fn determine_version(ver_str: &str, versions: &Versions) -> Result<Version> {
                 ))?;
                 let mut found: Option<Version> = None;
                 for cmp_ver in parse_versions(&versions) {
-                    if ver_req.matches(&cmp_ver)
-                        && !cmp_ver.is_prerelease()
-                        && cmp_ver
-                            .build
-                            .contains(&Identifier::AlphaNumeric("prod".to_string()))
-                    {
+                    if ver_req.matches(&cmp_ver) && is_prod_version(&cmp_ver) {
                         found = Some(cmp_ver);
                         break;
                     }
@@ -205,13 +281,15 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
         }
 
         debug!("mount path is '{}'", mount_path.display());
-        mount(
-            Some(loop_device.get_path()),
-            &mount_path,
-            Some(b"ext4".as_ref()),
-            MsFlags::empty(),
-            NIX_NONE,
-        )
+        retry_eintr(|| {
+            mount(
+                Some(loop_device.get_path()),
+                &mount_path,
+                Some(b"ext4".as_ref()),
+                MsFlags::empty(),
+                NIX_NONE,
+            )
+        })
         .upstream_with_context(&format!(
             "Failed to mount '{}' on '{}",
             loop_device.get_path().display(),
@@ -279,7 +357,7 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
             img_file_name.display()
         );
 
-        match umount(&mount_path) {
+        match retry_eintr(|| umount(&mount_path)) {
             Ok(_) => {
                 if let Err(why) = remove_dir(&mount_path) {
                     warn!(
@@ -321,6 +399,7 @@ pub(crate) fn download_image(
     work_dir: &Path,
     device_type: &str,
     version: &str,
+    download_headers: &DownloadHeaders,
 ) -> Result<PathBuf> {
     if !SUPPORTED_DEVICES.contains(&device_type) {
         return Err(Error::with_context(
@@ -340,7 +419,7 @@ pub(crate) fn download_image(
         "Failed to retrieve api-endpoint from config.json - unable to retrieve os-image",
     )?;
 
-    let versions = get_os_versions(&api_endpoint, &api_key, device_type)?;
+    let versions = get_os_versions(&api_endpoint, &api_key, device_type, download_headers)?;
 
     let version = determine_version(version, &versions)?;
 
@@ -351,7 +430,13 @@ pub(crate) fn download_image(
 
     // TODO: extract OS image for flasher
 
-    let stream = get_os_image(&api_endpoint, &api_key, device_type, &version.to_string())?;
+    let stream = get_os_image(
+        &api_endpoint,
+        &api_key,
+        device_type,
+        &version.to_string(),
+        download_headers,
+    )?;
 
     let img_file_name = path_append(
         work_dir,
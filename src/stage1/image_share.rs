@@ -0,0 +1,293 @@
+use std::fs::{create_dir_all, read_to_string, remove_dir};
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info, warn};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+
+use crate::{
+    common::{
+        cmd_runner::CommandRunner,
+        file_exists,
+        options::{Options, CIFS_URL_PREFIX, NFS_URL_PREFIX},
+        path_append, redact, Error, ErrorKind, Result, ToError,
+    },
+    stage1::{
+        kernel_modules::ensure_fs_supported,
+        utils::{resolve_host, retry_eintr},
+    },
+};
+
+// /run is a tmpfs, so the mountpoint is not on the flash device
+const IMAGE_SHARE_MP: &str = "/run/takeover-image-share";
+const NFS_PORT: u16 = 2049;
+const CIFS_PORT: u16 = 445;
+
+/// An image on a network share given as nfs://HOST/PATH or cifs://HOST/SHARE/PATH
+#[derive(Debug, PartialEq)]
+struct ShareUrl {
+    fs_type: &'static str,
+    host: String,
+    // the part of the path that is mounted, the export for NFS, the share for CIFS
+    remote_dir: String,
+    // the image path relative to the mounted directory
+    file: String,
+}
+
+fn parse_share_url(url: &str) -> Result<ShareUrl> {
+    let invalid = |reason: &str| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid image share URL '{}': {}", url, reason),
+        )
+    };
+
+    let (fs_type, rest) = if url.starts_with(NFS_URL_PREFIX) {
+        ("nfs", &url[NFS_URL_PREFIX.len()..])
+    } else if url.starts_with(CIFS_URL_PREFIX) {
+        ("cifs", &url[CIFS_URL_PREFIX.len()..])
+    } else {
+        return Err(invalid("expected an nfs:// or cifs:// URL"));
+    };
+
+    let pos = rest.find('/').ok_or_else(|| invalid("no path given"))?;
+    let (host, path) = (&rest[..pos], rest[pos + 1..].trim_end_matches('/'));
+    if host.is_empty() {
+        return Err(invalid("no host given"));
+    }
+
+    let (remote_dir, file) = if fs_type == "nfs" {
+        // the directory containing the image is mounted
+        let pos = path
+            .rfind('/')
+            .ok_or_else(|| invalid("no image file given"))?;
+        (format!("/{}", &path[..pos]), &path[pos + 1..])
+    } else {
+        // the share is mounted, the image can be in a sub directory of the share
+        let pos = path
+            .find('/')
+            .ok_or_else(|| invalid("expected cifs://HOST/SHARE/PATH"))?;
+        (path[..pos].to_string(), &path[pos + 1..])
+    };
+
+    if remote_dir.trim_matches('/').is_empty() || file.is_empty() {
+        return Err(invalid("no image file given"));
+    }
+
+    Ok(ShareUrl {
+        fs_type,
+        host: host.to_string(),
+        remote_dir,
+        file: file.to_string(),
+    })
+}
+
+/// Read username, password and domain from a --cifs-credentials file, the format of mount.cifs
+fn read_cifs_credentials(path: &Path) -> Result<Vec<String>> {
+    let content = read_to_string(path)
+        .upstream_with_context(&format!("Failed to read file '{}'", path.display()))?;
+    let mut options: Vec<String> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pos = line.find('=').ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid line in '{}', expected KEY=VALUE: '{}'",
+                    path.display(),
+                    redact(line)
+                ),
+            )
+        })?;
+        let key = match line[..pos].trim() {
+            "username" | "user" => "username",
+            "password" | "pass" => "password",
+            "domain" | "dom" => "domain",
+            key => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("Invalid key '{}' in '{}'", key, path.display()),
+                ))
+            }
+        };
+        // a comma in a value is escaped by doubling it in the cifs mount options
+        options.push(format!("{}={}", key, line[pos + 1..].replace(',', ",,")));
+    }
+    Ok(options)
+}
+
+/// The mount source and options, the kernel does not resolve host names so the address is passed
+fn get_mount_params(opts: &Options, share_url: &ShareUrl) -> Result<(String, String)> {
+    let port = if share_url.fs_type == "nfs" {
+        NFS_PORT
+    } else {
+        CIFS_PORT
+    };
+    let addr = resolve_host(&share_url.host, port)?[0].ip();
+
+    let mut options: Vec<String> = vec!["ro".to_string()];
+    let source = if share_url.fs_type == "nfs" {
+        options.push("nolock".to_string());
+        options.push(format!("addr={}", addr));
+        format!("{}:{}", share_url.host, share_url.remote_dir)
+    } else {
+        options.push(format!("ip={}", addr));
+        if let Some(credentials) = opts.cifs_credentials() {
+            options.append(&mut read_cifs_credentials(credentials)?);
+        } else {
+            options.push("guest".to_string());
+        }
+        format!("//{}/{}", share_url.host, share_url.remote_dir)
+    };
+
+    if let Some(share_mount_options) = opts.share_mount_options() {
+        options.push(share_mount_options.to_string());
+    }
+
+    Ok((source, options.join(",")))
+}
+
+/// A network share mounted to read the image from. It is unmounted when dropped unless kept
+/// for stage2, which unmounts it after copying the image to memory.
+#[derive(Debug)]
+pub(crate) struct ImageShare {
+    mountpoint: PathBuf,
+    keep: bool,
+}
+
+impl ImageShare {
+    /// Mount the share of the image URL, returns the share and the path of the image in it
+    pub fn mount(
+        opts: &Options,
+        url: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<(ImageShare, PathBuf)> {
+        let share_url = parse_share_url(url)?;
+
+        if !ensure_fs_supported(share_url.fs_type, runner) {
+            error!(
+                "The kernel does not support the {} file system and the module could not be loaded, please copy the image to the device and pass its path to --image",
+                share_url.fs_type
+            );
+            return Err(Error::displayed());
+        }
+
+        let (source, options) = get_mount_params(opts, &share_url)?;
+        let mountpoint = PathBuf::from(IMAGE_SHARE_MP);
+        create_dir_all(&mountpoint).upstream_with_context(&format!(
+            "Failed to create directory '{}'",
+            mountpoint.display()
+        ))?;
+
+        debug!(
+            "ImageShare::mount: mounting '{}' on '{}' with options '{}'",
+            source,
+            mountpoint.display(),
+            redact(&options)
+        );
+        if let Err(why) = retry_eintr(|| {
+            mount(
+                Some(source.as_str()),
+                &mountpoint,
+                Some(share_url.fs_type),
+                MsFlags::MS_RDONLY,
+                Some(options.as_str()),
+            )
+        }) {
+            let _res = remove_dir(&mountpoint);
+            error!(
+                "Failed to mount the image share '{}' on '{}', error: {}",
+                source,
+                mountpoint.display(),
+                why
+            );
+            return Err(Error::displayed());
+        }
+
+        let image_share = ImageShare {
+            mountpoint,
+            keep: false,
+        };
+
+        let image_path = path_append(&image_share.mountpoint, &share_url.file);
+        if !file_exists(&image_path) {
+            error!(
+                "The image '{}' could not be found on the share '{}'",
+                share_url.file, source
+            );
+            return Err(Error::displayed());
+        }
+
+        info!(
+            "Mounted the image share '{}' on '{}'",
+            source,
+            image_share.mountpoint.display()
+        );
+        Ok((image_share, image_path))
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        self.mountpoint.as_path()
+    }
+
+    /// Leave the share mounted when stage1 terminates, stage2 reads the image from it
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for ImageShare {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(why) = retry_eintr(|| umount2(&self.mountpoint, MntFlags::MNT_DETACH)) {
+            warn!(
+                "Failed to unmount the image share from '{}', error: {}",
+                self.mountpoint.display(),
+                why
+            );
+        } else if let Err(why) = remove_dir(&self.mountpoint) {
+            debug!(
+                "ImageShare::drop: failed to remove '{}': {}",
+                self.mountpoint.display(),
+                why
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_share_url() {
+        assert_eq!(
+            parse_share_url("nfs://10.0.0.5/srv/images/balena.img.gz").unwrap(),
+            ShareUrl {
+                fs_type: "nfs",
+                host: "10.0.0.5".to_string(),
+                remote_dir: "/srv/images".to_string(),
+                file: "balena.img.gz".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_share_url("cifs://fileserver/images/line-3/balena.img").unwrap(),
+            ShareUrl {
+                fs_type: "cifs",
+                host: "fileserver".to_string(),
+                remote_dir: "images".to_string(),
+                file: "line-3/balena.img".to_string(),
+            }
+        );
+        assert!(parse_share_url("nfs://10.0.0.5").is_err());
+        assert!(parse_share_url("nfs:///srv/balena.img").is_err());
+        assert!(parse_share_url("nfs://10.0.0.5/balena.img").is_err());
+        assert!(parse_share_url("cifs://fileserver/images").is_err());
+        assert!(parse_share_url("cifs://fileserver/images/").is_err());
+    }
+}
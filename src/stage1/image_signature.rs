@@ -0,0 +1,146 @@
+use std::fs::{remove_dir_all, set_permissions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use log::{debug, error, info, warn};
+
+use crate::{
+    common::{cmd_runner::CommandRunner, defs::GPG_CMD, Error, ErrorKind, Result, ToError},
+    stage1::utils::mktemp_with_fallback,
+};
+
+// gpg reports a good signature with a valid key on the status fd using this keyword
+const GPG_VALIDSIG: &str = "[GNUPG:] VALIDSIG ";
+
+/// Verify a detached GPG signature of the image against the supplied public key. A temporary
+/// gpg home directory in the work directory (or a fallback directory) is used, so the keyrings
+/// of the host are neither used nor modified.
+pub(crate) fn verify_image_signature(
+    image: &Path,
+    signature: &Path,
+    pubkey: &Path,
+    work_dir: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let gpg_home = mktemp_with_fallback(true, Some("gnupg-"), None, work_dir)?;
+    set_permissions(&gpg_home, Permissions::from_mode(0o700)).upstream_with_context(&format!(
+        "Failed to set permissions on '{}'",
+        gpg_home.display()
+    ))?;
+
+    let res = gpg_verify(image, signature, pubkey, &gpg_home, runner);
+
+    if let Err(why) = remove_dir_all(&gpg_home) {
+        warn!(
+            "Failed to remove temporary gpg home '{}', error: {}",
+            gpg_home.display(),
+            why
+        );
+    }
+    res
+}
+
+fn gpg_verify(
+    image: &Path,
+    signature: &Path,
+    pubkey: &Path,
+    gpg_home: &Path,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let gpg_home = gpg_home.to_string_lossy();
+
+    let cmd_res = runner.call(
+        GPG_CMD,
+        &[
+            "--homedir",
+            &*gpg_home,
+            "--batch",
+            "--quiet",
+            "--import",
+            &*pubkey.to_string_lossy(),
+        ],
+        true,
+    )?;
+    if !cmd_res.status.success() {
+        return Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "Failed to import public key '{}', error: {}",
+                pubkey.display(),
+                cmd_res.stderr
+            ),
+        ));
+    }
+
+    info!(
+        "Verifying signature '{}' of image '{}', this may take a while",
+        signature.display(),
+        image.display()
+    );
+
+    let cmd_res = runner.call(
+        GPG_CMD,
+        &[
+            "--homedir",
+            &*gpg_home,
+            "--batch",
+            "--status-fd",
+            "1",
+            "--verify",
+            &*signature.to_string_lossy(),
+            &*image.to_string_lossy(),
+        ],
+        true,
+    )?;
+
+    debug!("gpg_verify: status output: {}", cmd_res.stdout);
+
+    if cmd_res.status.success()
+        && cmd_res
+            .stdout
+            .lines()
+            .any(|line| line.starts_with(GPG_VALIDSIG))
+    {
+        info!(
+            "The signature of image '{}' was verified successfully",
+            image.display()
+        );
+        Ok(())
+    } else {
+        error!(
+            "The signature of image '{}' could not be verified using signature '{}' and public key '{}', error: {}",
+            image.display(),
+            signature.display(),
+            pubkey.display(),
+            cmd_res.stderr.trim()
+        );
+        Err(Error::displayed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::cmd_runner::mock::MockCommandRunner;
+
+    #[test]
+    fn test_gpg_verify() {
+        let paths = (
+            Path::new("/tmp/balena.img"),
+            Path::new("/tmp/balena.img.sig"),
+            Path::new("/tmp/key.asc"),
+            Path::new("/tmp/gnupg"),
+        );
+
+        let mut runner = MockCommandRunner::new();
+        runner.set_result(GPG_CMD, 0, "[GNUPG:] VALIDSIG 0123456789ABCDEF", "");
+        assert!(gpg_verify(paths.0, paths.1, paths.2, paths.3, &runner).is_ok());
+        assert_eq!(runner.calls().len(), 2);
+        assert!(runner.calls()[1].ends_with("--verify /tmp/balena.img.sig /tmp/balena.img"));
+
+        // a good signature made with an untrusted or expired key is not accepted
+        let mut runner = MockCommandRunner::new();
+        runner.set_result(GPG_CMD, 0, "[GNUPG:] GOODSIG 0123456789ABCDEF", "");
+        assert!(gpg_verify(paths.0, paths.1, paths.2, paths.3, &runner).is_err());
+    }
+}
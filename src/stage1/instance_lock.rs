@@ -0,0 +1,145 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+
+use crate::common::{dir_exists, path_append, Error, Result, ToError};
+
+const LOCK_NAME: &str = "takeover.lock";
+const LOCK_DIRS: [&str; 3] = ["/run", "/var/run", "/tmp"];
+
+/// System wide lock that prevents concurrent takeover invocations. The lock file holds the PID
+/// of the owner, the lock is released when the owner exits, also if it crashes.
+pub(crate) struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+fn is_alive(pid: i32) -> bool {
+    match kill(Pid::from_raw(pid), None) {
+        Ok(_) => true,
+        Err(why) => why.as_errno() == Some(Errno::EPERM),
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<i32> {
+    let mut pid_str = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut pid_str).ok()?;
+    pid_str.trim().parse::<i32>().ok()
+}
+
+impl InstanceLock {
+    pub fn acquire() -> Result<InstanceLock> {
+        let lock_dir = LOCK_DIRS
+            .iter()
+            .find(|dir| dir_exists(dir).unwrap_or(false))
+            .unwrap_or(&LOCK_DIRS[LOCK_DIRS.len() - 1]);
+        InstanceLock::acquire_at(&path_append(lock_dir, LOCK_NAME))
+    }
+
+    fn acquire_at(path: &Path) -> Result<InstanceLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .upstream_with_context(&format!("Failed to open lock file '{}'", path.display()))?;
+
+        if let Err(why) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            if why.as_errno() == Some(Errno::EAGAIN) {
+                match read_pid(&mut file) {
+                    Some(pid) if is_alive(pid) => error!(
+                        "Another takeover instance with PID {} is running, lock file: '{}'",
+                        pid,
+                        path.display()
+                    ),
+                    _ => error!(
+                        "Another takeover instance is running, lock file: '{}'",
+                        path.display()
+                    ),
+                }
+                return Err(Error::displayed());
+            }
+            return Err(Error::from_upstream(
+                Box::new(why),
+                &format!("Failed to lock '{}'", path.display()),
+            ));
+        }
+
+        if let Some(pid) = read_pid(&mut file) {
+            if !is_alive(pid) {
+                warn!(
+                    "Found stale lock file '{}' of PID {}, the previous run did not terminate properly",
+                    path.display(),
+                    pid
+                );
+            }
+        }
+
+        let pid = std::process::id();
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(format!("{}\n", pid).as_bytes()))
+            .upstream_with_context(&format!(
+                "Failed to write PID to lock file '{}'",
+                path.display()
+            ))?;
+
+        debug!("Acquired lock '{}' for PID {}", path.display(), pid);
+        Ok(InstanceLock {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // the file is not removed, a waiting instance might hold it open already
+        let _res = self.file.set_len(0);
+        debug!("Released lock '{}'", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    struct TestPath(PathBuf);
+
+    impl Drop for TestPath {
+        fn drop(&mut self) {
+            let _res = remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_instance_lock() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let path = TestPath(temp_dir().join(format!(
+            "takeover-test-{}-{}.lock",
+            std::process::id(),
+            nanos
+        )));
+        {
+            let _lock = InstanceLock::acquire_at(&path.0).unwrap();
+            assert!(InstanceLock::acquire_at(&path.0).is_err());
+        }
+        assert!(InstanceLock::acquire_at(&path.0).is_ok());
+    }
+}
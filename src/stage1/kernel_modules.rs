@@ -0,0 +1,129 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use log::{debug, info, warn};
+
+use crate::common::{
+    cmd_runner::CommandRunner,
+    defs::{BALENA_BOOT_FSTYPE, BALENA_DATA_FSTYPE, MODPROBE_CMD},
+    stage2_config::{ArchiveTarget, Stage2Config},
+    whereis,
+};
+
+const FILESYSTEMS_PATH: &str = "/proc/filesystems";
+const LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+const LOOP_MODULE_PATH: &str = "/sys/module/loop";
+const LOOP_MODULE: &str = "loop";
+
+/// The file systems the running kernel supports, built in or from loaded modules
+fn get_supported_fs_types() -> Vec<String> {
+    match read_to_string(FILESYSTEMS_PATH) {
+        // lines are 'nodev<TAB>proc' or '<TAB>ext4'
+        Ok(content) => content
+            .lines()
+            .filter_map(|line| line.split_whitespace().last())
+            .map(|fs_type| fs_type.to_string())
+            .collect(),
+        Err(why) => {
+            warn!("Failed to read '{}', error: {}", FILESYSTEMS_PATH, why);
+            Vec::new()
+        }
+    }
+}
+
+fn is_loop_supported() -> bool {
+    Path::new(LOOP_CONTROL_PATH).exists() || Path::new(LOOP_MODULE_PATH).exists()
+}
+
+/// Load a module, stage2 has no modprobe and no access to the modules of the old system, so
+/// missing modules have to be loaded before the takeover
+fn load_module(module: &str, runner: &dyn CommandRunner) -> bool {
+    let modprobe_path = match whereis(MODPROBE_CMD, runner) {
+        Ok(modprobe_path) => modprobe_path,
+        Err(why) => {
+            debug!("load_module: {} not found: {}", MODPROBE_CMD, why);
+            return false;
+        }
+    };
+
+    match runner.call(&modprobe_path, &[module], true) {
+        Ok(cmd_res) if cmd_res.status.success() => {
+            info!("Loaded kernel module '{}'", module);
+            true
+        }
+        Ok(cmd_res) => {
+            debug!(
+                "load_module: failed to load '{}': {}",
+                module, cmd_res.stderr
+            );
+            false
+        }
+        Err(why) => {
+            debug!("load_module: failed to load '{}': {}", module, why);
+            false
+        }
+    }
+}
+
+/// The file systems stage2 mounts and what it needs them for
+fn get_required_fs_types(s2_cfg: &Stage2Config) -> Vec<(String, &'static str)> {
+    let mut fs_types = vec![(
+        BALENA_BOOT_FSTYPE.to_string(),
+        "place the configuration on the balena boot partition",
+    )];
+    if s2_cfg.backup_path.is_some() {
+        fs_types.push((
+            BALENA_DATA_FSTYPE.to_string(),
+            "restore the backup to the balena data partition",
+        ));
+    }
+    if let Some(log_dev) = &s2_cfg.log_dev {
+        fs_types.push((log_dev.fs_type.clone(), "write the stage2 log"));
+    }
+    if let Some(ArchiveTarget::Device { fs_type, .. }) = &s2_cfg.archive_old_root {
+        fs_types.push((fs_type.clone(), "archive the old root file system"));
+    }
+    fs_types
+}
+
+/// Check that the kernel supports the file systems and loop devices stage2 needs after flashing,
+/// loading missing modules where possible. Missing ones are warned about, stage2 fails after the
+/// point of no return without them.
+pub(crate) fn check_kernel_modules(s2_cfg: &Stage2Config, runner: &dyn CommandRunner) {
+    if !is_loop_supported() && !(load_module(LOOP_MODULE, runner) && is_loop_supported()) {
+        warn!(
+            "The kernel does not support loop devices and the '{}' module could not be loaded, stage2 needs them to mount the flashed partitions",
+            LOOP_MODULE
+        );
+    }
+
+    let mut supported = get_supported_fs_types();
+    for (fs_type, purpose) in get_required_fs_types(s2_cfg) {
+        if supported.contains(&fs_type) {
+            debug!(
+                "check_kernel_modules: file system '{}' is supported",
+                fs_type
+            );
+            continue;
+        }
+
+        if load_module(&fs_type, runner) {
+            supported = get_supported_fs_types();
+            if supported.contains(&fs_type) {
+                continue;
+            }
+        }
+
+        warn!(
+            "The kernel does not support the {} file system and the module could not be loaded, stage2 needs it to {}",
+            fs_type, purpose
+        );
+    }
+}
+
+/// Check that the kernel supports fs_type, loading its module if required
+pub(crate) fn ensure_fs_supported(fs_type: &str, runner: &dyn CommandRunner) -> bool {
+    let fs_type = fs_type.to_string();
+    get_supported_fs_types().contains(&fs_type)
+        || (load_module(&fs_type, runner) && get_supported_fs_types().contains(&fs_type))
+}
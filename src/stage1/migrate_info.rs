@@ -1,23 +1,35 @@
 use log::{debug, error, info, warn};
 use nix::mount::umount;
-use std::fs::{read_to_string, remove_dir_all, OpenOptions};
+use std::fs::{read_dir, read_to_string, remove_dir_all, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::ptr::read_volatile;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::common::defs::BACKUP_ARCH_NAME;
 use crate::common::path_append;
 use crate::{
-    common::{file_exists, get_os_name, options::Options, Error, ErrorKind, Result, ToError},
+    common::{
+        cmd_runner::CommandRunner,
+        file_exists, get_os_name,
+        image_format::get_image_format,
+        image_slices::{is_slice_manifest, SliceManifest},
+        options::{is_share_url, NetworkBackend, Options},
+        Error, ErrorKind, Result, ToError,
+    },
     stage1::{
+        api_calls::DownloadHeaders,
         backup::config::backup_cfg_from_file,
         backup::{create, create_ext},
         defs::{DEV_TYPE_GEN_X86_64, GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON},
         device::Device,
-        device_impl::get_device,
+        hostname::resolve_hostname,
         image_retrieval::download_image,
-        migrate_info::balena_cfg_json::BalenaCfgJson,
-        utils::mktemp,
-        wifi_config::WifiConfig,
+        image_share::ImageShare,
+        image_signature::verify_image_signature,
+        migrate_info::balena_cfg_json::{parse_tag, BalenaCfgJson},
+        ssh_keys::read_ssh_keys,
+        utils::{mktemp, retry_eintr},
+        wifi_config::{check_nwmgr_file, WifiConfig},
     },
 };
 
@@ -37,22 +49,30 @@ pub(crate) struct MigrateInfo {
     mounts: Vec<PathBuf>,
     to_dir: Option<PathBuf>,
     image_path: PathBuf,
+    image_slices: Option<SliceManifest>,
+    image_share: Option<ImageShare>,
     device: Box<dyn Device>,
+    flash_offset: u64,
     config: BalenaCfgJson,
     work_dir: PathBuf,
     wifis: Vec<WifiConfig>,
     nwmgr_files: Vec<PathBuf>,
+    network_backend: NetworkBackend,
     backup: Option<PathBuf>,
+    total_staged_bytes: Option<u64>,
 }
 
 #[allow(dead_code)]
 impl MigrateInfo {
-    pub fn new(opts: &Options) -> Result<MigrateInfo> {
-        let device = get_device(opts)?;
+    pub fn new(
+        opts: &Options,
+        device: Box<dyn Device>,
+        runner: &dyn CommandRunner,
+    ) -> Result<MigrateInfo> {
         info!("Detected device type: {}", device.get_device_type());
 
-        let mut config = if let Some(balena_cfg) = opts.config() {
-            BalenaCfgJson::new(balena_cfg)?
+        let mut config = if !opts.config().is_empty() {
+            BalenaCfgJson::from_files(opts.config())?
         } else {
             match MigrateInfo::get_internal_cfg_json(&opts.work_dir()) {
                 Ok(balena_cfg_json) => balena_cfg_json,
@@ -84,7 +104,15 @@ impl MigrateInfo {
                 opts.work_dir().display()
             ))?;
 
-        let image_path = if let Some(image_path) = opts.image() {
+        let mut image_share: Option<ImageShare> = None;
+        let image_path = if opts.no_flash() {
+            info!("No image is flashed in no-flash mode");
+            PathBuf::new()
+        } else if let Some(url) = opts.image().as_ref().filter(|image| is_share_url(image)) {
+            let (share, image_path) = ImageShare::mount(opts, &url.to_string_lossy(), runner)?;
+            image_share = Some(share);
+            image_path
+        } else if let Some(image_path) = opts.image() {
             if file_exists(&image_path) {
                 image_path.canonicalize().upstream_with_context(&format!(
                     "Failed to canonicalize path '{}'",
@@ -103,6 +131,7 @@ impl MigrateInfo {
                 &work_dir,
                 config.get_device_type()?.as_str(),
                 opts.version(),
+                &DownloadHeaders::from_opts(opts)?,
             )?;
             image_path.canonicalize().upstream_with_context(&format!(
                 "Failed to canonicalize path '{}'",
@@ -119,18 +148,88 @@ impl MigrateInfo {
 
         debug!("image path: '{}'", image_path.display());
 
+        let image_slices = if opts.no_flash() {
+            None
+        } else if is_slice_manifest(&image_path) {
+            if opts.image_format().is_some() || opts.compress_image() || opts.zero_remainder() {
+                error!("The options --image-format, --compress-image and --zero-remainder can not be used with a slice manifest");
+                return Err(Error::displayed());
+            }
+            if opts.image_signature().is_some() {
+                error!("The option --image-signature can not be used with a slice manifest");
+                return Err(Error::displayed());
+            }
+            if opts.delta_flash() {
+                error!("The option --delta-flash can not be used with a slice manifest");
+                return Err(Error::displayed());
+            }
+            let image_slices = SliceManifest::from_file(&image_path)?;
+            info!(
+                "Image '{}' is a slice manifest with {} partitions",
+                image_path.display(),
+                image_slices.partitions.len()
+            );
+            Some(image_slices)
+        } else {
+            let image_format = get_image_format(&image_path, opts.image_format())?;
+            info!(
+                "Image '{}' will be processed as {} image",
+                image_path.display(),
+                image_format
+            );
+            None
+        };
+
+        if let (Some(signature), Some(pubkey)) = (opts.image_signature(), opts.image_pubkey()) {
+            verify_image_signature(&image_path, signature, pubkey, &work_dir, runner)?;
+        }
+
         let wifi_ssids = opts.wifis();
 
         let wifis: Vec<WifiConfig> = if !wifi_ssids.is_empty() || !opts.no_wifis() {
-            WifiConfig::scan(wifi_ssids)?
+            WifiConfig::scan(wifi_ssids, runner)?
         } else {
             Vec::new()
         };
 
-        let nwmgr_files = Vec::from(opts.nwmgr_cfg());
+        let network_backend = MigrateInfo::get_network_backend(opts, device.as_ref())?;
+
+        if !opts.nwmgr_cfg().is_empty() && network_backend != NetworkBackend::NetworkManager {
+            error!(
+                "NetworkManager files can not be used with network backend {}",
+                network_backend
+            );
+            return Err(Error::displayed());
+        }
+
+        let mut nwmgr_files: Vec<PathBuf> = Vec::new();
+        let mut skipped: Vec<String> = Vec::new();
+        for nwmgr_file in opts.nwmgr_cfg() {
+            match check_nwmgr_file(nwmgr_file) {
+                Ok(_) => nwmgr_files.push(nwmgr_file.clone()),
+                Err(why) => {
+                    if opts.continue_on_nonfatal() {
+                        warn!("Skipping network configuration: {}", why);
+                        skipped.push(nwmgr_file.display().to_string());
+                    } else {
+                        return Err(why);
+                    }
+                }
+            }
+        }
+
+        if !skipped.is_empty() {
+            warn!(
+                "Skipped {} invalid network configuration files: {}",
+                skipped.len(),
+                skipped.join(", ")
+            );
+        }
 
         if nwmgr_files.is_empty() && wifis.is_empty() {
-            if opts.no_nwmgr_check() {
+            if opts.default_dhcp_ethernet() {
+                info!("No network configurations were found, wired interfaces will be configured using DHCP");
+            } else if opts.no_nwmgr_check() {
                 warn!(
                     "No Network manager files were found, the device might not be able to come online"
                 );
@@ -147,7 +246,11 @@ impl MigrateInfo {
             let created = if opts.tar_internal() {
                 create(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
             } else {
-                create_ext(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
+                create_ext(
+                    backup_path.as_path(),
+                    backup_cfg_from_file(backup_cfg)?,
+                    runner,
+                )?
             };
             if created {
                 Some(backup_path)
@@ -158,7 +261,14 @@ impl MigrateInfo {
             None
         };
 
-        if opts.migrate_name() {
+        if let Some(template) = opts.hostname_template() {
+            let hostname = resolve_hostname(template)?;
+            info!(
+                "Writing hostname from template '{}' to config.json: '{}'",
+                template, hostname
+            );
+            config.set_host_name(&hostname);
+        } else if opts.migrate_name() {
             let hostname = read_to_string("/proc/sys/kernel/hostname")
                 .upstream_with_context("Failed to read file '/proc/sys/kernel/hostname'")?
                 .trim()
@@ -168,21 +278,98 @@ impl MigrateInfo {
             config.set_host_name(&hostname);
         }
 
+        if let Some(keys_path) = opts.ssh_authorized_keys() {
+            let keys = read_ssh_keys(keys_path)?;
+            let added = config.add_ssh_keys(&keys)?;
+            info!(
+                "Added {} of {} SSH keys from '{}' to config.json",
+                added,
+                keys.len(),
+                keys_path.display()
+            );
+        }
+
+        let os_name = get_os_name()?;
+        let mut tags: Vec<(String, String)> = Vec::new();
+        if opts.migration_tags() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .upstream_with_context("Failed to read the system time")?
+                .as_secs();
+            tags.push(("takeover_source_os".to_string(), os_name.clone()));
+            tags.push((
+                "takeover_version".to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            ));
+            tags.push(("takeover_migrated_at".to_string(), timestamp.to_string()));
+        }
+        for tag in opts.tags() {
+            tags.push(parse_tag(tag)?);
+        }
+
+        if !tags.is_empty() {
+            config.add_tags(&tags)?;
+            for (key, value) in &tags {
+                info!("Added tag '{}' = '{}' to config.json", key, value);
+            }
+        }
+
+        let flash_offset = if let Some(flash_offset) = opts.flash_offset() {
+            info!(
+                "Writing the image at offset 0x{:x} given with --flash-offset",
+                flash_offset
+            );
+            flash_offset
+        } else {
+            device.get_flash_offset()
+        };
+
         Ok(MigrateInfo {
             // assets: Assets::new(),
-            os_name: get_os_name()?,
+            os_name,
             to_dir: None,
             mounts: Vec::new(),
             config,
             image_path,
+            image_slices,
+            image_share,
+            flash_offset,
             device,
             work_dir,
             wifis,
             nwmgr_files,
+            network_backend,
             backup,
+            total_staged_bytes: None,
         })
     }
 
+    /// Use the configured network backend if the device type supports it, else the default
+    /// backend of the device type
+    fn get_network_backend(opts: &Options, device: &dyn Device) -> Result<NetworkBackend> {
+        let supported = device.get_network_backends();
+        if let Some(backend) = opts.network_backend() {
+            if supported.contains(&backend) {
+                Ok(backend)
+            } else {
+                let supported: Vec<String> = supported
+                    .iter()
+                    .map(|backend| backend.to_string())
+                    .collect();
+                error!(
+                    "Network backend {} is not supported by balena-os on device type {}, supported backends: {}",
+                    backend,
+                    device.get_device_type(),
+                    supported.join(", ")
+                );
+                Err(Error::displayed())
+            }
+        } else {
+            info!("Using network backend {}", supported[0]);
+            Ok(supported[0])
+        }
+    }
+
     pub fn update_config(&mut self) -> Result<()> {
         if self.config.is_modified() {
             let target_path = mktemp(false, Some("config."), Some(".json"), Some(&self.work_dir))?;
@@ -204,6 +391,76 @@ impl MigrateInfo {
         self.device.supports_device_type(DEV_TYPE_GEN_X86_64)
     }
 
+    /// Total size of all files that stage2 copies to tmpfs. It is computed on the first call and
+    /// cached, so it must not be called before all files are staged.
+    pub fn total_staged_bytes(&mut self) -> Result<u64> {
+        if let Some(total_staged_bytes) = self.total_staged_bytes {
+            return Ok(total_staged_bytes);
+        }
+
+        let mut files: Vec<PathBuf> = vec![self.config.get_path().to_path_buf()];
+
+        if let Some(ref image_slices) = self.image_slices {
+            for index in 0..image_slices.partitions.len() {
+                if let Some(image_path) = image_slices.image_path(index, Path::new("/")) {
+                    files.push(image_path);
+                }
+            }
+        } else if !self.image_path.as_os_str().is_empty() {
+            // there is no image in no-flash mode
+            files.push(self.image_path.clone());
+        }
+
+        if let Some(backup) = &self.backup {
+            files.push(backup.clone());
+        }
+
+        let nwmgr_path = path_append(&self.work_dir, self.network_backend.config_dir());
+        if nwmgr_path.exists() {
+            for dir_entry in read_dir(&nwmgr_path).upstream_with_context(&format!(
+                "Failed to read directory '{}'",
+                nwmgr_path.display()
+            ))? {
+                files.push(
+                    dir_entry
+                        .upstream_with_context(&format!(
+                            "Failed to read directory entry in '{}'",
+                            nwmgr_path.display()
+                        ))?
+                        .path(),
+                );
+            }
+        } else {
+            files.extend(self.nwmgr_files.iter().cloned());
+        }
+
+        let mut staged_size: u64 = 0;
+        for file in &files {
+            staged_size += file
+                .metadata()
+                .upstream_with_context(&format!(
+                    "Failed to retrieve file size for '{}'",
+                    file.display()
+                ))?
+                .len();
+        }
+
+        self.total_staged_bytes = Some(staged_size);
+        Ok(staged_size)
+    }
+
+    pub fn device_type(&self) -> String {
+        self.device.get_device_type().to_string()
+    }
+
+    pub fn flash_offset(&self) -> u64 {
+        self.flash_offset
+    }
+
+    pub fn flash_block_size(&self) -> u64 {
+        self.device.get_flash_block_size()
+    }
+
     pub fn backup(&self) -> Option<&Path> {
         if let Some(backup) = &self.backup {
             Some(backup.as_path())
@@ -216,6 +473,29 @@ impl MigrateInfo {
         self.image_path.as_path()
     }
 
+    pub fn image_share_mountpoint(&self) -> Option<&Path> {
+        if let Some(image_share) = &self.image_share {
+            Some(image_share.mountpoint())
+        } else {
+            None
+        }
+    }
+
+    /// Keep the image share mounted for stage2 once stage1 succeeded
+    pub fn keep_image_share(&mut self) {
+        if let Some(image_share) = &mut self.image_share {
+            image_share.keep();
+        }
+    }
+
+    pub fn set_image_path(&mut self, image_path: PathBuf) {
+        self.image_path = image_path
+    }
+
+    pub fn image_slices(&self) -> Option<&SliceManifest> {
+        self.image_slices.as_ref()
+    }
+
     pub fn balena_cfg(&self) -> &BalenaCfgJson {
         &self.config
     }
@@ -232,13 +512,17 @@ impl MigrateInfo {
         &self.nwmgr_files
     }
 
+    pub fn network_backend(&self) -> NetworkBackend {
+        self.network_backend
+    }
+
     pub fn wifis(&self) -> &Vec<WifiConfig> {
         &self.wifis
     }
 
     pub fn umount_all(&mut self) {
         while let Some(mountpoint) = self.mounts.pop() {
-            if let Err(why) = umount(&mountpoint) {
+            if let Err(why) = retry_eintr(|| umount(&mountpoint)) {
                 warn!(
                     "Failed to unmount mountpoint: '{}', error : {:?}",
                     mountpoint.display(),
@@ -1,25 +1,49 @@
 use crate::{
-    common::{Error, ErrorKind, Options, Result, ToError},
-    stage1::{device::Device, utils::check_tcp_connect},
+    common::{redact, Error, ErrorKind, Options, Result, ToError},
+    stage1::{
+        api_calls::{check_https_connect, get_device_by_uuid},
+        device::Device,
+        entropy::warn_if_entropy_low,
+        utils::{check_tcp_connect, resolve_host},
+    },
 };
 
-use log::{error, info};
-use serde_json::Value;
+use log::{error, info, warn};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use url::Url;
 
 pub const BALENA_API_PORT: u16 = 80;
+const API_PING_PATH: &str = "/ping";
+// fields a merged config.json has to contain
+const REQUIRED_FIELDS: [&str; 3] = ["applicationId", "deviceType", "apiEndpoint"];
+// the supervisor ignores this key, the tags have to be applied from config.json by other means
+const TAGS_KEY: &str = "takeoverTags";
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct BalenaCfgJson {
     config: HashMap<String, Value>,
     file: PathBuf,
     modified: bool,
 }
 
+impl fmt::Debug for BalenaCfgJson {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BalenaCfgJson")
+            .field(
+                "config",
+                &redact(&serde_json::to_string(&self.config).unwrap_or_default()),
+            )
+            .field("file", &self.file)
+            .field("modified", &self.modified)
+            .finish()
+    }
+}
+
 impl BalenaCfgJson {
     pub fn new<P: AsRef<Path>>(cfg_file: P) -> Result<BalenaCfgJson> {
         let cfg_file = cfg_file
@@ -31,21 +55,63 @@ impl BalenaCfgJson {
             ))?;
 
         Ok(BalenaCfgJson {
-            config: serde_json::from_reader(BufReader::new(
-                File::open(&cfg_file).upstream_with_context(&format!(
-                    "new: cannot open file '{}'",
-                    cfg_file.display()
-                ))?,
-            ))
-            .upstream_with_context(&format!(
-                "Failed to parse json from file '{}'",
-                cfg_file.display()
-            ))?,
+            config: BalenaCfgJson::read_file(&cfg_file)?,
             file: cfg_file,
             modified: false,
         })
     }
 
+    /// Read and deep-merge several config.json files in order, later files override values of
+    /// earlier ones. The merged config is written to the work directory like a modified config.
+    pub fn from_files(cfg_files: &[PathBuf]) -> Result<BalenaCfgJson> {
+        let (first, overlays) = if let Some((first, overlays)) = cfg_files.split_first() {
+            (first, overlays)
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "No config.json files given",
+            ));
+        };
+
+        let mut balena_cfg = BalenaCfgJson::new(first)?;
+        for cfg_file in overlays {
+            info!("Merging config.json '{}'", cfg_file.display());
+            for (name, value) in BalenaCfgJson::read_file(cfg_file)? {
+                merge_json(balena_cfg.config.entry(name).or_insert(Value::Null), value);
+            }
+            balena_cfg.modified = true;
+        }
+
+        if balena_cfg.modified {
+            let missing: Vec<&str> = REQUIRED_FIELDS
+                .iter()
+                .filter(|name| !balena_cfg.config.contains_key(**name))
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "The merged config.json is missing the required fields: {}",
+                        missing.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        Ok(balena_cfg)
+    }
+
+    fn read_file(cfg_file: &Path) -> Result<HashMap<String, Value>> {
+        serde_json::from_reader(BufReader::new(File::open(cfg_file).upstream_with_context(
+            &format!("new: cannot open file '{}'", cfg_file.display()),
+        )?))
+        .upstream_with_context(&format!(
+            "Failed to parse json from file '{}'",
+            cfg_file.display()
+        ))
+    }
+
     pub fn write<P: AsRef<Path>>(&mut self, target_path: P) -> Result<()> {
         let target_path = target_path.as_ref();
         let out_file = OpenOptions::new()
@@ -81,6 +147,10 @@ impl BalenaCfgJson {
             return Err(Error::displayed());
         }
 
+        if opts.check_uuid_unique() {
+            self.check_uuid_unique(opts)?;
+        }
+
         if opts.api_check() {
             let api_endpoint = &self.get_api_endpoint()?;
 
@@ -136,9 +206,133 @@ impl BalenaCfgJson {
             }
         }
 
+        // in addition to the checks above, --check-api-warn only applies to these
+        if opts.check_api() {
+            self.check_endpoints(opts)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check name resolution, TCP connection and for https the TLS handshake for the API and VPN
+    /// endpoints, reporting the status of each step
+    fn check_endpoints(&self, opts: &Options) -> Result<()> {
+        let api_endpoint = self.get_api_endpoint()?;
+        let api_url = Url::parse(&api_endpoint).upstream_with_context(&format!(
+            "Failed to parse balena api url '{}'",
+            api_endpoint
+        ))?;
+
+        let api_host = if let Some(api_host) = api_url.host_str() {
+            api_host.to_string()
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "failed to parse api server url from config.json: {}",
+                    api_endpoint
+                ),
+            ));
+        };
+
+        let api_port = if let Some(api_port) = api_url.port_or_known_default() {
+            api_port
+        } else {
+            BALENA_API_PORT
+        };
+
+        let mut failed =
+            !BalenaCfgJson::check_endpoint("api", &api_host, api_port, opts.check_timeout());
+
+        if api_url.scheme() == "https" {
+            let ping_url = format!("{}{}", api_endpoint.trim_end_matches('/'), API_PING_PATH);
+            match check_https_connect(&ping_url, opts.check_timeout()) {
+                Ok(_) => info!("api: TLS connection to '{}' is ok", ping_url),
+                Err(why) => {
+                    warn!("api: TLS connection to '{}' failed: {}", ping_url, why);
+                    failed = true;
+                }
+            }
+        }
+
+        let vpn_endpoint = self.get_vpn_endpoint()?;
+        let vpn_port = self.get_vpn_port()? as u16;
+        if !BalenaCfgJson::check_endpoint("vpn", &vpn_endpoint, vpn_port, opts.check_timeout()) {
+            failed = true;
+        }
+
+        if failed {
+            warn_if_entropy_low("Connecting to the balena endpoints");
+            if opts.check_api_warn() {
+                warn!("Not all balena endpoints are reachable, your device might not come online");
+            } else {
+                error!("Not all balena endpoints are reachable, your device might not come online");
+                return Err(Error::displayed());
+            }
+        }
+
         Ok(())
     }
 
+    /// Make sure that no other device is registered with the uuid in config.json, two devices
+    /// using the same uuid collide in the dashboard
+    fn check_uuid_unique(&self, opts: &Options) -> Result<()> {
+        let uuid = if let Some(uuid) = self.get_uuid() {
+            uuid
+        } else {
+            info!(
+                "config.json contains no uuid, balena-os will register the device with a new uuid"
+            );
+            return Ok(());
+        };
+
+        match get_device_by_uuid(
+            &self.get_api_endpoint()?,
+            &self.get_api_key()?,
+            &uuid,
+            opts.check_timeout(),
+        )? {
+            Some(device) => {
+                error!(
+                    "The uuid {} in config.json is already registered for device '{}', which is {}",
+                    uuid,
+                    device.device_name,
+                    if device.is_online {
+                        "online"
+                    } else {
+                        "offline"
+                    }
+                );
+                Err(Error::displayed())
+            }
+            None => {
+                info!("The uuid {} in config.json is not registered yet", uuid);
+                Ok(())
+            }
+        }
+    }
+
+    fn check_endpoint(name: &str, host: &str, port: u16, timeout: u64) -> bool {
+        match resolve_host(host, port) {
+            Ok(addrs) => info!("{}: '{}' resolves to {:?}", name, host, addrs),
+            Err(why) => {
+                warn!("{}: name resolution failed for '{}': {}", name, host, why);
+                return false;
+            }
+        }
+
+        match check_tcp_connect(host, port, timeout) {
+            Ok(_) => {
+                info!("{}: connection to {}:{} is ok", name, host, port);
+                true
+            }
+            Err(why) => {
+                warn!("{}: connection to {}:{} failed: {}", name, host, port, why);
+                false
+            }
+        }
+    }
+
     pub fn is_modified(&self) -> bool {
         self.modified
     }
@@ -207,10 +401,87 @@ impl BalenaCfgJson {
         }
     }
 
+    /// merge SSH public keys into os.sshKeys, keys already present are kept, returns the number
+    /// of keys added
+    pub fn add_ssh_keys(&mut self, keys: &[String]) -> Result<usize> {
+        let os_cfg = self
+            .config
+            .entry("os".to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let os_cfg = if let Some(os_cfg) = os_cfg.as_object_mut() {
+            os_cfg
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "Invalid type encountered for 'os', expected Object in config.json",
+            ));
+        };
+
+        let ssh_keys = os_cfg
+            .entry("sshKeys")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        let ssh_keys = if let Some(ssh_keys) = ssh_keys.as_array_mut() {
+            ssh_keys
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "Invalid type encountered for 'os.sshKeys', expected Array in config.json",
+            ));
+        };
+
+        let mut added = 0;
+        for key in keys {
+            if !ssh_keys
+                .iter()
+                .any(|curr| curr.as_str() == Some(key.as_str()))
+            {
+                ssh_keys.push(Value::String(key.clone()));
+                added += 1;
+            }
+        }
+
+        if added > 0 {
+            self.modified = true;
+        }
+        Ok(added)
+    }
+
+    /// merge tags into takeoverTags, tags already present are replaced
+    pub fn add_tags(&mut self, tags: &[(String, String)]) -> Result<()> {
+        let tags_cfg = self
+            .config
+            .entry(TAGS_KEY.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let tags_cfg = if let Some(tags_cfg) = tags_cfg.as_object_mut() {
+            tags_cfg
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid type encountered for '{}', expected Object in config.json",
+                    TAGS_KEY
+                ),
+            ));
+        };
+
+        for (key, value) in tags {
+            tags_cfg.insert(key.clone(), Value::String(value.clone()));
+        }
+
+        if !tags.is_empty() {
+            self.modified = true;
+        }
+        Ok(())
+    }
+
     pub fn get_app_id(&self) -> Result<u64> {
         self.get_uint_val("applicationId")
     }
 
+    fn get_uuid(&self) -> Option<String> {
+        self.get_str_val("uuid").ok()
+    }
+
     pub fn get_api_key(&self) -> Result<String> {
         self.get_str_val("apiKey")
     }
@@ -235,3 +506,79 @@ impl BalenaCfgJson {
         &self.file
     }
 }
+
+/// split a tag given as 'key=value', the value may be empty
+pub(crate) fn parse_tag(tag: &str) -> Result<(String, String)> {
+    if let Some(pos) = tag.find('=') {
+        let key = tag[..pos].trim();
+        if !key.is_empty() && !key.contains(char::is_whitespace) {
+            return Ok((key.to_string(), tag[pos + 1..].trim().to_string()));
+        }
+    }
+    Err(Error::with_context(
+        ErrorKind::InvParam,
+        &format!(
+            "Invalid tag '{}', expected 'key=value' with a key without whitespace",
+            tag
+        ),
+    ))
+}
+
+/// merge overlay into target, objects are merged recursively, other values are replaced
+fn merge_json(target: &mut Value, overlay: Value) {
+    match (target, overlay) {
+        (Value::Object(target), Value::Object(overlay)) => {
+            for (name, value) in overlay {
+                merge_json(target.entry(name).or_insert(Value::Null), value);
+            }
+        }
+        (target, overlay) => *target = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("migrated_by=takeover").unwrap(),
+            ("migrated_by".to_string(), "takeover".to_string())
+        );
+        assert_eq!(
+            parse_tag("site = berlin=1").unwrap(),
+            ("site".to_string(), "berlin=1".to_string())
+        );
+        assert_eq!(
+            parse_tag("empty=").unwrap(),
+            ("empty".to_string(), String::new())
+        );
+        assert!(parse_tag("novalue").is_err());
+        assert!(parse_tag("=value").is_err());
+        assert!(parse_tag("two words=value").is_err());
+    }
+
+    #[test]
+    fn test_merge_json() {
+        let mut target = json!({
+            "apiEndpoint": "https://api.balena-cloud.com",
+            "os": {"sshKeys": ["ssh-ed25519 AAAA base"], "udevRules": {"56": "rule"}},
+            "uuid": "base"
+        });
+        merge_json(
+            &mut target,
+            json!({"os": {"sshKeys": ["ssh-ed25519 AAAA device"]}, "uuid": "device", "deviceApiKey": "key"}),
+        );
+        assert_eq!(
+            target,
+            json!({
+                "apiEndpoint": "https://api.balena-cloud.com",
+                "os": {"sshKeys": ["ssh-ed25519 AAAA device"], "udevRules": {"56": "rule"}},
+                "uuid": "device",
+                "deviceApiKey": "key"
+            })
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{path_append, stage2_config::Stage2Config, Error, ErrorKind, Result, ToError};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The decisions stage1 made for a migration together with what they were based on. A saved
+/// plan can be replayed on the same device with the same image and config.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct MigrationPlan {
+    takeover_version: String,
+    device_type: String,
+    flash_dev: PathBuf,
+    flash_dev_size: u64,
+    image_sha256: String,
+    config_sha256: String,
+    stage2_config: Stage2Config,
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .upstream_with_context(&format!("Failed to open file '{}'", path.display()))?;
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut hasher = Sha256::new();
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .upstream_with_context(&format!("Failed to read from file '{}'", path.display()))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..bytes_read]);
+    }
+
+    Ok(hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn get_dev_size(dev_path: &Path) -> Result<u64> {
+    let dev_name = dev_path.file_name().ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid device path '{}'", dev_path.display()),
+        )
+    })?;
+    let size_path = path_append(path_append("/sys/block", dev_name), "size");
+    Ok(read_to_string(&size_path)
+        .upstream_with_context(&format!("Failed to read '{}'", size_path.display()))?
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!(
+            "Failed to parse device size from '{}'",
+            size_path.display()
+        ))?
+        * 512)
+}
+
+impl MigrationPlan {
+    pub fn new(device_type: &str, stage2_config: Stage2Config) -> Result<MigrationPlan> {
+        Ok(MigrationPlan {
+            takeover_version: env!("CARGO_PKG_VERSION").to_string(),
+            device_type: device_type.to_string(),
+            flash_dev: stage2_config.flash_dev.clone(),
+            flash_dev_size: get_dev_size(&stage2_config.flash_dev)?,
+            image_sha256: file_sha256(&stage2_config.image_path)?,
+            config_sha256: file_sha256(&stage2_config.config_path)?,
+            stage2_config,
+        })
+    }
+
+    pub fn load(plan_path: &Path) -> Result<MigrationPlan> {
+        let plan_str = read_to_string(plan_path).upstream_with_context(&format!(
+            "Failed to read migration plan from '{}'",
+            plan_path.display()
+        ))?;
+        serde_yaml::from_str(&plan_str).upstream_with_context(&format!(
+            "Failed to parse migration plan from '{}'",
+            plan_path.display()
+        ))
+    }
+
+    pub fn save(&self, plan_path: &Path) -> Result<()> {
+        let plan_str = serde_yaml::to_string(self)
+            .upstream_with_context("Failed to serialize migration plan")?;
+        let mut plan_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(plan_path)
+            .upstream_with_context(&format!(
+                "Failed to open migration plan file for writing: '{}'",
+                plan_path.display()
+            ))?;
+        plan_file
+            .write_all(plan_str.as_bytes())
+            .upstream_with_context(&format!(
+                "Failed to write migration plan to '{}'",
+                plan_path.display()
+            ))?;
+        info!("Saved migration plan to '{}'", plan_path.display());
+        Ok(())
+    }
+
+    /// Check that the device, image and config of the current run are the ones the plan was
+    /// made for, all mismatches are reported
+    pub fn verify(&self, current: &MigrationPlan) -> Result<()> {
+        let mut mismatches: Vec<String> = Vec::new();
+
+        if self.device_type != current.device_type {
+            mismatches.push(format!(
+                "device type is {}, the plan was made for {}",
+                current.device_type, self.device_type
+            ));
+        }
+
+        if self.flash_dev != current.flash_dev || self.flash_dev_size != current.flash_dev_size {
+            mismatches.push(format!(
+                "flash device is '{}' with {} bytes, the plan was made for '{}' with {} bytes",
+                current.flash_dev.display(),
+                current.flash_dev_size,
+                self.flash_dev.display(),
+                self.flash_dev_size
+            ));
+        }
+
+        let umount_devs = |s2_cfg: &Stage2Config| -> Vec<PathBuf> {
+            s2_cfg
+                .umount_parts
+                .iter()
+                .map(|part| part.dev_name.clone())
+                .collect()
+        };
+        if umount_devs(&self.stage2_config) != umount_devs(&current.stage2_config) {
+            mismatches.push("the partitions to unmount differ from the plan".to_string());
+        }
+
+        if self.image_sha256 != current.image_sha256 {
+            mismatches.push(format!(
+                "image '{}' does not match the image of the plan",
+                current.stage2_config.image_path.display()
+            ));
+        }
+
+        if self.config_sha256 != current.config_sha256 {
+            mismatches.push("config.json does not match the config.json of the plan".to_string());
+        }
+
+        if mismatches.is_empty() {
+            info!("The device matches the migration plan");
+            Ok(())
+        } else {
+            for mismatch in &mismatches {
+                error!("Migration plan mismatch: {}", mismatch);
+            }
+            error!("The device changed since the migration plan was saved, refusing to migrate");
+            Err(Error::displayed())
+        }
+    }
+
+    /// The stage2 config of the plan with the paths of the current run, the files stage2 reads
+    /// were verified to have the same contents. A plan saved in a --pretend run flashes when
+    /// it is replayed without --pretend.
+    pub fn replay(self, current: Stage2Config) -> Stage2Config {
+        Stage2Config {
+            pretend: current.pretend,
+            work_dir: current.work_dir,
+            image_path: current.image_path,
+            config_path: current.config_path,
+            backup_path: current.backup_path,
+            stage2_script: current.stage2_script,
+            tty: current.tty,
+            ..self.stage2_config
+        }
+    }
+}
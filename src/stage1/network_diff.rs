@@ -0,0 +1,198 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_dir, read_to_string};
+
+use log::debug;
+
+use crate::common::{cmd_runner::CommandRunner, path_append, Result, ToError};
+
+const SYS_NET_DIR: &str = "/sys/class/net";
+const IW_CMD: &str = "iw";
+
+/// A physical network interface of the host that is up
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ActiveLink {
+    Wired {
+        interface: String,
+    },
+    Wifi {
+        interface: String,
+        ssid: Option<String>,
+    },
+}
+
+impl ActiveLink {
+    pub fn interface(&self) -> &str {
+        match self {
+            ActiveLink::Wired { interface } => interface,
+            ActiveLink::Wifi { interface, .. } => interface,
+        }
+    }
+}
+
+/// How an active connection of the host or a configured wifi changes with the migration
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum NetworkChange {
+    /// active on the host and set up in balena-os
+    Kept(String),
+    /// configured for balena-os but not active on the host
+    Added(String),
+    /// active on the host but not set up in balena-os
+    Lost(String),
+}
+
+impl Display for NetworkChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkChange::Kept(desc) => write!(f, "= {}", desc),
+            NetworkChange::Added(desc) => write!(f, "+ {}", desc),
+            NetworkChange::Lost(desc) => write!(f, "- {}", desc),
+        }
+    }
+}
+
+/// get the SSID a wifi interface is associated with from 'iw dev <interface> link'
+fn get_ssid(runner: &dyn CommandRunner, interface: &str) -> Option<String> {
+    match runner.call_command(
+        IW_CMD,
+        &["dev", interface, "link"],
+        &format!("Failed to query link of '{}'", interface),
+    ) {
+        Ok(output) => output.lines().find_map(|line| {
+            let line = line.trim();
+            if line.starts_with("SSID:") {
+                Some(line["SSID:".len()..].trim().to_string())
+            } else {
+                None
+            }
+        }),
+        Err(why) => {
+            debug!(
+                "get_ssid: failed to retrieve SSID of '{}': {}",
+                interface, why
+            );
+            None
+        }
+    }
+}
+
+/// List the physical network interfaces that are up, virtual interfaces like bridges or
+/// container networks are skipped as they do not provide connectivity by themselves
+pub(crate) fn get_active_links(runner: &dyn CommandRunner) -> Result<Vec<ActiveLink>> {
+    let mut links: Vec<ActiveLink> = Vec::new();
+    for entry in read_dir(SYS_NET_DIR)
+        .upstream_with_context(&format!("Failed to read directory '{}'", SYS_NET_DIR))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry of '{}'",
+            SYS_NET_DIR
+        ))?;
+        let if_path = entry.path();
+        let interface = entry.file_name().to_string_lossy().to_string();
+
+        if !path_append(&if_path, "device").exists() {
+            debug!(
+                "get_active_links: skipping virtual interface '{}'",
+                interface
+            );
+            continue;
+        }
+
+        let operstate = read_to_string(path_append(&if_path, "operstate")).unwrap_or_default();
+        if operstate.trim() != "up" {
+            debug!(
+                "get_active_links: skipping interface '{}' in state '{}'",
+                interface,
+                operstate.trim()
+            );
+            continue;
+        }
+
+        if path_append(&if_path, "wireless").exists() || path_append(&if_path, "phy80211").exists()
+        {
+            let ssid = get_ssid(runner, &interface);
+            links.push(ActiveLink::Wifi { interface, ssid });
+        } else {
+            links.push(ActiveLink::Wired { interface });
+        }
+    }
+    links.sort_by(|link1, link2| link1.interface().cmp(link2.interface()));
+    Ok(links)
+}
+
+/// Compare the wifis configured for balena-os to the active links of the host. Wired
+/// interfaces are kept as balena-os configures them using DHCP.
+pub(crate) fn diff_network(configured: &[String], active: &[ActiveLink]) -> Vec<NetworkChange> {
+    let mut changes: Vec<NetworkChange> = Vec::new();
+    for link in active {
+        match link {
+            ActiveLink::Wired { interface } => {
+                changes.push(NetworkChange::Kept(format!("{} (wired, DHCP)", interface)))
+            }
+            ActiveLink::Wifi {
+                interface,
+                ssid: Some(ssid),
+            } => {
+                let desc = format!("wifi '{}' on {}", ssid, interface);
+                if configured.iter().any(|curr| curr == ssid) {
+                    changes.push(NetworkChange::Kept(desc));
+                } else {
+                    changes.push(NetworkChange::Lost(desc));
+                }
+            }
+            ActiveLink::Wifi {
+                interface,
+                ssid: None,
+            } => changes.push(NetworkChange::Lost(format!(
+                "wifi on {} (SSID unknown)",
+                interface
+            ))),
+        }
+    }
+
+    for ssid in configured {
+        let is_active = active.iter().any(|link| match link {
+            ActiveLink::Wifi {
+                ssid: Some(active_ssid),
+                ..
+            } => active_ssid == ssid,
+            _ => false,
+        });
+        if !is_active {
+            changes.push(NetworkChange::Added(format!("wifi '{}'", ssid)));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_network() {
+        let active = vec![
+            ActiveLink::Wired {
+                interface: "eth0".to_string(),
+            },
+            ActiveLink::Wifi {
+                interface: "wlan0".to_string(),
+                ssid: Some("office".to_string()),
+            },
+            ActiveLink::Wifi {
+                interface: "wlan1".to_string(),
+                ssid: Some("lab".to_string()),
+            },
+        ];
+        let configured = vec!["lab".to_string(), "home".to_string()];
+
+        assert_eq!(
+            diff_network(&configured, &active),
+            vec![
+                NetworkChange::Kept("eth0 (wired, DHCP)".to_string()),
+                NetworkChange::Lost("wifi 'office' on wlan0".to_string()),
+                NetworkChange::Kept("wifi 'lab' on wlan1".to_string()),
+                NetworkChange::Added("wifi 'home'".to_string()),
+            ]
+        );
+    }
+}
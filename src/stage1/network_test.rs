@@ -0,0 +1,274 @@
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, File};
+use std::io::Write;
+use std::path::Path;
+
+use log::{debug, error, info, warn};
+
+use crate::{
+    common::{
+        cmd_runner::CommandRunner, path_append, pidof, system::set_root_only, Error, Options,
+        Result, ToError,
+    },
+    stage1::{
+        api_calls::check_https_connect, migrate_info::MigrateInfo, utils::mktemp_with_fallback,
+        wifi_config::replace_nwmgr_id,
+    },
+};
+
+const NMCLI_CMD: &str = "nmcli";
+const NWMGR_PROC: &str = "NetworkManager";
+// connection files in here do not survive a reboot of the host
+const NWMGR_RUNTIME_DIR: &str = "/run/NetworkManager/system-connections";
+const TEST_CONN_PREFIX: &str = "takeover-test";
+const CONNECT_TIMEOUT: &str = "45";
+const API_PING_PATH: &str = "/ping";
+
+/// The UUIDs of the connections NetworkManager has active
+fn get_active_connections(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    Ok(runner
+        .call_command(
+            NMCLI_CMD,
+            &["-t", "-f", "UUID", "connection", "show", "--active"],
+            "Failed to list active connections",
+        )?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+/// Bring connections back up that were active before the test and are not anymore
+fn restore_connections(runner: &dyn CommandRunner, prev_active: &[String]) {
+    let active = match get_active_connections(runner) {
+        Ok(active) => active,
+        Err(why) => {
+            error!(
+                "Failed to restore the network connections of the host: {}",
+                why
+            );
+            return;
+        }
+    };
+
+    for uuid in prev_active.iter().filter(|uuid| !active.contains(uuid)) {
+        info!("Restoring network connection {}", uuid);
+        if let Err(why) = runner.call_command(
+            NMCLI_CMD,
+            &["--wait", CONNECT_TIMEOUT, "connection", "up", "uuid", uuid],
+            &format!("Failed to bring up connection {}", uuid),
+        ) {
+            error!(
+                "Failed to restore network connection {} of the host: {}",
+                uuid, why
+            );
+        }
+    }
+}
+
+/// Load the connection file, bring the connection up and run check on it. The connection is
+/// deleted and the previously active connections are restored whatever the outcome.
+fn run_test(
+    runner: &dyn CommandRunner,
+    id: &str,
+    conn_path: &Path,
+    prev_active: &[String],
+    check: &dyn Fn() -> Result<()>,
+) -> Result<()> {
+    let conn_path = conn_path.to_string_lossy();
+    runner.call_command(
+        NMCLI_CMD,
+        &["connection", "load", &conn_path],
+        &format!("Failed to load connection file '{}'", conn_path),
+    )?;
+
+    let res = runner
+        .call_command(
+            NMCLI_CMD,
+            &["--wait", CONNECT_TIMEOUT, "connection", "up", "id", id],
+            &format!("Failed to bring up connection '{}'", id),
+        )
+        .and_then(|_| check());
+
+    if let Err(why) = runner.call_command(
+        NMCLI_CMD,
+        &["connection", "delete", "id", id],
+        &format!("Failed to delete connection '{}'", id),
+    ) {
+        warn!("{}", why);
+    }
+    restore_connections(runner, prev_active);
+    res
+}
+
+/// The contents of the NetworkManager files that will be set up in balena-os together with a
+/// description for the log
+fn get_test_configs(opts: &Options, mig_info: &MigrateInfo) -> Result<Vec<(String, String)>> {
+    let mut configs: Vec<(String, String)> = Vec::new();
+    for nwmgr_file in mig_info.nwmgr_files() {
+        configs.push((
+            format!("'{}'", nwmgr_file.display()),
+            read_to_string(nwmgr_file).upstream_with_context(&format!(
+                "Failed to read file '{}'",
+                nwmgr_file.display()
+            ))?,
+        ));
+    }
+
+    if !mig_info.wifis().is_empty() {
+        let tmp_dir = mktemp_with_fallback(true, Some("network-test."), None, opts.work_dir())?;
+        let res: Result<()> = mig_info
+            .wifis()
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, wifi)| {
+                let index = wifi.create_nwmgr_file(&tmp_dir, index as u64 + 1)?;
+                let file_path = path_append(&tmp_dir, &format!("resin-wifi-{}", index));
+                configs.push((
+                    format!("wifi '{}'", wifi.get_ssid()),
+                    read_to_string(&file_path).upstream_with_context(&format!(
+                        "Failed to read file '{}'",
+                        file_path.display()
+                    ))?,
+                ));
+                Ok(())
+            });
+        if let Err(why) = remove_dir_all(&tmp_dir) {
+            warn!(
+                "Failed to remove directory '{}', error: {:?}",
+                tmp_dir.display(),
+                why
+            );
+        }
+        res?;
+    }
+
+    Ok(configs)
+}
+
+/// Bring the network configurations of balena-os up on the host one at a time and check that
+/// the balena API can be reached, the connections active before are restored after each test.
+/// Only hosts managed by NetworkManager can be tested.
+pub(crate) fn test_network(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    if pidof(NWMGR_PROC, runner)?.is_empty() {
+        warn!("NetworkManager is not running on this host, skipping the network test");
+        return Ok(());
+    }
+
+    let configs = get_test_configs(opts, mig_info)?;
+    if configs.is_empty() {
+        info!("No network configurations to test");
+        return Ok(());
+    }
+
+    let ping_url = format!(
+        "{}{}",
+        mig_info
+            .balena_cfg()
+            .get_api_endpoint()?
+            .trim_end_matches('/'),
+        API_PING_PATH
+    );
+    let prev_active = get_active_connections(runner)?;
+    debug!("test_network: active connections: {:?}", prev_active);
+
+    warn!(
+        "Testing network configurations, the network connections of this host will be interrupted"
+    );
+    create_dir_all(NWMGR_RUNTIME_DIR).upstream_with_context(&format!(
+        "Failed to create directory '{}'",
+        NWMGR_RUNTIME_DIR
+    ))?;
+
+    let mut failed: Vec<String> = Vec::new();
+    for (index, (desc, content)) in configs.iter().enumerate() {
+        let id = format!("{}-{}", TEST_CONN_PREFIX, index + 1);
+        let conn_path = path_append(NWMGR_RUNTIME_DIR, &format!("{}.nmconnection", id));
+        info!("Testing network configuration {}", desc);
+
+        let res = replace_nwmgr_id(content, &id).and_then(|content| {
+            let mut conn_file = File::create(&conn_path).upstream_with_context(&format!(
+                "Failed to create file '{}'",
+                conn_path.display()
+            ))?;
+            set_root_only(&conn_path)?;
+            conn_file
+                .write_all(content.as_bytes())
+                .upstream_with_context(&format!(
+                    "Failed to write to file '{}'",
+                    conn_path.display()
+                ))?;
+            run_test(runner, &id, &conn_path, &prev_active, &|| {
+                check_https_connect(&ping_url, opts.check_timeout())
+            })
+        });
+
+        if conn_path.exists() {
+            if let Err(why) = remove_file(&conn_path) {
+                warn!(
+                    "Failed to remove file '{}', error: {:?}",
+                    conn_path.display(),
+                    why
+                );
+            }
+        }
+
+        match res {
+            Ok(_) => info!("Network configuration {} reached '{}'", desc, ping_url),
+            Err(why) => {
+                error!("Network configuration {} failed: {}", desc, why);
+                failed.push(desc.clone());
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        info!(
+            "All {} network configurations passed the test",
+            configs.len()
+        );
+        Ok(())
+    } else {
+        error!(
+            "{} of {} network configurations failed the test: {}",
+            failed.len(),
+            configs.len(),
+            failed.join(", ")
+        );
+        Err(Error::displayed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{cmd_runner::mock::MockCommandRunner, ErrorKind};
+
+    #[test]
+    fn test_run_test_restores_connections() {
+        let runner = MockCommandRunner::new();
+        let prev_active = vec!["0d5e2a4c".to_string()];
+
+        let res = run_test(
+            &runner,
+            "takeover-test-1",
+            Path::new("/run/test.nmconnection"),
+            &prev_active,
+            &|| Err(Error::with_context(ErrorKind::InvState, "unreachable")),
+        );
+        assert!(res.is_err());
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "nmcli connection load /run/test.nmconnection",
+                "nmcli --wait 45 connection up id takeover-test-1",
+                "nmcli connection delete id takeover-test-1",
+                "nmcli -t -f UUID connection show --active",
+                "nmcli --wait 45 connection up uuid 0d5e2a4c",
+            ]
+        );
+    }
+}
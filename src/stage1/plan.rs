@@ -0,0 +1,394 @@
+use std::fs::{metadata, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        cmd_runner::SysCommandRunner,
+        crc32_update, dir_exists,
+        disk_util::LabelType,
+        format_size_with_unit, get_mem_info,
+        image_format::{get_image_format, ImageFormat},
+        is_admin,
+        options::{is_share_url, Options},
+        Result, ToError,
+    },
+    stage1::{
+        benchmark::measure_write_throughput,
+        block_device_info::BlockDeviceInfo,
+        defs::DEV_TYPE_GEN_X86_64,
+        device_impl::get_device,
+        exe_copy::ExeCopy,
+        get_copy_commands, get_flash_device,
+        migrate_info::balena_cfg_json::BalenaCfgJson,
+        network_diff::{diff_network, get_active_links, NetworkChange},
+        wifi_config::{get_nwmgr_file_ssid, WifiConfig},
+    },
+};
+
+const CRC_BUFFER_SIZE: usize = 1024 * 1024;
+
+// the migration time estimate is based on writing these amounts of data to tmpfs and to a file
+// system on the flash device
+const TMPFS_BENCH_DIR: &str = "/dev/shm";
+const TMPFS_BENCH_SIZE: u64 = 16 * 1024 * 1024;
+const FLASH_BENCH_SIZE: u64 = 4 * 1024 * 1024;
+// time spent restarting init, unmounting and rebooting, not measured
+const EST_FIXED_SECS: u64 = 120;
+
+// steps of stage1 that modify the system and are never executed in plan mode
+const NOT_EXECUTED: [&str; 6] = [
+    "disable swap",
+    "mount takeover tmpfs and copy files",
+    "copy executables",
+    "write stage2 config",
+    "replace init and restart it using telinit",
+    "flash the image",
+];
+
+fn print_item(name: &str, value: &str) {
+    println!("  {:<20} {}", format!("{}:", name), value);
+}
+
+fn print_failed(name: &str, why: &dyn std::fmt::Display) {
+    print_item(name, &format!("FAILED: {}", why));
+}
+
+fn file_crc32(path: &Path) -> Result<u32> {
+    let mut file = File::open(path)
+        .upstream_with_context(&format!("Failed to open file '{}'", path.display()))?;
+    let mut buffer = vec![0u8; CRC_BUFFER_SIZE];
+    let mut crc: u32 = 0;
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .upstream_with_context(&format!("Failed to read from file '{}'", path.display()))?;
+        if bytes_read == 0 {
+            return Ok(crc);
+        }
+        crc = crc32_update(crc, &buffer[0..bytes_read]);
+    }
+}
+
+/// The size of the data written to the flash device, None if it can not be determined cheaply.
+/// For gzip images the size is taken from the gzip trailer which is only valid for images
+/// smaller than 4 GiB.
+fn get_flash_size(image_path: &Path, image_format: ImageFormat, image_size: u64) -> Option<u64> {
+    match image_format {
+        ImageFormat::Raw => Some(image_size),
+        ImageFormat::Gzip => {
+            let mut file = File::open(image_path).ok()?;
+            file.seek(SeekFrom::End(-4)).ok()?;
+            let mut trailer = [0u8; 4];
+            file.read_exact(&mut trailer).ok()?;
+            let flash_size = u64::from(u32::from_le_bytes(trailer));
+            if flash_size >= image_size {
+                Some(flash_size)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    if secs >= 60 {
+        format!("{} min {} s", secs / 60, secs % 60)
+    } else {
+        format!("{} s", secs)
+    }
+}
+
+/// Print an estimate of the migration duration from the measured tmpfs and flash device
+/// throughput
+fn print_estimate(image_size: Option<u64>, flash_size: Option<u64>, flash_mount: Option<&Path>) {
+    println!("Estimate (measured on this system, the actual duration may differ):");
+
+    let tmpfs_rate = if dir_exists(TMPFS_BENCH_DIR).unwrap_or(false) {
+        match measure_write_throughput(Path::new(TMPFS_BENCH_DIR), TMPFS_BENCH_SIZE) {
+            Ok(rate) => {
+                print_item(
+                    "tmpfs throughput",
+                    &format!("{}/s", format_size_with_unit(rate)),
+                );
+                Some(rate)
+            }
+            Err(why) => {
+                print_failed("tmpfs throughput", &why);
+                None
+            }
+        }
+    } else {
+        print_item(
+            "tmpfs throughput",
+            &format!("skipped ('{}' not found)", TMPFS_BENCH_DIR),
+        );
+        None
+    };
+
+    let flash_rate = if let Some(flash_mount) = flash_mount {
+        match measure_write_throughput(flash_mount, FLASH_BENCH_SIZE) {
+            Ok(rate) => {
+                print_item(
+                    "flash throughput",
+                    &format!("{}/s", format_size_with_unit(rate)),
+                );
+                Some(rate)
+            }
+            Err(why) => {
+                print_failed("flash throughput", &why);
+                None
+            }
+        }
+    } else {
+        print_item(
+            "flash throughput",
+            "skipped (requires root and a mounted partition on the flash device)",
+        );
+        None
+    };
+
+    match (image_size, tmpfs_rate, flash_rate) {
+        (Some(image_size), Some(tmpfs_rate), Some(flash_rate)) => {
+            let (flash_size, qualifier) = if let Some(flash_size) = flash_size {
+                (flash_size, "")
+            } else {
+                // uncompressed size is unknown, the compressed size gives a lower bound
+                (image_size, "at least ")
+            };
+            let secs = image_size / std::cmp::max(tmpfs_rate, 1)
+                + flash_size / std::cmp::max(flash_rate, 1)
+                + EST_FIXED_SECS;
+            print_item(
+                "estimated duration",
+                &format!("{}{} (estimate)", qualifier, format_duration(secs)),
+            );
+        }
+        (None, _, _) => print_item("estimated duration", "not available, image size unknown"),
+        _ => print_item(
+            "estimated duration",
+            "not available, throughput could not be measured",
+        ),
+    }
+}
+
+/// Print the wifis configured for balena-os against the active connections of the host
+fn print_network_diff(opts: &Options, runner: &SysCommandRunner) {
+    println!("Network (= carried over, + added, - lost):");
+
+    let mut configured: Vec<String> = Vec::new();
+    if !opts.wifis().is_empty() || !opts.no_wifis() {
+        match WifiConfig::scan(opts.wifis(), runner) {
+            Ok(wifis) => configured.extend(wifis.iter().map(|wifi| wifi.get_ssid().to_string())),
+            Err(why) => print_failed("wifi scan", &why),
+        }
+    }
+    for nwmgr_file in opts.nwmgr_cfg() {
+        match get_nwmgr_file_ssid(nwmgr_file) {
+            Ok(Some(ssid)) => configured.push(ssid),
+            Ok(None) => (),
+            Err(why) => print_failed(&nwmgr_file.display().to_string(), &why),
+        }
+    }
+    configured.sort();
+    configured.dedup();
+
+    let active = match get_active_links(runner) {
+        Ok(active) => active,
+        Err(why) => {
+            print_failed("active connections", &why);
+            return;
+        }
+    };
+
+    let changes = diff_network(&configured, &active);
+    for change in &changes {
+        println!("  {}", change);
+    }
+
+    let kept = changes
+        .iter()
+        .any(|change| matches!(change, NetworkChange::Kept(_)));
+    if !active.is_empty() && !kept {
+        print_item(
+            "network check",
+            "FAILED: no active connection is carried over, the device might be unreachable after migration",
+        );
+    }
+}
+
+/// Print what takeover would do without modifying the system.
+/// Checks that fail are reported and do not stop the plan, checks that require root privileges
+/// are skipped when not running as root.
+pub(crate) fn print_plan(opts: &Options) -> Result<()> {
+    let runner = SysCommandRunner::new();
+    let admin = is_admin()?;
+
+    println!("Takeover plan:");
+    print_item("running as root", if admin { "yes" } else { "no" });
+
+    let device = match get_device(opts, &runner) {
+        Ok(device) => {
+            print_item("device type", &device.get_device_type().to_string());
+            Some(device)
+        }
+        Err(why) => {
+            print_failed("device type", &why);
+            None
+        }
+    };
+
+    let mut req_space: u64 = 0;
+    let mut image_size: Option<u64> = None;
+    let mut flash_size: Option<u64> = None;
+    let mut flash_mount: Option<PathBuf> = None;
+
+    if !opts.config().is_empty() {
+        let config_paths: Vec<String> = opts
+            .config()
+            .iter()
+            .map(|config_path| config_path.display().to_string())
+            .collect();
+        print_item("config", &config_paths.join(", "));
+        match BalenaCfgJson::from_files(opts.config()) {
+            Ok(balena_cfg) => {
+                match balena_cfg.get_device_type() {
+                    Ok(device_type) => print_item("config device type", &device_type),
+                    Err(why) => print_failed("config device type", &why),
+                }
+                match balena_cfg.get_app_id() {
+                    Ok(app_id) => print_item("application id", &app_id.to_string()),
+                    Err(why) => print_failed("application id", &why),
+                }
+                match balena_cfg.get_api_endpoint() {
+                    Ok(api_endpoint) => print_item("api endpoint", &api_endpoint),
+                    Err(why) => print_failed("api endpoint", &why),
+                }
+                if let Some(ref device) = device {
+                    match balena_cfg.check(opts, device.as_ref()) {
+                        Ok(_) => print_item("config check", "ok"),
+                        Err(why) => print_failed("config check", &why),
+                    }
+                }
+                for config_path in opts.config() {
+                    if let Ok(config_meta) = metadata(config_path) {
+                        req_space += config_meta.len();
+                    }
+                }
+            }
+            Err(why) => print_failed("config", &why),
+        }
+    } else {
+        print_item("config", "not given");
+    }
+
+    if let Some(image_path) = opts.image().as_ref().filter(|image| is_share_url(image)) {
+        print_item(
+            "image",
+            &format!("on network share '{}', not inspected", image_path.display()),
+        );
+    } else if let Some(image_path) = opts.image() {
+        print_item("image", &image_path.display().to_string());
+        match metadata(image_path) {
+            Ok(image_meta) => {
+                req_space += image_meta.len();
+                image_size = Some(image_meta.len());
+                print_item("image size", &format_size_with_unit(image_meta.len()));
+                match get_image_format(image_path, opts.image_format()) {
+                    Ok(image_format) => {
+                        print_item("image format", &image_format.to_string());
+                        flash_size = get_flash_size(image_path, image_format, image_meta.len());
+                    }
+                    Err(why) => print_failed("image format", &why),
+                }
+                match file_crc32(image_path) {
+                    Ok(crc) => print_item("image crc32", &format!("{:08x}", crc)),
+                    Err(why) => print_failed("image crc32", &why),
+                }
+            }
+            Err(why) => print_failed("image size", &why),
+        }
+    } else {
+        print_item(
+            "image",
+            &format!("would be downloaded, version: '{}'", opts.version()),
+        );
+    }
+
+    let is_x86 = if let Some(ref device) = device {
+        device.supports_device_type(DEV_TYPE_GEN_X86_64)
+    } else {
+        false
+    };
+
+    match get_copy_commands(opts, is_x86).and_then(|commands| ExeCopy::new(commands, &runner)) {
+        Ok(commands) => {
+            req_space += commands.get_req_space();
+            print_item(
+                "executables size",
+                &format_size_with_unit(commands.get_req_space()),
+            );
+        }
+        Err(why) => print_failed("executables size", &why),
+    }
+
+    print_item("required space", &format_size_with_unit(req_space));
+    match get_mem_info() {
+        Ok((_mem_tot, mem_free)) => {
+            print_item("free memory", &format_size_with_unit(mem_free));
+            if mem_free < req_space {
+                print_item("memory check", "FAILED: not enough free memory");
+            }
+        }
+        Err(why) => print_failed("free memory", &why),
+    }
+
+    match BlockDeviceInfo::new(opts.ignore_fs_types(), &runner) {
+        Ok(block_dev_info) => match get_flash_device(opts, &block_dev_info) {
+            Ok(flash_dev) => {
+                print_item(
+                    "flash device",
+                    &flash_dev.get_dev_path().display().to_string(),
+                );
+                if admin {
+                    flash_mount = block_dev_info
+                        .get_devices()
+                        .values()
+                        .filter(|device| {
+                            device.get_name() == flash_dev.get_name()
+                                || device.get_parent().map(|parent| parent.get_name())
+                                    == Some(flash_dev.get_name())
+                        })
+                        .find_map(|device| {
+                            device
+                                .get_mountpoint()
+                                .as_ref()
+                                .map(|mount| mount.get_mountpoint().to_path_buf())
+                        });
+                    match LabelType::from_device(flash_dev.get_dev_path()) {
+                        Ok(label_type) => {
+                            print_item("partition table", &format!("{:?}", label_type))
+                        }
+                        Err(why) => print_failed("partition table", &why),
+                    }
+                } else {
+                    print_item("partition table", "skipped (requires root)");
+                }
+            }
+            Err(why) => print_failed("flash device", &why),
+        },
+        Err(why) => print_failed("flash device", &why),
+    }
+
+    print_network_diff(opts, &runner);
+
+    print_estimate(image_size, flash_size, flash_mount.as_deref());
+
+    println!("Not executed in plan mode:");
+    for step in NOT_EXECUTED.iter() {
+        println!("  - {}", step);
+    }
+
+    Ok(())
+}
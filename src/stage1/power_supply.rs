@@ -0,0 +1,210 @@
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use log::{debug, error, info, warn};
+
+use crate::common::{options::Options, path_append, Error, Result};
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+#[derive(Debug, Clone, PartialEq)]
+struct PowerSupply {
+    // Mains, Battery, USB, UPS ..
+    kind: String,
+    online: Option<bool>,
+    capacity: Option<u64>,
+    status: Option<String>,
+    // System or Device, peripherals like wireless mice report their batteries as Device
+    scope: Option<String>,
+}
+
+/// The power state of the device derived from its power supplies
+#[derive(Debug, Clone, PartialEq)]
+enum PowerState {
+    /// no power supplies are reported, e.g. most single board computers and servers
+    Unknown,
+    /// mains or USB power is present
+    ExternalPower,
+    /// running on battery with the lowest reported charge in percent
+    Battery(Option<u64>),
+}
+
+fn read_attribute(supply_dir: &Path, name: &str) -> Option<String> {
+    read_to_string(path_append(supply_dir, name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+fn get_power_supplies() -> Vec<PowerSupply> {
+    let entries = match read_dir(POWER_SUPPLY_DIR) {
+        Ok(entries) => entries,
+        Err(why) => {
+            debug!(
+                "get_power_supplies: failed to read '{}': {}",
+                POWER_SUPPLY_DIR, why
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut supplies: Vec<PowerSupply> = Vec::new();
+    for entry in entries.flatten() {
+        let supply_dir = entry.path();
+        if let Some(kind) = read_attribute(&supply_dir, "type") {
+            let supply = PowerSupply {
+                kind,
+                online: read_attribute(&supply_dir, "online").map(|online| online == "1"),
+                capacity: read_attribute(&supply_dir, "capacity")
+                    .and_then(|capacity| capacity.parse::<u64>().ok()),
+                status: read_attribute(&supply_dir, "status"),
+                scope: read_attribute(&supply_dir, "scope"),
+            };
+            debug!(
+                "get_power_supplies: '{}': {:?}",
+                supply_dir.display(),
+                supply
+            );
+            supplies.push(supply);
+        }
+    }
+    supplies
+}
+
+fn get_power_state(supplies: &[PowerSupply]) -> PowerState {
+    // supplies of peripherals do not power the device
+    let supplies: Vec<&PowerSupply> = supplies
+        .iter()
+        .filter(|supply| supply.scope.as_deref() != Some("Device"))
+        .collect();
+    let external = supplies
+        .iter()
+        .any(|supply| supply.kind != "Battery" && supply.online == Some(true));
+    let batteries: Vec<&PowerSupply> = supplies
+        .iter()
+        .copied()
+        .filter(|supply| supply.kind == "Battery")
+        .collect();
+    // a charging battery implies external power even if the adapter is not reported
+    let charging = batteries.iter().any(|battery| {
+        matches!(
+            battery.status.as_ref().map(|status| status.as_str()),
+            Some("Charging") | Some("Full")
+        )
+    });
+
+    if external || charging {
+        PowerState::ExternalPower
+    } else if !batteries.is_empty() {
+        PowerState::Battery(
+            batteries
+                .iter()
+                .filter_map(|battery| battery.capacity)
+                .min(),
+        )
+    } else {
+        PowerState::Unknown
+    }
+}
+
+/// Check that the device does not run on a low battery before the takeover starts, a device that
+/// loses power while flashing does not boot. Fails unless --ignore-power is given.
+pub(crate) fn check_power(opts: &Options) -> Result<()> {
+    match get_power_state(&get_power_supplies()) {
+        PowerState::Unknown => {
+            info!("No power supply status reported, assuming the device runs on mains power");
+            Ok(())
+        }
+        PowerState::ExternalPower => {
+            info!("The device runs on external power");
+            Ok(())
+        }
+        PowerState::Battery(capacity) => {
+            let min_battery = opts.min_battery();
+            let low = if let Some(capacity) = capacity {
+                info!("The device runs on battery with {}% charge left", capacity);
+                capacity < min_battery
+            } else {
+                info!("The device runs on battery with unknown charge");
+                true
+            };
+
+            if !low {
+                warn!(
+                    "No external power is connected, the device must not lose power while flashing"
+                );
+                Ok(())
+            } else if opts.ignore_power() {
+                warn!(
+                    "The battery charge is below {}% and no external power is connected, proceeding due to --ignore-power",
+                    min_battery
+                );
+                Ok(())
+            } else {
+                error!(
+                    "The battery charge is below {}% and no external power is connected, a device that loses power while flashing does not boot. Connect external power or use --ignore-power",
+                    min_battery
+                );
+                Err(Error::displayed())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(
+        kind: &str,
+        online: Option<bool>,
+        capacity: Option<u64>,
+        status: &str,
+    ) -> PowerSupply {
+        PowerSupply {
+            kind: kind.to_string(),
+            online,
+            capacity,
+            status: Some(status.to_string()),
+            scope: None,
+        }
+    }
+
+    #[test]
+    fn test_get_power_state() {
+        assert_eq!(get_power_state(&[]), PowerState::Unknown);
+        assert_eq!(
+            get_power_state(&[
+                supply("Mains", Some(false), None, ""),
+                supply("Battery", None, Some(40), "Discharging"),
+            ]),
+            PowerState::Battery(Some(40))
+        );
+        assert_eq!(
+            get_power_state(&[
+                supply("Mains", Some(true), None, ""),
+                supply("Battery", None, Some(40), "Discharging"),
+            ]),
+            PowerState::ExternalPower
+        );
+        assert_eq!(
+            get_power_state(&[supply("Battery", None, Some(90), "Charging")]),
+            PowerState::ExternalPower
+        );
+        assert_eq!(
+            get_power_state(&[
+                supply("Battery", None, Some(70), "Discharging"),
+                supply("Battery", None, None, "Discharging"),
+                supply("Battery", None, Some(30), "Discharging"),
+            ]),
+            PowerState::Battery(Some(30))
+        );
+
+        let mut mouse = supply("Battery", None, Some(5), "Discharging");
+        mouse.scope = Some("Device".to_string());
+        assert_eq!(get_power_state(&[mouse.clone()]), PowerState::Unknown);
+        assert_eq!(
+            get_power_state(&[supply("Battery", None, Some(80), "Discharging"), mouse]),
+            PowerState::Battery(Some(80))
+        );
+    }
+}
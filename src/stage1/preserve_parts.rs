@@ -0,0 +1,212 @@
+use std::fs::read_to_string;
+use std::rc::Rc;
+
+use log::{info, warn};
+
+use crate::{
+    common::{
+        disk_util::{Disk, PartitionIterator, DEF_BLOCK_SIZE},
+        format_size_with_unit,
+        image_format::{get_image_format, ImageFormat},
+        path_append,
+        stage2_config::PreservePart,
+        Error, ErrorKind, Options, Result, ToError,
+    },
+    stage1::{
+        block_device_info::{BlockDevice, BlockDeviceInfo},
+        migrate_info::MigrateInfo,
+    },
+};
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+const SECTOR_SIZE: u64 = 512;
+
+/// The partitions the new image creates
+enum TargetParts {
+    /// partition number, start, size and whether the slice manifest writes an image into it
+    Slices(Vec<(usize, u64, u64, bool)>),
+    /// partition number and size from the partition table of the image
+    Image(Vec<(usize, u64)>),
+    /// the partition table can not be read from the image before it is flashed
+    Unknown,
+}
+
+fn read_sys_attr(dev_name: &str, attr: &str) -> Result<u64> {
+    let attr_path = path_append(path_append(SYS_CLASS_BLOCK, dev_name), attr);
+    read_to_string(&attr_path)
+        .upstream_with_context(&format!("Failed to read '{}'", attr_path.display()))?
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!("Failed to parse '{}'", attr_path.display()))
+}
+
+/// Find the partition of the flash device given by number, file system label or GPT partition
+/// label
+fn find_partition(
+    spec: &str,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<PreservePart> {
+    let number = spec.parse::<usize>().ok();
+    for device in block_dev_info.get_devices().values() {
+        if device.get_parent().map(|parent| parent.get_name()) != Some(flash_dev.get_name()) {
+            continue;
+        }
+
+        let index = read_sys_attr(device.get_name(), "partition")? as usize;
+        let matches = if let Some(number) = number {
+            index == number
+        } else if let Some(part_info) = device.get_partition_info() {
+            part_info.fs_label() == Some(spec) || part_info.label() == Some(spec)
+        } else {
+            false
+        };
+
+        if matches {
+            return Ok(PreservePart {
+                name: spec.to_string(),
+                index,
+                start: read_sys_attr(device.get_name(), "start")? * SECTOR_SIZE,
+                size: read_sys_attr(device.get_name(), "size")? * SECTOR_SIZE,
+                in_place: false,
+            });
+        }
+    }
+
+    Err(Error::with_context(
+        ErrorKind::DeviceNotFound,
+        &format!(
+            "The partition '{}' to preserve was not found on the flash device '{}'",
+            spec,
+            flash_dev.get_dev_path().display()
+        ),
+    ))
+}
+
+fn get_target_parts(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    flash_dev: &Rc<dyn BlockDevice>,
+) -> Result<TargetParts> {
+    if let Some(image_slices) = mig_info.image_slices() {
+        let dev_size = read_sys_attr(flash_dev.get_name(), "size")? * SECTOR_SIZE;
+        return Ok(TargetParts::Slices(
+            image_slices
+                .layout(dev_size)?
+                .iter()
+                .zip(image_slices.partitions.iter())
+                .enumerate()
+                .map(|(index, (layout, partition))| {
+                    (
+                        index + 1,
+                        layout.start,
+                        layout.size,
+                        partition.image.is_some(),
+                    )
+                })
+                .collect(),
+        ));
+    }
+
+    let image_path = mig_info.image_path();
+    let mut disk = match get_image_format(image_path, opts.image_format())? {
+        ImageFormat::Raw => Disk::from_drive_file(image_path, None)?,
+        ImageFormat::Gzip => Disk::from_gzip_img(image_path)?,
+        _ => return Ok(TargetParts::Unknown),
+    };
+    let parts = PartitionIterator::new(&mut disk)?
+        .map(|part| (part.index, part.num_sectors * DEF_BLOCK_SIZE as u64))
+        .collect();
+    Ok(TargetParts::Image(parts))
+}
+
+/// Resolve the partitions given with --preserve-partition and check that they fit into the
+/// partitions with the same number the new image creates
+pub(crate) fn get_preserve_parts(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<Vec<PreservePart>> {
+    let mut preserve_parts: Vec<PreservePart> = Vec::new();
+    if opts.preserve_partitions().is_empty() {
+        return Ok(preserve_parts);
+    }
+
+    let target_parts = get_target_parts(opts, mig_info, flash_dev)?;
+
+    for spec in opts.preserve_partitions() {
+        let mut part = find_partition(spec, flash_dev, block_dev_info)?;
+        if preserve_parts.iter().any(|curr| curr.index == part.index) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Partition {} is preserved more than once", part.index),
+            ));
+        }
+
+        let target_size = match target_parts {
+            TargetParts::Slices(ref slices) => slices
+                .iter()
+                .find(|(index, _, _, _)| *index == part.index)
+                .map(|(_, start, size, written)| {
+                    part.in_place = !written && *start == part.start && *size >= part.size;
+                    *size
+                }),
+            TargetParts::Image(ref parts) => parts
+                .iter()
+                .find(|(index, _)| *index == part.index)
+                .map(|(_, size)| *size),
+            TargetParts::Unknown => {
+                warn!(
+                    "The partition table of the image can not be read in this image format, stage2 checks that partition '{}' fits after flashing",
+                    spec
+                );
+                None
+            }
+        };
+
+        match target_size {
+            Some(target_size) if target_size < part.size => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Partition '{}' of {} does not fit into partition {} of {} created by the new image",
+                        spec,
+                        format_size_with_unit(part.size),
+                        part.index,
+                        format_size_with_unit(target_size)
+                    ),
+                ));
+            }
+            None if !matches!(target_parts, TargetParts::Unknown) => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "The new image has no partition {} to restore partition '{}' to",
+                        part.index, spec
+                    ),
+                ));
+            }
+            _ => (),
+        }
+
+        if part.in_place {
+            info!(
+                "Partition '{}' (partition {}, {}) is left in place, the slice manifest does not write it",
+                spec,
+                part.index,
+                format_size_with_unit(part.size)
+            );
+        } else {
+            info!(
+                "Partition '{}' (partition {}, {}) is saved in memory and written back after flashing",
+                spec,
+                part.index,
+                format_size_with_unit(part.size)
+            );
+        }
+        preserve_parts.push(part);
+    }
+
+    Ok(preserve_parts)
+}
@@ -0,0 +1,95 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use openssl::base64::decode_block;
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+const SSH_KEY_TYPES: [&str; 8] = [
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Check that a public key line is '<type> <base64 blob> [comment]' and that the blob encodes
+/// the given key type
+fn parse_ssh_key(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    let key_type = words.next()?;
+    if !SSH_KEY_TYPES.contains(&key_type) {
+        return None;
+    }
+
+    let blob = decode_block(words.next()?).ok()?;
+    if blob.len() < 4 {
+        return None;
+    }
+    let type_len = u32::from_be_bytes([blob[0], blob[1], blob[2], blob[3]]) as usize;
+    if blob.len() < 4 + type_len || &blob[4..4 + type_len] != key_type.as_bytes() {
+        return None;
+    }
+
+    Some(line.trim().to_string())
+}
+
+/// Read SSH public keys in authorized_keys format, empty lines and comments are skipped. Keys
+/// with options are not supported as balena-os does not accept them in config.json.
+pub(crate) fn read_ssh_keys(keys_path: &Path) -> Result<Vec<String>> {
+    let content = read_to_string(keys_path).upstream_with_context(&format!(
+        "Failed to read SSH keys from '{}'",
+        keys_path.display()
+    ))?;
+
+    let mut keys: Vec<String> = Vec::new();
+    for (line_no, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(key) = parse_ssh_key(line) {
+            keys.push(key);
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid SSH public key in line {} of '{}'",
+                    line_no + 1,
+                    keys_path.display()
+                ),
+            ));
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No SSH public keys found in '{}'", keys_path.display()),
+        ));
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ssh_key() {
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHVzZXJrZXlkYXRhZm9ydGVzdGluZ29ubHkxMjM0NTY3 tech@site";
+        assert_eq!(parse_ssh_key(key).unwrap(), key);
+        // blob encodes a different key type
+        assert!(parse_ssh_key(
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIHVzZXJrZXlkYXRhZm9ydGVzdGluZ29ubHkxMjM0NTY3"
+        )
+        .is_none());
+        assert!(parse_ssh_key("ssh-ed25519 not-base64").is_none());
+        assert!(parse_ssh_key("command=\"ls\" ssh-ed25519 AAAA").is_none());
+    }
+}
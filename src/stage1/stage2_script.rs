@@ -0,0 +1,239 @@
+use std::fs::{read_to_string, set_permissions, File, Permissions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use log::{debug, info};
+use regex::Regex;
+
+use crate::common::{
+    defs::{OLD_ROOT_MP, STAGE2_SCRIPT_PATH},
+    path_append, Error, ErrorKind, Result, ToError,
+};
+
+// shells that can be copied to the takeover directory to run the stage2 script
+const SCRIPT_SHELLS: [&str; 4] = ["sh", "ash", "dash", "bash"];
+const SHELL_DIRS: [&str; 2] = ["/bin", "/usr/bin"];
+const ENV_PATH: &str = "/usr/bin/env";
+
+lazy_static! {
+    static ref TEMPLATE_VAR_REGEX: Regex = Regex::new(r"__[A-Z][A-Z0-9_]*__").unwrap();
+}
+
+/// Parse the shebang line of the stage2 script, returns the shell and its arguments. The shell
+/// has to be one of SCRIPT_SHELLS, called directly or using env.
+fn parse_shebang(script: &str) -> Option<(&'static str, Vec<&str>)> {
+    let first_line = script.lines().next()?;
+    if !first_line.starts_with("#!") {
+        return None;
+    }
+
+    let mut words = first_line[2..].split_whitespace();
+    let mut interpreter = words.next()?;
+    if interpreter == ENV_PATH {
+        interpreter = words.next()?;
+    } else {
+        let interpreter_path = Path::new(interpreter);
+        if !SHELL_DIRS
+            .iter()
+            .any(|dir| interpreter_path.parent() == Some(Path::new(dir)))
+        {
+            return None;
+        }
+    }
+
+    let shell_name = Path::new(interpreter).file_name()?.to_str()?;
+    let shell = SCRIPT_SHELLS.iter().find(|shell| **shell == shell_name)?;
+    Some((shell, words.collect()))
+}
+
+fn read_script(script_path: &Path) -> Result<String> {
+    read_to_string(script_path).upstream_with_context(&format!(
+        "Failed to read stage2 script '{}'",
+        script_path.display()
+    ))
+}
+
+/// The shell the stage2 script is run with, it has to be copied to the takeover directory
+pub(crate) fn get_script_shell(script_path: &Path) -> Result<&'static str> {
+    let script = read_script(script_path)?;
+    if let Some((shell, _args)) = parse_shebang(&script) {
+        Ok(shell)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The stage2 script '{}' is not a shell script, the first line has to call one of {:?} from {:?} or using {}",
+                script_path.display(),
+                SCRIPT_SHELLS,
+                SHELL_DIRS,
+                ENV_PATH
+            ),
+        ))
+    }
+}
+
+/// The template variables of the stage2 script and their values
+fn get_template_vars(tty: &Path, log_level: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("__TAKEOVER__", format!("/bin/{}", env!("CARGO_PKG_NAME"))),
+        ("__TTY__", tty.to_string_lossy().to_string()),
+        ("__LOG_LEVEL__", log_level.to_string()),
+        ("__OLD_ROOT__", OLD_ROOT_MP.to_string()),
+    ]
+}
+
+/// Check that every template variable in text is known and has a value
+fn check_template_vars(text: &str, vars: &[(&'static str, String)]) -> Result<()> {
+    for token in TEMPLATE_VAR_REGEX.find_iter(text) {
+        let name = token.as_str();
+        match vars.iter().find(|(var, _value)| *var == name) {
+            Some((_var, value)) if value.is_empty() => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("The stage2 script template variable {} has no value", name),
+                ))
+            }
+            Some(_) => (),
+            None => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Unknown template variable {} in the stage2 script, supported are {}",
+                        name,
+                        vars.iter()
+                            .map(|(var, _value)| *var)
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the shebang line with the shell in the takeover directory and substitute the template
+/// variables
+fn render_script(script: &str, vars: &[(&'static str, String)]) -> Option<String> {
+    let (shell, args) = parse_shebang(script)?;
+    let mut rendered = format!("#!/bin/{}", shell);
+    for arg in args {
+        rendered.push(' ');
+        rendered.push_str(arg);
+    }
+    rendered.push('\n');
+
+    for line in script.lines().skip(1) {
+        let mut line = line.to_string();
+        for (var, value) in vars {
+            line = line.replace(var, value);
+        }
+        rendered.push_str(&line);
+        rendered.push('\n');
+    }
+    Some(rendered)
+}
+
+/// Write the stage2 script to the takeover directory, returns its path after the new init has
+/// switched root to the takeover directory. Init runs the script instead of stage2 with the
+/// arguments it would pass to stage2, the script is expected to exec __TAKEOVER__ "$@" once its
+/// own tasks are done.
+pub(crate) fn write_stage2_script(
+    script_path: &Path,
+    takeover_dir: &Path,
+    tty: &Path,
+    log_level: &str,
+) -> Result<PathBuf> {
+    let script = read_script(script_path)?;
+    let vars = get_template_vars(tty, log_level);
+    check_template_vars(&script, &vars).error_with_all(
+        ErrorKind::InvParam,
+        &format!("Invalid stage2 script '{}'", script_path.display()),
+    )?;
+    let rendered = if let Some(rendered) = render_script(&script, &vars) {
+        // a substituted value must not leave a template variable behind
+        if let Some(token) = TEMPLATE_VAR_REGEX.find(&rendered) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The template variable {} remains in the rendered stage2 script '{}'",
+                    token.as_str(),
+                    script_path.display()
+                ),
+            ));
+        }
+        rendered
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The stage2 script '{}' is not a shell script",
+                script_path.display()
+            ),
+        ));
+    };
+
+    let target_path = path_append(takeover_dir, STAGE2_SCRIPT_PATH);
+    let mut file = File::create(&target_path).upstream_with_context(&format!(
+        "Failed to create stage2 script '{}'",
+        target_path.display()
+    ))?;
+    file.write_all(rendered.as_bytes())
+        .upstream_with_context(&format!(
+            "Failed to write stage2 script '{}'",
+            target_path.display()
+        ))?;
+    set_permissions(&target_path, Permissions::from_mode(0o755)).upstream_with_context(
+        &format!(
+            "Failed to set permissions on stage2 script '{}'",
+            target_path.display()
+        ),
+    )?;
+
+    debug!("write_stage2_script: rendered script:\n{}", rendered);
+    info!(
+        "Copied stage2 script '{}' to '{}'",
+        script_path.display(),
+        target_path.display()
+    );
+    Ok(PathBuf::from(STAGE2_SCRIPT_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_script() {
+        let script = "#!/usr/bin/env bash -e\necho __TTY__ > __OLD_ROOT__/log\nexec __TAKEOVER__ --stage2 --s2-log-level __LOG_LEVEL__\n";
+        let vars = get_template_vars(Path::new("/dev/pts/0"), "debug");
+        let rendered = render_script(script, &vars).unwrap();
+        assert_eq!(
+            rendered,
+            "#!/bin/bash -e\necho /dev/pts/0 > /mnt/old_root/log\nexec /bin/takeover --stage2 --s2-log-level debug\n"
+        );
+        assert!(check_template_vars(script, &vars).is_ok());
+        assert!(TEMPLATE_VAR_REGEX.find(&rendered).is_none());
+
+        assert!(parse_shebang("#!/bin/sh\n").is_some());
+        assert!(parse_shebang("#!/usr/local/bin/bash\n").is_none());
+        assert!(parse_shebang("#!/usr/bin/python3\n").is_none());
+        assert!(parse_shebang("echo no shebang\n").is_none());
+    }
+
+    #[test]
+    fn test_check_template_vars() {
+        let vars = get_template_vars(Path::new("/dev/pts/0"), "debug");
+        let res = check_template_vars("#!/bin/sh\necho __FLASH_DEV__\n", &vars);
+        assert!(res.unwrap_err().to_string().contains("__FLASH_DEV__"));
+
+        let vars = get_template_vars(Path::new(""), "debug");
+        let res = check_template_vars("#!/bin/sh\necho __TTY__\n", &vars);
+        assert!(res.unwrap_err().to_string().contains("__TTY__"));
+        // unused variables do not need a value
+        assert!(check_template_vars("#!/bin/sh\necho __LOG_LEVEL__\n", &vars).is_ok());
+        assert!(check_template_vars("#!/bin/sh\necho __init__ $__\n", &vars).is_ok());
+    }
+}
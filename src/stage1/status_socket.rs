@@ -0,0 +1,352 @@
+use std::fs::{remove_file, symlink_metadata};
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+// a stuck client must not block the migration
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Clone)]
+pub struct StatusEvent {
+    pub step: String,
+    pub message: String,
+    pub terminal: bool,
+    pub success: Option<bool>,
+    pub percent: Option<u8>,
+    pub summary: Option<StatusSummary>,
+}
+
+/// Carried by the terminal event, which is always sent
+#[derive(Debug, Serialize, Clone)]
+pub struct StatusSummary {
+    pub steps: Vec<String>,
+    pub duration_secs: u64,
+    pub events_sent: u64,
+    pub events_suppressed: u64,
+}
+
+/// Receives the status events in the process running stage1, e.g. to render progress natively
+pub type ProgressCallback = Arc<dyn Fn(&StatusEvent) + Send + Sync>;
+
+struct SocketState {
+    clients: Vec<UnixStream>,
+    last_event: Option<String>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    steps: Vec<String>,
+    last_percent: Option<u8>,
+    events_sent: u64,
+    events_suppressed: u64,
+}
+
+/// In report on change mode a progress event is only sent for a new step, on completion or
+/// when the percentage grew by at least threshold since the last event sent
+fn progress_changed(last_percent: Option<u8>, percent: u8, threshold: u8) -> bool {
+    match last_percent {
+        None => true,
+        Some(last) => percent > last && (percent >= 100 || percent - last >= threshold.max(1)),
+    }
+}
+
+/// Streams status events as JSON lines to all clients connected to a unix domain socket.
+/// Clients connecting late receive the last event first. Events are passed to the progress
+/// callback too, if one is set. Without a socket path or callback all calls are no-ops. Clones
+/// share the socket.
+/// With a change threshold progress events are only sent when the percentage crossed it,
+/// otherwise every progress event is sent.
+#[derive(Clone)]
+pub(crate) struct StatusSocket {
+    path: Option<PathBuf>,
+    state: Arc<Mutex<SocketState>>,
+    progress: Arc<Mutex<ProgressState>>,
+    on_progress: Option<ProgressCallback>,
+    change_threshold: Option<u8>,
+    start: Instant,
+}
+
+impl StatusSocket {
+    pub fn new(
+        path: Option<&Path>,
+        on_progress: Option<ProgressCallback>,
+        change_threshold: Option<u8>,
+    ) -> Result<StatusSocket> {
+        let state = Arc::new(Mutex::new(SocketState {
+            clients: Vec::new(),
+            last_event: None,
+        }));
+
+        let path = if let Some(path) = path {
+            if let Ok(metadata) = symlink_metadata(path) {
+                // the path is user supplied, anything but a stale socket is left alone
+                if !metadata.file_type().is_socket() {
+                    return Err(Error::with_context(
+                        ErrorKind::FileExists,
+                        &format!(
+                            "Refusing to replace '{}' with the status socket, it exists and is not a socket",
+                            path.display()
+                        ),
+                    ));
+                }
+                remove_file(path).upstream_with_context(&format!(
+                    "Failed to remove stale status socket '{}'",
+                    path.display()
+                ))?;
+            }
+
+            let listener = UnixListener::bind(path).upstream_with_context(&format!(
+                "Failed to create status socket '{}'",
+                path.display()
+            ))?;
+
+            info!("Listening for status clients on '{}'", path.display());
+
+            let thread_state = state.clone();
+            thread::spawn(move || StatusSocket::accept_clients(listener, thread_state));
+            Some(path.to_path_buf())
+        } else {
+            None
+        };
+
+        Ok(StatusSocket {
+            path,
+            state,
+            progress: Arc::new(Mutex::new(ProgressState::default())),
+            on_progress,
+            change_threshold,
+            start: Instant::now(),
+        })
+    }
+
+    fn accept_clients(listener: UnixListener, state: Arc<Mutex<SocketState>>) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    debug!("accept_clients: status client connected");
+                    if let Err(why) = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+                        warn!("Failed to set timeout on status client, error: {}", why);
+                        continue;
+                    }
+                    if let Ok(mut state) = state.lock() {
+                        if let Some(last_event) = &state.last_event {
+                            if stream.write_all(last_event.as_bytes()).is_err() {
+                                continue;
+                            }
+                        }
+                        state.clients.push(stream);
+                    }
+                }
+                Err(why) => {
+                    warn!("Failed to accept status client, error: {}", why);
+                }
+            }
+        }
+    }
+
+    fn send_event(&self, event: &StatusEvent) {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.events_sent += 1;
+        }
+
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(event);
+        }
+
+        if self.path.is_none() {
+            return;
+        }
+
+        let mut event_str = match serde_json::to_string(event) {
+            Ok(event_str) => event_str,
+            Err(why) => {
+                warn!("Failed to serialize status event, error: {}", why);
+                return;
+            }
+        };
+        event_str.push('\n');
+
+        if let Ok(mut state) = self.state.lock() {
+            // clients that have gone away are dropped
+            state
+                .clients
+                .retain(|mut client| client.write_all(event_str.as_bytes()).is_ok());
+            state.last_event = Some(event_str);
+        }
+    }
+
+    pub fn step(&self, step: &str, message: &str) {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.steps.push(step.to_string());
+            progress.last_percent = None;
+        }
+
+        self.send_event(&StatusEvent {
+            step: step.to_string(),
+            message: message.to_string(),
+            terminal: false,
+            success: None,
+            percent: None,
+            summary: None,
+        });
+    }
+
+    /// send the progress of the current step, subject to the change threshold
+    pub fn progress(&self, step: &str, message: &str, percent: u8) {
+        let percent = percent.min(100);
+        if let Ok(mut progress) = self.progress.lock() {
+            if let Some(threshold) = self.change_threshold {
+                if !progress_changed(progress.last_percent, percent, threshold) {
+                    progress.events_suppressed += 1;
+                    return;
+                }
+            }
+            progress.last_percent = Some(percent);
+        }
+
+        self.send_event(&StatusEvent {
+            step: step.to_string(),
+            message: message.to_string(),
+            terminal: false,
+            success: None,
+            percent: Some(percent),
+            summary: None,
+        });
+    }
+
+    /// send the terminal event with the summary of the run and remove the socket
+    pub fn finish(&self, success: bool, message: &str) {
+        let summary = if let Ok(progress) = self.progress.lock() {
+            Some(StatusSummary {
+                steps: progress.steps.clone(),
+                duration_secs: self.start.elapsed().as_secs(),
+                // the terminal event itself included
+                events_sent: progress.events_sent + 1,
+                events_suppressed: progress.events_suppressed,
+            })
+        } else {
+            None
+        };
+
+        self.send_event(&StatusEvent {
+            step: "done".to_string(),
+            message: message.to_string(),
+            terminal: true,
+            success: Some(success),
+            percent: None,
+            summary,
+        });
+
+        if let Some(path) = &self.path {
+            if let Err(why) = remove_file(path) {
+                warn!(
+                    "Failed to remove status socket '{}', error: {}",
+                    path.display(),
+                    why
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn test_refuse_non_socket_path() {
+        let path = PathBuf::from(format!("/tmp/takeover-test-{}.status", std::process::id()));
+        write(&path, "keep me").unwrap();
+        assert!(StatusSocket::new(Some(path.as_path()), None, None).is_err());
+        assert!(path.exists());
+        remove_file(&path).unwrap();
+    }
+
+    fn collect_events(
+        change_threshold: Option<u8>,
+    ) -> (StatusSocket, Arc<Mutex<Vec<StatusEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let status = StatusSocket::new(
+            None,
+            Some(Arc::new(move |event: &StatusEvent| {
+                sink.lock().unwrap().push(event.clone())
+            })),
+            change_threshold,
+        )
+        .unwrap();
+        (status, events)
+    }
+
+    #[test]
+    fn test_progress_on_change() {
+        let (status, events) = collect_events(Some(25));
+        status.step("copy_files", "Copying files");
+        for percent in 0..=100 {
+            status.progress("copy_files", "Copying files", percent);
+        }
+        status.step("restart_init", "Restarting init");
+        status.progress("restart_init", "Restarting init", 3);
+        status.finish(true, "done");
+
+        let events = events.lock().unwrap();
+        let percents: Vec<Option<u8>> = events.iter().map(|event| event.percent).collect();
+        assert_eq!(
+            percents,
+            vec![
+                None,
+                Some(0),
+                Some(25),
+                Some(50),
+                Some(75),
+                Some(100),
+                None,
+                Some(3),
+                None
+            ]
+        );
+
+        let last = events.last().unwrap();
+        assert!(last.terminal);
+        let summary = last.summary.as_ref().unwrap();
+        assert_eq!(summary.steps, vec!["copy_files", "restart_init"]);
+        assert_eq!(summary.events_sent, 9);
+        assert_eq!(summary.events_suppressed, 96);
+    }
+
+    #[test]
+    fn test_progress_every_event() {
+        let (status, events) = collect_events(None);
+        for percent in 0..10 {
+            status.progress("copy_files", "Copying files", percent);
+        }
+        status.finish(false, "failed");
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 11);
+        let summary = events.last().unwrap().summary.as_ref().unwrap();
+        assert_eq!(summary.events_suppressed, 0);
+        assert_eq!(summary.events_sent, 11);
+    }
+
+    #[test]
+    fn test_progress_changed() {
+        assert!(progress_changed(None, 0, 10));
+        assert!(!progress_changed(Some(0), 9, 10));
+        assert!(progress_changed(Some(0), 10, 10));
+        assert!(progress_changed(Some(95), 100, 10));
+        assert!(!progress_changed(Some(100), 100, 10));
+        assert!(!progress_changed(Some(50), 40, 10));
+        assert!(progress_changed(Some(50), 51, 0));
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{read_to_string, File};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use flate2::{write::GzEncoder, Compression};
+use log::{info, warn};
+use tar::{Builder, Header};
+
+use crate::{
+    common::{
+        cmd_runner::SysCommandRunner,
+        error::{Result, ToError},
+        options::Options,
+        redact,
+    },
+    stage1::block_device_list::get_block_devices_json,
+};
+
+const BUNDLE_FILES: [&str; 8] = [
+    "/etc/os-release",
+    "/proc/version",
+    "/proc/cmdline",
+    "/proc/mounts",
+    "/proc/meminfo",
+    "/proc/partitions",
+    "/proc/swaps",
+    "/proc/cpuinfo",
+];
+
+#[derive(Default)]
+struct BundleState {
+    device_type: Option<String>,
+    stage2_config: Option<String>,
+}
+
+/// What stage1 learns on the way that the support bundle needs, kept in memory as the takeover
+/// directory might be unmounted by the time the bundle is written. Clones share the state.
+#[derive(Clone, Default)]
+pub(crate) struct BundleInfo {
+    state: Arc<Mutex<BundleState>>,
+}
+
+impl BundleInfo {
+    pub fn set_device_type(&self, device_type: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.device_type = Some(device_type.to_string());
+        }
+    }
+
+    pub fn set_stage2_config(&self, stage2_config: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.stage2_config = Some(stage2_config.to_string());
+        }
+    }
+
+    fn get(&self) -> (Option<String>, Option<String>) {
+        if let Ok(state) = self.state.lock() {
+            (state.device_type.clone(), state.stage2_config.clone())
+        } else {
+            (None, None)
+        }
+    }
+}
+
+struct SupportBundle {
+    archive: Builder<GzEncoder<File>>,
+}
+
+impl SupportBundle {
+    fn new(file: &Path) -> Result<SupportBundle> {
+        Ok(SupportBundle {
+            archive: Builder::new(GzEncoder::new(
+                File::create(file).upstream_with_context(&format!(
+                    "Failed to create support bundle in file '{}'",
+                    file.display()
+                ))?,
+                Compression::default(),
+            )),
+        })
+    }
+
+    fn add_text(&mut self, name: &str, text: &str) -> Result<()> {
+        let text = redact(text);
+        let mut header = Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        self.archive
+            .append_data(&mut header, name, text.as_bytes())
+            .upstream_with_context(&format!("Failed to append '{}' to support bundle", name))
+    }
+
+    /// add a file, a missing or unreadable file is noted in the bundle rather than failing
+    fn add_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<()> {
+        let path = path.as_ref();
+        match read_to_string(path) {
+            Ok(content) => self.add_text(name, &content),
+            Err(why) => {
+                warn!(
+                    "Failed to read '{}' for support bundle, error: {}",
+                    path.display(),
+                    why
+                );
+                self.add_text(
+                    name,
+                    &format!("Failed to read '{}', error: {}", path.display(), why),
+                )
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.archive
+            .finish()
+            .upstream_with_context("Failed to create support bundle archive")
+    }
+}
+
+pub(crate) fn create_support_bundle(
+    opts: &Options,
+    info: &BundleInfo,
+    bundle_path: &Path,
+    status: &str,
+) -> Result<()> {
+    let mut bundle = SupportBundle::new(bundle_path)?;
+
+    bundle.add_text("status.txt", status)?;
+    bundle.add_text("options.txt", &format!("{:#?}", opts))?;
+
+    let (device_type, stage2_config) = info.get();
+    bundle.add_text(
+        "device-type.txt",
+        device_type
+            .as_deref()
+            .unwrap_or("The device type was not detected"),
+    )?;
+    bundle.add_text(
+        "stage2-config.yml",
+        stage2_config
+            .as_deref()
+            .unwrap_or("# The stage2 config was not created"),
+    )?;
+
+    match get_block_devices_json(opts, &SysCommandRunner::new()) {
+        Ok(block_devices) => bundle.add_text("block-devices.json", &block_devices)?,
+        Err(why) => {
+            warn!(
+                "Failed to list block devices for support bundle, error: {}",
+                why
+            );
+            bundle.add_text(
+                "block-devices.txt",
+                &format!("Failed to list block devices, error: {}", why),
+            )?
+        }
+    }
+
+    for file in BUNDLE_FILES.iter() {
+        let name = file.trim_start_matches('/').replace('/', "_");
+        bundle.add_file(&name, file)?;
+    }
+
+    for (index, config) in opts.config().iter().enumerate() {
+        let name = if index == 0 {
+            "config.json".to_string()
+        } else {
+            format!("config-{}.json", index)
+        };
+        bundle.add_file(&name, config)?;
+    }
+
+    if let Some(log_file) = opts.log_file() {
+        bundle.add_file("stage1.log", log_file)?;
+    }
+
+    bundle.finish()?;
+
+    info!("Wrote support bundle to '{}'", bundle_path.display());
+    Ok(())
+}
@@ -1,32 +1,42 @@
-use libc::S_IFREG;
+use libc::{STDIN_FILENO, S_IFREG};
 use log::info;
+use nix::errno::Errno;
 use nix::mount::{mount, MsFlags};
+use nix::unistd::isatty;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::cmp::min;
 use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use crate::{
     common::{
-        call,
+        cmd_runner::CommandRunner,
         defs::{MOKUTIL_CMD, NIX_NONE, SYS_EFI_DIR},
         dir_exists,
+        options::Options,
         system::{mkdir, mknod, uname},
         whereis, Error, ErrorKind, Result, ToError,
     },
     stage1::defs::OSArch,
 };
 
-use log::{error, trace, warn};
+use log::{debug, error, trace, warn};
 use regex::Regex;
 
 use crate::common::path_append;
 use crate::stage1::migrate_info::MigrateInfo;
 
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, read_to_string};
 use std::io::Read;
 
+const MOUNT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MKTEMP_ATTEMPTS: u32 = 16;
+const MKTEMP_FALLBACK_DIRS: [&str; 3] = ["/run", "/tmp", "/dev/shm"];
+
 pub(crate) fn get_os_arch() -> Result<OSArch> {
     trace!("get_os_arch: entered");
 
@@ -50,13 +60,13 @@ pub(crate) fn get_os_arch() -> Result<OSArch> {
  * assuming secure boot is not enabled if mokutil is absent
  ******************************************************************/
 
-pub(crate) fn is_secure_boot() -> Result<bool> {
+pub(crate) fn is_secure_boot(runner: &dyn CommandRunner) -> Result<bool> {
     trace!("is_secure_boot: entered");
 
     // TODO: check for efi vars
 
     if dir_exists(SYS_EFI_DIR)? {
-        let mokutil_path = match whereis(MOKUTIL_CMD) {
+        let mokutil_path = match whereis(MOKUTIL_CMD, runner) {
             Ok(path) => path,
             Err(_why) => {
                 warn!("The mokutil command '{}' could not be found", MOKUTIL_CMD);
@@ -64,7 +74,7 @@ pub(crate) fn is_secure_boot() -> Result<bool> {
             }
         };
 
-        let cmd_res = call(&mokutil_path, &["--sb-state"], true)?;
+        let cmd_res = runner.call(&mokutil_path, &["--sb-state"], true)?;
         if cmd_res.stderr.is_empty() {
             let regex = Regex::new(r"^SecureBoot\s+(disabled|enabled)$").unwrap();
             let lines = cmd_res.stdout.lines();
@@ -102,13 +112,15 @@ pub(crate) fn is_secure_boot() -> Result<bool> {
     }
 }
 
-pub(crate) fn mktemp<P: AsRef<Path>>(
+/// Create a file or directory with a random name in base, names that exist already are retried
+/// with new random names up to MKTEMP_ATTEMPTS times
+fn mktemp_in(
     dir: bool,
     prefix: Option<&str>,
     suffix: Option<&str>,
-    path: Option<P>,
+    base: &Path,
 ) -> Result<PathBuf> {
-    loop {
+    for _ in 0..MKTEMP_ATTEMPTS {
         let mut file_name = String::new();
         if let Some(prefix) = prefix {
             file_name.push_str(prefix);
@@ -124,11 +136,7 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
             file_name.push_str(suffix);
         }
 
-        let new_path = if let Some(path) = &path {
-            path_append(path.as_ref(), file_name.as_str())
-        } else {
-            path_append("/tmp", file_name.as_str())
-        };
+        let new_path = path_append(base, file_name.as_str());
 
         match if dir {
             mkdir(new_path.as_path(), 0o755)
@@ -138,16 +146,105 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
             Ok(_) => return Ok(new_path),
             Err(why) => {
                 if why.kind() != ErrorKind::FileExists {
-                    return Err(Error::with_cause(ErrorKind::Upstream, Box::new(why)));
+                    return Err(Error::from_upstream(
+                        Box::new(why),
+                        &format!(
+                            "Failed to create a temporary {} in '{}'",
+                            if dir { "directory" } else { "file" },
+                            base.display()
+                        ),
+                    ));
                 }
+                debug!("mktemp_in: '{}' exists, retrying", new_path.display());
             }
         }
     }
+
+    Err(Error::with_context(
+        ErrorKind::FileExists,
+        &format!(
+            "Failed to find an unused temporary file name in '{}' after {} attempts",
+            base.display(),
+            MKTEMP_ATTEMPTS
+        ),
+    ))
+}
+
+pub(crate) fn mktemp<P: AsRef<Path>>(
+    dir: bool,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+    path: Option<P>,
+) -> Result<PathBuf> {
+    if let Some(path) = &path {
+        mktemp_in(dir, prefix, suffix, path.as_ref())
+    } else {
+        mktemp_in(dir, prefix, suffix, Path::new("/tmp"))
+    }
+}
+
+/// Like mktemp but falls back to MKTEMP_FALLBACK_DIRS if nothing can be created in path, e.g.
+/// on a read-only or full file system. For scratch files that do not need to be in path.
+pub(crate) fn mktemp_with_fallback<P: AsRef<Path>>(
+    dir: bool,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+    path: P,
+) -> Result<PathBuf> {
+    let mut bases: Vec<&Path> = vec![path.as_ref()];
+    for fallback in &MKTEMP_FALLBACK_DIRS {
+        let fallback = Path::new(fallback);
+        if !bases.contains(&fallback) && dir_exists(fallback).unwrap_or(false) {
+            bases.push(fallback);
+        }
+    }
+
+    let mut failures: Vec<String> = Vec::new();
+    for base in bases {
+        match mktemp_in(dir, prefix, suffix, base) {
+            Ok(new_path) => {
+                if !failures.is_empty() {
+                    warn!(
+                        "Created temporary file '{}' in fallback directory after: {}",
+                        new_path.display(),
+                        failures.join(", ")
+                    );
+                }
+                return Ok(new_path);
+            }
+            Err(why) => failures.push(format!("'{}': {}", base.display(), why)),
+        }
+    }
+
+    Err(Error::with_context(
+        ErrorKind::Upstream,
+        &format!(
+            "Failed to create a temporary {} in any of the directories tried: {}, make one of them writable or select another one with --work-dir",
+            if dir { "directory" } else { "file" },
+            failures.join(", ")
+        ),
+    ))
+}
+
+pub(crate) fn resolve_host(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let url = format!("{}:{}", host, port);
+    let addrs: Vec<SocketAddr> = url
+        .to_socket_addrs()
+        .upstream_with_context(&format!("Failed to resolve host address: '{}'", url))?
+        .collect();
+
+    if addrs.is_empty() {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No results from name resolution for: '{}'", url),
+        ))
+    } else {
+        Ok(addrs)
+    }
 }
 
 pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<()> {
-    use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-    use std::time::Duration;
+    use std::net::{Shutdown, TcpStream};
     let url = format!("{}:{}", host, port);
     let mut addrs_iter = url.to_socket_addrs().upstream_with_context(&format!(
         "check_tcp_connect: failed to resolve host address: '{}'",
@@ -174,11 +271,42 @@ pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<(
     }
 }
 
+/// Retry a syscall as long as it is interrupted by a signal
+pub(crate) fn retry_eintr<T, F: FnMut() -> nix::Result<T>>(mut syscall: F) -> nix::Result<T> {
+    loop {
+        match syscall() {
+            Err(why) if why.as_errno() == Some(Errno::EINTR) => {
+                trace!("retry_eintr: interrupted by a signal, retrying");
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Sleep for the full duration, sleeps again for the remaining time if the sleep ends early
+pub(crate) fn sleep_full(duration: Duration) {
+    let start = Instant::now();
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return;
+        }
+        sleep(duration - elapsed);
+    }
+}
+
+/// Errors that may go away when the mount is tried again, e.g. on a busy system
+fn is_transient_mount_error(errno: Option<Errno>) -> bool {
+    matches!(errno, Some(Errno::EBUSY) | Some(Errno::EAGAIN))
+}
+
+/// Mount fs on mount_dir, retrying up to retries times after transient errors
 pub(crate) fn mount_fs<P: AsRef<Path>>(
     mount_dir: P,
     fs: &str,
     fs_type: &str,
     mig_info: Option<&mut MigrateInfo>,
+    retries: u32,
 ) -> Result<()> {
     let mount_dir = mount_dir.as_ref();
     if !dir_exists(mount_dir)? {
@@ -188,19 +316,53 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
         ))?;
     }
 
-    mount(
-        Some(fs.as_bytes()),
-        mount_dir,
-        Some(fs_type.as_bytes()),
-        MsFlags::empty(),
-        NIX_NONE,
-    )
-    .upstream_with_context(&format!(
-        "Failed to mount {} on {} with fstype {}",
-        fs,
-        mount_dir.display(),
-        fs_type
-    ))?;
+    let mut attempt = 0;
+    let res = loop {
+        let res = retry_eintr(|| {
+            mount(
+                Some(fs.as_bytes()),
+                mount_dir,
+                Some(fs_type.as_bytes()),
+                MsFlags::empty(),
+                NIX_NONE,
+            )
+        });
+        match res {
+            Err(why) if attempt < retries && is_transient_mount_error(why.as_errno()) => {
+                attempt += 1;
+                warn!(
+                    "Failed to mount {} on {}: {}, retrying ({}/{})",
+                    fs,
+                    mount_dir.display(),
+                    why,
+                    attempt,
+                    retries
+                );
+                sleep(MOUNT_RETRY_DELAY);
+            }
+            res => break res,
+        }
+    };
+
+    if let Err(why) = res {
+        let kind = match why.as_errno() {
+            Some(Errno::EPERM) => ErrorKind::NotPermitted,
+            Some(Errno::EACCES) => ErrorKind::Permission,
+            Some(Errno::ENOENT) => ErrorKind::FileNotFound,
+            Some(Errno::ENODEV) => ErrorKind::DeviceNotFound,
+            _ => ErrorKind::Upstream,
+        };
+        return Err(Error::with_all(
+            kind,
+            &format!(
+                "Failed to mount {} on {} with fstype {}",
+                fs,
+                mount_dir.display(),
+                fs_type
+            ),
+            Box::new(why),
+        ));
+    }
 
     if let Some(mig_info) = mig_info {
         mig_info.add_mount(mount_dir);
@@ -211,6 +373,65 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
     Ok(())
 }
 
+/// list all mountpoints at or below dir in mount order
+pub(crate) fn get_mounts_below<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    let mounts = read_to_string("/proc/mounts")
+        .upstream_with_context("Failed to read from '/proc/mounts'")?;
+
+    Ok(mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(PathBuf::from)
+        .filter(|mountpoint| mountpoint.starts_with(dir))
+        .collect())
+}
+
+/// Ask the user for confirmation, always confirms if --assume-yes was given
+pub(crate) fn confirm(opts: &Options, question: &str) -> Result<bool> {
+    if opts.assume_yes() {
+        info!("{} - assuming yes", question);
+        return Ok(true);
+    }
+
+    // an unattended run would wait forever for an answer
+    if !isatty(STDIN_FILENO).unwrap_or(false) {
+        error!(
+            "{} - stdin is not a terminal, use -y / --assume-yes to confirm unattended runs",
+            question
+        );
+        return Err(Error::displayed());
+    }
+
+    println!("{} [Y/n]", question);
+    loop {
+        let mut buffer = String::new();
+        match std::io::stdin().read_line(&mut buffer) {
+            Ok(0) => {
+                error!(
+                    "{} - stdin was closed before an answer was given, use -y / --assume-yes to confirm unattended runs",
+                    question
+                );
+                return Err(Error::displayed());
+            }
+            Ok(_) => match buffer.trim() {
+                "Y" | "y" => return Ok(true),
+                "n" => return Ok(false),
+                _ => {
+                    println!("please type Y for yes or n for no");
+                    continue;
+                }
+            },
+            Err(why) => {
+                return Err(Error::from_upstream(
+                    Box::new(why),
+                    "Failed to read line from stdin",
+                ))
+            }
+        }
+    }
+}
+
 pub(crate) struct ReadBuffer<'a> {
     buffer: &'a [u8],
     read_pos: usize,
@@ -0,0 +1,79 @@
+use std::process::exit;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use mod_logger::Logger;
+
+use crate::common::{Result, ToError};
+
+/// exit code of a stage1 run that was aborted by the watchdog, same as coreutils timeout
+pub(crate) const TIMEOUT_EXIT_CODE: i32 = 124;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchdogState {
+    Armed,
+    Disarmed,
+    Expired,
+}
+
+/// Terminates stage1 if it does not reach the point of no return within the timeout. On expiry
+/// the cleanup function is run on the watchdog thread and the process exits with
+/// TIMEOUT_EXIT_CODE.
+pub(crate) struct Watchdog {
+    state: Arc<(Mutex<WatchdogState>, Condvar)>,
+}
+
+impl Watchdog {
+    pub fn start<F: FnOnce() + Send + 'static>(timeout: Duration, cleanup: F) -> Result<Watchdog> {
+        let state = Arc::new((Mutex::new(WatchdogState::Armed), Condvar::new()));
+        let thread_state = state.clone();
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || Watchdog::watch(thread_state, timeout, cleanup))
+            .upstream_with_context("Failed to start watchdog thread")?;
+        debug!("Watchdog started with a timeout of {} s", timeout.as_secs());
+        Ok(Watchdog { state })
+    }
+
+    fn watch<F: FnOnce()>(
+        state: Arc<(Mutex<WatchdogState>, Condvar)>,
+        timeout: Duration,
+        cleanup: F,
+    ) {
+        let (lock, cvar) = &*state;
+        let deadline = Instant::now() + timeout;
+        let mut curr_state = match lock.lock() {
+            Ok(curr_state) => curr_state,
+            Err(_) => return,
+        };
+
+        while *curr_state == WatchdogState::Armed {
+            let now = Instant::now();
+            if now >= deadline {
+                // the lock is held until the process exits, so disarm can not pass the point of
+                // no return while cleaning up
+                *curr_state = WatchdogState::Expired;
+                cleanup();
+                Logger::flush();
+                exit(TIMEOUT_EXIT_CODE);
+            }
+            curr_state = match cvar.wait_timeout(curr_state, deadline - now) {
+                Ok((curr_state, _)) => curr_state,
+                Err(_) => return,
+            };
+        }
+    }
+
+    /// Disarm the watchdog before passing the point of no return. Does not return if the
+    /// watchdog has expired already.
+    pub fn disarm(&self) {
+        let (lock, cvar) = &*self.state;
+        if let Ok(mut curr_state) = lock.lock() {
+            *curr_state = WatchdogState::Disarmed;
+            cvar.notify_one();
+            debug!("Watchdog disarmed");
+        }
+    }
+}
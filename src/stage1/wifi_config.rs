@@ -1,4 +1,4 @@
-use log::{info, trace, warn};
+use log::{debug, info, trace, warn};
 use std::fs::{read_to_string, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -10,12 +10,18 @@ mod connmgr_parser;
 mod nwmgr_parser;
 mod wpa_parser;
 
+pub(crate) use nwmgr_parser::{check_nwmgr_file, get_nwmgr_file_ssid, replace_nwmgr_id};
+
 use crate::{
-    common::{dir_exists, file_exists, path_append, pidof, Result, ToError},
+    common::{
+        cmd_runner::CommandRunner, dir_exists, file_exists, format_size_with_unit,
+        options::NetworkBackend, path_append, pidof, system::set_root_only, Error, ErrorKind,
+        Result, ToError,
+    },
     stage1::wifi_config::{
         connmgr_parser::{parse_connmgr_config, CONNMGR_CONFIG_DIR},
+        nwmgr_parser::parse_nwmgr_config,
         nwmgr_parser::NWMGR_CONFIG_DIR,
-        nwmgr_parser::{parse_nwmgr_config, replace_nwmgr_id},
         wpa_parser::{WpaParser, WPA_CONFIG_FILE},
     },
 };
@@ -47,6 +53,60 @@ key-mgmt=wpa-psk
 psk=__PSK__
 "##;
 
+const NETWORKD_CONTENT: &str = r##"## created by balena-migrate
+[Match]
+Type=wlan
+SSID=__SSID__
+
+[Network]
+DHCP=yes
+"##;
+
+const WPA_SUPPLICANT_CONTENT: &str = r##"## created by balena-migrate
+network={
+    ssid="__SSID__"
+    scan_ssid=1
+__KEY__}
+"##;
+
+const CONNMAN_CONTENT: &str = r##"## created by balena-migrate
+[service___FILE_NAME__]
+Type=wifi
+Name=__SSID__
+"##;
+
+// DHCP fallback for wired interfaces, connections with a higher priority are preferred
+const NWMGR_DHCP_ETHERNET_CONTENT: &str = r##"## created by balena-migrate
+[connection]
+id=balena-dhcp-ethernet
+type=ethernet
+autoconnect-priority=-100
+
+[ipv4]
+method=auto
+
+[ipv6]
+addr-gen-mode=stable-privacy
+method=auto
+"##;
+
+const NETWORKD_DHCP_ETHERNET_CONTENT: &str = r##"## created by balena-migrate
+[Match]
+Type=ether
+
+[Network]
+DHCP=yes
+"##;
+
+const CONNMAN_DHCP_ETHERNET_CONTENT: &str = r##"## created by balena-migrate
+[service_balena_dhcp_ethernet]
+Type=ethernet
+IPv4=dhcp
+IPv6=auto
+"##;
+
+const DHCP_ETHERNET_NAME: &str = "balena-dhcp-ethernet";
+
 #[derive(Debug)]
 pub(crate) struct Params {
     ssid: String,
@@ -68,13 +128,13 @@ pub(crate) enum WifiConfig {
 }
 
 impl<'a> WifiConfig {
-    pub fn scan(ssid_filter: &[String]) -> Result<Vec<WifiConfig>> {
+    pub fn scan(ssid_filter: &[String], runner: &dyn CommandRunner) -> Result<Vec<WifiConfig>> {
         trace!("WifiConfig::scan: entered with {:?}", ssid_filter);
-        if !pidof("NetworkManager")?.is_empty() && dir_exists(NWMGR_CONFIG_DIR)? {
+        if !pidof("NetworkManager", runner)?.is_empty() && dir_exists(NWMGR_CONFIG_DIR)? {
             Ok(parse_nwmgr_config(ssid_filter)?)
-        } else if !pidof("wpa_supplicant")?.is_empty() && file_exists(WPA_CONFIG_FILE) {
+        } else if !pidof("wpa_supplicant", runner)?.is_empty() && file_exists(WPA_CONFIG_FILE) {
             Ok(WpaParser::parse_config(ssid_filter)?)
-        } else if !pidof("wpa_supplicant")?.is_empty() && dir_exists(CONNMGR_CONFIG_DIR)? {
+        } else if !pidof("wpa_supplicant", runner)?.is_empty() && dir_exists(CONNMGR_CONFIG_DIR)? {
             Ok(parse_connmgr_config(ssid_filter)?)
         } else {
             warn!("No supported network managers found, no wifis will be migrated");
@@ -89,6 +149,78 @@ impl<'a> WifiConfig {
         }
     }
 
+    /// Create the configuration files for this wifi in the format of the given network backend
+    pub(crate) fn create_network_file<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+        index: u64,
+        backend: NetworkBackend,
+    ) -> Result<u64> {
+        match backend {
+            NetworkBackend::NetworkManager => self.create_nwmgr_file(base_path, index),
+            NetworkBackend::SystemdNetworkd => self.create_networkd_files(base_path, index),
+            NetworkBackend::Connman => self.create_connman_file(base_path, index),
+        }
+    }
+
+    /// Wifi parameters for backends other than NetworkManager, NetworkManager files found on
+    /// the system can not be converted
+    fn get_params(&self, backend: NetworkBackend) -> Result<&Params> {
+        match self {
+            WifiConfig::Params(params) => Ok(params),
+            WifiConfig::NwMgrFile(nwmgr_file) => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The NetworkManager configuration for wifi '{}' in '{}' can not be converted to a {} configuration",
+                    nwmgr_file.ssid,
+                    nwmgr_file.file.display(),
+                    backend
+                ),
+            )),
+        }
+    }
+
+    fn create_networkd_files<P: AsRef<Path>>(&self, base_path: P, index: u64) -> Result<u64> {
+        let params = self.get_params(NetworkBackend::SystemdNetworkd)?;
+        let base_path = base_path.as_ref();
+
+        let path = path_append(base_path, &format!("resin-wifi-{}.network", index));
+        info!("Creating systemd-networkd file in '{}'", path.display());
+        let content = NETWORKD_CONTENT.replace("__SSID__", &params.ssid);
+        write_file(&path, &content)?;
+
+        let path = path_append(base_path, &format!("resin-wifi-{}.conf", index));
+        info!("Creating wpa_supplicant file in '{}'", path.display());
+        let key = if let Some(ref psk) = params.psk {
+            format!("    psk=\"{}\"\n", psk)
+        } else {
+            String::from("    key_mgmt=NONE\n")
+        };
+        let content = WPA_SUPPLICANT_CONTENT
+            .replace("__SSID__", &params.ssid)
+            .replace("__KEY__", &key);
+        write_file(&path, &content)?;
+
+        Ok(index)
+    }
+
+    fn create_connman_file<P: AsRef<Path>>(&self, base_path: P, index: u64) -> Result<u64> {
+        let params = self.get_params(NetworkBackend::Connman)?;
+        let name = format!("resin-wifi-{}", index);
+        let path = path_append(base_path.as_ref(), &format!("{}.config", name));
+        info!("Creating connman file in '{}'", path.display());
+
+        let mut content = CONNMAN_CONTENT
+            .replace("__SSID__", &params.ssid)
+            .replace("__FILE_NAME__", &name);
+        if let Some(ref psk) = params.psk {
+            content.push_str(&format!("Passphrase={}\n", psk));
+        }
+        write_file(&path, &content)?;
+
+        Ok(index)
+    }
+
     pub(crate) fn create_nwmgr_file<P: AsRef<Path>>(
         &self,
         base_path: P,
@@ -100,6 +232,7 @@ impl<'a> WifiConfig {
         info!("Creating NetworkManager file in '{}'", path.display());
         let mut nwmgr_file = File::create(&path)
             .upstream_with_context(&format!("Failed to create file in '{}'", path.display()))?;
+        set_root_only(&path)?;
 
         let name = path.file_name().unwrap().to_string_lossy();
 
@@ -133,6 +266,20 @@ impl<'a> WifiConfig {
         };
 
         trace!("writing nwmgr file as: \n{}", content);
+        match self {
+            WifiConfig::Params(config) => debug!(
+                "create_nwmgr_file: wifi '{}' -> '{}', {}",
+                config.ssid,
+                path.display(),
+                format_size_with_unit(content.len() as u64)
+            ),
+            WifiConfig::NwMgrFile(nwmgr_file) => debug!(
+                "create_nwmgr_file: '{}' -> '{}', {}",
+                nwmgr_file.file.display(),
+                path.display(),
+                format_size_with_unit(content.len() as u64)
+            ),
+        }
 
         nwmgr_file
             .write_all(content.as_bytes())
@@ -140,3 +287,81 @@ impl<'a> WifiConfig {
         Ok(index)
     }
 }
+
+/// Create a configuration that brings up wired interfaces using DHCP in the format of the given
+/// network backend
+pub(crate) fn create_dhcp_ethernet_file<P: AsRef<Path>>(
+    base_path: P,
+    backend: NetworkBackend,
+) -> Result<()> {
+    let (file_name, content) = match backend {
+        NetworkBackend::NetworkManager => {
+            (DHCP_ETHERNET_NAME.to_string(), NWMGR_DHCP_ETHERNET_CONTENT)
+        }
+        NetworkBackend::SystemdNetworkd => (
+            format!("{}.network", DHCP_ETHERNET_NAME),
+            NETWORKD_DHCP_ETHERNET_CONTENT,
+        ),
+        NetworkBackend::Connman => (
+            format!("{}.config", DHCP_ETHERNET_NAME),
+            CONNMAN_DHCP_ETHERNET_CONTENT,
+        ),
+    };
+
+    let path = path_append(base_path.as_ref(), &file_name);
+    info!(
+        "Creating {} DHCP ethernet configuration in '{}'",
+        backend,
+        path.display()
+    );
+    write_file(&path, content)
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    trace!("writing '{}' as: \n{}", path.display(), content);
+    debug!(
+        "write_file: '{}', {}",
+        path.display(),
+        format_size_with_unit(content.len() as u64)
+    );
+    let mut file = File::create(path)
+        .upstream_with_context(&format!("Failed to create file '{}'", path.display()))?;
+    set_root_only(path)?;
+    file.write_all(content.as_bytes())
+        .upstream_with_context(&format!("Failed to write file '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, metadata, read_dir, remove_dir_all};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_network_file_mode() {
+        let base_path = PathBuf::from(format!("/tmp/takeover-test-wifi-{}", std::process::id()));
+        create_dir(&base_path).unwrap();
+
+        let wifi_config = WifiConfig::Params(Params {
+            ssid: "test-ssid".to_string(),
+            psk: Some("secret-psk".to_string()),
+        });
+        for backend in &[
+            NetworkBackend::NetworkManager,
+            NetworkBackend::SystemdNetworkd,
+            NetworkBackend::Connman,
+        ] {
+            wifi_config
+                .create_network_file(&base_path, 1, *backend)
+                .unwrap();
+            create_dhcp_ethernet_file(&base_path, *backend).unwrap();
+        }
+
+        for entry in read_dir(&base_path).unwrap() {
+            let path = entry.unwrap().path();
+            let mode = metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "mode of '{}'", path.display());
+        }
+        remove_dir_all(&base_path).unwrap();
+    }
+}
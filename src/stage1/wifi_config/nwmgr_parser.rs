@@ -1,3 +1,4 @@
+use lazy_static::lazy_static;
 use log::{debug, warn};
 use regex::Regex;
 use std::fs::{read_dir, read_to_string};
@@ -193,6 +194,74 @@ pub(crate) fn replace_nwmgr_id(content: &str, id: &str) -> Result<String> {
     }
 }
 
+/// the SSID of a NetworkManager connection file, None if it is not a wifi connection
+pub(crate) fn get_nwmgr_file_ssid<P: AsRef<Path>>(cfg_file: P) -> Result<Option<String>> {
+    let mut wifis: Vec<WifiConfig> = Vec::new();
+    ParserState::new().parse_file(cfg_file, &[], &mut wifis)?;
+    Ok(wifis.pop().map(|wifi| wifi.get_ssid().to_string()))
+}
+
+/// Check that a NetworkManager connection file is a keyfile with a connection section that
+/// contains the connection type
+pub(crate) fn check_nwmgr_file<P: AsRef<Path>>(cfg_file: P) -> Result<()> {
+    lazy_static! {
+        static ref SKIP_RE: Regex = Regex::new(r##"^(\s*#.*|\s*)$"##).unwrap();
+        static ref SECTION_RE: Regex = Regex::new(r##"^\s*\[([^]]+)]\s*$"##).unwrap();
+        static ref PARAM_RE: Regex = Regex::new(r##"^\s*([^=\[\s#][^=]*?)\s*=.*$"##).unwrap();
+    }
+
+    let cfg_file = cfg_file.as_ref();
+    let content = read_to_string(cfg_file)
+        .upstream_with_context(&format!("Failed to read file: '{}'", cfg_file.display()))?;
+
+    let mut section: Option<String> = None;
+    let mut has_type = false;
+    for (line_no, line) in content.lines().enumerate() {
+        if SKIP_RE.is_match(line) {
+            continue;
+        } else if let Some(captures) = SECTION_RE.captures(line) {
+            section = Some(captures.get(1).unwrap().as_str().to_string());
+        } else if let Some(captures) = PARAM_RE.captures(line) {
+            if section.is_none() {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Invalid NetworkManager file '{}': parameter outside of a section in line {}",
+                        cfg_file.display(),
+                        line_no + 1
+                    ),
+                ));
+            }
+            if section.as_deref() == Some("connection")
+                && captures.get(1).unwrap().as_str() == "type"
+            {
+                has_type = true;
+            }
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid NetworkManager file '{}': invalid line {}",
+                    cfg_file.display(),
+                    line_no + 1
+                ),
+            ));
+        }
+    }
+
+    if has_type {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid NetworkManager file '{}': no connection type found",
+                cfg_file.display()
+            ),
+        ))
+    }
+}
+
 pub(crate) fn parse_nwmgr_config(ssid_filter: &[String]) -> Result<Vec<WifiConfig>> {
     if dir_exists(NWMGR_CONFIG_DIR)? {
         let mut wifis: Vec<WifiConfig> = Vec::new();
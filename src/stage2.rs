@@ -1,22 +1,27 @@
+use std::cmp::{max, min};
 use std::fs::{
-    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir, File, OpenOptions,
+    copy, create_dir, create_dir_all, metadata, read, read_dir, read_to_string, remove_dir, File,
+    OpenOptions,
 };
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
-use std::process::{exit, Command, Stdio};
+use std::process::{exit, Command, ExitStatus, Stdio};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use nix::{
-    mount::{mount, umount, MsFlags},
+    mount::{mount, umount, umount2, MntFlags, MsFlags},
+    sys::statvfs::statvfs,
     unistd::sync,
 };
 
 use std::path::{Path, PathBuf};
 
-use flate2::read::GzDecoder;
-use libc::{ioctl, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, SIGKILL, SIGTERM};
+use libc::{
+    ioctl, EBUSY, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, O_EXCL, SIGKILL, SIGTERM,
+};
 use log::{debug, error, info, trace, warn, Level};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 
@@ -26,21 +31,62 @@ use crate::common::{
     defs::{
         IoctlReq, BACKUP_ARCH_NAME, BALENA_BOOT_FSTYPE, BALENA_BOOT_MP, BALENA_BOOT_PART,
         BALENA_CONFIG_PATH, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_IMAGE_NAME,
-        BALENA_IMAGE_PATH, BALENA_PART_MP, DD_CMD, DISK_BY_LABEL_PATH, EFIBOOTMGR_CMD, NIX_NONE,
-        OLD_ROOT_MP, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYS_EFI_DIR,
+        BALENA_IMAGE_PATH, BALENA_PART_MP, BOOT_LOCALE_NAME, BOOT_LOG_MAX_SIZE, BOOT_LOG_NAME,
+        BOOT_REPORT_NAME, BOOT_TIMEZONE_NAME, BUSYBOX_CMD, DD_CMD, DISK_BY_LABEL_PATH, E2FSCK_CMD,
+        EFIBOOTMGR_CMD, FSCK_VFAT_CMD, NIX_NONE, OLD_ROOT_MP, SFDISK_CMD, STAGE2_CONFIG_NAME,
+        SYS_EFI_DIR,
     },
     dir_exists,
     disk_util::{Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
     error::{Error, ErrorKind, Result, ToError},
-    file_exists, format_size_with_unit, get_mem_info,
+    file_exists,
+    flash_transform::{apply_transforms, FlashTransform},
+    format_size_with_unit, get_mem_info,
+    image_format::{get_image_format, open_image, ImageFormat},
+    image_slices::{SliceLayout, SliceManifest},
+    log_writer::set_log_writer,
     loop_device::LoopDevice,
     options::Options,
     path_append,
     stage2_config::{Stage2Config, UmountPart},
-    system::{fuser, get_process_infos},
+    stage2_report::Stage2Report,
+    system::{fuser, get_process_infos, set_root_only},
 };
 use regex::Regex;
 
+mod approval;
+use approval::await_approval;
+
+mod archive;
+use archive::archive_old_root;
+
+mod delta_flash;
+use delta_flash::flash_delta;
+
+mod heartbeat;
+use heartbeat::{add_flashed_bytes, Heartbeat};
+
+mod image_stream;
+use image_stream::estimate_compressed_size;
+
+mod preserve;
+use preserve::{get_preserve_size, restore_partitions, save_partitions};
+
+mod resume;
+use resume::flash_resumable;
+
+mod rtc;
+use rtc::set_rtc_from_system;
+
+mod speed_guard;
+use speed_guard::SpeedGuard;
+
+mod uuids;
+use uuids::randomize_uuids;
+
+mod verify_sample;
+use verify_sample::verify_sample;
+
 const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
 
 const VALIDATE_MAX_ERR: usize = 20;
@@ -48,10 +94,22 @@ const DO_VALIDATE: bool = false;
 const VALIDATE_BLOCK_SIZE: usize = 64 * 1024; // 4_194_304;
 
 const IOCTL_BLK_RRPART: IoctlReq = 0x1295;
+const IOCTL_BLK_DISCARD: IoctlReq = 0x1277;
+// #define BLKFLSBUF _IO(0x12,97), what blockdev --flushbufs does
+const IOCTL_BLK_FLSBUF: IoctlReq = 0x1261;
+const FLASH_OPEN_RETRY_DELAY: Duration = Duration::from_secs(2);
 
 const TRANSFER_DIR: &str = "/transfer";
+const S2_LOG_PATH: &str = "/stage2.log";
+const LOG_DEV_MP: &str = "/mnt/log";
+const CONSOLE_DEV: &str = "/dev/console";
+const LOG_DEV_PATH: &str = "/mnt/log/stage2.log";
+const LOG_DEV_REPORT_NAME: &str = "stage2-report.json";
 
 const S2_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024;
+const S2_MIN_FREE_MEM: u64 = 2 * 1024 * 1024;
+const MEM_CHECK_INTERVAL: u64 = 16 * 1024 * 1024;
+const BOOT_FILE_COPY_ATTEMPTS: u32 = 2;
 
 pub(crate) fn reboot() -> ! {
     trace!("reboot entered");
@@ -63,71 +121,248 @@ pub(crate) fn reboot() -> ! {
     exit(1);
 }
 
+/// true if the image is to be compressed when it is copied to tmpfs
+fn compress_image(s2_cfg: &Stage2Config, image_path: &Path) -> Result<bool> {
+    Ok(s2_cfg.compress_image
+        && get_image_format(image_path, s2_cfg.image_format)? == ImageFormat::Raw)
+}
+
+/// path of the image of the partition at index in tmpfs
+fn slice_transfer_path(index: usize) -> PathBuf {
+    path_append(TRANSFER_DIR, &format!("slice-{:02}.img", index + 1))
+}
+
+/// The tmpfs space needed for the files stage1 staged. Stage1 computes their total once, the
+/// image is accounted with its estimated compressed size if it is staged compressed.
 fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
+    let mut req_size = s2_cfg.total_staged_bytes;
+
     let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.image_path);
-    let mut req_size = curr_file
-        .metadata()
-        .upstream_with_context(&format!(
-            "Failed to retrieve imagesize for '{}'",
-            curr_file.display()
-        ))?
-        .len() as u64;
+    if !s2_cfg.no_flash && s2_cfg.image_slices.is_none() && compress_image(s2_cfg, &curr_file)? {
+        req_size = req_size.saturating_sub(get_file_size(&curr_file)?)
+            + estimate_compressed_size(&curr_file, s2_cfg.compression_level)?;
+    }
 
-    let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.config_path);
-    req_size += curr_file
-        .metadata()
-        .upstream_with_context(&format!(
-            "Failed to retrieve file size for '{}'",
-            curr_file.display()
-        ))?
-        .len() as u64;
+    req_size += get_preserve_size(s2_cfg);
+    Ok(req_size)
+}
 
-    if let Some(ref backup_path) = s2_cfg.backup_path {
-        let curr_file = path_append(OLD_ROOT_MP, backup_path);
-        req_size += curr_file
-            .metadata()
+/// Memory accounting for the takeover tmpfs.
+/// Files written to the tmpfs are held in memory, so staging files intentionally lowers free
+/// memory. Free memory plus the tmpfs usage stays constant while only we are writing to the tmpfs,
+/// a drop of that sum is memory consumed by something other than our own staged files.
+struct MemAccounting {
+    base_avail: u64,
+}
+
+impl MemAccounting {
+    fn new() -> Result<MemAccounting> {
+        let (mem_free, tmpfs_used) = MemAccounting::get_usage()?;
+        Ok(MemAccounting {
+            base_avail: mem_free + tmpfs_used,
+        })
+    }
+
+    /// returns free memory and the usage of the tmpfs mounted on /
+    fn get_usage() -> Result<(u64, u64)> {
+        let (_mem_tot, mem_free) = get_mem_info()?;
+        let stat = statvfs("/").upstream_with_context("Failed to stat root file system")?;
+        let tmpfs_used =
+            (stat.blocks() as u64 - stat.blocks_free() as u64) * stat.fragment_size() as u64;
+        Ok((mem_free, tmpfs_used))
+    }
+
+    /// returns free memory and the memory consumed outside the tmpfs since accounting started
+    fn check(&self) -> Result<(u64, u64)> {
+        let (mem_free, tmpfs_used) = MemAccounting::get_usage()?;
+        Ok((
+            mem_free,
+            self.base_avail.saturating_sub(mem_free + tmpfs_used),
+        ))
+    }
+}
+
+/// copy a file to tmpfs while monitoring free memory, aborting before memory runs out,
+/// the copy is zstd compressed with the given level if compress_level is set
+fn copy_with_mem_check(
+    src_path: &Path,
+    to_path: &Path,
+    compress_level: Option<i32>,
+) -> Result<u64> {
+    let mut src_file = File::open(src_path).upstream_with_context(&format!(
+        "Failed to open '{}' for reading",
+        src_path.display()
+    ))?;
+    let mut to_file = File::create(to_path).upstream_with_context(&format!(
+        "Failed to open '{}' for writing",
+        to_path.display()
+    ))?;
+    let src_size = get_file_size(src_path)?;
+
+    let tot_bytes = if let Some(level) = compress_level {
+        let mut encoder = zstd::stream::write::Encoder::new(to_file, level).upstream_with_context(
+            &format!("Failed to create zstd encoder for '{}'", to_path.display()),
+        )?;
+        let tot_bytes = copy_stream_with_mem_check(&mut src_file, &mut encoder, to_path)?;
+        encoder.finish().upstream_with_context(&format!(
+            "Failed to finish zstd stream in '{}'",
+            to_path.display()
+        ))?;
+        tot_bytes
+    } else {
+        copy_stream_with_mem_check(&mut src_file, &mut to_file, to_path)?
+    };
+
+    // the source partition is unmounted once the copy is done, a short copy can not be repeated
+    if tot_bytes != src_size {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Copied {} of {} from '{}', the source file changed or its file system went away",
+                format_size_with_unit(tot_bytes),
+                format_size_with_unit(src_size),
+                src_path.display()
+            ),
+        ));
+    }
+    Ok(tot_bytes)
+}
+
+fn copy_stream_with_mem_check<R: Read, W: Write>(
+    src_file: &mut R,
+    to_file: &mut W,
+    to_path: &Path,
+) -> Result<u64> {
+    let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
+    let mut tot_bytes: u64 = 0;
+    let mut last_check: u64 = 0;
+    let mut warned = false;
+    let mut warned_unexpected = false;
+    let mem_accounting = MemAccounting::new()?;
+
+    loop {
+        let bytes_read = fill_buffer(&mut buffer, src_file)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        to_file
+            .write_all(&buffer[0..bytes_read])
             .upstream_with_context(&format!(
-                "Failed to retrieve file size for '{}'",
-                curr_file.display()
-            ))?
-            .len() as u64;
+                "Failed to write to '{}' at offset 0x{:x}",
+                to_path.display(),
+                tot_bytes
+            ))?;
+        tot_bytes += bytes_read as u64;
+
+        if tot_bytes - last_check >= MEM_CHECK_INTERVAL {
+            last_check = tot_bytes;
+            // the absolute free memory decides about aborting, the unexpected usage is only
+            // reported as it is not caused by the copy
+            let (mem_free, unexpected) = mem_accounting.check()?;
+            if unexpected > S2_XTRA_FS_SIZE && !warned_unexpected {
+                warn!(
+                    "Memory used outside of the takeover file system grew by {} while copying to '{}'",
+                    format_size_with_unit(unexpected),
+                    to_path.display()
+                );
+                warned_unexpected = true;
+            }
+
+            if mem_free < S2_MIN_FREE_MEM {
+                error!(
+                    "Free memory dropped to {} after copying {} to '{}', aborting copy",
+                    format_size_with_unit(mem_free),
+                    format_size_with_unit(tot_bytes),
+                    to_path.display()
+                );
+                return Err(Error::displayed());
+            } else if mem_free < S2_XTRA_FS_SIZE && !warned {
+                warn!(
+                    "Free memory is low: {} after copying {} to '{}'",
+                    format_size_with_unit(mem_free),
+                    format_size_with_unit(tot_bytes),
+                    to_path.display()
+                );
+                warned = true;
+            }
+        }
+
+        if bytes_read < DD_BLOCK_SIZE {
+            break;
+        }
     }
 
+    Ok(tot_bytes)
+}
+
+fn get_required_inodes(s2_cfg: &Stage2Config) -> Result<u64> {
     let nwmgr_path = path_append(
         OLD_ROOT_MP,
-        path_append(&s2_cfg.work_dir, SYSTEM_CONNECTIONS_DIR),
+        path_append(&s2_cfg.work_dir, &s2_cfg.network_dir),
     );
 
-    for dir_entry in read_dir(&nwmgr_path).upstream_with_context(&format!(
-        "Failed to read drectory '{}'",
-        nwmgr_path.display()
-    ))? {
-        match dir_entry {
-            Ok(dir_entry) => {
-                req_size += dir_entry
-                    .path()
-                    .metadata()
-                    .upstream_with_context(&format!(
-                        "Failed to retrieve file size for: '{}'",
-                        dir_entry.path().display()
-                    ))?
-                    .len()
-            }
-            Err(why) => {
-                return Err(Error::from_upstream(
-                    Box::new(why),
-                    &format!(
-                        "Failed to retrieve directory entry for '{}'",
-                        nwmgr_path.display()
-                    ),
-                ));
-            }
-        }
+    let network_files = read_dir(&nwmgr_path)
+        .upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            nwmgr_path.display()
+        ))?
+        .count() as u64;
+
+    Ok(count_required_inodes(s2_cfg, network_files))
+}
+
+/// The inodes needed on the RAMFS for the files copied there, network_files is the number of
+/// files in the network configuration directory
+fn count_required_inodes(s2_cfg: &Stage2Config, network_files: u64) -> u64 {
+    // transfer dir, image, config.json and system-connections dir
+    let mut req_inodes: u64 = 4;
+
+    if s2_cfg.backup_path.is_some() {
+        req_inodes += 1;
     }
-    Ok(req_size)
+
+    req_inodes += s2_cfg
+        .preserve_parts
+        .iter()
+        .filter(|part| !part.in_place)
+        .count() as u64;
+
+    req_inodes + network_files
+}
+
+fn check_free_inodes(s2_cfg: &Stage2Config) -> Result<()> {
+    let req_inodes = get_required_inodes(s2_cfg)?;
+
+    let fs_stat = statvfs("/").upstream_with_context("Failed to statvfs tmpfs root")?;
+    let free_inodes = fs_stat.files_available() as u64;
+    debug!(
+        "Found {} free inodes on tmpfs, {} are required",
+        free_inodes, req_inodes
+    );
+
+    if free_inodes < req_inodes {
+        error!(
+            "Not enough free inodes found to copy files to RAMFS, required inodes: {}, free inodes: {}",
+            req_inodes, free_inodes
+        );
+        return Err(Error::displayed());
+    }
+
+    Ok(())
+}
+
+fn log_file_copy(src_path: &Path, to_path: &Path, size: u64) {
+    debug!(
+        "copy_files: '{}' -> '{}', {}",
+        src_path.display(),
+        to_path.display(),
+        format_size_with_unit(size)
+    );
 }
 
-fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
+/// copy the files needed in stage2 to tmpfs, returns the format of the staged image
+fn copy_files(s2_cfg: &Stage2Config) -> Result<ImageFormat> {
     let (mem_tot, mem_free) = get_mem_info()?;
     info!(
         "Found {} total, {} free memory",
@@ -146,7 +381,7 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
         return Err(Error::displayed());
     }
 
-    // TODO: check free mem against files to copy
+    check_free_inodes(s2_cfg)?;
 
     if !dir_exists(TRANSFER_DIR)? {
         create_dir(TRANSFER_DIR).upstream_with_context(&format!(
@@ -160,39 +395,81 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
 
     let src_path = path_append(OLD_ROOT_MP, &s2_cfg.image_path);
     let to_path = path_append(TRANSFER_DIR, BALENA_IMAGE_NAME);
-    copy(&src_path, &to_path).upstream_with_context(&format!(
-        "Failed to copy '{}' to {}",
-        src_path.display(),
-        &to_path.display()
-    ))?;
-    info!("Copied image to '{}'", to_path.display());
+    let image_format = if s2_cfg.no_flash {
+        // there is no image in no-flash mode
+        ImageFormat::Raw
+    } else if let Some(ref slices) = s2_cfg.image_slices {
+        for (index, partition) in slices.partitions.iter().enumerate() {
+            if let Some(src_path) = slices.image_path(index, Path::new(OLD_ROOT_MP)) {
+                let to_path = slice_transfer_path(index);
+                let image_size = copy_with_mem_check(&src_path, &to_path, None)?;
+                log_file_copy(&src_path, &to_path, image_size);
+                info!(
+                    "Copied image of partition '{}' to '{}'",
+                    partition.name,
+                    to_path.display()
+                );
+            }
+        }
+        ImageFormat::Raw
+    } else if compress_image(s2_cfg, &src_path)? {
+        let image_size = copy_with_mem_check(&src_path, &to_path, Some(s2_cfg.compression_level))?;
+        log_file_copy(&src_path, &to_path, image_size);
+        info!(
+            "Copied image to '{}', compressed {} to {}",
+            to_path.display(),
+            format_size_with_unit(image_size),
+            format_size_with_unit(get_file_size(&to_path)?)
+        );
+        ImageFormat::Zstd
+    } else {
+        let image_size = copy_with_mem_check(&src_path, &to_path, None)?;
+        log_file_copy(&src_path, &to_path, image_size);
+        info!("Copied image to '{}'", to_path.display());
+        get_image_format(&src_path, s2_cfg.image_format)?
+    };
+
+    if let Some(ref image_share) = s2_cfg.image_share {
+        // the network share must not keep the old root busy
+        let mountpoint = path_append(OLD_ROOT_MP, image_share);
+        match umount2(&mountpoint, MntFlags::MNT_DETACH) {
+            Ok(_) => info!("Unmounted the image share from '{}'", mountpoint.display()),
+            Err(why) => warn!(
+                "Failed to unmount the image share from '{}', error: {}",
+                mountpoint.display(),
+                why
+            ),
+        }
+    }
 
     let src_path = path_append(OLD_ROOT_MP, &s2_cfg.config_path);
     let to_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
-    copy(&src_path, &to_path).upstream_with_context(&format!(
+    let size = copy(&src_path, &to_path).upstream_with_context(&format!(
         "Failed to copy '{}' to {}",
         src_path.display(),
         &to_path.display()
     ))?;
+    log_file_copy(&src_path, &to_path, size);
     info!("Copied config to '{}'", to_path.display());
 
     if let Some(ref backup_path) = s2_cfg.backup_path {
         let src_path = path_append(OLD_ROOT_MP, backup_path);
         let to_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
-        copy(&src_path, &to_path).upstream_with_context(&format!(
+        let size = copy(&src_path, &to_path).upstream_with_context(&format!(
             "Failed to copy '{}' to {}",
             src_path.display(),
             &to_path.display()
         ))?;
+        log_file_copy(&src_path, &to_path, size);
         info!("Copied backup to '{}'", to_path.display());
     }
 
     let nwmgr_path = path_append(
         OLD_ROOT_MP,
-        path_append(&s2_cfg.work_dir, SYSTEM_CONNECTIONS_DIR),
+        path_append(&s2_cfg.work_dir, &s2_cfg.network_dir),
     );
 
-    let to_dir = path_append(TRANSFER_DIR, SYSTEM_CONNECTIONS_DIR);
+    let to_dir = path_append(TRANSFER_DIR, &s2_cfg.network_dir);
     if !dir_exists(&to_dir)? {
         create_dir_all(&to_dir).upstream_with_context(&format!(
             "Failed to create directory: '{}'",
@@ -201,18 +478,20 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
     }
 
     for dir_entry in read_dir(&nwmgr_path).upstream_with_context(&format!(
-        "Failed to read drectory '{}'",
+        "Failed to read directory '{}'",
         nwmgr_path.display()
     ))? {
         match dir_entry {
             Ok(dir_entry) => {
                 if let Some(filename) = dir_entry.path().file_name() {
                     let to_path = path_append(&to_dir, filename);
-                    copy(dir_entry.path(), &to_path).upstream_with_context(&format!(
+                    let size = copy(dir_entry.path(), &to_path).upstream_with_context(&format!(
                         "Failed to copy '{}' to '{}'",
                         dir_entry.path().display(),
                         to_path.display()
                     ))?;
+                    set_root_only(&to_path)?;
+                    log_file_copy(&dir_entry.path(), &to_path, size);
                     info!("Copied network config to '{}'", to_path.display());
                 } else {
                     return Err(Error::with_context(
@@ -236,7 +515,7 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(image_format)
 }
 
 pub(crate) fn read_stage2_config<P: AsRef<Path>>(path_prefix: Option<P>) -> Result<Stage2Config> {
@@ -252,8 +531,10 @@ pub(crate) fn read_stage2_config<P: AsRef<Path>>(path_prefix: Option<P>) -> Resu
             s2_cfg_path.display(),
         ))?;
 
-        Ok(Stage2Config::deserialze(&s2_cfg_txt)
-            .upstream_with_context("Failed to deserialize stage 2 config")?)
+        Stage2Config::deserialze(&s2_cfg_txt).upstream_with_context(&format!(
+            "Failed to deserialize stage 2 config from '{}'",
+            s2_cfg_path.display()
+        ))
     } else {
         Err(Error::with_context(
             ErrorKind::FileNotFound,
@@ -265,24 +546,33 @@ pub(crate) fn read_stage2_config<P: AsRef<Path>>(path_prefix: Option<P>) -> Resu
     }
 }
 
-fn setup_logging(log_dev: Option<&LogDevice>) {
+/// Log redacted records to stderr and log_path, as JSON records if json_log is set
+fn set_log_file(log_path: &Path, json_log: bool) -> Result<()> {
+    set_log_writer("stage2", Some(log_path), false, json_log)
+}
+
+/// Set up the stage2 log file, returns the path of the log file if one is written
+fn setup_logging(
+    log_dev: Option<&LogDevice>,
+    log_to_boot: bool,
+    json_log: bool,
+) -> Option<PathBuf> {
+    let mut log_path = None;
     if log_dev.is_some() {
         // Device should have been mounted by stage2-init
         match dir_exists("/mnt/log/") {
             Ok(exists) => {
                 if exists {
-                    Logger::set_log_file(
-                        &LogDestination::StreamStderr,
-                        PathBuf::from("/mnt/log/stage2.log").as_path(),
-                        false,
-                    )
-                    .unwrap_or_else(|why| {
-                        error!(
-                            "Failed to setup logging to /mnt/log/stage2.log, error: {:?}",
-                            why
-                        )
-                    });
-                    info!("Set logfile to /mnt/log/stage2.log");
+                    match set_log_file(PathBuf::from(LOG_DEV_PATH).as_path(), json_log) {
+                        Ok(_) => {
+                            info!("Set logfile to {}", LOG_DEV_PATH);
+                            log_path = Some(PathBuf::from(LOG_DEV_PATH));
+                        }
+                        Err(why) => error!(
+                            "Failed to setup logging to {}, error: {:?}",
+                            LOG_DEV_PATH, why
+                        ),
+                    }
                 }
             }
             Err(why) => {
@@ -291,8 +581,87 @@ fn setup_logging(log_dev: Option<&LogDevice>) {
         }
     }
 
+    if log_path.is_none() && log_to_boot {
+        // log to RAMFS, the log is transferred to the boot partition after flashing
+        match set_log_file(PathBuf::from(S2_LOG_PATH).as_path(), json_log) {
+            Ok(_) => {
+                info!("Set logfile to {}", S2_LOG_PATH);
+                log_path = Some(PathBuf::from(S2_LOG_PATH));
+            }
+            Err(why) => error!(
+                "Failed to setup logging to {}, error: {:?}",
+                S2_LOG_PATH, why
+            ),
+        }
+    }
+
+    if log_path.is_none() {
+        if let Err(why) = set_log_writer("stage2", None, false, json_log) {
+            error!("Failed to setup logging to stderr, error: {:?}", why);
+        }
+    }
+
     Logger::flush();
     sync();
+    log_path
+}
+
+/// Write the last BOOT_LOG_MAX_SIZE bytes of the stage2 log to the boot partition mounted on boot_mp.
+/// If the log was truncated the partial first line is dropped and replaced with '[...]'
+fn write_boot_log<P: AsRef<Path>>(log_path: &Path, boot_mp: P) -> Result<()> {
+    Logger::flush();
+
+    let mut log_file = File::open(log_path)
+        .upstream_with_context(&format!("Failed to open log file '{}'", log_path.display()))?;
+
+    let log_size = log_file
+        .metadata()
+        .upstream_with_context(&format!(
+            "Failed to read metadata from log file '{}'",
+            log_path.display()
+        ))?
+        .len();
+
+    let truncated = log_size > BOOT_LOG_MAX_SIZE;
+    if truncated {
+        log_file
+            .seek(SeekFrom::Start(log_size - BOOT_LOG_MAX_SIZE))
+            .upstream_with_context(&format!(
+                "Failed to seek in log file '{}'",
+                log_path.display()
+            ))?;
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    log_file
+        .read_to_end(&mut buffer)
+        .upstream_with_context(&format!("Failed to read log file '{}'", log_path.display()))?;
+
+    let target_path = path_append(boot_mp, BOOT_LOG_NAME);
+    let mut target_file = File::create(&target_path).upstream_with_context(&format!(
+        "Failed to create log file '{}'",
+        target_path.display()
+    ))?;
+
+    let content = if truncated {
+        target_file
+            .write_all(b"[...]\n")
+            .upstream_with_context(&format!("Failed to write to '{}'", target_path.display()))?;
+        if let Some(pos) = buffer.iter().position(|byte| *byte == b'\n') {
+            &buffer[pos + 1..]
+        } else {
+            &buffer[..]
+        }
+    } else {
+        &buffer[..]
+    };
+
+    target_file
+        .write_all(content)
+        .upstream_with_context(&format!("Failed to write to '{}'", target_path.display()))?;
+
+    info!("Wrote stage2 log to '{}'", target_path.display());
+    Ok(())
 }
 
 fn kill_procs(log_level: Level) -> Result<()> {
@@ -448,120 +817,394 @@ fn part_reread(device: &Path) -> Result<()> {
     }
 }
 
-fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
-    let src_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
-    let target_path = path_append(dev_root.as_ref(), BALENA_CONFIG_PATH);
-    copy(&src_path, &target_path).upstream_with_context(&format!(
-        "Failed to copy {} to {}",
-        src_path.display(),
-        target_path.display()
-    ))?;
-
-    info!("Successfully copied config.json to boot partition",);
-
-    let src_path = path_append(TRANSFER_DIR, SYSTEM_CONNECTIONS_DIR);
-    let dir_list = read_dir(&src_path).upstream_with_context(&format!(
-        "Failed to read directory '{}'",
-        src_path.display()
-    ))?;
-
-    let target_dir = path_append(dev_root.as_ref(), SYSTEM_CONNECTIONS_DIR);
-    debug!(
-        "Transfering files from '{}' to '{}'",
-        src_path.display(),
-        target_dir.display()
-    );
-
-    for entry in dir_list {
-        match entry {
-            Ok(entry) => {
-                let curr_file = entry.path();
-                debug!("Found source file '{}'", curr_file.display());
-                if entry
-                    .metadata()
-                    .upstream_with_context(&format!(
-                        "Failed to read metadata from file '{}'",
-                        curr_file.display()
-                    ))?
-                    .is_file()
-                {
-                    if let Some(filename) = curr_file.file_name() {
-                        let target_path = path_append(&target_dir, filename);
-                        copy(&curr_file, &target_path).upstream_with_context(&format!(
-                            "Failed to copy '{}' to '{}'",
-                            curr_file.display(),
-                            target_path.display()
-                        ))?;
-                        info!(
-                            "Successfully copied '{}' to boot partition as '{}",
-                            curr_file.display(),
-                            target_path.display()
-                        );
-                    } else {
-                        warn!(
-                            "Failed to extract filename from path '{}'",
-                            curr_file.display()
-                        );
-                    }
+/// Wait for the kernel to release the flash device once its partitions are unmounted, a partition
+/// rescan can keep it busy for a moment. The device is opened exclusively, which fails with EBUSY
+/// while it is in use, and its buffers are flushed. Flashing opens the device again.
+fn wait_for_flash_device(device: &Path, retries: u32) -> Result<()> {
+    let mut attempt: u32 = 0;
+    loop {
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_EXCL)
+            .open(device)
+        {
+            Ok(device_file) => {
+                let ioctl_res = unsafe { ioctl(device_file.as_raw_fd(), IOCTL_BLK_FLSBUF) };
+                if ioctl_res != 0 {
+                    warn!(
+                        "Failed to flush the buffers of '{}', error: {}",
+                        device.display(),
+                        io::Error::last_os_error()
+                    );
                 }
+                return Ok(());
+            }
+            Err(why) if why.raw_os_error() == Some(EBUSY) && attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "The flash device '{}' is busy, retrying in {} seconds ({}/{})",
+                    device.display(),
+                    FLASH_OPEN_RETRY_DELAY.as_secs(),
+                    attempt,
+                    retries
+                );
+                sync();
+                sleep(FLASH_OPEN_RETRY_DELAY);
             }
             Err(why) => {
-                return Err(Error::with_all(
-                    ErrorKind::Upstream,
-                    "Failed to read directory entry",
+                return Err(Error::from_upstream(
                     Box::new(why),
-                ));
+                    &format!(
+                        "Failed to open the flash device '{}' after {} retries",
+                        device.display(),
+                        attempt
+                    ),
+                ))
             }
         }
     }
-
-    Ok(())
 }
 
-fn get_partition_infos(device: &Path) -> Result<(PartInfo, PartInfo)> {
-    let mut disk = Disk::from_drive_file(device, None)?;
-    let part_iterator = PartitionIterator::new(&mut disk)?;
-    let mut boot_part: Option<PartInfo> = None;
-    let mut data_part: Option<PartInfo> = None;
-
-    for partition in part_iterator {
-        debug!(
-            "partition: {}, start: {}, sectors: {}",
-            partition.index, partition.start_lba, partition.num_sectors
-        );
-
-        match partition.index {
-            1 => {
-                boot_part = Some(partition);
-            }
-            2..=5 => debug!("Skipping partition {}", partition.index),
-            6 => {
-                data_part = Some(partition);
-                break;
-            }
-            _ => {
-                return Err(Error::with_context(
-                    ErrorKind::InvParam,
-                    &format!("Invalid partition index encountered: {}", partition.index),
-                ));
-            }
+/// Size of the regular files in dir, sub directories are not included
+fn get_dir_files_size(dir: &Path) -> Result<u64> {
+    let mut size: u64 = 0;
+    for entry in read_dir(dir)
+        .upstream_with_context(&format!("Failed to read directory '{}'", dir.display()))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry in '{}'",
+            dir.display()
+        ))?;
+        let metadata = entry.metadata().upstream_with_context(&format!(
+            "Failed to read metadata from file '{}'",
+            entry.path().display()
+        ))?;
+        if metadata.is_file() {
+            size += metadata.len();
         }
     }
+    Ok(size)
+}
 
-    if let Some(boot_part) = boot_part {
-        if let Some(data_part) = data_part {
-            Ok((boot_part, data_part))
-        } else {
-            Err(Error::with_context(
-                ErrorKind::NotFound,
-                &format!("Data partition could not be found on '{}", device.display()),
-            ))
-        }
-    } else {
+fn get_file_size(path: &Path) -> Result<u64> {
+    Ok(metadata(path)
+        .upstream_with_context(&format!(
+            "Failed to read metadata from file '{}'",
+            path.display()
+        ))?
+        .len())
+}
+
+/// Make sure the partition mounted on mountpoint has room for required bytes
+fn check_part_space<P: AsRef<Path>>(mountpoint: P, part_name: &str, required: u64) -> Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    let stat = statvfs(mountpoint).upstream_with_context(&format!(
+        "Failed to stat file system on '{}'",
+        mountpoint.display()
+    ))?;
+    let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    debug!(
+        "Partition {}: available space {}, required space {}",
+        part_name,
+        format_size_with_unit(available),
+        format_size_with_unit(required)
+    );
+    if available < required {
         Err(Error::with_context(
-            ErrorKind::NotFound,
-            &format!("Boot partition could not be found on '{}", device.display()),
+            ErrorKind::PartitionFull,
+            &format!(
+                "Target partition {} is full: {} required, {} available, short by {}",
+                part_name,
+                format_size_with_unit(required),
+                format_size_with_unit(available),
+                format_size_with_unit(required - available)
+            ),
         ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Space needed on the boot partition for the files copied by transfer_boot_files.
+/// Files that are replaced on the boot partition free their current size.
+fn get_boot_files_size<P: AsRef<Path>>(dev_root: P, network_dir: &str) -> Result<u64> {
+    let dev_root = dev_root.as_ref();
+    let mut required: u64 = 0;
+
+    let src_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
+    required += get_file_size(&src_path)?;
+    let target_path = path_append(dev_root, BALENA_CONFIG_PATH);
+    if let Ok(target_meta) = metadata(&target_path) {
+        required = required.saturating_sub(target_meta.len());
+    }
+
+    required += get_dir_files_size(&path_append(TRANSFER_DIR, network_dir))?;
+    Ok(required)
+}
+
+/// Check that the file at target_path has the same content as src_content and, for JSON files,
+/// that it parses
+fn verify_copy(src_content: &[u8], target_path: &Path, is_json: bool) -> Result<()> {
+    File::open(target_path)
+        .and_then(|file| file.sync_all())
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))?;
+
+    let target_content = read(target_path)
+        .upstream_with_context(&format!("Failed to read back '{}'", target_path.display()))?;
+
+    if target_content != src_content {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The content read back from '{}' does not match the source, read {} of {} bytes",
+                target_path.display(),
+                target_content.len(),
+                src_content.len()
+            ),
+        ));
+    }
+
+    if is_json {
+        serde_json::from_slice::<serde_json::Value>(&target_content).upstream_with_context(
+            &format!("'{}' does not contain valid JSON", target_path.display()),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Copy a file to the boot partition and read it back to make sure it landed intact, retrying
+/// the copy on a mismatch
+fn copy_verified(src_path: &Path, target_path: &Path, is_json: bool) -> Result<()> {
+    let src_content = read(src_path)
+        .upstream_with_context(&format!("Failed to read '{}'", src_path.display()))?;
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        copy(src_path, target_path).upstream_with_context(&format!(
+            "Failed to copy '{}' to '{}'",
+            src_path.display(),
+            target_path.display()
+        ))?;
+
+        match verify_copy(&src_content, target_path, is_json) {
+            Ok(_) => return Ok(()),
+            Err(why) => {
+                if attempt < BOOT_FILE_COPY_ATTEMPTS {
+                    warn!("{}, copying it again", why);
+                } else {
+                    error!(
+                        "Failed to verify '{}' after {} attempts: {}",
+                        target_path.display(),
+                        attempt,
+                        why
+                    );
+                    return Err(Error::displayed());
+                }
+            }
+        }
+    }
+}
+
+fn transfer_boot_files<P: AsRef<Path>>(dev_root: P, network_dir: &str) -> Result<()> {
+    check_part_space(
+        dev_root.as_ref(),
+        BALENA_BOOT_PART,
+        get_boot_files_size(dev_root.as_ref(), network_dir)?,
+    )?;
+
+    let src_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
+    let target_path = path_append(dev_root.as_ref(), BALENA_CONFIG_PATH);
+    copy_verified(&src_path, &target_path, true)?;
+
+    info!("Successfully copied and verified config.json on boot partition",);
+
+    let src_path = path_append(TRANSFER_DIR, network_dir);
+    let dir_list = read_dir(&src_path).upstream_with_context(&format!(
+        "Failed to read directory '{}'",
+        src_path.display()
+    ))?;
+
+    let target_dir = path_append(dev_root.as_ref(), network_dir);
+    if !dir_exists(&target_dir)? {
+        create_dir_all(&target_dir).upstream_with_context(&format!(
+            "Failed to create directory '{}'",
+            target_dir.display()
+        ))?;
+    }
+    debug!(
+        "Transfering files from '{}' to '{}'",
+        src_path.display(),
+        target_dir.display()
+    );
+
+    for entry in dir_list {
+        match entry {
+            Ok(entry) => {
+                let curr_file = entry.path();
+                debug!("Found source file '{}'", curr_file.display());
+                if entry
+                    .metadata()
+                    .upstream_with_context(&format!(
+                        "Failed to read metadata from file '{}'",
+                        curr_file.display()
+                    ))?
+                    .is_file()
+                {
+                    if let Some(filename) = curr_file.file_name() {
+                        let target_path = path_append(&target_dir, filename);
+                        copy_verified(&curr_file, &target_path, false)?;
+                        // the boot partition is usually vfat which has no per file permissions
+                        if let Err(why) = set_root_only(&target_path) {
+                            debug!(
+                                "Failed to restrict permissions of '{}', error: {}",
+                                target_path.display(),
+                                why
+                            );
+                        }
+                        info!(
+                            "Successfully copied '{}' to boot partition as '{}",
+                            curr_file.display(),
+                            target_path.display()
+                        );
+                    } else {
+                        warn!(
+                            "Failed to extract filename from path '{}'",
+                            curr_file.display()
+                        );
+                    }
+                }
+            }
+            Err(why) => {
+                return Err(Error::with_all(
+                    ErrorKind::Upstream,
+                    "Failed to read directory entry",
+                    Box::new(why),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the timezone and locale stage1 read from the old system to the boot partition,
+/// failures are logged only
+fn write_host_settings<P: AsRef<Path>>(s2_config: &Stage2Config, boot_mp: P) {
+    let settings = [
+        (
+            BOOT_TIMEZONE_NAME,
+            "timezone",
+            s2_config.timezone.as_ref().map(|tz| format!("{}\n", tz)),
+        ),
+        (
+            BOOT_LOCALE_NAME,
+            "locale",
+            s2_config
+                .locale
+                .as_ref()
+                .map(|locale| format!("LANG={}\n", locale)),
+        ),
+    ];
+
+    for (name, what, content) in settings.iter() {
+        if let Some(content) = content {
+            let target_path = path_append(boot_mp.as_ref(), name);
+            match File::create(&target_path).and_then(|mut file| file.write_all(content.as_bytes()))
+            {
+                Ok(_) => info!(
+                    "Wrote the {} '{}' to '{}'",
+                    what,
+                    content.trim_end(),
+                    target_path.display()
+                ),
+                Err(why) => warn!(
+                    "Failed to write the {} to '{}', error: {}",
+                    what,
+                    target_path.display(),
+                    why
+                ),
+            }
+        }
+    }
+}
+
+fn get_partition_infos(device: &Path, flash_offset: u64) -> Result<(PartInfo, PartInfo)> {
+    let mut disk = Disk::from_drive_file_at(device, None, flash_offset)?;
+    let partitions: Vec<PartInfo> = PartitionIterator::new(&mut disk)?.collect();
+
+    let mut labelled: Vec<(PartInfo, Option<String>)> = Vec::new();
+    for partition in partitions {
+        debug!(
+            "partition: {}, start: {}, sectors: {}",
+            partition.index, partition.start_lba, partition.num_sectors
+        );
+
+        let label = match disk.get_fs_label(&partition) {
+            Ok(Some(label)) => {
+                debug!("partition {} has label '{}'", partition.index, label);
+                Some(label)
+            }
+            Ok(None) => {
+                debug!("partition {} has no label", partition.index);
+                None
+            }
+            Err(why) => {
+                warn!(
+                    "Failed to read label of partition {}, error: {}",
+                    partition.index, why
+                );
+                None
+            }
+        };
+        labelled.push((partition, label));
+    }
+
+    select_balena_partitions(device, &labelled)
+}
+
+/// Find the balena boot and data partitions by their labels. The partition indices differ
+/// between device types, so a missing label is an error rather than a guess.
+fn select_balena_partitions(
+    device: &Path,
+    labelled: &[(PartInfo, Option<String>)],
+) -> Result<(PartInfo, PartInfo)> {
+    let find = |wanted: &str| {
+        labelled
+            .iter()
+            .find(|(_, label)| label.as_ref().map(|label| label.as_str()) == Some(wanted))
+            .map(|(partition, _)| partition.clone())
+    };
+
+    match (find(BALENA_BOOT_PART), find(BALENA_DATA_PART)) {
+        (Some(boot_part), Some(data_part)) => Ok((boot_part, data_part)),
+        (boot_part, _) => {
+            let found: Vec<String> = labelled
+                .iter()
+                .map(|(partition, label)| {
+                    format!(
+                        "{}: '{}'",
+                        partition.index,
+                        label.as_ref().map_or("<none>", |label| label.as_str())
+                    )
+                })
+                .collect();
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "No partition labelled '{}' could be found on '{}', found labels: [{}]",
+                    if boot_part.is_none() {
+                        BALENA_BOOT_PART
+                    } else {
+                        BALENA_DATA_PART
+                    },
+                    device.display(),
+                    found.join(", ")
+                ),
+            ))
+        }
     }
 }
 
@@ -629,8 +1272,71 @@ fn efi_setup(device: &Path) -> Result<()> {
     Ok(())
 }
 
-fn raw_mount_balena(device: &Path) -> Result<()> {
+/// Check the file system on device, file systems are repaired if possible.
+/// Fails if the check reports errors that could not be corrected.
+fn fsck_partition(device: &Path, fs_type: &str, part_name: &str) -> Result<()> {
+    let (fsck_cmd, fsck_arg, max_exit_code) = match fs_type {
+        // 0: no errors, 1: errors were corrected
+        BALENA_BOOT_FSTYPE => (FSCK_VFAT_CMD, "-a", 1),
+        // 0: no errors, 1: errors were corrected, 2: errors were corrected, reboot required
+        BALENA_DATA_FSTYPE => (E2FSCK_CMD, "-p", 2),
+        _ => {
+            warn!(
+                "No file system check available for file system type '{}' on partition {}",
+                fs_type, part_name
+            );
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Checking {} file system on partition {} using {}",
+        fs_type, part_name, fsck_cmd
+    );
+
+    let cmd_res = call(
+        &format!("/bin/{}", fsck_cmd),
+        &[fsck_arg, &*device.to_string_lossy()],
+        true,
+    )?;
+
+    for line in cmd_res.stdout.lines().chain(cmd_res.stderr.lines()) {
+        info!("{}: {}", fsck_cmd, line);
+    }
+
+    match cmd_res.status.code() {
+        Some(exit_code) if exit_code <= max_exit_code => {
+            if exit_code > 0 {
+                warn!(
+                    "{} corrected errors on partition {}, exit code: {}",
+                    fsck_cmd, part_name, exit_code
+                );
+            } else {
+                info!("File system on partition {} is clean", part_name);
+            }
+            Ok(())
+        }
+        Some(exit_code) => Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "{} reported unrecoverable errors on partition {}, exit code: {}",
+                fsck_cmd, part_name, exit_code
+            ),
+        )),
+        None => Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "{} was terminated by a signal while checking partition {}",
+                fsck_cmd, part_name
+            ),
+        )),
+    }
+}
+
+fn raw_mount_balena(s2_config: &Stage2Config, boot_log: Option<&Path>) -> Result<bool> {
     debug!("raw_mount_balena called");
+    let device = s2_config.flash_dev.as_path();
+    let flash_offset = s2_config.flash_offset;
 
     if !dir_exists(BALENA_PART_MP)? {
         create_dir(BALENA_PART_MP).upstream_with_context(&format!(
@@ -639,11 +1345,11 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
         ))?;
     }
 
-    let (boot_part, data_part) = get_partition_infos(device)?;
+    let (boot_part, data_part) = get_partition_infos(device, flash_offset)?;
 
     let mut loop_device = LoopDevice::get_free(true)?;
     info!("Create loop device: '{}'", loop_device.get_path().display());
-    let byte_offset = boot_part.start_lba * DEF_BLOCK_SIZE as u64;
+    let byte_offset = flash_offset + boot_part.start_lba * DEF_BLOCK_SIZE as u64;
     let size_limit = boot_part.num_sectors * DEF_BLOCK_SIZE as u64;
 
     debug!(
@@ -663,6 +1369,10 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
         loop_device.get_path().display()
     );
 
+    if s2_config.fsck_before_mount {
+        fsck_partition(loop_device.get_path(), BALENA_BOOT_FSTYPE, BALENA_BOOT_PART)?;
+    }
+
     mount(
         Some(loop_device.get_path()),
         BALENA_PART_MP,
@@ -683,10 +1393,25 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
     );
     // TODO: copy files
 
-    transfer_boot_files(BALENA_PART_MP)?;
+    if s2_config.config_in_image {
+        info!("config.json and the network configuration were placed in the image by stage1");
+    } else {
+        transfer_boot_files(BALENA_PART_MP, &s2_config.network_dir)?;
+    }
 
     efi_setup(device)?;
 
+    write_host_settings(s2_config, BALENA_PART_MP);
+
+    if let Some(log_path) = boot_log {
+        if let Err(why) = write_boot_log(log_path, BALENA_PART_MP) {
+            warn!(
+                "Failed to write stage2 log to boot partition, error: {:?}",
+                why
+            );
+        }
+    }
+
     sync();
 
     umount(BALENA_PART_MP).upstream_with_context("Failed to unmount boot partition")?;
@@ -694,9 +1419,10 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
     info!("Unmounted boot partition from {}", BALENA_PART_MP);
 
     let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
+    let backup_restored = file_exists(&backup_path);
 
-    if file_exists(&backup_path) {
-        let byte_offset = data_part.start_lba * DEF_BLOCK_SIZE as u64;
+    if backup_restored {
+        let byte_offset = flash_offset + data_part.start_lba * DEF_BLOCK_SIZE as u64;
         let size_limit = data_part.num_sectors * DEF_BLOCK_SIZE as u64;
 
         loop_device.modify_offset(byte_offset, size_limit)?;
@@ -709,6 +1435,10 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
             loop_device.get_path().display()
         );
 
+        if s2_config.fsck_before_mount {
+            fsck_partition(loop_device.get_path(), BALENA_DATA_FSTYPE, BALENA_DATA_PART)?;
+        }
+
         mount(
             Some(loop_device.get_path()),
             BALENA_PART_MP,
@@ -730,6 +1460,12 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
 
         // TODO: copy files
 
+        check_part_space(
+            BALENA_PART_MP,
+            BALENA_DATA_PART,
+            get_file_size(&backup_path)?,
+        )?;
+
         let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
         copy(&backup_path, &target_path).upstream_with_context(&format!(
             "Failed to copy '{}' to '{}'",
@@ -752,68 +1488,246 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
 
     loop_device.unset()?;
 
-    Ok(())
+    Ok(backup_restored)
 }
 
-#[allow(dead_code)]
-fn sys_mount_balena() -> Result<()> {
-    debug!("sys_mount_balena called");
-    sleep(Duration::from_secs(1));
-
-    let part_label = path_append(DISK_BY_LABEL_PATH, BALENA_BOOT_PART);
-    if !file_exists(&part_label) {
-        return Err(Error::with_context(
-            ErrorKind::NotFound,
-            &format!(
-                "Failed to locate path to boot partition in '{}'",
-                part_label.display()
-            ),
-        ));
+/// write the stage2 report to the boot partition of the flashed device
+fn write_boot_report(device: &Path, flash_offset: u64, report: &Stage2Report) -> Result<()> {
+    if !dir_exists(BALENA_PART_MP)? {
+        create_dir(BALENA_PART_MP).upstream_with_context(&format!(
+            "Failed to create balena partition mountpoint: '{}'",
+            BALENA_PART_MP
+        ))?;
     }
-    create_dir(BALENA_BOOT_MP).upstream_with_context(&format!(
-        "Failed to create balena-boot mountpoint: '{}'",
-        BALENA_BOOT_MP
-    ))?;
+
+    let (boot_part, _data_part) = get_partition_infos(device, flash_offset)?;
+
+    let mut loop_device = LoopDevice::get_free(true)?;
+    let byte_offset = flash_offset + boot_part.start_lba * DEF_BLOCK_SIZE as u64;
+    let size_limit = boot_part.num_sectors * DEF_BLOCK_SIZE as u64;
+    loop_device.setup(&device, Some(byte_offset), Some(size_limit))?;
 
     mount(
-        Some(&part_label),
-        BALENA_BOOT_MP,
+        Some(loop_device.get_path()),
+        BALENA_PART_MP,
         Some(BALENA_BOOT_FSTYPE.as_bytes()),
         MsFlags::empty(),
         NIX_NONE,
     )
     .upstream_with_context(&format!(
-        "Failed to mount '{}' to '{}'",
-        part_label.display(),
-        BALENA_BOOT_MP,
+        "Failed to mount {} on {}",
+        loop_device.get_path().display(),
+        BALENA_PART_MP
     ))?;
 
-    transfer_boot_files(BALENA_BOOT_MP)?;
+    let report_path = path_append(BALENA_PART_MP, BOOT_REPORT_NAME);
+    let res = write_report(&report_path, report);
 
-    umount(BALENA_BOOT_MP).upstream_with_context(&format!(
-        "Failed to unmount '{}' from '{}'",
-        part_label.display(),
-        BALENA_BOOT_MP
+    sync();
+    umount(BALENA_PART_MP).upstream_with_context("Failed to unmount boot partition")?;
+    loop_device.unset()?;
+
+    res
+}
+
+fn write_report(report_path: &Path, report: &Stage2Report) -> Result<()> {
+    let mut report_file = File::create(report_path).upstream_with_context(&format!(
+        "Failed to create stage2 report '{}'",
+        report_path.display()
     ))?;
+    report_file
+        .write_all(report.to_json()?.as_bytes())
+        .upstream_with_context(&format!(
+            "Failed to write stage2 report to '{}'",
+            report_path.display()
+        ))?;
+    info!("Wrote stage2 report to '{}'", report_path.display());
+    Ok(())
+}
 
-    let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
-    if file_exists(&backup_path) {
-        let part_label = path_append(DISK_BY_LABEL_PATH, BALENA_DATA_PART);
-        mount(
-            Some(&part_label),
-            BALENA_BOOT_MP,
-            Some(BALENA_DATA_FSTYPE.as_bytes()),
-            MsFlags::empty(),
-            NIX_NONE,
-        )
+fn write_metrics(metrics_path: &Path, metrics: &str) -> Result<()> {
+    let mut metrics_file = File::create(metrics_path).upstream_with_context(&format!(
+        "Failed to create metrics file '{}'",
+        metrics_path.display()
+    ))?;
+    metrics_file
+        .write_all(metrics.as_bytes())
         .upstream_with_context(&format!(
-            "Failed to mount '{}' to '{}'",
-            part_label.display(),
-            BALENA_BOOT_MP,
+            "Failed to write metrics to '{}'",
+            metrics_path.display()
         ))?;
+    info!("Wrote metrics to '{}'", metrics_path.display());
+    Ok(())
+}
 
-        let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
-        copy(&backup_path, &target_path).upstream_with_context(&format!(
+/// Complete the stage2 report, write it to the log device and to the boot partition if the
+/// image was flashed and reboot
+fn report_and_reboot(report: &mut Stage2Report, s2_config: &Stage2Config, start: Instant) -> ! {
+    report.finish(Instant::now().duration_since(start).as_secs());
+    info!(
+        "Stage2 {}, steps: {}",
+        if report.success {
+            "succeeded"
+        } else {
+            "failed"
+        },
+        report
+            .steps
+            .iter()
+            .map(|step| format!(
+                "{}: {}",
+                step.name,
+                if step.success { "ok" } else { "failed" }
+            ))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    if s2_config.log_dev().is_some() {
+        if let Ok(true) = dir_exists(LOG_DEV_MP) {
+            if let Err(why) = write_report(&path_append(LOG_DEV_MP, LOG_DEV_REPORT_NAME), report) {
+                warn!(
+                    "Failed to write stage2 report to log device, error: {:?}",
+                    why
+                );
+            }
+            if let Some(metrics) = &s2_config.metrics {
+                if let Err(why) = write_metrics(
+                    &path_append(LOG_DEV_MP, &metrics.path),
+                    &report.to_metrics(&metrics.device_type, &metrics.image_version),
+                ) {
+                    warn!("Failed to write metrics to log device, error: {:?}", why);
+                }
+            }
+        }
+    }
+
+    if report.bytes_flashed.is_some() {
+        if let Err(why) = write_boot_report(&s2_config.flash_dev, s2_config.flash_offset, report) {
+            warn!(
+                "Failed to write stage2 report to boot partition, error: {:?}",
+                why
+            );
+        }
+    }
+
+    if s2_config.set_rtc {
+        if let Err(why) = set_rtc_from_system() {
+            warn!("Failed to set the hardware clock, error: {:?}", why);
+        }
+    }
+
+    if s2_config.shell_after_flash && report.success && report.bytes_flashed.is_some() {
+        run_post_flash_shell();
+    }
+
+    Logger::flush();
+    sync();
+    reboot();
+}
+
+/// Run a busybox shell on the console and wait for the operator to exit it
+fn run_post_flash_shell() {
+    warn!("**********************************************************************");
+    warn!("The device is in a post-flash, pre-reboot state: balena OS has been");
+    warn!("flashed and files have been placed, the device has not been rebooted.");
+    warn!(
+        "Starting a busybox shell on {}, exit the shell to reboot.",
+        CONSOLE_DEV
+    );
+    warn!("**********************************************************************");
+    Logger::flush();
+    sync();
+
+    let console = match OpenOptions::new().read(true).write(true).open(CONSOLE_DEV) {
+        Ok(console) => console,
+        Err(why) => {
+            error!("Failed to open console '{}', error: {:?}", CONSOLE_DEV, why);
+            return;
+        }
+    };
+
+    match spawn_shell(console) {
+        Ok(status) => info!("Post-flash shell exited with {}, rebooting", status),
+        Err(why) => error!("Failed to run post-flash shell, error: {:?}", why),
+    }
+}
+
+fn spawn_shell(console: File) -> io::Result<ExitStatus> {
+    Command::new(format!("/bin/{}", BUSYBOX_CMD))
+        .arg("sh")
+        .env("PATH", "/bin")
+        .stdin(console.try_clone()?)
+        .stdout(console.try_clone()?)
+        .stderr(console)
+        .status()
+}
+
+#[allow(dead_code)]
+fn sys_mount_balena(network_dir: &str) -> Result<()> {
+    debug!("sys_mount_balena called");
+    sleep(Duration::from_secs(1));
+
+    let part_label = path_append(DISK_BY_LABEL_PATH, BALENA_BOOT_PART);
+    if !file_exists(&part_label) {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "Failed to locate path to boot partition in '{}'",
+                part_label.display()
+            ),
+        ));
+    }
+    create_dir(BALENA_BOOT_MP).upstream_with_context(&format!(
+        "Failed to create balena-boot mountpoint: '{}'",
+        BALENA_BOOT_MP
+    ))?;
+
+    mount(
+        Some(&part_label),
+        BALENA_BOOT_MP,
+        Some(BALENA_BOOT_FSTYPE.as_bytes()),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' to '{}'",
+        part_label.display(),
+        BALENA_BOOT_MP,
+    ))?;
+
+    transfer_boot_files(BALENA_BOOT_MP, network_dir)?;
+
+    umount(BALENA_BOOT_MP).upstream_with_context(&format!(
+        "Failed to unmount '{}' from '{}'",
+        part_label.display(),
+        BALENA_BOOT_MP
+    ))?;
+
+    let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
+    if file_exists(&backup_path) {
+        let part_label = path_append(DISK_BY_LABEL_PATH, BALENA_DATA_PART);
+        mount(
+            Some(&part_label),
+            BALENA_BOOT_MP,
+            Some(BALENA_DATA_FSTYPE.as_bytes()),
+            MsFlags::empty(),
+            NIX_NONE,
+        )
+        .upstream_with_context(&format!(
+            "Failed to mount '{}' to '{}'",
+            part_label.display(),
+            BALENA_BOOT_MP,
+        ))?;
+
+        check_part_space(
+            BALENA_PART_MP,
+            BALENA_DATA_PART,
+            get_file_size(&backup_path)?,
+        )?;
+
+        let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
+        copy(&backup_path, &target_path).upstream_with_context(&format!(
             "Failed to copy '{}' to '{}'",
             backup_path.display(),
             target_path.display()
@@ -832,12 +1746,12 @@ fn sys_mount_balena() -> Result<()> {
 }
 
 enum FlashState {
-    Success,
+    Success(u64),
     FailRecoverable,
     FailNonRecoverable,
 }
 
-fn fill_buffer<I: Read>(buffer: &mut [u8], input: &mut I) -> Result<usize> {
+fn fill_buffer<I: Read + ?Sized>(buffer: &mut [u8], input: &mut I) -> Result<usize> {
     // fill buffer
     let mut buff_fill: usize = 0;
     loop {
@@ -857,13 +1771,54 @@ fn fill_buffer<I: Read>(buffer: &mut [u8], input: &mut I) -> Result<usize> {
     Ok(buff_fill)
 }
 
-fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
+/// read the next block into buffer, the block returned is shorter than the buffer at the end of
+/// input and empty when the input is exhausted
+fn read_block<'a, I: Read + ?Sized>(buffer: &'a mut [u8], input: &mut I) -> Result<&'a [u8]> {
+    let buff_fill = fill_buffer(buffer, input)?;
+    Ok(&buffer[..buff_fill])
+}
+
+/// check that dd supports the flags used to write with O_DIRECT, busybox dd only has them if it
+/// was built with FEATURE_DD_IBS_OBS. The usage text lists the supported flags.
+fn dd_supports_direct(dd_cmd: &str) -> bool {
+    match Command::new(dd_cmd).arg("--help").output() {
+        Ok(output) => {
+            let usage = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            usage.contains("fullblock") && usage.contains("direct")
+        }
+        Err(why) => {
+            warn!("Failed to execute '{} --help', error: {:?}", dd_cmd, why);
+            false
+        }
+    }
+}
+
+/// Open the image as it is written to the flash device, with the flash transforms applied
+fn open_flash_image(
+    image_path: &Path,
+    image_format: ImageFormat,
+    transforms: &[FlashTransform],
+) -> Result<Box<dyn Read>> {
+    Ok(apply_transforms(
+        open_image(image_path, image_format)?,
+        transforms,
+    ))
+}
+
+fn validate(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    flash_offset: u64,
+    transforms: &[FlashTransform],
+) -> Result<bool> {
     debug!("Validate: opening: '{}'", image_path.display());
 
-    let mut decoder = GzDecoder::new(File::open(&image_path).upstream_with_context(&format!(
-        "Validate: Failed to open image file '{}'",
-        image_path.display(),
-    ))?);
+    let mut decoder = open_flash_image(image_path, image_format, transforms)?;
 
     debug!("Validate: opening output file '{}'", target_path.display());
     let mut target = OpenOptions::new()
@@ -876,6 +1831,14 @@ fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
             target_path.display(),
         ))?;
 
+    target
+        .seek(SeekFrom::Start(flash_offset))
+        .upstream_with_context(&format!(
+            "Validate: Failed to seek to offset 0x{:x} in output file '{}'",
+            flash_offset,
+            target_path.display(),
+        ))?;
+
     let mut gz_buffer: [u8; VALIDATE_BLOCK_SIZE] = [0; VALIDATE_BLOCK_SIZE];
     let mut tgt_buffer: [u8; VALIDATE_BLOCK_SIZE] = [0; VALIDATE_BLOCK_SIZE];
 
@@ -925,46 +1888,282 @@ fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
     Ok(err_count == 0)
 }
 
-fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashState {
-    let mut fail_res = FlashState::FailRecoverable;
-
-    let mut decoder = GzDecoder::new(match File::open(&image_path) {
-        Ok(file) => file,
+fn flash_external(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    dd_cmd: &str,
+    s2_config: &Stage2Config,
+) -> FlashState {
+    let mut decoder = match open_flash_image(image_path, image_format, &s2_config.flash_transforms)
+    {
+        Ok(decoder) => decoder,
         Err(why) => {
             error!(
                 "Flash: Failed to open image file '{}', error: {:?}",
                 image_path.display(),
                 why
             );
-            return fail_res;
+            return FlashState::FailRecoverable;
+        }
+    };
+
+    flash_stream(
+        target_path,
+        &mut decoder,
+        image_path,
+        s2_config.flash_offset,
+        dd_cmd,
+        s2_config,
+    )
+}
+
+fn get_device_size(device: &Path) -> Result<u64> {
+    File::open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?
+        .seek(SeekFrom::End(0))
+        .upstream_with_context(&format!(
+            "Failed to determine size of device '{}'",
+            device.display()
+        ))
+}
+
+/// write the partition table of the slice manifest to the device using sfdisk
+fn partition_device(device: &Path, script: &str) -> Result<()> {
+    debug!("partition_device: sfdisk script:\n{}", script);
+    let mut sfdisk = Command::new(format!("/bin/{}", SFDISK_CMD))
+        .arg(device)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .upstream_with_context(&format!("Failed to execute '{}'", SFDISK_CMD))?;
+
+    if let Some(mut stdin) = sfdisk.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .upstream_with_context(&format!("Failed to write to '{}' stdin", SFDISK_CMD))?;
+    }
+
+    let output = sfdisk
+        .wait_with_output()
+        .upstream_with_context(&format!("Failed to wait for '{}'", SFDISK_CMD))?;
+    if output.status.success() {
+        info!("Partitioned device '{}'", device.display());
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "{} failed to partition '{}': {}",
+                SFDISK_CMD,
+                device.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ))
+    }
+}
+
+/// Partition the device as described in the slice manifest and write the images into their
+/// partitions
+fn flash_slices(slices: &SliceManifest, dd_cmd: &str, s2_config: &Stage2Config) -> FlashState {
+    let target_path = &s2_config.flash_dev;
+
+    // the images have been copied to tmpfs
+    let mut staged = slices.clone();
+    for (index, partition) in staged.partitions.iter_mut().enumerate() {
+        if partition.image.is_some() {
+            partition.image = Some(slice_transfer_path(index));
+        }
+    }
+
+    let layout = match get_device_size(target_path).and_then(|dev_size| {
+        let layout = staged.layout(dev_size)?;
+        staged.check_images(&layout, Path::new("/"))?;
+        Ok(layout)
+    }) {
+        Ok(layout) => layout,
+        Err(why) => {
+            error!("Flash: invalid partition layout, error: {:?}", why);
+            return FlashState::FailRecoverable;
+        }
+    };
+
+    if let Some(part_layout) = layout
+        .iter()
+        .find(|part_layout| part_layout.start % s2_config.flash_block_size != 0)
+    {
+        error!(
+            "Flash: the partition offset 0x{:x} is not a multiple of the block size {}",
+            part_layout.start, s2_config.flash_block_size
+        );
+        return FlashState::FailRecoverable;
+    }
+
+    if let Err(why) = partition_device(target_path, &staged.sfdisk_script(&layout)) {
+        error!("Flash: failed to partition device, error: {:?}", why);
+        return FlashState::FailNonRecoverable;
+    }
+
+    write_slices(target_path, &staged, &layout, dd_cmd, s2_config)
+}
+
+/// Write the images of the slice manifest to the start of their partitions in layout
+fn write_slices(
+    target_path: &Path,
+    slices: &SliceManifest,
+    layout: &[SliceLayout],
+    dd_cmd: &str,
+    s2_config: &Stage2Config,
+) -> FlashState {
+    let mut tot_bytes: u64 = 0;
+    for (index, partition) in slices.partitions.iter().enumerate() {
+        if let Some(ref image_path) = partition.image {
+            info!(
+                "Flash: writing '{}' to partition '{}' at offset 0x{:x}",
+                image_path.display(),
+                partition.name,
+                layout[index].start
+            );
+            let mut image_file = match File::open(image_path) {
+                Ok(image_file) => image_file,
+                Err(why) => {
+                    error!(
+                        "Flash: Failed to open image file '{}', error: {:?}",
+                        image_path.display(),
+                        why
+                    );
+                    return FlashState::FailNonRecoverable;
+                }
+            };
+            match flash_stream(
+                target_path,
+                &mut image_file,
+                image_path,
+                layout[index].start,
+                dd_cmd,
+                s2_config,
+            ) {
+                FlashState::Success(bytes) => tot_bytes += bytes,
+                _ => return FlashState::FailNonRecoverable,
+            }
+        }
+    }
+
+    FlashState::Success(tot_bytes)
+}
+
+/// write the data read from decoder to target_path at flash_offset using dd
+fn flash_stream(
+    target_path: &Path,
+    decoder: &mut dyn Read,
+    image_path: &Path,
+    flash_offset: u64,
+    dd_cmd: &str,
+    s2_config: &Stage2Config,
+) -> FlashState {
+    let mut fail_res = FlashState::FailRecoverable;
+    let block_size = s2_config.flash_block_size;
+
+    if block_size == 0 || flash_offset % block_size != 0 {
+        error!(
+            "Flash: the flash offset 0x{:x} is not a multiple of the block size {}",
+            flash_offset, block_size
+        );
+        return fail_res;
+    }
+
+    // compressed images are only known to fit if their uncompressed size was checked in stage1
+    let dev_size = match get_device_size(target_path) {
+        Ok(dev_size) => Some(dev_size),
+        Err(why) => {
+            warn!(
+                "Flash: failed to determine the size of '{}', error: {}",
+                target_path.display(),
+                why
+            );
+            None
         }
-    });
+    };
+
+    // notrunc keeps the data behind the written range if the target is a file
+    let mut dd_args = vec![
+        format!("of={}", &target_path.to_string_lossy()),
+        format!("bs={}", block_size),
+        format!("seek={}", flash_offset / block_size),
+        "conv=notrunc".to_string(),
+    ];
+
+    let flash_direct = if s2_config.flash_direct {
+        if dd_supports_direct(dd_cmd) {
+            // reads from the pipe can be short, O_DIRECT needs complete blocks
+            dd_args.push("iflag=fullblock".to_string());
+            dd_args.push("oflag=direct".to_string());
+            true
+        } else {
+            warn!("Flash: '{}' does not support iflag=fullblock and oflag=direct, writing without O_DIRECT", dd_cmd);
+            false
+        }
+    } else {
+        false
+    };
 
-    debug!("invoking dd");
+    debug!("invoking dd with {:?}", dd_args);
     match Command::new(dd_cmd)
-        .args(&[
-            &format!("of={}", &target_path.to_string_lossy()),
-            &format!("bs={}", DD_BLOCK_SIZE),
-        ])
+        .args(&dd_args)
         .stdin(Stdio::piped())
         .spawn()
     {
         Ok(mut dd_cmd) => {
+            let mut tot_bytes: u64 = 0;
+            let mut too_slow = false;
+            let mut too_large = false;
             if let Some(stdin) = dd_cmd.stdin.as_mut() {
-                let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
-                let mut tot_bytes: u64 = 0;
+                let mut buffer: Vec<u8> = vec![0; block_size as usize];
                 let start_time = Instant::now();
+                let mut speed_guard = s2_config
+                    .min_flash_speed
+                    .map(|min_speed| SpeedGuard::new(min_speed, s2_config.min_flash_speed_window));
                 fail_res = FlashState::FailNonRecoverable;
 
                 loop {
                     // fill buffer
-                    match fill_buffer(&mut buffer, &mut decoder) {
-                        Ok(buff_fill) => {
+                    match read_block(&mut buffer, decoder) {
+                        Ok(block) => {
+                            let buff_fill = block.len();
                             if buff_fill > 0 {
-                                match stdin.write_all(&buffer) {
+                                if let Some(dev_size) = dev_size {
+                                    if flash_offset + tot_bytes + buff_fill as u64 > dev_size {
+                                        error!(
+                                            "The decompressed image exceeds the size {} of the flash device '{}' after {}, aborting",
+                                            format_size_with_unit(dev_size),
+                                            target_path.display(),
+                                            format_size_with_unit(tot_bytes)
+                                        );
+                                        too_large = true;
+                                        break;
+                                    }
+                                }
+                                // a short last block must not repeat stale data from the
+                                // previous one
+                                match stdin.write_all(block) {
                                     Ok(_) => {
                                         tot_bytes += buff_fill as u64;
-                                        if buff_fill < DD_BLOCK_SIZE {
+                                        add_flashed_bytes(buff_fill as u64);
+                                        if buff_fill < block_size as usize {
+                                            break;
+                                        }
+                                        if let Some(speed) = speed_guard
+                                            .as_mut()
+                                            .and_then(|guard| guard.check(tot_bytes))
+                                        {
+                                            error!(
+                                                "The flash device is too slow and likely failing: {}/sec during the last {} seconds, the minimum is {}/sec",
+                                                format_size_with_unit(speed),
+                                                s2_config.min_flash_speed_window,
+                                                format_size_with_unit(s2_config.min_flash_speed.unwrap_or(0))
+                                            );
+                                            too_slow = true;
                                             break;
                                         }
                                     }
@@ -982,7 +2181,7 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
                         }
                         Err(why) => {
                             error!(
-                                "Failed to read compressed data from '{}' at offset 0x{:x}:{}, error: {}:?",
+                                "Failed to read image data from '{}' at offset 0x{:x}:{}, error: {}:?",
                                 image_path.display(),
                                 tot_bytes,
                                 format_size_with_unit(tot_bytes),
@@ -995,22 +2194,32 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
 
                 let elapsed = Instant::now().duration_since(start_time).as_secs();
                 info!(
-                    "Wrote {} bytes, {} to dd in {} seconds @ {}/sec",
+                    "Wrote {} bytes, {} to dd in {} seconds @ {}/sec, block size {}{}",
                     tot_bytes,
                     format_size_with_unit(tot_bytes),
                     elapsed,
-                    format_size_with_unit(tot_bytes / elapsed),
+                    format_size_with_unit(tot_bytes / max(elapsed, 1)),
+                    format_size_with_unit(block_size),
+                    if flash_direct { ", O_DIRECT" } else { "" }
                 );
             } else {
                 error!("Failed to retrieve dd stdin");
                 return FlashState::FailRecoverable;
             }
 
+            if too_slow || too_large {
+                if let Err(why) = dd_cmd.kill() {
+                    warn!("Failed to terminate dd, error: {:?}", why);
+                }
+                let _res = dd_cmd.wait();
+                return fail_res;
+            }
+
             match dd_cmd.wait() {
                 Ok(status) => {
                     if status.success() {
                         info!("dd terminated successfully");
-                        FlashState::Success
+                        FlashState::Success(tot_bytes)
                     } else {
                         error!("dd terminated with exit code: {:?}", status.code());
                         FlashState::FailNonRecoverable
@@ -1032,6 +2241,253 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
     }
 }
 
+/// The range (start, length) behind the image on a device of dev_size bytes that is cleared,
+/// None if the image fills the device or slices were written, they bring their own layout
+fn remainder_range(s2_config: &Stage2Config, image_size: u64, dev_size: u64) -> Option<(u64, u64)> {
+    if s2_config.image_slices.is_some() {
+        return None;
+    }
+
+    let start = s2_config.flash_offset + image_size;
+    if dev_size > start {
+        Some((start, dev_size - start))
+    } else {
+        None
+    }
+}
+
+fn zero_remainder(s2_config: &Stage2Config, image_size: u64) -> Result<()> {
+    let device = s2_config.flash_dev.as_path();
+    let mut device_file = OpenOptions::new()
+        .write(true)
+        .create(false)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    let dev_size = device_file
+        .seek(SeekFrom::End(0))
+        .upstream_with_context(&format!(
+            "Failed to determine size of device '{}'",
+            device.display()
+        ))?;
+
+    let (start, length) = if let Some(range) = remainder_range(s2_config, image_size, dev_size) {
+        range
+    } else {
+        info!(
+            "No space left on device '{}' beyond image",
+            device.display()
+        );
+        return Ok(());
+    };
+
+    info!(
+        "Clearing {} on device '{}' beyond image at offset 0x{:x}",
+        format_size_with_unit(length),
+        device.display(),
+        start
+    );
+
+    let range: [u64; 2] = [start, length];
+    let ioctl_res = unsafe { ioctl(device_file.as_raw_fd(), IOCTL_BLK_DISCARD, range.as_ptr()) };
+    if ioctl_res == 0 {
+        info!("Discarded space beyond image on '{}'", device.display());
+        return Ok(());
+    }
+
+    debug!(
+        "Device BLKDISCARD IOCTRL to '{}' failed with error: {}, writing zeros instead",
+        device.display(),
+        io::Error::last_os_error()
+    );
+
+    device_file
+        .seek(SeekFrom::Start(start))
+        .upstream_with_context(&format!(
+            "Failed to seek to offset 0x{:x} on device '{}'",
+            start,
+            device.display()
+        ))?;
+
+    let buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let bytes = min(remaining, DD_BLOCK_SIZE as u64) as usize;
+        device_file
+            .write_all(&buffer[0..bytes])
+            .upstream_with_context(&format!(
+                "Failed to write zeros to device '{}' at offset 0x{:x}",
+                device.display(),
+                dev_size - remaining
+            ))?;
+        remaining -= bytes as u64;
+    }
+
+    info!("Zeroed space beyond image on '{}'", device.display());
+    Ok(())
+}
+
+/// Flash the staged image to the flash device and verify it, reboots if flashing failed
+fn flash(
+    s2_config: &Stage2Config,
+    image_format: ImageFormat,
+    report: &mut Stage2Report,
+    start: Instant,
+) {
+    let image_path = path_append(TRANSFER_DIR, BALENA_IMAGE_PATH);
+
+    let res = wait_for_flash_device(&s2_config.flash_dev, s2_config.flash_open_retries);
+    if !report.add_step("open_flash_device", &res) {
+        if let Err(why) = res {
+            error!("{}", why);
+        }
+        sleep(Duration::from_secs(10));
+        report_and_reboot(report, s2_config, start);
+    }
+
+    // the size of compressed images is not known before they are decompressed
+    let flash_total = if s2_config.image_slices.is_none() && image_format == ImageFormat::Raw {
+        metadata(&image_path).ok().map(|image_md| image_md.len())
+    } else {
+        None
+    };
+    let heartbeat = Heartbeat::start(flash_total);
+
+    // falls back to flashing the whole image if too much of it differs
+    let flash_start = Instant::now();
+    let delta_state = if s2_config.delta_flash && s2_config.image_slices.is_none() {
+        flash_delta(&s2_config.flash_dev, &image_path, image_format, s2_config)
+    } else {
+        None
+    };
+
+    let flash_state = if let Some(ref slices) = s2_config.image_slices {
+        flash_slices(slices, &format!("/bin/{}", DD_CMD), s2_config)
+    } else if let Some(flash_state) = delta_state {
+        flash_state
+    } else if s2_config.resumable_flash {
+        flash_resumable(&s2_config.flash_dev, &image_path, image_format, s2_config)
+    } else {
+        flash_external(
+            &s2_config.flash_dev,
+            &image_path,
+            image_format,
+            &format!("/bin/{}", DD_CMD),
+            s2_config,
+        )
+    };
+
+    let image_size = match flash_state {
+        FlashState::Success(image_size) => {
+            report.add_step("flash", &Ok(()));
+            report.bytes_flashed = Some(image_size);
+            report.flash_secs = Some(flash_start.elapsed().as_secs());
+            image_size
+        }
+        FlashState::FailRecoverable => {
+            heartbeat.stop();
+            report.add_failed_step("flash", "Flashing failed before the device was written to");
+            sleep(Duration::from_secs(10));
+            report_and_reboot(report, s2_config, start);
+        }
+        FlashState::FailNonRecoverable => {
+            heartbeat.stop();
+            report.add_failed_step("flash", "Flashing failed after the device was written to");
+            sleep(Duration::from_secs(10));
+            report_and_reboot(report, s2_config, start);
+        }
+    };
+
+    sync();
+
+    if s2_config.zero_remainder && s2_config.image_slices.is_none() {
+        let res = zero_remainder(s2_config, image_size);
+        report.add_step("zero_remainder", &res);
+        if let Err(why) = res {
+            error!(
+                "Failed to clear device '{}' beyond the image, error: {:?}",
+                s2_config.flash_dev.display(),
+                why
+            );
+        }
+        sync();
+    }
+    heartbeat.stop();
+
+    if !s2_config.preserve_parts.is_empty() {
+        let res = restore_partitions(s2_config);
+        report.add_step("restore_partitions", &res);
+        if let Err(why) = res {
+            error!("{}", why);
+        }
+        sync();
+    }
+    sleep(Duration::from_secs(5));
+
+    if DO_VALIDATE && s2_config.image_slices.is_none() {
+        let res = validate(
+            &s2_config.flash_dev,
+            &image_path,
+            image_format,
+            s2_config.flash_offset,
+            &s2_config.flash_transforms,
+        );
+        report.add_step("validate", &res);
+        match res {
+            Ok(res) => {
+                report.validated = Some(res);
+                if res {
+                    info!("Image validated successfully");
+                } else {
+                    error!("Image validation failed");
+                }
+            }
+            Err(why) => {
+                error!("Image validation returned error: {:?}", why);
+            }
+        }
+    }
+
+    if let (Some(percent), None) = (s2_config.verify_sample, &s2_config.image_slices) {
+        let res = verify_sample(
+            &s2_config.flash_dev,
+            &image_path,
+            image_format,
+            s2_config,
+            percent,
+        );
+        report.add_step("verify_sample", &res);
+        match res {
+            Ok(res) => {
+                report.validated = Some(res);
+                if res {
+                    info!("Image verified successfully using a {}% sample", percent);
+                } else {
+                    error!("Image verification using a {}% sample failed", percent);
+                }
+            }
+            Err(why) => {
+                error!("Image verification returned error: {}", why);
+            }
+        }
+    }
+
+    sleep(Duration::from_secs(5));
+
+    if s2_config.randomize_uuids {
+        let res = randomize_uuids(&s2_config.flash_dev, s2_config.flash_offset);
+        report.add_step("randomize_uuids", &res);
+        if let Err(why) = res {
+            error!(
+                "Failed to randomize UUIDs on '{}', error: {:?}",
+                s2_config.flash_dev.display(),
+                why
+            );
+        }
+        sync();
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn stage2(opts: &Options) -> ! {
     Logger::set_default_level(opts.s2_log_level());
@@ -1056,85 +2512,277 @@ pub fn stage2(opts: &Options) -> ! {
 
     info!("Stage 2 config was read successfully");
 
-    setup_logging(s2_config.log_dev());
+    let start = Instant::now();
+    let mut report = Stage2Report::new(&s2_config.flash_dev, s2_config.flash_offset);
+
+    let log_path = setup_logging(
+        s2_config.log_dev(),
+        s2_config.log_to_boot,
+        s2_config.json_log,
+    );
+
+    let res = kill_procs(opts.s2_log_level());
+    report.add_step("kill_procs", &res);
+    if let Err(why) = res {
+        error!("kill_procs failed, error {}", why);
+        report_and_reboot(&mut report, &s2_config, start);
+    }
 
-    match kill_procs(opts.s2_log_level()) {
-        Ok(_) => (),
+    let res = copy_files(&s2_config);
+    report.add_step("copy_files", &res);
+    let image_format = match res {
+        Ok(image_format) => image_format,
         Err(why) => {
-            error!("kill_procs failed, error {}", why);
-            reboot();
+            error!("Failed to copy files to RAMFS, error: {:?}", why);
+            report_and_reboot(&mut report, &s2_config, start);
         }
     };
 
-    match copy_files(&s2_config) {
-        Ok(_) => (),
-        Err(why) => {
-            error!("Failed to copy files to RAMFS, error: {:?}", why);
-            reboot();
+    if let Some(ref archive_target) = s2_config.archive_old_root {
+        let res = archive_old_root(archive_target);
+        report.add_step("archive_old_root", &res);
+        if let Err(why) = res {
+            error!("Failed to archive old root, error: {:?}", why);
+            report_and_reboot(&mut report, &s2_config, start);
         }
     }
 
-    match unmount_partitions(&s2_config.umount_parts) {
-        Ok(_) => (),
-        Err(why) => {
-            error!("unmount_partitions failed; {:?}", why);
-            reboot();
+    let res = unmount_partitions(&s2_config.umount_parts);
+    report.add_step("unmount_partitions", &res);
+    if let Err(why) = res {
+        error!("unmount_partitions failed; {:?}", why);
+        report_and_reboot(&mut report, &s2_config, start);
+    }
+
+    if let Some(timeout) = s2_config.await_approval {
+        let approval_dir = if s2_config.log_dev().is_some() {
+            Some(Path::new(LOG_DEV_MP))
+        } else {
+            None
+        };
+        let res = await_approval(timeout, approval_dir);
+        report.add_step("await_approval", &res);
+        if let Err(why) = res {
+            error!("{}", why);
+            report_and_reboot(&mut report, &s2_config, start);
+        }
+    }
+
+    if !s2_config.preserve_parts.is_empty() {
+        let res = save_partitions(&s2_config);
+        report.add_step("save_partitions", &res);
+        if let Err(why) = res {
+            error!("Failed to save partitions to preserve, error: {:?}", why);
+            report_and_reboot(&mut report, &s2_config, start);
         }
     }
 
     if s2_config.pretend {
         info!("Not flashing due to pretend mode");
-        reboot();
+        report_and_reboot(&mut report, &s2_config, start);
     }
 
     sync();
 
-    let image_path = path_append(TRANSFER_DIR, BALENA_IMAGE_PATH);
+    if s2_config.no_flash {
+        info!("Not flashing due to no-flash mode, placing files on the existing balena partitions");
+    } else {
+        flash(&s2_config, image_format, &mut report, start);
+    }
 
-    match flash_external(
-        &s2_config.flash_dev,
-        &image_path,
-        &format!("/bin/{}", DD_CMD),
-    ) {
-        FlashState::Success => (),
-        _ => {
-            sleep(Duration::from_secs(10));
-            reboot();
+    if (opts.s2_log_level() == Level::Debug) || (opts.s2_log_level() == Level::Trace) {
+        use crate::common::debug::check_loop_control;
+        check_loop_control("Stage2 after flash", "/dev");
+    }
+
+    let boot_log = if s2_config.log_to_boot {
+        log_path.as_deref()
+    } else {
+        None
+    };
+
+    let res = raw_mount_balena(&s2_config, boot_log);
+    report.add_step("transfer_files", &res);
+    match res {
+        Ok(backup_restored) => {
+            report.backup_restored = backup_restored;
+            info!("Migration succeded successfully");
         }
+        Err(why) => error!("Failed to transfer files to balena OS, error: {:?}", why),
     }
 
     sync();
-    sleep(Duration::from_secs(5));
 
-    if DO_VALIDATE {
-        match validate(&s2_config.flash_dev, &image_path) {
-            Ok(res) => {
-                if res {
-                    info!("Image validated successfully");
-                } else {
-                    error!("Image validation failed");
-                }
-            }
-            Err(why) => {
-                error!("Image validation returned error: {:?}", why);
-            }
+    report_and_reboot(&mut report, &s2_config, start);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::stage2_config::{test_config, PreservePart};
+    use std::fs::remove_dir_all;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn preserve_part(index: usize, in_place: bool) -> PreservePart {
+        PreservePart {
+            name: index.to_string(),
+            index,
+            start: 0,
+            size: 1024,
+            in_place,
         }
     }
 
-    sleep(Duration::from_secs(5));
+    fn partition(index: usize, label: Option<&str>) -> (PartInfo, Option<String>) {
+        (
+            PartInfo {
+                index,
+                ptype: 0x83,
+                status: 0,
+                start_lba: index as u64 * 2048,
+                num_sectors: 2048,
+            },
+            label.map(|label| label.to_string()),
+        )
+    }
 
-    if (opts.s2_log_level() == Level::Debug) || (opts.s2_log_level() == Level::Trace) {
-        use crate::common::debug::check_loop_control;
-        check_loop_control("Stage2 after flash", "/dev");
+    #[test]
+    fn test_select_balena_partitions() {
+        let device = Path::new("/dev/mmcblk0");
+        let labelled = vec![
+            partition(1, Some(BALENA_BOOT_PART)),
+            partition(2, Some("resin-rootA")),
+            partition(5, None),
+            partition(6, Some(BALENA_DATA_PART)),
+        ];
+        let (boot_part, data_part) = select_balena_partitions(device, &labelled).unwrap();
+        assert_eq!((boot_part.index, data_part.index), (1, 6));
+
+        // partition 6 exists but is not labelled as the data partition, it must not be used
+        let labelled = vec![
+            partition(1, Some(BALENA_BOOT_PART)),
+            partition(6, Some("other")),
+        ];
+        let why = select_balena_partitions(device, &labelled).unwrap_err();
+        assert_eq!(why.kind(), ErrorKind::NotFound);
+        assert!(why.to_string().contains("found labels: [1: '"));
+        assert!(why.to_string().contains("6: 'other'"));
     }
 
-    if let Err(why) = raw_mount_balena(&s2_config.flash_dev) {
-        error!("Failed to transfer files to balena OS, error: {:?}", why);
-    } else {
-        info!("Migration succeded successfully");
+    #[test]
+    fn test_count_required_inodes() {
+        let mut s2_cfg = test_config();
+        assert_eq!(count_required_inodes(&s2_cfg, 0), 4);
+        assert_eq!(count_required_inodes(&s2_cfg, 3), 7);
+
+        s2_cfg.backup_path = Some(PathBuf::from("/home/takeover/backup.tgz"));
+        s2_cfg.preserve_parts = vec![preserve_part(5, false), preserve_part(6, true)];
+        // the in place partition is not copied to the RAMFS
+        assert_eq!(count_required_inodes(&s2_cfg, 2), 8);
     }
 
-    sync();
+    #[test]
+    fn test_remainder_range() {
+        const DEV_SIZE: u64 = 64 * 1024 * 1024;
+        let mut s2_cfg = test_config();
 
-    reboot();
+        assert_eq!(
+            remainder_range(&s2_cfg, 1000, DEV_SIZE),
+            Some((1000, DEV_SIZE - 1000))
+        );
+        // the image fills the device exactly
+        assert_eq!(remainder_range(&s2_cfg, DEV_SIZE, DEV_SIZE), None);
+
+        s2_cfg.flash_offset = 4 * 1024 * 1024;
+        assert_eq!(
+            remainder_range(&s2_cfg, 1000, DEV_SIZE),
+            Some((
+                s2_cfg.flash_offset + 1000,
+                DEV_SIZE - s2_cfg.flash_offset - 1000
+            ))
+        );
+        assert_eq!(
+            remainder_range(&s2_cfg, DEV_SIZE - s2_cfg.flash_offset, DEV_SIZE),
+            None
+        );
+
+        s2_cfg.flash_offset = 0;
+        s2_cfg.image_slices = Some(
+            SliceManifest::parse("label: gpt\npartitions:\n- name: data\n  type: 83\n").unwrap(),
+        );
+        assert_eq!(remainder_range(&s2_cfg, 1000, DEV_SIZE), None);
+    }
+
+    #[test]
+    fn test_slice_write_ends_at_image() {
+        const DEV_SIZE: usize = 16 * 1024 * 1024;
+        const FILL: u8 = 0xaa;
+
+        let test_dir = std::env::temp_dir().join(format!(
+            "takeover-test-slices-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .subsec_nanos()
+        ));
+        create_dir_all(&test_dir).unwrap();
+
+        // the images end short of a block and of their partitions, the last partition has
+        // no image
+        let manifest = SliceManifest::parse(
+            "label: dos\n\
+             partitions:\n\
+             - name: boot\n  type: c\n  size: 1048576\n  image: boot.img\n\
+             - name: root\n  type: 83\n  size: 4194304\n  image: root.img\n\
+             - name: data\n  type: 83\n",
+        )
+        .unwrap();
+        let layout = manifest.layout(DEV_SIZE as u64).unwrap();
+
+        let mut images: Vec<Vec<u8>> = Vec::new();
+        for (index, size) in [(0usize, 1048576 - 1000), (1, 300_000)].iter() {
+            let image: Vec<u8> = (0..*size as u32)
+                .map(|offset| (offset % 251) as u8 + *index as u8 + 1)
+                .collect();
+            std::fs::write(manifest.image_path(*index, &test_dir).unwrap(), &image).unwrap();
+            images.push(image);
+        }
+
+        let device_path = test_dir.join("device.img");
+        std::fs::write(&device_path, vec![FILL; DEV_SIZE]).unwrap();
+
+        let mut staged = manifest.clone();
+        for (index, partition) in staged.partitions.iter_mut().enumerate() {
+            partition.image = manifest.image_path(index, &test_dir);
+        }
+
+        let s2_config = test_config();
+        let res = write_slices(&device_path, &staged, &layout, "dd", &s2_config);
+        let device = read(&device_path).unwrap();
+        remove_dir_all(&test_dir).unwrap();
+
+        match res {
+            FlashState::Success(bytes) => {
+                assert_eq!(bytes, (images[0].len() + images[1].len()) as u64)
+            }
+            _ => panic!("write_slices failed"),
+        }
+        assert_eq!(device.len(), DEV_SIZE);
+        assert!(device[..layout[0].start as usize]
+            .iter()
+            .all(|byte| *byte == FILL));
+        for (index, image) in images.iter().enumerate() {
+            let start = layout[index].start as usize;
+            let image_end = start + image.len();
+            assert_eq!(&device[start..image_end], image.as_slice());
+            // nothing is written past the image, up to the next partition
+            assert!(device[image_end..layout[index + 1].start as usize]
+                .iter()
+                .all(|byte| *byte == FILL));
+        }
+        assert!(device[layout[2].start as usize..]
+            .iter()
+            .all(|byte| *byte == FILL));
+    }
 }
@@ -0,0 +1,128 @@
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use log::{info, warn};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use crate::common::{
+    error::{Error, ErrorKind, Result, ToError},
+    file_exists, path_append,
+};
+
+const APPROVE_FILE_NAME: &str = "takeover-approve";
+const DENY_FILE_NAME: &str = "takeover-deny";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REMINDER_INTERVAL: u64 = 60;
+
+const PENDING: u8 = 0;
+const APPROVED: u8 = 1;
+const DENIED: u8 = 2;
+
+static APPROVAL: AtomicU8 = AtomicU8::new(PENDING);
+
+extern "C" fn handle_signal(signal: c_int) {
+    if signal == Signal::SIGUSR1 as c_int {
+        APPROVAL.store(APPROVED, Ordering::SeqCst);
+    } else if signal == Signal::SIGUSR2 as c_int {
+        APPROVAL.store(DENIED, Ordering::SeqCst);
+    }
+}
+
+fn set_handler(signal: Signal, handler: SigHandler) -> Result<()> {
+    let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(signal, &action) }
+        .upstream_with_context(&format!("Failed to set handler for signal {:?}", signal))?;
+    Ok(())
+}
+
+/// Block until flashing is approved by SIGUSR1 or by a takeover-approve file on the log device.
+/// Fails if it is denied by SIGUSR2 or a takeover-deny file or if nothing happens within the
+/// timeout. Nothing has been written to the flash device at this point, so the device still
+/// boots the old OS after a failure.
+pub(crate) fn await_approval(timeout: u64, approval_dir: Option<&Path>) -> Result<()> {
+    set_handler(Signal::SIGUSR1, SigHandler::Handler(handle_signal))?;
+    set_handler(Signal::SIGUSR2, SigHandler::Handler(handle_signal))?;
+
+    let approve_file = approval_dir.map(|dir| path_append(dir, APPROVE_FILE_NAME));
+    let deny_file = approval_dir.map(|dir| path_append(dir, DENY_FILE_NAME));
+
+    let pid = process::id();
+    warn!(
+        "Waiting up to {} seconds for approval to flash, the device has not been written to yet",
+        timeout
+    );
+    info!(
+        "To approve send SIGUSR1 (kill -USR1 {}), to abort send SIGUSR2 (kill -USR2 {})",
+        pid, pid
+    );
+    if let (Some(approve_file), Some(deny_file)) = (&approve_file, &deny_file) {
+        info!(
+            "Alternatively create '{}' to approve or '{}' to abort on the log device",
+            approve_file.display(),
+            deny_file.display()
+        );
+    }
+
+    let start = Instant::now();
+    let mut last_reminder = 0;
+    let res = loop {
+        let mut approval = APPROVAL.load(Ordering::SeqCst);
+        if approval == PENDING {
+            // a deny file wins over an approve file
+            if let Some(approve_file) = &approve_file {
+                if file_exists(approve_file) {
+                    approval = APPROVED;
+                }
+            }
+            if let Some(deny_file) = &deny_file {
+                if file_exists(deny_file) {
+                    approval = DENIED;
+                }
+            }
+        }
+
+        match approval {
+            APPROVED => {
+                info!("Flashing was approved");
+                break Ok(());
+            }
+            DENIED => {
+                break Err(Error::with_context(
+                    ErrorKind::InvState,
+                    "Flashing was denied, not flashing",
+                ));
+            }
+            _ => (),
+        }
+
+        let elapsed = start.elapsed().as_secs();
+        if elapsed >= timeout {
+            break Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Flashing was not approved within {} seconds, not flashing",
+                    timeout
+                ),
+            ));
+        }
+
+        if elapsed / REMINDER_INTERVAL > last_reminder {
+            last_reminder = elapsed / REMINDER_INTERVAL;
+            info!(
+                "Still waiting for approval to flash, {} seconds left",
+                timeout - elapsed
+            );
+        }
+
+        sleep(POLL_INTERVAL);
+    };
+
+    // the default action would terminate stage2 while flashing
+    set_handler(Signal::SIGUSR1, SigHandler::SigIgn)?;
+    set_handler(Signal::SIGUSR2, SigHandler::SigIgn)?;
+    res
+}
@@ -0,0 +1,263 @@
+use std::fs::{create_dir_all, read_dir, remove_file, symlink_metadata, File};
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+use flate2::{write::GzEncoder, Compression};
+use libc::{ENOSPC, MS_RDONLY, MS_REMOUNT};
+use log::{debug, error, info, warn};
+use nix::{
+    mount::{mount, umount, MsFlags},
+    sys::statvfs::statvfs,
+};
+use tar::Builder;
+
+use crate::common::{
+    defs::{NIX_NONE, OLD_ROOT_MP},
+    error::{Error, ErrorKind, Result, ToError},
+    format_size_with_unit, path_append,
+    stage2_config::ArchiveTarget,
+};
+
+const ARCHIVE_MP: &str = "/mnt/archive";
+const OLD_ROOT_ARCHIVE_NAME: &str = "takeover-old-root.tar.gz";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counts the bytes written and remembers if the destination ran out of space
+struct ArchiveWriter<W: Write> {
+    inner: W,
+    written: u64,
+    full: bool,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(inner: W) -> ArchiveWriter<W> {
+        ArchiveWriter {
+            inner,
+            written: 0,
+            full: false,
+        }
+    }
+}
+
+impl<W: Write> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(bytes) => {
+                self.written += bytes as u64;
+                Ok(bytes)
+            }
+            Err(why) => {
+                if why.raw_os_error() == Some(ENOSPC) {
+                    self.full = true;
+                }
+                Err(why)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Archive the old root file system to the target, returns the size of the archive.
+/// The old root is remounted read-only first so the archive is consistent. Only the root file
+/// system is archived, file systems mounted below it are skipped.
+pub(crate) fn archive_old_root(target: &ArchiveTarget) -> Result<u64> {
+    if let Err(why) = mount(
+        NIX_NONE,
+        OLD_ROOT_MP,
+        NIX_NONE,
+        MsFlags::from_bits(MS_REMOUNT | MS_RDONLY).unwrap(),
+        NIX_NONE,
+    ) {
+        warn!(
+            "Failed to remount '{}' as readonly, error: {:?}",
+            OLD_ROOT_MP, why
+        );
+    } else {
+        info!("Remounted '{}' as readonly", OLD_ROOT_MP);
+    }
+
+    match target {
+        ArchiveTarget::Device { dev_name, fs_type } => archive_to_device(dev_name, fs_type),
+        ArchiveTarget::Network { address } => {
+            info!("Archiving old root to {}", address);
+            let stream = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)
+                .upstream_with_context(&format!("Failed to connect to '{}'", address))?;
+            let mut writer = ArchiveWriter::new(stream);
+            write_archive(&mut writer, &address.to_string())?;
+            if let Err(why) = writer.inner.shutdown(Shutdown::Both) {
+                warn!("Failed to shut down connection to {}: {:?}", address, why);
+            }
+            info!(
+                "Sent old root archive of {} to {}",
+                format_size_with_unit(writer.written),
+                address
+            );
+            Ok(writer.written)
+        }
+    }
+}
+
+fn archive_to_device(dev_name: &Path, fs_type: &str) -> Result<u64> {
+    create_dir_all(ARCHIVE_MP).upstream_with_context(&format!(
+        "Failed to create archive mountpoint '{}'",
+        ARCHIVE_MP
+    ))?;
+
+    mount(
+        Some(dev_name),
+        ARCHIVE_MP,
+        Some(fs_type),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' on '{}'",
+        dev_name.display(),
+        ARCHIVE_MP
+    ))?;
+
+    let res = write_archive_file(dev_name);
+
+    if let Err(why) = umount(ARCHIVE_MP) {
+        warn!(
+            "Failed to unmount archive device '{}', error: {:?}",
+            dev_name.display(),
+            why
+        );
+    }
+    res
+}
+
+fn write_archive_file(dev_name: &Path) -> Result<u64> {
+    if let (Ok(root_stat), Ok(dest_stat)) = (statvfs(OLD_ROOT_MP), statvfs(ARCHIVE_MP)) {
+        let used = (root_stat.blocks() as u64 - root_stat.blocks_free() as u64)
+            * root_stat.fragment_size() as u64;
+        let available = dest_stat.blocks_available() as u64 * dest_stat.fragment_size() as u64;
+        if available < used {
+            warn!(
+                "The archive device '{}' has {} available for {} of old root, the archive fits only if it compresses well",
+                dev_name.display(),
+                format_size_with_unit(available),
+                format_size_with_unit(used)
+            );
+        }
+    }
+
+    let archive_path = path_append(ARCHIVE_MP, OLD_ROOT_ARCHIVE_NAME);
+    info!(
+        "Archiving old root to '{}' on '{}'",
+        OLD_ROOT_ARCHIVE_NAME,
+        dev_name.display()
+    );
+
+    let file = File::create(&archive_path).upstream_with_context(&format!(
+        "Failed to create archive file '{}'",
+        archive_path.display()
+    ))?;
+    let mut writer = ArchiveWriter::new(file);
+    let res = write_archive(&mut writer, &dev_name.display().to_string()).and_then(|_| {
+        writer.inner.sync_all().upstream_with_context(&format!(
+            "Failed to sync archive file '{}'",
+            archive_path.display()
+        ))
+    });
+
+    if let Err(why) = res {
+        // a partial archive is of no use and would occupy the space on the device
+        if let Err(why) = remove_file(&archive_path) {
+            warn!(
+                "Failed to remove partial archive '{}', error: {:?}",
+                archive_path.display(),
+                why
+            );
+        }
+        return Err(why);
+    }
+
+    info!(
+        "Wrote old root archive of {} to '{}'",
+        format_size_with_unit(writer.written),
+        dev_name.display()
+    );
+    Ok(writer.written)
+}
+
+/// write a gzip compressed tar of the old root to writer
+fn write_archive<W: Write>(writer: &mut ArchiveWriter<W>, dest: &str) -> Result<()> {
+    let root_dev = symlink_metadata(OLD_ROOT_MP)
+        .upstream_with_context(&format!("Failed to stat '{}'", OLD_ROOT_MP))?
+        .dev();
+
+    let res = {
+        let mut builder = Builder::new(GzEncoder::new(&mut *writer, Compression::default()));
+        builder.follow_symlinks(false);
+        append_dir(
+            &mut builder,
+            root_dev,
+            Path::new(OLD_ROOT_MP),
+            Path::new(""),
+        )
+        .and_then(|_| builder.into_inner()?.finish().map(|_| ()))
+    };
+
+    match res {
+        Ok(_) => Ok(()),
+        Err(why) => {
+            if writer.full {
+                error!(
+                    "The archive destination '{}' is full after writing {}",
+                    dest,
+                    format_size_with_unit(writer.written)
+                );
+                Err(Error::with_context(
+                    ErrorKind::PartitionFull,
+                    &format!("The archive destination '{}' is full", dest),
+                ))
+            } else {
+                Err(Error::from_upstream(
+                    Box::new(why),
+                    &format!("Failed to write old root archive to '{}'", dest),
+                ))
+            }
+        }
+    }
+}
+
+/// add the contents of dir to the archive, directories on other devices are added empty
+fn append_dir<W: Write>(
+    builder: &mut Builder<W>,
+    root_dev: u64,
+    dir: &Path,
+    name: &Path,
+) -> io::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let entry_name = name.join(entry.file_name());
+        let metadata = symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            builder.append_dir(&entry_name, &path)?;
+            if metadata.dev() == root_dev {
+                append_dir(builder, root_dev, &path, &entry_name)?;
+            } else {
+                debug!(
+                    "append_dir: not descending into mount point '{}'",
+                    path.display()
+                );
+            }
+        } else if file_type.is_file() || file_type.is_symlink() {
+            builder.append_path_with_name(&path, &entry_name)?;
+        } else {
+            debug!("append_dir: skipping special file '{}'", path.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc::{posix_fadvise, POSIX_FADV_DONTNEED};
+use log::{error, info, warn};
+use openssl::sha::Sha256;
+
+use crate::common::{
+    error::{Error, ErrorKind, Result, ToError},
+    format_size_with_unit,
+    image_format::ImageFormat,
+    stage2_config::Stage2Config,
+};
+
+use super::{fill_buffer, heartbeat::add_flashed_bytes, open_flash_image, FlashState};
+
+const DELTA_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// chunks of the image that differ from the flash device
+struct Comparison {
+    changed: Vec<u64>,
+    chunks: u64,
+    image_size: u64,
+}
+
+fn seek_to(device: &mut File, offset: u64, target_path: &Path) -> Result<()> {
+    device
+        .seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!(
+            "Failed to seek to offset 0x{:x} on '{}'",
+            offset,
+            target_path.display()
+        ))?;
+    Ok(())
+}
+
+/// Read image and flash device chunk by chunk and collect the chunks that differ
+fn compare(
+    device: &mut File,
+    target_path: &Path,
+    decoder: &mut dyn Read,
+    flash_offset: u64,
+) -> Result<Comparison> {
+    seek_to(device, flash_offset, target_path)?;
+
+    let mut img_buffer: Vec<u8> = vec![0; DELTA_CHUNK_SIZE];
+    let mut dev_buffer: Vec<u8> = vec![0; DELTA_CHUNK_SIZE];
+    let mut comparison = Comparison {
+        changed: Vec::new(),
+        chunks: 0,
+        image_size: 0,
+    };
+
+    loop {
+        let img_read = fill_buffer(&mut img_buffer, decoder)?;
+        if img_read == 0 {
+            break;
+        }
+        let dev_read = fill_buffer(&mut dev_buffer[0..img_read], device)?;
+        if dev_read < img_read || img_buffer[0..img_read] != dev_buffer[0..img_read] {
+            comparison.changed.push(comparison.chunks);
+        }
+        comparison.chunks += 1;
+        comparison.image_size += img_read as u64;
+        if img_read < DELTA_CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok(comparison)
+}
+
+/// Write the changed chunks of the image to the flash device, returns the number of bytes
+/// written and the SHA-256 of the whole image
+fn write_changed(
+    device: &mut File,
+    target_path: &Path,
+    decoder: &mut dyn Read,
+    flash_offset: u64,
+    changed: &[u64],
+) -> Result<(u64, [u8; 32])> {
+    let mut buffer: Vec<u8> = vec![0; DELTA_CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut changed = changed.iter().peekable();
+    let mut chunk: u64 = 0;
+    let mut written: u64 = 0;
+
+    loop {
+        let img_read = fill_buffer(&mut buffer, decoder)?;
+        if img_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..img_read]);
+
+        if changed.peek() == Some(&&chunk) {
+            changed.next();
+            let offset = flash_offset + chunk * DELTA_CHUNK_SIZE as u64;
+            seek_to(device, offset, target_path)?;
+            device
+                .write_all(&buffer[0..img_read])
+                .upstream_with_context(&format!(
+                    "Failed to write to '{}' at offset 0x{:x}",
+                    target_path.display(),
+                    offset
+                ))?;
+            written += img_read as u64;
+            add_flashed_bytes(img_read as u64);
+        }
+
+        chunk += 1;
+        if img_read < DELTA_CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok((written, hasher.finish()))
+}
+
+/// SHA-256 of size bytes of the flash device starting at flash_offset, read from the device
+/// rather than the page cache
+pub(crate) fn hash_device(
+    device: &mut File,
+    target_path: &Path,
+    flash_offset: u64,
+    size: u64,
+) -> Result<[u8; 32]> {
+    device
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))?;
+    if unsafe { posix_fadvise(device.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) } != 0 {
+        warn!(
+            "Failed to drop cached data of '{}', the hash might be computed from the cache",
+            target_path.display()
+        );
+    }
+
+    seek_to(device, flash_offset, target_path)?;
+    let mut buffer: Vec<u8> = vec![0; DELTA_CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut remaining = size;
+    while remaining > 0 {
+        let to_read = std::cmp::min(remaining, DELTA_CHUNK_SIZE as u64) as usize;
+        let dev_read = fill_buffer(&mut buffer[0..to_read], device)?;
+        if dev_read < to_read {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Unexpected end of '{}' while verifying the flashed image",
+                    target_path.display()
+                ),
+            ));
+        }
+        hasher.update(&buffer[0..dev_read]);
+        remaining -= dev_read as u64;
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Write only the parts of the image that differ from the contents of the flash device and
+/// verify the result. Returns None if the whole image has to be flashed instead, either because
+/// more than the configured percentage of the image differs or because the comparison failed.
+/// Nothing has been written to the device in that case.
+pub(crate) fn flash_delta(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    s2_config: &Stage2Config,
+) -> Option<FlashState> {
+    let flash_offset = s2_config.flash_offset;
+    let mut device = match OpenOptions::new().read(true).write(true).open(target_path) {
+        Ok(device) => device,
+        Err(why) => {
+            warn!(
+                "Delta flash: failed to open '{}', error: {:?}",
+                target_path.display(),
+                why
+            );
+            return None;
+        }
+    };
+
+    let comparison = match open_flash_image(image_path, image_format, &s2_config.flash_transforms)
+        .and_then(|mut decoder| compare(&mut device, target_path, &mut decoder, flash_offset))
+    {
+        Ok(comparison) => comparison,
+        Err(why) => {
+            warn!(
+                "Delta flash: failed to compare image to flash device: {}",
+                why
+            );
+            return None;
+        }
+    };
+
+    let changed_percent = if comparison.chunks > 0 {
+        comparison.changed.len() as u64 * 100 / comparison.chunks
+    } else {
+        0
+    };
+    info!(
+        "Delta flash: {} of {} chunks ({}%) of the image differ from '{}'",
+        comparison.changed.len(),
+        comparison.chunks,
+        changed_percent,
+        target_path.display()
+    );
+
+    if changed_percent > s2_config.delta_flash_threshold {
+        info!(
+            "Delta flash: more than {}% differ, flashing the whole image",
+            s2_config.delta_flash_threshold
+        );
+        return None;
+    }
+
+    let image_hash = match open_flash_image(image_path, image_format, &s2_config.flash_transforms)
+        .and_then(|mut decoder| {
+            write_changed(
+                &mut device,
+                target_path,
+                &mut decoder,
+                flash_offset,
+                &comparison.changed,
+            )
+        }) {
+        Ok((written, image_hash)) => {
+            info!(
+                "Delta flash: wrote {} of {} to '{}'",
+                format_size_with_unit(written),
+                format_size_with_unit(comparison.image_size),
+                target_path.display()
+            );
+            image_hash
+        }
+        Err(why) => {
+            error!("Delta flash: failed to write changed chunks: {}", why);
+            return Some(FlashState::FailNonRecoverable);
+        }
+    };
+
+    match hash_device(
+        &mut device,
+        target_path,
+        flash_offset,
+        comparison.image_size,
+    ) {
+        Ok(device_hash) => {
+            if device_hash == image_hash {
+                info!("Delta flash: SHA-256 of the flash device matches the image");
+                Some(FlashState::Success(comparison.image_size))
+            } else {
+                error!("Delta flash: SHA-256 of the flash device does not match the image");
+                Some(FlashState::FailNonRecoverable)
+            }
+        }
+        Err(why) => {
+            error!("Delta flash: failed to verify the flash device: {}", why);
+            Some(FlashState::FailNonRecoverable)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use mod_logger::Logger;
+
+use crate::common::format_size_with_unit;
+
+const HEARTBEAT_INTERVAL: u64 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// bytes written to the flash device, updated by the flash loop
+static FLASHED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn add_flashed_bytes(bytes: u64) {
+    FLASHED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Logs a message at a fixed interval from its own thread while the flash device is written
+/// and synced, so a flash that is slowly progressing can be told apart from a hung stage2 on the
+/// console and in the log, even while no data is being written.
+pub(crate) struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Start the heartbeat, total is the number of bytes that will be flashed if it is known
+    pub fn start(total: Option<u64>) -> Heartbeat {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let start = Instant::now();
+
+        let thread = thread::Builder::new()
+            .name("heartbeat".to_string())
+            .spawn(move || {
+                let mut next_beat = HEARTBEAT_INTERVAL;
+                while !thread_stop.load(Ordering::SeqCst) {
+                    sleep(POLL_INTERVAL);
+                    let elapsed = start.elapsed().as_secs();
+                    if elapsed < next_beat {
+                        continue;
+                    }
+                    next_beat = elapsed + HEARTBEAT_INTERVAL;
+
+                    let flashed = FLASHED_BYTES.load(Ordering::Relaxed);
+                    let percent = match total {
+                        Some(total) if total > 0 => {
+                            format!(" ({}%)", (flashed * 100 / total).min(100))
+                        }
+                        _ => String::new(),
+                    };
+                    info!(
+                        "Flash alive, {} written{} after {} seconds",
+                        format_size_with_unit(flashed),
+                        percent,
+                        elapsed
+                    );
+                    Logger::flush();
+                }
+            });
+
+        let thread = match thread {
+            Ok(thread) => Some(thread),
+            Err(why) => {
+                warn!("Failed to start the flash heartbeat, error: {:?}", why);
+                None
+            }
+        };
+
+        Heartbeat { stop, thread }
+    }
+
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::debug;
+
+use crate::common::{
+    error::{Result, ToError},
+    format_size_with_unit,
+};
+
+// compressed size is estimated from samples spread over the image
+const EST_SAMPLE_COUNT: u64 = 16;
+const EST_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+/// Estimate the size of the image compressed with zstd at the given level by compressing samples
+/// taken at evenly spaced offsets, the estimate includes a margin of 10%
+pub(crate) fn estimate_compressed_size(image_path: &Path, level: i32) -> Result<u64> {
+    let mut file = File::open(image_path).upstream_with_context(&format!(
+        "Failed to open image file '{}'",
+        image_path.display()
+    ))?;
+
+    let image_size = file
+        .metadata()
+        .upstream_with_context(&format!(
+            "Failed to retrieve image size for '{}'",
+            image_path.display()
+        ))?
+        .len();
+
+    if image_size == 0 {
+        return Ok(0);
+    }
+
+    let mut buffer: Vec<u8> = vec![0; EST_SAMPLE_SIZE as usize];
+    let mut sampled: u64 = 0;
+    let mut compressed: u64 = 0;
+
+    let stride = image_size / EST_SAMPLE_COUNT;
+    for sample in 0..EST_SAMPLE_COUNT {
+        file.seek(SeekFrom::Start(sample * stride))
+            .upstream_with_context(&format!(
+                "Failed to seek in image file '{}'",
+                image_path.display()
+            ))?;
+        let bytes_read = (&mut file)
+            .take(EST_SAMPLE_SIZE)
+            .read(&mut buffer)
+            .upstream_with_context(&format!(
+                "Failed to read from image file '{}'",
+                image_path.display()
+            ))?;
+        if bytes_read == 0 {
+            continue;
+        }
+        sampled += bytes_read as u64;
+        compressed += zstd::stream::encode_all(&buffer[0..bytes_read], level)
+            .upstream_with_context("Failed to compress image sample")?
+            .len() as u64;
+    }
+
+    if sampled == 0 {
+        return Ok(image_size);
+    }
+
+    let estimate = image_size * compressed / sampled;
+    let estimate = std::cmp::min(estimate + estimate / 10, image_size);
+    debug!(
+        "estimate_compressed_size: image size {}, estimated compressed size {}",
+        format_size_with_unit(image_size),
+        format_size_with_unit(estimate)
+    );
+    Ok(estimate)
+}
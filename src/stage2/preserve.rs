@@ -0,0 +1,211 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use log::{error, info};
+
+use crate::common::{
+    disk_util::{Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
+    error::{Error, ErrorKind, Result, ToError},
+    format_size_with_unit, path_append,
+    stage2_config::{PreservePart, Stage2Config},
+};
+
+use super::{copy_stream_with_mem_check, get_device_size, TRANSFER_DIR};
+
+/// path of the saved contents of the partition in tmpfs
+fn preserve_path(part: &PreservePart) -> PathBuf {
+    path_append(TRANSFER_DIR, &format!("preserve-{:02}.img", part.index))
+}
+
+/// Memory needed to save the partitions that are not left in place
+pub(crate) fn get_preserve_size(s2_cfg: &Stage2Config) -> u64 {
+    s2_cfg
+        .preserve_parts
+        .iter()
+        .filter(|part| !part.in_place)
+        .map(|part| part.size)
+        .sum()
+}
+
+/// Copy the partitions to preserve from the flash device to tmpfs, the partitions must be
+/// unmounted
+pub(crate) fn save_partitions(s2_cfg: &Stage2Config) -> Result<()> {
+    let device_path = &s2_cfg.flash_dev;
+    for part in &s2_cfg.preserve_parts {
+        if part.in_place {
+            info!(
+                "Partition '{}' (partition {}) is left in place on '{}'",
+                part.name,
+                part.index,
+                device_path.display()
+            );
+            continue;
+        }
+
+        let mut device = File::open(device_path).upstream_with_context(&format!(
+            "Failed to open '{}' for reading",
+            device_path.display()
+        ))?;
+        device
+            .seek(SeekFrom::Start(part.start))
+            .upstream_with_context(&format!(
+                "Failed to seek to offset 0x{:x} on '{}'",
+                part.start,
+                device_path.display()
+            ))?;
+
+        let to_path = preserve_path(part);
+        let mut to_file = File::create(&to_path).upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            to_path.display()
+        ))?;
+        let saved =
+            copy_stream_with_mem_check(&mut device.take(part.size), &mut to_file, &to_path)?;
+        if saved != part.size {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Read {} of {} from partition '{}' on '{}'",
+                    format_size_with_unit(saved),
+                    format_size_with_unit(part.size),
+                    part.name,
+                    device_path.display()
+                ),
+            ));
+        }
+
+        info!(
+            "Saved partition '{}' (partition {}, {}) to '{}'",
+            part.name,
+            part.index,
+            format_size_with_unit(saved),
+            to_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Offset and size in bytes of the partitions on the flashed device by partition number, slice
+/// manifests are laid out the same way as when the device was partitioned
+fn get_target_parts(s2_cfg: &Stage2Config) -> Result<Vec<(usize, u64, u64)>> {
+    if let Some(ref slices) = s2_cfg.image_slices {
+        return Ok(slices
+            .layout(get_device_size(&s2_cfg.flash_dev)?)?
+            .iter()
+            .enumerate()
+            .map(|(index, layout)| (index + 1, layout.start, layout.size))
+            .collect());
+    }
+
+    let mut disk = Disk::from_drive_file_at(&s2_cfg.flash_dev, None, s2_cfg.flash_offset)?;
+    let partitions: Vec<PartInfo> = PartitionIterator::new(&mut disk)?.collect();
+    Ok(partitions
+        .iter()
+        .map(|part| {
+            (
+                part.index,
+                s2_cfg.flash_offset + part.start_lba * DEF_BLOCK_SIZE as u64,
+                part.num_sectors * DEF_BLOCK_SIZE as u64,
+            )
+        })
+        .collect())
+}
+
+fn restore_partition(
+    s2_cfg: &Stage2Config,
+    part: &PreservePart,
+    target_start: u64,
+    target_size: u64,
+) -> Result<()> {
+    let device_path = &s2_cfg.flash_dev;
+    if target_size < part.size {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Partition '{}' of {} does not fit into partition {} of {} of the flashed image",
+                part.name,
+                format_size_with_unit(part.size),
+                part.index,
+                format_size_with_unit(target_size)
+            ),
+        ));
+    }
+
+    let from_path = preserve_path(part);
+    let mut from_file = File::open(&from_path).upstream_with_context(&format!(
+        "Failed to open '{}' for reading",
+        from_path.display()
+    ))?;
+
+    let mut device = OpenOptions::new()
+        .write(true)
+        .open(device_path)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            device_path.display()
+        ))?;
+    device
+        .seek(SeekFrom::Start(target_start))
+        .upstream_with_context(&format!(
+            "Failed to seek to offset 0x{:x} on '{}'",
+            target_start,
+            device_path.display()
+        ))?;
+    let written = io::copy(&mut from_file, &mut device).upstream_with_context(&format!(
+        "Failed to write partition '{}' to '{}'",
+        part.name,
+        device_path.display()
+    ))?;
+    device
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", device_path.display()))?;
+
+    info!(
+        "Restored partition '{}' ({}) to partition {} at offset 0x{:x} of '{}'",
+        part.name,
+        format_size_with_unit(written),
+        part.index,
+        target_start,
+        device_path.display()
+    );
+    Ok(())
+}
+
+/// Write the saved partitions to the partitions with the same number on the flashed device. All
+/// partitions are attempted before failures are reported.
+pub(crate) fn restore_partitions(s2_cfg: &Stage2Config) -> Result<()> {
+    let target_parts = get_target_parts(s2_cfg)?;
+
+    let mut failed: Vec<&str> = Vec::new();
+    for part in s2_cfg.preserve_parts.iter().filter(|part| !part.in_place) {
+        let res = if let Some((_, start, size)) = target_parts
+            .iter()
+            .find(|(index, _, _)| *index == part.index)
+        {
+            restore_partition(s2_cfg, part, *start, *size)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!("The flashed image has no partition {}", part.index),
+            ))
+        };
+
+        if let Err(why) = res {
+            error!(
+                "Failed to restore partition '{}', error: {}",
+                part.name, why
+            );
+            failed.push(&part.name);
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Failed to restore partitions: {}", failed.join(", ")),
+        ))
+    }
+}
@@ -0,0 +1,375 @@
+use std::convert::TryInto;
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::{error, info, warn};
+use openssl::sha::Sha256;
+
+use crate::common::{
+    error::{Error, ErrorKind, Result, ToError},
+    format_size_with_unit,
+    image_format::ImageFormat,
+    stage2_config::Stage2Config,
+};
+
+use super::{
+    delta_flash::hash_device, fill_buffer, get_device_size, heartbeat::add_flashed_bytes,
+    open_flash_image, FlashState,
+};
+
+const RESUME_CHUNK_SIZE: usize = 1024 * 1024;
+// the progress is recorded after this many bytes have been written
+const MARKER_INTERVAL: u64 = 64 * 1024 * 1024;
+// the marker is kept in the last block of this size on the flash device
+const MARKER_SIZE: u64 = 4096;
+const MARKER_MAGIC: &[u8; 8] = b"TKRESUME";
+const MARKER_LEN: usize = 64;
+
+/// The progress of an interrupted flash, kept at the end of the flash device
+#[derive(Debug, PartialEq)]
+struct ResumeMarker {
+    flash_offset: u64,
+    image_size: u64,
+    written: u64,
+    hash: [u8; 32],
+}
+
+impl ResumeMarker {
+    fn to_bytes(&self) -> [u8; MARKER_LEN] {
+        let mut bytes = [0u8; MARKER_LEN];
+        bytes[0..8].copy_from_slice(MARKER_MAGIC);
+        bytes[8..16].copy_from_slice(&self.flash_offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.image_size.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.written.to_le_bytes());
+        bytes[32..64].copy_from_slice(&self.hash);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<ResumeMarker> {
+        if bytes.len() < MARKER_LEN || &bytes[0..8] != MARKER_MAGIC {
+            return None;
+        }
+        let read_u64 = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[32..64]);
+        Some(ResumeMarker {
+            flash_offset: read_u64(8),
+            image_size: read_u64(16),
+            written: read_u64(24),
+            hash,
+        })
+    }
+}
+
+fn seek_to(device: &mut File, offset: u64, target_path: &Path) -> Result<()> {
+    device
+        .seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!(
+            "Failed to seek to offset 0x{:x} on '{}'",
+            offset,
+            target_path.display()
+        ))?;
+    Ok(())
+}
+
+fn read_marker(
+    device: &mut File,
+    target_path: &Path,
+    marker_pos: u64,
+) -> Result<Option<ResumeMarker>> {
+    seek_to(device, marker_pos, target_path)?;
+    let mut bytes = [0u8; MARKER_LEN];
+    device
+        .read_exact(&mut bytes)
+        .upstream_with_context(&format!(
+            "Failed to read the flash progress from '{}'",
+            target_path.display()
+        ))?;
+    Ok(ResumeMarker::from_bytes(&bytes))
+}
+
+/// Write the marker, or clear the marker block if marker is None
+fn write_marker(
+    device: &mut File,
+    target_path: &Path,
+    marker_pos: u64,
+    marker: Option<&ResumeMarker>,
+) -> Result<()> {
+    let mut block = vec![0u8; MARKER_SIZE as usize];
+    if let Some(marker) = marker {
+        block[0..MARKER_LEN].copy_from_slice(&marker.to_bytes());
+    }
+    seek_to(device, marker_pos, target_path)?;
+    device.write_all(&block).upstream_with_context(&format!(
+        "Failed to write the flash progress to '{}'",
+        target_path.display()
+    ))?;
+    device
+        .sync_data()
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))
+}
+
+/// Hash the first size bytes of the image, None if the image is shorter
+fn hash_image_prefix(decoder: &mut dyn Read, size: u64) -> Result<Option<Sha256>> {
+    let mut buffer: Vec<u8> = vec![0; RESUME_CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut remaining = size;
+    while remaining > 0 {
+        let to_read = std::cmp::min(remaining, RESUME_CHUNK_SIZE as u64) as usize;
+        let img_read = fill_buffer(&mut buffer[0..to_read], decoder)?;
+        if img_read < to_read {
+            return Ok(None);
+        }
+        hasher.update(&buffer[0..img_read]);
+        remaining -= img_read as u64;
+    }
+    Ok(Some(hasher))
+}
+
+/// Check that the recorded part of the image is the same as the start of this image and that
+/// it is intact on the flash device. Returns the hasher over the recorded part on success.
+fn verify_progress(
+    device: &mut File,
+    target_path: &Path,
+    decoder: &mut dyn Read,
+    marker: &ResumeMarker,
+) -> Result<Option<Sha256>> {
+    let hasher = match hash_image_prefix(decoder, marker.written)? {
+        Some(hasher) if hasher.clone().finish() == marker.hash => hasher,
+        _ => {
+            warn!("Resumable flash: the recorded progress does not match the image");
+            return Ok(None);
+        }
+    };
+
+    if hash_device(device, target_path, marker.flash_offset, marker.written)? == marker.hash {
+        Ok(Some(hasher))
+    } else {
+        warn!(
+            "Resumable flash: the data written to '{}' does not match the recorded progress",
+            target_path.display()
+        );
+        Ok(None)
+    }
+}
+
+/// Write the image from written on and record the progress every MARKER_INTERVAL bytes
+fn write_image(
+    device: &mut File,
+    target_path: &Path,
+    decoder: &mut dyn Read,
+    marker_pos: u64,
+    marker: &mut ResumeMarker,
+    mut hasher: Sha256,
+) -> Result<()> {
+    seek_to(device, marker.flash_offset + marker.written, target_path)?;
+    let mut buffer: Vec<u8> = vec![0; RESUME_CHUNK_SIZE];
+    let mut last_marker = marker.written;
+
+    loop {
+        let img_read = fill_buffer(&mut buffer, decoder)?;
+        if img_read == 0 {
+            break;
+        }
+
+        if marker.flash_offset + marker.written + img_read as u64 > marker_pos {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The image does not fit on '{}' in front of the flash progress marker",
+                    target_path.display()
+                ),
+            ));
+        }
+
+        device
+            .write_all(&buffer[0..img_read])
+            .upstream_with_context(&format!(
+                "Failed to write to '{}' at offset 0x{:x}",
+                target_path.display(),
+                marker.flash_offset + marker.written
+            ))?;
+        hasher.update(&buffer[0..img_read]);
+        marker.written += img_read as u64;
+        add_flashed_bytes(img_read as u64);
+
+        if marker.written - last_marker >= MARKER_INTERVAL {
+            // the data has to be on the device before the marker claims it is
+            device
+                .sync_data()
+                .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))?;
+            marker.hash = hasher.clone().finish();
+            write_marker(device, target_path, marker_pos, Some(marker))?;
+            seek_to(device, marker.flash_offset + marker.written, target_path)?;
+            last_marker = marker.written;
+        }
+
+        if img_read < RESUME_CHUNK_SIZE {
+            break;
+        }
+    }
+
+    device
+        .sync_data()
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))
+}
+
+/// Flash the image and record the progress in the last block of the flash device. If a previous
+/// flash of the same image was interrupted, for example by a power loss, the part written before
+/// is verified against the image and flashing resumes after it.
+pub(crate) fn flash_resumable(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    s2_config: &Stage2Config,
+) -> FlashState {
+    let flash_offset = s2_config.flash_offset;
+    let setup = || -> Result<(File, u64, u64)> {
+        let image_size = metadata(image_path)
+            .upstream_with_context(&format!(
+                "Failed to read metadata of '{}'",
+                image_path.display()
+            ))?
+            .len();
+        let dev_size = get_device_size(target_path)?;
+        if dev_size < MARKER_SIZE * 2 {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("The device '{}' is too small", target_path.display()),
+            ));
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(target_path)
+            .upstream_with_context(&format!(
+                "Failed to open '{}' for writing",
+                target_path.display()
+            ))?;
+        Ok((
+            device,
+            image_size,
+            (dev_size - MARKER_SIZE) / MARKER_SIZE * MARKER_SIZE,
+        ))
+    };
+
+    let (mut device, image_size, marker_pos) = match setup() {
+        Ok(res) => res,
+        Err(why) => {
+            error!("Resumable flash: {}", why);
+            return FlashState::FailRecoverable;
+        }
+    };
+
+    let mut decoder = match open_flash_image(image_path, image_format, &s2_config.flash_transforms)
+    {
+        Ok(decoder) => decoder,
+        Err(why) => {
+            error!("Resumable flash: {}", why);
+            return FlashState::FailRecoverable;
+        }
+    };
+
+    let prev_marker = match read_marker(&mut device, target_path, marker_pos) {
+        Ok(prev_marker) => prev_marker,
+        Err(why) => {
+            warn!("Resumable flash: {}", why);
+            None
+        }
+    };
+
+    let mut resumed = None;
+    if let Some(prev_marker) = prev_marker {
+        if prev_marker.flash_offset == flash_offset && prev_marker.image_size == image_size {
+            info!(
+                "Resumable flash: verifying {} written by an interrupted flash",
+                format_size_with_unit(prev_marker.written)
+            );
+            match verify_progress(&mut device, target_path, &mut *decoder, &prev_marker) {
+                Ok(Some(hasher)) => resumed = Some((prev_marker, hasher)),
+                Ok(None) => (),
+                Err(why) => warn!("Resumable flash: {}", why),
+            }
+        } else {
+            warn!("Resumable flash: the recorded progress belongs to a different image");
+        }
+
+        if resumed.is_none() {
+            decoder = match open_flash_image(image_path, image_format, &s2_config.flash_transforms)
+            {
+                Ok(decoder) => decoder,
+                Err(why) => {
+                    error!("Resumable flash: {}", why);
+                    return FlashState::FailRecoverable;
+                }
+            };
+        }
+    }
+
+    let (mut marker, hasher) = if let Some((prev_marker, hasher)) = resumed {
+        info!(
+            "Resumable flash: resuming at {} of the image",
+            format_size_with_unit(prev_marker.written)
+        );
+        (prev_marker, hasher)
+    } else {
+        (
+            ResumeMarker {
+                flash_offset,
+                image_size,
+                written: 0,
+                hash: [0; 32],
+            },
+            Sha256::new(),
+        )
+    };
+
+    let resumed_at = marker.written;
+    let res = write_image(
+        &mut device,
+        target_path,
+        &mut *decoder,
+        marker_pos,
+        &mut marker,
+        hasher,
+    )
+    .and_then(|_| write_marker(&mut device, target_path, marker_pos, None));
+
+    match res {
+        Ok(_) => {
+            info!(
+                "Resumable flash: wrote {} of {} to '{}'",
+                format_size_with_unit(marker.written - resumed_at),
+                format_size_with_unit(marker.written),
+                target_path.display()
+            );
+            FlashState::Success(marker.written)
+        }
+        Err(why) => {
+            error!("Resumable flash: {}", why);
+            if marker.written > 0 {
+                FlashState::FailNonRecoverable
+            } else {
+                FlashState::FailRecoverable
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_bytes() {
+        let marker = ResumeMarker {
+            flash_offset: 0x10000,
+            image_size: 1234567,
+            written: 64 * 1024 * 1024,
+            hash: [0xa5; 32],
+        };
+        assert_eq!(ResumeMarker::from_bytes(&marker.to_bytes()), Some(marker));
+        assert_eq!(ResumeMarker::from_bytes(&[0u8; MARKER_LEN]), None);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc::{c_int, gmtime_r, ioctl, time, time_t, tm};
+use log::info;
+
+use crate::common::{
+    defs::IoctlReq,
+    error::{Error, ErrorKind, Result, ToError},
+};
+
+const RTC_DEVICE: &str = "/dev/rtc0";
+// _IOW('p', 0x0a, struct rtc_time)
+const IOCTL_RTC_SET_TIME: IoctlReq = 0x4024_700a;
+
+/// struct rtc_time from linux/rtc.h, only read by the kernel
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct RtcTime {
+    tm_sec: c_int,
+    tm_min: c_int,
+    tm_hour: c_int,
+    tm_mday: c_int,
+    tm_mon: c_int,
+    tm_year: c_int,
+    tm_wday: c_int,
+    tm_yday: c_int,
+    tm_isdst: c_int,
+}
+
+/// Write the system time to the hardware clock in UTC, so the flashed OS starts with the time
+/// of the host even before it can sync the time over the network
+pub(crate) fn set_rtc_from_system() -> Result<()> {
+    let now: time_t = unsafe { time(ptr::null_mut()) };
+    let mut utc: tm = unsafe { mem::zeroed() };
+    if unsafe { gmtime_r(&now, &mut utc) }.is_null() {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to convert the system time to UTC, error: {}",
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+
+    let rtc_time = RtcTime {
+        tm_sec: utc.tm_sec,
+        tm_min: utc.tm_min,
+        tm_hour: utc.tm_hour,
+        tm_mday: utc.tm_mday,
+        tm_mon: utc.tm_mon,
+        tm_year: utc.tm_year,
+        tm_wday: utc.tm_wday,
+        tm_yday: utc.tm_yday,
+        ..Default::default()
+    };
+
+    let rtc_file = OpenOptions::new()
+        .read(true)
+        .open(RTC_DEVICE)
+        .upstream_with_context(&format!("Failed to open RTC device '{}'", RTC_DEVICE))?;
+
+    let ioctl_res = unsafe { ioctl(rtc_file.as_raw_fd(), IOCTL_RTC_SET_TIME, &rtc_time) };
+    if ioctl_res == 0 {
+        info!(
+            "Set the hardware clock to {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            utc.tm_year + 1900,
+            utc.tm_mon + 1,
+            utc.tm_mday,
+            utc.tm_hour,
+            utc.tm_min,
+            utc.tm_sec
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "RTC_SET_TIME IOCTRL to '{}' failed with error: {}",
+                RTC_DEVICE,
+                io::Error::last_os_error()
+            ),
+        ))
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+/// Detects a flash device whose write throughput stays below a minimum for a whole window,
+/// a sign of failing media that would otherwise make the flash appear to hang for hours
+pub(crate) struct SpeedGuard {
+    min_speed: u64,
+    window: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl SpeedGuard {
+    pub fn new(min_speed: u64, window: u64) -> SpeedGuard {
+        SpeedGuard {
+            min_speed,
+            window: Duration::from_secs(window),
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Update with the total number of bytes written, returns the throughput of the last
+    /// window in bytes/sec if it was below the minimum
+    pub fn check(&mut self, tot_bytes: u64) -> Option<u64> {
+        self.check_at(tot_bytes, Instant::now())
+    }
+
+    fn check_at(&mut self, tot_bytes: u64, now: Instant) -> Option<u64> {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < self.window {
+            return None;
+        }
+
+        let speed = (tot_bytes - self.window_bytes) * 1000 / (elapsed.as_millis() as u64).max(1);
+        self.window_start = now;
+        self.window_bytes = tot_bytes;
+        if speed < self.min_speed {
+            Some(speed)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_guard() {
+        let mut guard = SpeedGuard::new(1024 * 1024, 10);
+        let start = guard.window_start;
+
+        assert_eq!(guard.check_at(1024, start + Duration::from_secs(5)), None);
+        assert_eq!(
+            guard.check_at(20 * 1024 * 1024, start + Duration::from_secs(10)),
+            None
+        );
+        assert_eq!(
+            guard.check_at(25 * 1024 * 1024, start + Duration::from_secs(20)),
+            Some(512 * 1024)
+        );
+    }
+}
@@ -0,0 +1,796 @@
+use std::fs::{create_dir, read_dir, read_to_string, write, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::{debug, error, info, warn};
+use nix::mount::{mount, umount, MsFlags};
+use rand::{thread_rng, Rng};
+use regex::RegexBuilder;
+
+use crate::{
+    common::{
+        call, crc32,
+        defs::{BALENA_BOOT_FSTYPE, BALENA_PART_MP, NIX_NONE, TUNE2FS_CMD},
+        dir_exists,
+        disk_util::{Disk, LabelType, PartitionIterator, DEF_BLOCK_SIZE},
+        error::{Error, ErrorKind, Result, ToError},
+        loop_device::LoopDevice,
+    },
+    stage2::get_partition_infos,
+};
+
+const MBR_DISK_SIG_OFFSET: u64 = 0x1B8;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HDR_SIZE_OFFSET: usize = 12;
+const GPT_HDR_CRC_OFFSET: usize = 16;
+const GPT_ALT_LBA_OFFSET: usize = 32;
+const GPT_DISK_GUID_OFFSET: usize = 56;
+const GPT_ENTRIES_LBA_OFFSET: usize = 72;
+const GPT_NUM_ENTRIES_OFFSET: usize = 80;
+const GPT_ENTRY_SIZE_OFFSET: usize = 84;
+const GPT_ENTRIES_CRC_OFFSET: usize = 88;
+const GPT_ENTRY_GUID_OFFSET: usize = 16;
+const GPT_ENTRY_START_OFFSET: usize = 32;
+const GPT_ENTRY_END_OFFSET: usize = 40;
+const GUID_SIZE: usize = 16;
+
+const EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_MAGIC_OFFSET: usize = 0x38;
+const EXT_UUID_OFFSET: usize = 0x68;
+const EXT_MAGIC: u16 = 0xEF53;
+const FAT_BOOT_SIG_OFFSET: usize = 0x1FE;
+const FAT16_FSTYPE_OFFSET: usize = 0x36;
+const FAT16_VOL_ID_OFFSET: u64 = 0x27;
+const FAT32_FSTYPE_OFFSET: usize = 0x52;
+const FAT32_VOL_ID_OFFSET: u64 = 0x43;
+const BTRFS_MAGIC_OFFSET: usize = 0x1_0040;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+const FS_PROBE_SIZE: usize = 0x1_0048;
+// boot configuration files are small text files, anything larger is not rewritten
+const BOOT_CONFIG_MAX_SIZE: u64 = 1024 * 1024;
+const BOOT_CONFIG_EXTENSIONS: [&str; 4] = ["txt", "cfg", "conf", "env"];
+
+#[derive(Debug)]
+struct FsRegion {
+    index: usize,
+    start: u64,
+    size: u64,
+}
+
+/// An id that was changed, formatted as it is referenced in boot configurations, e.g. in
+/// root=PARTUUID= or root=UUID= kernel parameters
+#[derive(Debug, PartialEq)]
+struct IdChange {
+    old: String,
+    new: String,
+}
+
+/// The result of rewriting a GPT header and its partition entries
+struct GptRewrite {
+    alt_lba: u64,
+    regions: Vec<FsRegion>,
+    part_guids: Vec<[u8; GUID_SIZE]>,
+    changes: Vec<IdChange>,
+}
+
+fn le_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn le_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// format a UUID stored in byte order, as ext file systems do
+fn format_uuid(bytes: &[u8; GUID_SIZE]) -> String {
+    format!(
+        "{}-{}-{}-{}-{}",
+        to_hex(&bytes[0..4]),
+        to_hex(&bytes[4..6]),
+        to_hex(&bytes[6..8]),
+        to_hex(&bytes[8..10]),
+        to_hex(&bytes[10..16])
+    )
+}
+
+/// format a GUID stored in the mixed endian GPT layout
+fn format_guid(bytes: &[u8; GUID_SIZE]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        le_u32(bytes, 0),
+        le_u16(bytes, 4),
+        le_u16(bytes, 6),
+        to_hex(&bytes[8..10]),
+        to_hex(&bytes[10..16])
+    )
+}
+
+fn format_fat_id(id: &[u8; 4]) -> String {
+    format!("{:02X}{:02X}-{:02X}{:02X}", id[3], id[2], id[1], id[0])
+}
+
+fn read_at<F: Read + Seek>(file: &mut F, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!("Failed to seek to offset 0x{:x}", offset))?;
+    file.read_exact(buffer)
+        .upstream_with_context(&format!("Failed to read from offset 0x{:x}", offset))
+}
+
+fn write_at<F: Write + Seek>(file: &mut F, offset: u64, buffer: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!("Failed to seek to offset 0x{:x}", offset))?;
+    file.write_all(buffer)
+        .upstream_with_context(&format!("Failed to write to offset 0x{:x}", offset))
+}
+
+/// give the flashed image a new disk id / GUIDs and new file system UUIDs and rewrite the
+/// references to the old ids in the boot configuration
+pub(crate) fn randomize_uuids(device: &Path, flash_offset: u64) -> Result<()> {
+    let mut device_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    let mut changes: Vec<IdChange> = Vec::new();
+    let regions = match Disk::from_drive_file_at(device, None, flash_offset)?.get_label()? {
+        LabelType::Dos => {
+            changes.push(randomize_mbr_id(&mut device_file, flash_offset)?);
+            let mut disk = Disk::from_drive_file_at(device, None, flash_offset)?;
+            PartitionIterator::new(&mut disk)?
+                .map(|part| FsRegion {
+                    index: part.index,
+                    start: flash_offset + part.start_lba * DEF_BLOCK_SIZE as u64,
+                    size: part.num_sectors * DEF_BLOCK_SIZE as u64,
+                })
+                .collect()
+        }
+        LabelType::GPT => {
+            let (regions, gpt_changes) = randomize_gpt_guids(&mut device_file, flash_offset)?;
+            changes.extend(gpt_changes);
+            regions
+        }
+        LabelType::Other => {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!("No partition table found on device '{}'", device.display()),
+            ))
+        }
+    };
+
+    device_file
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync device '{}'", device.display()))?;
+
+    for region in regions {
+        match randomize_fs_uuid(&mut device_file, device, &region) {
+            Ok(Some(change)) => changes.push(change),
+            Ok(None) => (),
+            Err(why) => error!(
+                "Failed to change file system UUID on partition {}, error: {:?}",
+                region.index, why
+            ),
+        }
+    }
+
+    rewrite_boot_config(device, flash_offset, &changes)
+}
+
+/// Replace the old ids in the text of a boot configuration, ignoring case as hex digits are
+/// written either way. Returns None if the text does not reference any of them
+fn replace_ids(text: &str, changes: &[IdChange]) -> Option<String> {
+    let mut replaced = text.to_string();
+    let mut found = false;
+    for change in changes {
+        let id_regex = RegexBuilder::new(&regex::escape(&change.old))
+            .case_insensitive(true)
+            .build()
+            .expect("an escaped id is a valid regex");
+        if id_regex.is_match(&replaced) {
+            found = true;
+            replaced = id_regex
+                .replace_all(&replaced, change.new.as_str())
+                .to_string();
+        }
+    }
+
+    if found {
+        Some(replaced)
+    } else {
+        None
+    }
+}
+
+/// rewrite references to the changed ids in the configuration files in dir and below
+fn rewrite_config_files(dir: &Path, changes: &[IdChange]) -> Result<()> {
+    for entry in read_dir(dir)
+        .upstream_with_context(&format!("Failed to read directory '{}'", dir.display()))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry in '{}'",
+            dir.display()
+        ))?;
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .upstream_with_context(&format!("Failed to read metadata of '{}'", path.display()))?;
+
+        if metadata.is_dir() {
+            rewrite_config_files(&path, changes)?;
+            continue;
+        }
+
+        let is_config = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map_or(false, |ext| {
+                BOOT_CONFIG_EXTENSIONS.contains(&ext.to_lowercase().as_str())
+            })
+            || path
+                .file_name()
+                .map_or(false, |name| name.to_string_lossy().starts_with("uEnv"));
+        if !metadata.is_file() || !is_config || metadata.len() > BOOT_CONFIG_MAX_SIZE {
+            continue;
+        }
+
+        // binary files that happen to have a matching name are left alone
+        let text = if let Ok(text) = read_to_string(&path) {
+            text
+        } else {
+            continue;
+        };
+
+        if let Some(replaced) = replace_ids(&text, changes) {
+            write(&path, replaced)
+                .upstream_with_context(&format!("Failed to write '{}'", path.display()))?;
+            info!("Rewrote the changed ids in '{}'", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Mount the boot partition of the flashed image and rewrite references to the changed ids,
+/// e.g. root=PARTUUID= in cmdline.txt or grub.cfg. Without that the image might not find its
+/// root file system.
+fn rewrite_boot_config(device: &Path, flash_offset: u64, changes: &[IdChange]) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let (boot_part, _) = get_partition_infos(device, flash_offset)?;
+
+    if !dir_exists(BALENA_PART_MP)? {
+        create_dir(BALENA_PART_MP).upstream_with_context(&format!(
+            "Failed to create balena partition mountpoint: '{}'",
+            BALENA_PART_MP
+        ))?;
+    }
+
+    let mut loop_device = LoopDevice::get_free(true)?;
+    loop_device.setup(
+        device,
+        Some(flash_offset + boot_part.start_lba * DEF_BLOCK_SIZE as u64),
+        Some(boot_part.num_sectors * DEF_BLOCK_SIZE as u64),
+    )?;
+
+    mount(
+        Some(loop_device.get_path()),
+        BALENA_PART_MP,
+        Some(BALENA_BOOT_FSTYPE.as_bytes()),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount {} on {}",
+        loop_device.get_path().display(),
+        BALENA_PART_MP
+    ))?;
+
+    let res = rewrite_config_files(Path::new(BALENA_PART_MP), changes);
+
+    umount(BALENA_PART_MP).upstream_with_context("Failed to unmount boot partition")?;
+    loop_device.unset()?;
+
+    res
+}
+
+fn randomize_mbr_id<F: Read + Write + Seek>(
+    device_file: &mut F,
+    flash_offset: u64,
+) -> Result<IdChange> {
+    let mut old_id = [0u8; 4];
+    read_at(device_file, flash_offset + MBR_DISK_SIG_OFFSET, &mut old_id)?;
+
+    let mut new_id = [0u8; 4];
+    thread_rng().fill(&mut new_id);
+    write_at(device_file, flash_offset + MBR_DISK_SIG_OFFSET, &new_id)?;
+
+    info!(
+        "Changed disk id from 0x{:08x} to 0x{:08x}",
+        u32::from_le_bytes(old_id),
+        u32::from_le_bytes(new_id)
+    );
+
+    // the PARTUUID of a MBR partition is the disk id followed by the partition number
+    Ok(IdChange {
+        old: format!("{:08x}-", u32::from_le_bytes(old_id)),
+        new: format!("{:08x}-", u32::from_le_bytes(new_id)),
+    })
+}
+
+/// replace the disk GUID and all partition GUIDs in the primary and backup GPT,
+/// returns the partitions found and the changed partition GUIDs
+fn randomize_gpt_guids<F: Read + Write + Seek>(
+    device_file: &mut F,
+    flash_offset: u64,
+) -> Result<(Vec<FsRegion>, Vec<IdChange>)> {
+    let mut disk_guid = [0u8; GUID_SIZE];
+    thread_rng().fill(&mut disk_guid);
+
+    let primary = rewrite_gpt(device_file, flash_offset, 1, &disk_guid, None)?;
+    rewrite_gpt(
+        device_file,
+        flash_offset,
+        primary.alt_lba,
+        &disk_guid,
+        Some(&primary.part_guids),
+    )?;
+
+    Ok((primary.regions, primary.changes))
+}
+
+/// rewrite GUIDs in the GPT header at header_lba and its partition entries. New partition
+/// GUIDs are created unless given
+fn rewrite_gpt<F: Read + Write + Seek>(
+    device_file: &mut F,
+    flash_offset: u64,
+    header_lba: u64,
+    disk_guid: &[u8; GUID_SIZE],
+    part_guids: Option<&[[u8; GUID_SIZE]]>,
+) -> Result<GptRewrite> {
+    let header_offset = flash_offset + header_lba * DEF_BLOCK_SIZE as u64;
+    let mut header = [0u8; DEF_BLOCK_SIZE];
+    read_at(device_file, header_offset, &mut header)?;
+
+    if &header[0..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("No GPT header found at LBA {}", header_lba),
+        ));
+    }
+
+    let header_size = le_u32(&header, GPT_HDR_SIZE_OFFSET) as usize;
+    if header_size < GPT_ENTRIES_CRC_OFFSET + 4 || header_size > DEF_BLOCK_SIZE {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Invalid GPT header size {} at LBA {}",
+                header_size, header_lba
+            ),
+        ));
+    }
+
+    let alt_lba = le_u64(&header, GPT_ALT_LBA_OFFSET);
+    let entries_lba = le_u64(&header, GPT_ENTRIES_LBA_OFFSET);
+    let num_entries = le_u32(&header, GPT_NUM_ENTRIES_OFFSET) as usize;
+    let entry_size = le_u32(&header, GPT_ENTRY_SIZE_OFFSET) as usize;
+
+    let entries_offset = flash_offset + entries_lba * DEF_BLOCK_SIZE as u64;
+    let mut entries = vec![0u8; num_entries * entry_size];
+    read_at(device_file, entries_offset, &mut entries)?;
+
+    let mut regions: Vec<FsRegion> = Vec::new();
+    let mut new_guids: Vec<[u8; GUID_SIZE]> = Vec::new();
+    let mut changes: Vec<IdChange> = Vec::new();
+    for index in 0..num_entries {
+        let entry = &mut entries[index * entry_size..(index + 1) * entry_size];
+        if entry[0..GUID_SIZE].iter().all(|byte| *byte == 0) {
+            // unused entry
+            continue;
+        }
+
+        let mut guid = [0u8; GUID_SIZE];
+        if let Some(part_guids) = part_guids {
+            if let Some(part_guid) = part_guids.get(new_guids.len()) {
+                guid = *part_guid;
+            }
+        } else {
+            thread_rng().fill(&mut guid);
+        }
+
+        let guid_range = GPT_ENTRY_GUID_OFFSET..GPT_ENTRY_GUID_OFFSET + GUID_SIZE;
+        if part_guids.is_none() {
+            let mut old_guid = [0u8; GUID_SIZE];
+            old_guid.copy_from_slice(&entry[guid_range.clone()]);
+            info!(
+                "Changing GUID of partition {} from {} to {}",
+                index + 1,
+                format_guid(&old_guid),
+                format_guid(&guid)
+            );
+            changes.push(IdChange {
+                old: format_guid(&old_guid),
+                new: format_guid(&guid),
+            });
+        }
+        entry[guid_range].copy_from_slice(&guid);
+        new_guids.push(guid);
+
+        let start_lba = le_u64(entry, GPT_ENTRY_START_OFFSET);
+        let end_lba = le_u64(entry, GPT_ENTRY_END_OFFSET);
+        regions.push(FsRegion {
+            index: index + 1,
+            start: flash_offset + start_lba * DEF_BLOCK_SIZE as u64,
+            size: (end_lba + 1 - start_lba) * DEF_BLOCK_SIZE as u64,
+        });
+    }
+
+    write_at(device_file, entries_offset, &entries)?;
+
+    if part_guids.is_none() {
+        info!(
+            "Changing disk GUID from {} to {}",
+            to_hex(&header[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + GUID_SIZE]),
+            to_hex(disk_guid)
+        );
+    }
+
+    header[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + GUID_SIZE].copy_from_slice(disk_guid);
+    header[GPT_ENTRIES_CRC_OFFSET..GPT_ENTRIES_CRC_OFFSET + 4]
+        .copy_from_slice(&crc32(&entries).to_le_bytes());
+    header[GPT_HDR_CRC_OFFSET..GPT_HDR_CRC_OFFSET + 4].copy_from_slice(&[0; 4]);
+    let header_crc = crc32(&header[0..header_size]);
+    header[GPT_HDR_CRC_OFFSET..GPT_HDR_CRC_OFFSET + 4].copy_from_slice(&header_crc.to_le_bytes());
+
+    write_at(device_file, header_offset, &header)?;
+
+    debug!("rewrite_gpt: rewrote GPT header at LBA {}", header_lba);
+
+    Ok(GptRewrite {
+        alt_lba,
+        regions,
+        part_guids: new_guids,
+        changes,
+    })
+}
+
+fn randomize_fs_uuid(
+    device_file: &mut File,
+    device: &Path,
+    region: &FsRegion,
+) -> Result<Option<IdChange>> {
+    if region.size < FS_PROBE_SIZE as u64 {
+        warn!(
+            "Partition {} is too small to hold a supported file system, not changing its UUID",
+            region.index
+        );
+        return Ok(None);
+    }
+
+    let mut buffer = vec![0u8; FS_PROBE_SIZE];
+    read_at(device_file, region.start, &mut buffer)?;
+
+    let ext_sb = EXT_SUPERBLOCK_OFFSET as usize;
+    if le_u16(&buffer, ext_sb + EXT_MAGIC_OFFSET) == EXT_MAGIC {
+        Ok(Some(randomize_ext_uuid(device_file, device, region)?))
+    } else if &buffer[BTRFS_MAGIC_OFFSET..BTRFS_MAGIC_OFFSET + BTRFS_MAGIC.len()] == BTRFS_MAGIC {
+        warn!(
+            "Changing the UUID of the btrfs file system on partition {} is not supported",
+            region.index
+        );
+        Ok(None)
+    } else if buffer[FAT_BOOT_SIG_OFFSET] == 0x55 && buffer[FAT_BOOT_SIG_OFFSET + 1] == 0xAA {
+        if &buffer[FAT32_FSTYPE_OFFSET..FAT32_FSTYPE_OFFSET + 5] == b"FAT32" {
+            Ok(Some(randomize_fat_id(
+                device_file,
+                region,
+                FAT32_VOL_ID_OFFSET,
+            )?))
+        } else if &buffer[FAT16_FSTYPE_OFFSET..FAT16_FSTYPE_OFFSET + 3] == b"FAT" {
+            Ok(Some(randomize_fat_id(
+                device_file,
+                region,
+                FAT16_VOL_ID_OFFSET,
+            )?))
+        } else {
+            warn!(
+                "Unknown file system on partition {}, not changing its UUID",
+                region.index
+            );
+            Ok(None)
+        }
+    } else {
+        warn!(
+            "Unknown file system on partition {}, not changing its UUID",
+            region.index
+        );
+        Ok(None)
+    }
+}
+
+fn randomize_fat_id<F: Read + Write + Seek>(
+    device_file: &mut F,
+    region: &FsRegion,
+    id_offset: u64,
+) -> Result<IdChange> {
+    let mut old_id = [0u8; 4];
+    read_at(device_file, region.start + id_offset, &mut old_id)?;
+
+    let mut new_id = [0u8; 4];
+    thread_rng().fill(&mut new_id);
+    write_at(device_file, region.start + id_offset, &new_id)?;
+
+    info!(
+        "Changed volume id of FAT file system on partition {} from {} to {}",
+        region.index,
+        format_fat_id(&old_id),
+        format_fat_id(&new_id)
+    );
+    Ok(IdChange {
+        old: format_fat_id(&old_id),
+        new: format_fat_id(&new_id),
+    })
+}
+
+fn read_ext_uuid<F: Read + Seek>(device_file: &mut F, region: &FsRegion) -> Result<String> {
+    let mut uuid = [0u8; GUID_SIZE];
+    read_at(
+        device_file,
+        region.start + EXT_SUPERBLOCK_OFFSET + EXT_UUID_OFFSET as u64,
+        &mut uuid,
+    )?;
+    Ok(format_uuid(&uuid))
+}
+
+fn randomize_ext_uuid(
+    device_file: &mut File,
+    device: &Path,
+    region: &FsRegion,
+) -> Result<IdChange> {
+    let old_uuid = read_ext_uuid(device_file, region)?;
+
+    // tune2fs needs a block device for the partition, the kernel might not know the partitions
+    // of the flashed image, so a loop device is used
+    let mut loop_device = LoopDevice::get_free(true)?;
+    loop_device.setup(device, Some(region.start), Some(region.size))?;
+
+    let tune2fs_path = format!("/bin/{}", TUNE2FS_CMD);
+    let loop_path = loop_device.get_path().to_string_lossy().to_string();
+    let cmd_res = call(&tune2fs_path, &["-U", "random", &loop_path], true)?;
+    loop_device.unset()?;
+
+    if !cmd_res.status.success() {
+        return Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "{} failed on partition {}, stderr: {}",
+                TUNE2FS_CMD, region.index, cmd_res.stderr
+            ),
+        ));
+    }
+
+    let new_uuid = read_ext_uuid(device_file, region)?;
+    info!(
+        "Changed UUID of ext file system on partition {} from {} to {}",
+        region.index, old_uuid, new_uuid
+    );
+    Ok(IdChange {
+        old: old_uuid,
+        new: new_uuid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SECTOR: usize = DEF_BLOCK_SIZE;
+    const DISK_SECTORS: usize = 64;
+    const NUM_ENTRIES: usize = 4;
+    const ENTRY_SIZE: usize = 128;
+    const GPT_HDR_SIZE: usize = 92;
+
+    fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+        buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(buffer: &mut [u8], offset: usize, value: u64) {
+        buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn gpt_entries() -> Vec<u8> {
+        let mut entries = vec![0u8; NUM_ENTRIES * ENTRY_SIZE];
+        for (index, (start, end)) in [(34u64, 41u64), (42, 59)].iter().enumerate() {
+            let entry = &mut entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            // type and partition GUID only need to be non zero
+            entry[0..GUID_SIZE].copy_from_slice(&[0xAA; GUID_SIZE]);
+            entry[GPT_ENTRY_GUID_OFFSET..GPT_ENTRY_GUID_OFFSET + GUID_SIZE]
+                .copy_from_slice(&[index as u8 + 1; GUID_SIZE]);
+            put_u64(entry, GPT_ENTRY_START_OFFSET, *start);
+            put_u64(entry, GPT_ENTRY_END_OFFSET, *end);
+        }
+        entries
+    }
+
+    fn gpt_header(alt_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; SECTOR];
+        header[0..GPT_SIGNATURE.len()].copy_from_slice(GPT_SIGNATURE);
+        put_u32(&mut header, GPT_HDR_SIZE_OFFSET, GPT_HDR_SIZE as u32);
+        put_u64(&mut header, GPT_ALT_LBA_OFFSET, alt_lba);
+        header[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + GUID_SIZE]
+            .copy_from_slice(&[0x55; GUID_SIZE]);
+        put_u64(&mut header, GPT_ENTRIES_LBA_OFFSET, entries_lba);
+        put_u32(&mut header, GPT_NUM_ENTRIES_OFFSET, NUM_ENTRIES as u32);
+        put_u32(&mut header, GPT_ENTRY_SIZE_OFFSET, ENTRY_SIZE as u32);
+        put_u32(&mut header, GPT_ENTRIES_CRC_OFFSET, crc32(entries));
+        let header_crc = crc32(&header[0..GPT_HDR_SIZE]);
+        put_u32(&mut header, GPT_HDR_CRC_OFFSET, header_crc);
+        header
+    }
+
+    /// a disk with a primary GPT at LBA 1, its entries at LBA 2 and the backup GPT in the last
+    /// two sectors, placed at offset in the buffer
+    fn gpt_disk(offset: usize) -> Vec<u8> {
+        let mut disk = vec![0u8; offset + DISK_SECTORS * SECTOR];
+        let entries = gpt_entries();
+        let last_lba = (DISK_SECTORS - 1) as u64;
+        let mut put_sector = |lba: u64, data: &[u8]| {
+            let start = offset + lba as usize * SECTOR;
+            disk[start..start + data.len()].copy_from_slice(data);
+        };
+        put_sector(1, &gpt_header(last_lba, 2, &entries));
+        put_sector(2, &entries);
+        put_sector(last_lba - 1, &entries);
+        put_sector(last_lba, &gpt_header(1, last_lba - 1, &entries));
+        disk
+    }
+
+    /// check the CRCs of the GPT header at header_lba and return its disk GUID and entries
+    fn check_gpt(disk: &[u8], offset: usize, header_lba: usize) -> ([u8; GUID_SIZE], Vec<u8>) {
+        let start = offset + header_lba * SECTOR;
+        let mut header = disk[start..start + SECTOR].to_vec();
+        let header_crc = le_u32(&header, GPT_HDR_CRC_OFFSET);
+        put_u32(&mut header, GPT_HDR_CRC_OFFSET, 0);
+        assert_eq!(crc32(&header[0..GPT_HDR_SIZE]), header_crc);
+
+        let entries_start = offset + le_u64(&header, GPT_ENTRIES_LBA_OFFSET) as usize * SECTOR;
+        let entries = disk[entries_start..entries_start + NUM_ENTRIES * ENTRY_SIZE].to_vec();
+        assert_eq!(crc32(&entries), le_u32(&header, GPT_ENTRIES_CRC_OFFSET));
+
+        let mut disk_guid = [0u8; GUID_SIZE];
+        disk_guid.copy_from_slice(&header[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + GUID_SIZE]);
+        (disk_guid, entries)
+    }
+
+    #[test]
+    fn test_randomize_gpt_guids() {
+        const OFFSET: usize = 8 * SECTOR;
+        let mut device = Cursor::new(gpt_disk(OFFSET));
+        let (regions, changes) = randomize_gpt_guids(&mut device, OFFSET as u64).unwrap();
+        let disk = device.into_inner();
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].index, 1);
+        assert_eq!(regions[0].start, (OFFSET + 34 * SECTOR) as u64);
+        assert_eq!(regions[0].size, (8 * SECTOR) as u64);
+        assert_eq!(regions[1].start, (OFFSET + 42 * SECTOR) as u64);
+        assert_eq!(regions[1].size, (18 * SECTOR) as u64);
+
+        let (primary_guid, primary_entries) = check_gpt(&disk, OFFSET, 1);
+        let (backup_guid, backup_entries) = check_gpt(&disk, OFFSET, DISK_SECTORS - 1);
+        assert_ne!(primary_guid, [0x55; GUID_SIZE]);
+        assert_eq!(primary_guid, backup_guid);
+        assert_eq!(primary_entries, backup_entries);
+
+        assert_eq!(changes.len(), 2);
+        for (index, change) in changes.iter().enumerate() {
+            let entry = &primary_entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+            let mut guid = [0u8; GUID_SIZE];
+            guid.copy_from_slice(&entry[GPT_ENTRY_GUID_OFFSET..GPT_ENTRY_GUID_OFFSET + GUID_SIZE]);
+            assert_eq!(change.old, format_guid(&[index as u8 + 1; GUID_SIZE]));
+            assert_eq!(change.new, format_guid(&guid));
+            assert_ne!(change.old, change.new);
+            // the rest of the entry is unchanged
+            assert_eq!(le_u64(entry, GPT_ENTRY_START_OFFSET), [34, 42][index]);
+        }
+
+        // nothing outside the GPT structures was touched
+        assert!(disk[0..OFFSET + SECTOR].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_randomize_gpt_guids_no_gpt() {
+        let mut device = Cursor::new(vec![0u8; DISK_SECTORS * SECTOR]);
+        assert!(randomize_gpt_guids(&mut device, 0).is_err());
+    }
+
+    #[test]
+    fn test_randomize_mbr_id() {
+        let mut disk = vec![0u8; SECTOR];
+        disk[MBR_DISK_SIG_OFFSET as usize..MBR_DISK_SIG_OFFSET as usize + 4]
+            .copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        let mut device = Cursor::new(disk);
+        let change = randomize_mbr_id(&mut device, 0).unwrap();
+        let disk = device.into_inner();
+
+        assert_eq!(change.old, "1234abcd-");
+        let new_id = le_u32(&disk, MBR_DISK_SIG_OFFSET as usize);
+        assert_eq!(change.new, format!("{:08x}-", new_id));
+        assert!(disk[..MBR_DISK_SIG_OFFSET as usize]
+            .iter()
+            .chain(disk[MBR_DISK_SIG_OFFSET as usize + 4..].iter())
+            .all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_randomize_fat_id() {
+        let region = FsRegion {
+            index: 1,
+            start: SECTOR as u64,
+            size: SECTOR as u64,
+        };
+        let mut disk = vec![0u8; 2 * SECTOR];
+        let id_offset = SECTOR + FAT32_VOL_ID_OFFSET as usize;
+        disk[id_offset..id_offset + 4].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        let mut device = Cursor::new(disk);
+        let change = randomize_fat_id(&mut device, &region, FAT32_VOL_ID_OFFSET).unwrap();
+        let disk = device.into_inner();
+
+        assert_eq!(change.old, "1234-5678");
+        let mut new_id = [0u8; 4];
+        new_id.copy_from_slice(&disk[id_offset..id_offset + 4]);
+        assert_eq!(change.new, format_fat_id(&new_id));
+    }
+
+    #[test]
+    fn test_format_ids() {
+        // the EFI system partition type GUID as stored on disk
+        let guid = [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ];
+        assert_eq!(format_guid(&guid), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(format_uuid(&guid), "28732ac1-1ff8-d211-ba4b-00a0c93ec93b");
+    }
+
+    #[test]
+    fn test_replace_ids() {
+        let changes = vec![
+            IdChange {
+                old: "1234abcd-".to_string(),
+                new: "deadbeef-".to_string(),
+            },
+            IdChange {
+                old: "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".to_string(),
+                new: "0a1b2c3d-0000-1111-2222-333344445555".to_string(),
+            },
+        ];
+        assert_eq!(
+            replace_ids("console=tty1 root=PARTUUID=1234ABCD-02 rootwait", &changes).unwrap(),
+            "console=tty1 root=PARTUUID=deadbeef-02 rootwait"
+        );
+        assert_eq!(
+            replace_ids(
+                "linux /vmlinuz root=PARTUUID=C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+                &changes
+            )
+            .unwrap(),
+            "linux /vmlinuz root=PARTUUID=0a1b2c3d-0000-1111-2222-333344445555"
+        );
+        assert!(replace_ids("root=LABEL=resin-rootA", &changes).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc::{posix_fadvise, POSIX_FADV_DONTNEED};
+use log::{info, warn};
+
+use crate::common::{
+    error::{Result, ToError},
+    format_size_with_unit,
+    image_format::ImageFormat,
+    stage2_config::Stage2Config,
+};
+
+use super::{fill_buffer, open_flash_image};
+
+const SAMPLE_BLOCK_SIZE: usize = 1024 * 1024;
+// fixed so that the same blocks are sampled for the same image on every run
+const SAMPLE_SEED: u64 = 0x5441_4b45_4f56_4552;
+const MAX_REPORTED_MISMATCHES: usize = 10;
+
+/// xorshift64*, good enough to spread the samples over the image
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn sample(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+/// Preserved partitions are written back after flashing and differ from the image
+fn is_preserved(s2_config: &Stage2Config, start: u64, size: u64) -> bool {
+    s2_config
+        .preserve_parts
+        .iter()
+        .any(|part| start < part.start + part.size && part.start < start + size)
+}
+
+/// Read back a reproducible random sample of percent of the image blocks from the flash device
+/// and compare them to the image. The whole image is decompressed but only the sampled blocks
+/// are read from the flash device.
+pub(crate) fn verify_sample(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    s2_config: &Stage2Config,
+    percent: u64,
+) -> Result<bool> {
+    let mut decoder = open_flash_image(image_path, image_format, &s2_config.flash_transforms)?;
+    let mut device = OpenOptions::new()
+        .read(true)
+        .open(target_path)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for reading",
+            target_path.display()
+        ))?;
+
+    // the sampled blocks have to come from the device, not from the cache dd wrote through
+    if unsafe { posix_fadvise(device.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) } != 0 {
+        warn!(
+            "Failed to drop cached data of '{}', the sample might be read from the cache",
+            target_path.display()
+        );
+    }
+
+    let mut rng = SampleRng(SAMPLE_SEED);
+    let mut img_buffer: Vec<u8> = vec![0; SAMPLE_BLOCK_SIZE];
+    let mut dev_buffer: Vec<u8> = vec![0; SAMPLE_BLOCK_SIZE];
+    let mut offset: u64 = 0;
+    let mut sampled: u64 = 0;
+    let mut mismatches: usize = 0;
+
+    loop {
+        let img_read = fill_buffer(&mut img_buffer, &mut decoder)?;
+        if img_read == 0 {
+            break;
+        }
+
+        let dev_offset = s2_config.flash_offset + offset;
+        if rng.sample(percent) && !is_preserved(s2_config, dev_offset, img_read as u64) {
+            device
+                .seek(SeekFrom::Start(dev_offset))
+                .upstream_with_context(&format!(
+                    "Failed to seek to offset 0x{:x} on '{}'",
+                    dev_offset,
+                    target_path.display()
+                ))?;
+            let dev_read = fill_buffer(&mut dev_buffer[0..img_read], &mut device)?;
+            if dev_read < img_read || img_buffer[0..img_read] != dev_buffer[0..img_read] {
+                if mismatches < MAX_REPORTED_MISMATCHES {
+                    warn!(
+                        "Verify sample: block at offset 0x{:x} differs from the image",
+                        dev_offset
+                    );
+                }
+                mismatches += 1;
+            }
+            sampled += img_read as u64;
+        }
+
+        offset += img_read as u64;
+        if img_read < SAMPLE_BLOCK_SIZE {
+            break;
+        }
+    }
+
+    info!(
+        "Verify sample: compared {} of {} of the image on '{}', {} blocks differ",
+        format_size_with_unit(sampled),
+        format_size_with_unit(offset),
+        target_path.display(),
+        mismatches
+    );
+    Ok(mismatches == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rng() {
+        let mut rng1 = SampleRng(SAMPLE_SEED);
+        let mut rng2 = SampleRng(SAMPLE_SEED);
+        let picks: Vec<bool> = (0..1000).map(|_| rng1.sample(10)).collect();
+        let repeated: Vec<bool> = (0..1000).map(|_| rng2.sample(10)).collect();
+        assert_eq!(picks, repeated);
+        let count = picks.iter().filter(|pick| **pick).count();
+        assert!(count > 50 && count < 150);
+
+        let mut rng = SampleRng(SAMPLE_SEED);
+        assert!((0..100).all(|_| rng.sample(100)));
+    }
+}